@@ -0,0 +1,13 @@
+#![no_main]
+
+use cargo_flatpak::sources::{git_reference, LockFile};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &str| {
+    let Ok(lock) = toml::from_str::<LockFile>(data) else {
+        return;
+    };
+    for source in lock.package.iter().filter_map(|package| package.source.as_deref()) {
+        let _ = git_reference(source);
+    }
+});
@@ -0,0 +1,11 @@
+#![no_main]
+
+use cargo_flatpak::sources::parse_url;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &str| {
+    if let Ok((canonical, vendored)) = parse_url(data) {
+        assert_eq!((canonical.query(), canonical.fragment()), (None, None));
+        assert_eq!(vendored["git"], canonical.as_str());
+    }
+});
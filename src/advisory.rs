@@ -0,0 +1,240 @@
+use std::path::{Path, PathBuf};
+
+use rustsec::advisory::{Informational, Metadata, Severity, Versions};
+use rustsec::cargo_lock::{Package, ResolveVersion, SourceId};
+use rustsec::platforms::target::{Arch, OS};
+use rustsec::report::{Report, Settings};
+use rustsec::{Database, Lockfile};
+
+use crate::cli::{Deny, FlatpakArch};
+use crate::list::ListedPackage;
+use crate::net;
+use crate::policy::SourceKind;
+
+const ADVISORY_DB: &str = "https://github.com/rustsec/advisory-db";
+
+/// Reads the advisories of a database checkout
+pub fn load_db(db: &Path) -> anyhow::Result<Database> {
+    let database = Database::open(db).map_err(|e| anyhow::anyhow!("failed to read the advisory database {}: {e}", db.display()))?;
+    if database.iter().next().is_none() {
+        anyhow::bail!("{} has no advisories, is it a checkout of {ADVISORY_DB}?", db.display());
+    }
+    Ok(database)
+}
+
+/// The database at `db`, or the clone in the cache, updated unless `offline`
+pub fn advisory_db(db: Option<&Path>, offline: bool) -> anyhow::Result<PathBuf> {
+    if let Some(db) = db {
+        return Ok(db.to_path_buf());
+    }
+    let cache = net::cache_dir()
+        .ok_or_else(|| anyhow::anyhow!("no cache directory, pass --db"))?
+        .join("advisory-db");
+    let cached = cache.join(".git").exists();
+    if offline {
+        if !cached {
+            anyhow::bail!("no advisory database cached at {}, run without --offline once", cache.display());
+        }
+        return Ok(cache);
+    }
+    // Killed after the network timeout, failing with what git printed otherwise
+    let git = |command: &mut std::process::Command| -> anyhow::Result<()> {
+        let output = net::output_with_timeout(command, net::client().retry().timeout)?;
+        match output.status.success() {
+            true => Ok(()),
+            false => anyhow::bail!("{}", String::from_utf8_lossy(&output.stderr).trim_end()),
+        }
+    };
+    let updated = if cached {
+        git(net::git().arg("-C").arg(&cache).args(["fetch", "--quiet", "--depth", "1", "origin", "HEAD"]))
+            .and_then(|()| git(net::git().arg("-C").arg(&cache).args(["reset", "--quiet", "--hard", "FETCH_HEAD"])))
+    } else {
+        std::fs::create_dir_all(cache.parent().unwrap())?;
+        git(net::git().args(["clone", "--quiet", "--depth", "1", ADVISORY_DB]).arg(&cache))
+    };
+    match updated {
+        Ok(()) => Ok(cache),
+        Err(e) if cached => {
+            let message = format!("failed to update the advisory database, using the cached one: {e:#}");
+            crate::diagnostics::warn("advisory-db", message);
+            Ok(cache)
+        }
+        Err(e) => Err(e.context(format!("failed to clone {ADVISORY_DB}"))),
+    }
+}
+
+/// A vendored crate an advisory applies to
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct Finding {
+    pub name: String,
+    pub version: String,
+    pub id: String,
+    pub title: String,
+    /// `vulnerability`, or the kind of informational advisory
+    pub kind: String,
+    pub severity: Option<Severity>,
+    pub score: Option<f64>,
+    pub patched: Vec<String>,
+}
+
+impl Finding {
+    fn new(package: &Package, advisory: &Metadata, kind: &str, versions: &Versions) -> Finding {
+        Finding {
+            name: package.name.to_string(),
+            version: package.version.to_string(),
+            id: advisory.id.to_string(),
+            title: advisory.title.clone(),
+            kind: kind.into(),
+            severity: advisory.cvss.as_ref().map(|cvss| cvss.severity()),
+            score: advisory.cvss.as_ref().map(|cvss| cvss.score().value()),
+            patched: versions.patched().iter().map(ToString::to_string).collect(),
+        }
+    }
+
+    pub fn is_vulnerability(&self) -> bool {
+        self.kind == "vulnerability"
+    }
+}
+
+/// The target architecture of a flatpak architecture, which advisories may be limited to
+fn target_arch(arch: FlatpakArch) -> Arch {
+    match arch {
+        FlatpakArch::X86_64 => Arch::X86_64,
+        FlatpakArch::Aarch64 => Arch::AArch64,
+        FlatpakArch::I386 => Arch::X86,
+        FlatpakArch::Arm => Arch::Arm,
+    }
+}
+
+/// Matches the advisories against the registry crates of `packages`, which are
+/// the crates.io packages the advisories are about, as built for linux on `arches`
+pub fn audit(db: &Database, packages: &[ListedPackage], arches: &[FlatpakArch]) -> Vec<Finding> {
+    let packages = packages.iter().filter(|p| p.kind == SourceKind::Registry).filter_map(|package| {
+        Some(Package {
+            name: package.name.parse().ok()?,
+            version: package.version.parse().ok()?,
+            source: Some(SourceId::default()),
+            checksum: None,
+            dependencies: Vec::new(),
+            replace: None,
+        })
+    });
+    let lockfile = Lockfile {
+        version: ResolveVersion::V3,
+        packages: packages.collect(),
+        root: None,
+        metadata: Default::default(),
+        patch: Default::default(),
+    };
+    let settings = Settings {
+        target_arch: arches.iter().copied().map(target_arch).collect(),
+        target_os: vec![OS::Linux],
+        informational_warnings: vec![Informational::Notice, Informational::Unmaintained, Informational::Unsound],
+        ..Default::default()
+    };
+    let report = Report::generate(db, &lockfile, &settings);
+    let mut findings: Vec<_> = report
+        .vulnerabilities
+        .list
+        .iter()
+        .map(|vulnerability| Finding::new(&vulnerability.package, &vulnerability.advisory, "vulnerability", &vulnerability.versions))
+        .collect();
+    for (kind, warnings) in &report.warnings {
+        for warning in warnings {
+            if let (Some(advisory), Some(versions)) = (&warning.advisory, &warning.versions) {
+                findings.push(Finding::new(&warning.package, advisory, kind.as_str(), versions));
+            }
+        }
+    }
+    findings.sort_by(|a, b| (!a.is_vulnerability(), &a.name, &a.id).cmp(&(!b.is_vulnerability(), &b.name, &b.id)));
+    findings
+}
+
+/// Fails if a finding is denied: vulnerabilities always, informational advisories with `Deny::Warnings`
+pub fn check(findings: &[Finding], deny: Deny) -> anyhow::Result<()> {
+    let vulnerabilities = findings.iter().filter(|f| f.is_vulnerability()).count();
+    let warnings = findings.len() - vulnerabilities;
+    match deny {
+        _ if vulnerabilities > 0 => anyhow::bail!("{vulnerabilities} vulnerabilities in the vendored crates"),
+        Deny::Warnings if warnings > 0 => anyhow::bail!("{warnings} warnings in the vendored crates"),
+        _ => Ok(()),
+    }
+}
+
+pub fn table(findings: &[Finding]) -> String {
+    let mut table = String::new();
+    for finding in findings {
+        let severity = match (finding.severity, finding.score) {
+            (Some(severity), Some(score)) => format!("{severity} {score:.1}"),
+            _ => finding.kind.clone(),
+        };
+        table += &format!("{}  {} {}  {severity}  {}\n", finding.id, finding.name, finding.version, finding.title);
+        match finding.patched.as_slice() {
+            [] => table += "  no patched version\n",
+            patched => table += &format!("  patched: {}\n", patched.join(", ")),
+        }
+    }
+    let vulnerabilities = findings.iter().filter(|f| f.is_vulnerability()).count();
+    table += &format!("{vulnerabilities} vulnerabilities, {} warnings\n", findings.len() - vulnerabilities);
+    table
+}
+
+#[test]
+fn audit_vendored_crates() {
+    use clap::Parser;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let (metadata, cargo_lock) = crate::generate::fixture_workspace(tmp.path());
+    let advisory = |package: &str, id: &str, extra: &str| {
+        let path = tmp.path().join(format!("db/crates/{package}/{id}.md"));
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        // Every advisory of the database has versions, if only an empty list of patched ones
+        let versions = if extra.contains("[versions]") { "" } else { "\n[versions]\npatched = []" };
+        let markdown = format!(
+            "```toml\n[advisory]\nid = \"{id}\"\npackage = \"{package}\"\ndate = \"2024-01-01\"\n{extra}{versions}\n```\n\n# Problem in {package}\n\nDetails.\n"
+        );
+        std::fs::write(path, markdown).unwrap();
+    };
+    // url 2.5.0 and anstream 0.6.15 are vendored
+    advisory(
+        "url",
+        "RUSTSEC-2099-0001",
+        "cvss = \"CVSS:3.1/AV:N/AC:L/PR:N/UI:N/S:U/C:H/I:H/A:H\"\n\n[versions]\npatched = [\">= 2.5.4\"]\nunaffected = [\"< 2.0\"]",
+    );
+    advisory("url", "RUSTSEC-2099-0002", "\n[versions]\npatched = [\">= 2.4.0\"]");
+    advisory("url", "RUSTSEC-2099-0003", "withdrawn = \"2024-02-01\"\n");
+    advisory("anstream", "RUSTSEC-2099-0004", "informational = \"unmaintained\"\n");
+    advisory("gtk4", "RUSTSEC-2099-0005", "");
+    // Flatpak builds neither for windows nor for 32-bit x86 by default
+    advisory("url", "RUSTSEC-2099-0006", "\n[affected]\nos = [\"windows\"]");
+    advisory("url", "RUSTSEC-2099-0007", "\n[affected]\narch = [\"x86\"]");
+
+    let advisories = load_db(&tmp.path().join("db")).unwrap();
+    assert_eq!(advisories.iter().count(), 7);
+    let args = crate::cli::Args::parse_from(["cargo-flatpak"]);
+    let packages = crate::list::list(&cargo_lock, &metadata, &args).unwrap();
+    let findings = audit(&advisories, &packages, &args.arches);
+    let found: Vec<_> = findings.iter().map(|f| (f.name.as_str(), f.id.as_str(), f.kind.as_str())).collect();
+    assert_eq!(
+        found,
+        [("url", "RUSTSEC-2099-0001", "vulnerability"), ("anstream", "RUSTSEC-2099-0004", "unmaintained")]
+    );
+    assert_eq!(findings[0].severity, Some(Severity::Critical));
+    assert_eq!(findings[0].title, "Problem in url");
+    assert_eq!(findings[0].patched, [">=2.5.4"]);
+    assert_eq!(
+        table(&findings),
+        "RUSTSEC-2099-0001  url 2.5.0  critical 9.8  Problem in url\n  patched: >=2.5.4\n\
+         RUSTSEC-2099-0004  anstream 0.6.15  unmaintained  Problem in anstream\n  no patched version\n\
+         1 vulnerabilities, 1 warnings\n"
+    );
+
+    let arches = [FlatpakArch::X86_64, FlatpakArch::I386];
+    let ids: Vec<_> = audit(&advisories, &packages, &arches).into_iter().map(|f| f.id).collect();
+    assert_eq!(ids, ["RUSTSEC-2099-0001", "RUSTSEC-2099-0007", "RUSTSEC-2099-0004"]);
+
+    assert!(check(&findings, Deny::Vulnerabilities).is_err());
+    assert!(check(&findings[1..], Deny::Vulnerabilities).is_ok());
+    assert_eq!(check(&findings[1..], Deny::Warnings).unwrap_err().to_string(), "1 warnings in the vendored crates");
+    assert_eq!(advisory_db(Some(&tmp.path().join("db")), true).unwrap(), tmp.path().join("db"));
+}
@@ -0,0 +1,355 @@
+use std::process::ExitCode;
+
+use crate::{
+    advisory, audit, bump, cache, checkout, cli, diagnostics, doctor, explain, fmt, generate, import, index, init, lint,
+    list, net, settings, size, sources, summary, test_build, toolchain, trace, vendored, verify, watch,
+};
+use cargo_metadata::CargoOpt;
+use clap::Parser;
+use cli::{OutputFormat, Command, SubCommand};
+use sources::{find_lockfile_hash, lockfile_hash};
+
+/// The `cargo flatpak` command
+pub fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            if let Some(failed) = e.downcast_ref::<test_build::BuildFailed>() {
+                return ExitCode::from(failed.exit_code());
+            }
+            diagnostics::report(&e);
+            match e.is::<generate::Incomplete>() {
+                true => ExitCode::from(generate::INCOMPLETE_EXIT_CODE),
+                false => ExitCode::FAILURE,
+            }
+        }
+    }
+}
+
+fn run() -> anyhow::Result<()> {
+    let argv: Vec<_> = std::env::args_os().collect();
+    // Validate the command line before running cargo metadata
+    let Command::Flatpak(cli) = Command::parse_from(&argv);
+    diagnostics::set_format(cli.error_format);
+    // For cargo metadata, and what else runs cargo, like the fetches and build tests
+    if let Some(cargo) = &cli.cargo_path {
+        std::env::set_var("CARGO", cargo);
+    }
+    if cli.verbose {
+        diagnostics::note("toolchain", format!("cargo: {}", toolchain::describe_cargo(&toolchain::cargo())?));
+    }
+    if let Some(dir) = &cli.cache_dir {
+        net::set_cache_root(dir.clone())?;
+    }
+    // Flushed as the run ends
+    let _trace = cli.trace.as_deref().map(trace::start).transpose()?;
+    // The manifest's repository needn't be a cargo workspace
+    if let Some(SubCommand::Bump { manifest, tag, commit, source_index }) = &cli.command {
+        let revision = match (tag, commit) {
+            (Some(tag), _) => bump::Revision::Tag(tag),
+            (None, commit) => bump::Revision::Commit(commit.as_deref().unwrap()),
+        };
+        let cargo_proxy = net::cargo_http_proxy(&std::env::current_dir()?);
+        net::configure(net::ProxyConfig::new(cli.proxy.as_deref(), cargo_proxy, |name| std::env::var(name).ok()), cli.network_retry())?;
+        let cache = checkout::default_cache().ok_or_else(|| anyhow::anyhow!("no cache directory to clone the application into"))?;
+        std::env::set_var("CARGO_HOME", checkout::cargo_home(&cache));
+        let bumped = bump::bump(manifest, &cli.output, revision, *source_index, &argv, &cache)?;
+        diagnostics::note("bump", format!("moved {} from {} to {}", bumped.url, bumped.from, bumped.to));
+        diagnostics::note("bump", format!("wrote {}: {}", bumped.output.display(), bumped.changes));
+        return Ok(());
+    }
+    if let Some(SubCommand::Fmt { files, check }) = &cli.command {
+        return fmt::fmt(files, *check, cli.group_by);
+    }
+    if let Some(SubCommand::Cache { action }) = &cli.command {
+        return cache::cache(action);
+    }
+    // Before cargo metadata, which writes the Cargo.lock doctor looks for
+    if let Some(SubCommand::Doctor { offline }) = &cli.command {
+        let cargo_proxy = net::cargo_http_proxy(&std::env::current_dir()?);
+        net::configure(net::ProxyConfig::new(cli.proxy.as_deref(), cargo_proxy, |name| std::env::var(name).ok()), cli.network_retry())?;
+        return doctor::doctor(&cli, *offline);
+    }
+    let mut metadata_command = toolchain::metadata_command(&toolchain::cargo());
+    // Cargo.lock covers every feature, and so must the resolve graph the vendored set comes from
+    metadata_command.features(CargoOpt::AllFeatures);
+    // The sources of an upstream project are written to the current directory
+    let upstream = cli.from_git.is_some() || cli.from_crate.is_some();
+    if upstream {
+        let cargo_proxy = net::cargo_http_proxy(&std::env::current_dir()?);
+        net::configure(net::ProxyConfig::new(cli.proxy.as_deref(), cargo_proxy, |name| std::env::var(name).ok()), cli.network_retry())?;
+    }
+    if let Some(url) = &cli.from_git {
+        let cache = checkout::default_cache().ok_or_else(|| anyhow::anyhow!("no cache directory to clone {url} into"))?;
+        let checkout = checkout::checkout(url, cli.git_ref(), &cache)?;
+        // The dependencies of upstream projects are fetched apart from the user's
+        std::env::set_var("CARGO_HOME", checkout::cargo_home(&cache));
+        metadata_command.manifest_path(checkout.join("Cargo.toml"));
+    }
+    let unpacked = match &cli.from_crate {
+        Some((name, version)) => {
+            let index_cache = index::default_cache();
+            let unpacked = checkout::unpack_crate(
+                name,
+                version,
+                &cli.crate_url_template,
+                &cli.crates_io_index,
+                index_cache.as_deref(),
+                cli.allow_missing_lockfile,
+            )?;
+            metadata_command.manifest_path(unpacked.dir.join("Cargo.toml"));
+            Some(unpacked)
+        }
+        None => None,
+    };
+    let metadata_span = tracing::info_span!("metadata").entered();
+    let (cargo_metadata, stale_lockfile) = match cli.lockfile_only {
+        true => (generate::lockfile_only_metadata(&std::env::current_dir()?)?, None),
+        false => generate::locked_metadata(&metadata_command)?,
+    };
+    metadata_span.exit();
+    let settings = settings::resolve(&argv, &cargo_metadata)?;
+    diagnostics::set_format(settings.args.error_format);
+    for warning in &settings.warnings {
+        diagnostics::warn("settings", warning.clone());
+    }
+    if let Some(format) = settings.args.print_options {
+        match format {
+            cli::ConfigFormat::Toml => print!("{}", toml::to_string(&settings.options_value())?),
+            cli::ConfigFormat::Json => println!("{}", serde_json::to_string_pretty(&settings.options_value())?),
+        }
+        return Ok(());
+    }
+    let mut args = settings.args;
+    args.fetch |= cli.from_git.is_some();
+    // Only the workspace's own lockfile is what the manifests are compared with
+    let stale_lockfile = stale_lockfile.filter(|_| args.lockfile.is_none());
+    if let Some(stale) = &stale_lockfile {
+        let message = generate::stale_lockfile_message(&stale.mismatches);
+        if args.strict {
+            return Err(diagnostics::Annotated::new(anyhow::anyhow!(message), generate::UPDATE_LOCKFILE).into());
+        }
+        diagnostics::emit(diagnostics::Diagnostic::warning("stale-lockfile", message).with_suggestion(generate::UPDATE_LOCKFILE));
+    }
+    if let Some(format) = args.audit_build_scripts {
+        let crates = audit::build_script_crates(&cargo_metadata);
+        match format {
+            OutputFormat::Table => print!("{}", audit::table(&crates)),
+            OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&crates)?),
+        }
+        return Ok(());
+    }
+    if let Some(format) = args.suggest_system_deps {
+        let dependencies = audit::system_dependencies(&audit::build_script_crates(&cargo_metadata));
+        match format {
+            OutputFormat::Table => print!("{}", audit::system_dependencies_report(&dependencies)),
+            OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&dependencies)?),
+        }
+        return Ok(());
+    }
+    let workspace = cargo_metadata.workspace_root.as_std_path();
+    if !upstream {
+        let cargo_proxy = net::cargo_http_proxy(workspace);
+        net::configure(net::ProxyConfig::new(args.proxy.as_deref(), cargo_proxy, |name| std::env::var(name).ok()), args.network_retry())?;
+    }
+    let lockfile = workspace.join("Cargo.lock");
+
+    let cargo_lock = match stale_lockfile {
+        Some(stale) => stale.cargo_lock,
+        None => generate::read_lockfile(&args, workspace, std::io::stdin())?,
+    };
+    // The application's commit is generated into the sources, like the lockfile
+    let app_git = match (&unpacked, &args.app_source) {
+        (None, Some(app @ cli::AppSource::Git)) => Some(checkout::app_source(app, None, workspace, args.dest("."))?),
+        _ => None,
+    };
+    if let Some(sources::Source::Git(git)) = &app_git {
+        args.app_origin = Some((git.url.clone(), git.commit.clone()));
+    }
+    let lock_hash = lockfile_hash(&cargo_lock, &args);
+    let out_dir = match upstream {
+        true => std::env::current_dir()?,
+        false => workspace.to_path_buf(),
+    };
+    let output = out_dir.join(&args.output);
+    let git_output = args.separate_git.as_ref().map(|path| out_dir.join(path));
+    if args.verify_hash {
+        let sources = generate::read_sources(&output, git_output.as_deref())?;
+        return match find_lockfile_hash(&sources) {
+            Some(hash) if hash == lock_hash => {
+                println!("{} is up to date", output.display());
+                Ok(())
+            }
+            // Generating with --write-config would overwrite the config in the process
+            Some(_) if args.write_config.is_none() => {
+                generate::ensure_git_checkouts(&args, &cargo_metadata, &cargo_lock)?;
+                let generated = generate::generate(&args, &cargo_metadata, &cargo_lock, lock_hash, &output)?;
+                anyhow::bail!("{} is stale, regenerate it{}", output.display(), sources.diff(&generated))
+            }
+            Some(_) => anyhow::bail!("{} is stale, regenerate it", output.display()),
+            None => anyhow::bail!("{} has no x-cargo-lock-hash annotation", output.display()),
+        };
+    }
+    let toolchain = match args.ignore_toolchain {
+        true => None,
+        false => toolchain::pinned(workspace)?,
+    };
+    if let Some(toolchain) = &toolchain {
+        toolchain.warn();
+    }
+    if let Some(SubCommand::Init { app_id, template, runtime, sdk, runtime_version, command }) = &args.command {
+        let platform = (runtime.as_deref(), sdk.as_deref(), runtime_version.as_deref());
+        let (path, platform) = init::init(&cargo_metadata, &args, app_id, template, platform, command.as_deref(), toolchain.as_ref())?;
+        println!("{}", path.strip_prefix(workspace).unwrap_or(&path).display());
+        if let Some(branch) = platform.and_then(|p| p.rust_extension_branch) {
+            let extension = toolchain.as_ref().map_or(toolchain::RUST_STABLE, toolchain::Toolchain::sdk_extension);
+            diagnostics::emit(
+                diagnostics::Diagnostic::note("rust-extension", "build it with the rust extension")
+                    .with_suggestion(format!("flatpak install flathub org.freedesktop.Sdk.Extension.{extension}//{branch}")),
+            );
+        }
+        return Ok(());
+    }
+    // Everything but checking a given sources file reads the git checkouts
+    let from_file = matches!(&args.command, Some(SubCommand::VerifyUrls { file: Some(_), .. } | SubCommand::Lint { file: Some(_), .. }));
+    if !args.lockfile_only && !from_file {
+        generate::ensure_git_checkouts(&args, &cargo_metadata, &cargo_lock)?;
+    }
+    if let Some(SubCommand::VerifyUrls { file, jobs }) = &args.command {
+        let sources = match file {
+            Some(file) => serde_json::from_str(&std::fs::read_to_string(file)?)?,
+            None => {
+                let generated = generate::generate(&args, &cargo_metadata, &cargo_lock, lock_hash, &output)?;
+                serde_json::to_value(generated.sources())?
+            }
+        };
+        return verify::verify_urls(&sources, *jobs);
+    }
+    if let Some(SubCommand::Lint { file, deny, allow, format }) = &args.command {
+        let sources = match file {
+            Some(file) => serde_json::from_str(&std::fs::read_to_string(file)?)?,
+            None => {
+                let generated = generate::generate(&args, &cargo_metadata, &cargo_lock, lock_hash, &output)?;
+                serde_json::to_value(generated.sources())?
+            }
+        };
+        let findings = lint::lint(&sources, &lint::severities(deny, allow));
+        match format {
+            OutputFormat::Table => print!("{}", lint::table(&findings)),
+            OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&findings)?),
+        }
+        return lint::check(&findings);
+    }
+    if let Some(SubCommand::VerifyVendored { builddir }) = &args.command {
+        let generated = generate::generate(&args, &cargo_metadata, &cargo_lock, lock_hash, &output)?;
+        return vendored::verify_vendored(&generated, builddir);
+    }
+    if let Some(SubCommand::Explain { package }) = &args.command {
+        let trace = explain::explain(package, &cargo_lock, &cargo_metadata, &args)?;
+        print!("{}", explain::render(&trace));
+        return Ok(());
+    }
+    if let Some(SubCommand::List { format, only }) = &args.command {
+        let mut packages = list::list(&cargo_lock, &cargo_metadata, &args)?;
+        packages.retain(|p| only.is_none_or(|only| p.kind == only));
+        match format {
+            OutputFormat::Table => print!("{}", list::table(&packages)),
+            OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&packages)?),
+        }
+        return Ok(());
+    }
+    if let Some(SubCommand::Audit { db, offline, deny, format }) = &args.command {
+        let advisories = advisory::load_db(&advisory::advisory_db(db.as_deref(), *offline)?)?;
+        let findings = advisory::audit(&advisories, &list::list(&cargo_lock, &cargo_metadata, &args)?, &args.arches);
+        match format {
+            OutputFormat::Table => print!("{}", advisory::table(&findings)),
+            OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&findings)?),
+        }
+        return advisory::check(&findings, *deny);
+    }
+    if let Some(SubCommand::Import { file }) = &args.command {
+        let old: Vec<serde_json::Value> = serde_json::from_str(&std::fs::read_to_string(file)?)?;
+        let imported = import::import(&old, &cargo_lock, &args)?;
+        for (entry, reason) in &imported.unmapped {
+            diagnostics::emit(
+                diagnostics::Diagnostic::warning("unmapped-source", format!("{reason}: {entry}"))
+                    .with_suggestion("carry it over by hand"),
+            );
+        }
+        generate::write_output_with(&output, args.no_clobber, |out| Ok(imported.sources.write_json(out)?))?;
+        println!(
+            "imported {} of {} sources into {}",
+            old.len() - imported.unmapped.len(),
+            old.len(),
+            output.strip_prefix(&out_dir).unwrap_or(&output).display()
+        );
+        return Ok(());
+    }
+    if let Some(SubCommand::TestBuild { keep, quick }) = &args.command {
+        let generated = generate::generate(&args, &cargo_metadata, &cargo_lock, lock_hash, &output)?;
+        let manifest_dir = generate::manifest_dir(&args, &out_dir, &output);
+        let status = test_build::test_build(&generated, workspace, &manifest_dir, &args, *keep, *quick)?;
+        if !status.success() {
+            return Err(test_build::BuildFailed(status).into());
+        }
+        return Ok(());
+    }
+    if args.estimate_size {
+        let generated = generate::generate(&args, &cargo_metadata, &cargo_lock, lock_hash, &output)?;
+        let sizes = size::estimate(&serde_json::to_value(generated.sources())?, 8, size::default_cache().as_deref());
+        print!("{}", size::report(&sizes));
+        return Ok(());
+    }
+
+    // The application's own source, first in the module or the sources
+    let app_source = match (&unpacked, &args.app_source) {
+        (Some(unpacked), _) => Some(unpacked.source(args.dest("."))),
+        (None, Some(cli::AppSource::Git)) => app_git,
+        (None, Some(app)) => Some(checkout::app_source(app, args.sha256.as_ref(), workspace, args.dest("."))?),
+        (None, None) => None,
+    };
+    let (generated, failed) = match args.keep_going {
+        true => generate::generate_keep_going(&args, &cargo_metadata, &cargo_lock, lock_hash, &output)?,
+        false => (generate::generate(&args, &cargo_metadata, &cargo_lock, lock_hash, &output)?, Vec::new()),
+    };
+    if args.print_config {
+        print!("{}", generated.config.to_toml()?);
+    }
+
+    // Read before it's overwritten, a first run adds every crate
+    let previous = args
+        .summary_markdown
+        .as_ref()
+        .map(|_| generate::read_sources(&output, git_output.as_deref()).unwrap_or_else(|_| sources::SourceSet::new(&args.vendor_dir())));
+    generate::write_outputs(&args, &cargo_metadata, &generated, app_source.as_ref(), toolchain.as_ref(), &out_dir, &output)?;
+    if let (Some(path), Some(previous)) = (&args.summary_markdown, &previous) {
+        generate::write_output(path, summary::markdown(previous, &generated).as_bytes(), false)?;
+    }
+    if !failed.is_empty() {
+        let incomplete = generate::Incomplete {
+            output: output.strip_prefix(&out_dir).unwrap_or(&output).to_path_buf(),
+            errors: generate::PackageErrors(failed),
+        };
+        if !args.watch {
+            return Err(incomplete.into());
+        }
+        diagnostics::report(&incomplete.into());
+    }
+    if args.watch {
+        let mut previous = generated;
+        let files = watch::watched_files(&cargo_metadata);
+        return watch::watch(files, watch::DEBOUNCE, || {
+            let cargo_metadata = toolchain::metadata_command(&toolchain::cargo()).features(CargoOpt::AllFeatures).exec()?;
+            let cargo_lock = std::fs::read_to_string(&lockfile)?;
+            let lock_hash = lockfile_hash(&cargo_lock, &args);
+            generate::ensure_git_checkouts(&args, &cargo_metadata, &cargo_lock)?;
+            let generated = generate::generate(&args, &cargo_metadata, &cargo_lock, lock_hash, &output)?;
+            generate::write_outputs(&args, &cargo_metadata, &generated, app_source.as_ref(), toolchain.as_ref(), &out_dir, &output)?;
+            let summary = previous.diff(&generated).summary();
+            previous = generated;
+            let summary = format!("regenerated {}: {summary}", output.strip_prefix(&out_dir).unwrap_or(&output).display());
+            Ok((summary, watch::watched_files(&cargo_metadata)))
+        });
+    }
+    Ok(())
+}
@@ -0,0 +1,272 @@
+use cargo_metadata::Metadata;
+
+/// A vendored crate that runs code at build time or links a native library
+#[derive(Debug, PartialEq, serde::Serialize)]
+pub struct BuildScriptCrate {
+    pub name: String,
+    pub version: String,
+    #[serde(rename = "build-script")]
+    pub build_script: bool,
+    pub links: Option<String>,
+    pub sys: bool,
+}
+
+/// Lists the crates outside of the workspace with a build script or a `links`
+/// key, `-sys` crates first
+pub fn build_script_crates(metadata: &Metadata) -> Vec<BuildScriptCrate> {
+    let members = &metadata.workspace_members;
+    let mut crates: Vec<_> = metadata
+        .packages
+        .iter()
+        .filter(|p| !members.contains(&p.id))
+        .map(|p| BuildScriptCrate {
+            name: p.name.clone(),
+            version: p.version.to_string(),
+            build_script: p.targets.iter().any(|t| t.is_custom_build()),
+            links: p.links.clone(),
+            sys: p.name.ends_with("-sys"),
+        })
+        .filter(|c| c.build_script || c.links.is_some())
+        .collect();
+    crates.sort_by(|a, b| (!a.sys, &a.name, &a.version).cmp(&(!b.sys, &b.name, &b.version)));
+    crates
+}
+
+pub fn table(crates: &[BuildScriptCrate]) -> String {
+    let width = crates.iter().map(|c| c.name.len() + c.version.len() + 1).max().unwrap_or(0).max(5);
+    let mut table = String::new();
+    for (sys, title) in [(true, "-sys crates"), (false, "other crates")] {
+        let group: Vec<_> = crates.iter().filter(|c| c.sys == sys).collect();
+        if group.is_empty() {
+            continue;
+        }
+        if !table.is_empty() {
+            table.push('\n');
+        }
+        table += &format!("{title} ({}):\n", group.len());
+        table += &format!("  {:width$}  build.rs  links\n", "crate");
+        for c in group {
+            let build_script = if c.build_script { "yes" } else { "no" };
+            let links = c.links.as_deref().unwrap_or("-");
+            table += &format!("  {:width$}  {build_script:8}  {links}\n", format!("{} {}", c.name, c.version));
+        }
+    }
+    if table.is_empty() {
+        table = "no vendored crate has a build script or links a native library\n".into();
+    }
+    table
+}
+
+/// Where the native library of a `-sys` crate comes from in a flatpak build
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Provision {
+    /// The Freedesktop runtime, and so the GNOME and KDE ones built on it
+    Freedesktop,
+    /// The GNOME runtime only
+    Gnome,
+    /// A module of the manifest, or the crate's own bundled copy
+    Module,
+    /// Not in the table, verify manually
+    Unknown,
+}
+
+/// A well-known `-sys` crate, the library it `links`, and where that comes from
+struct SystemLibrary {
+    name: &'static str,
+    links: &'static str,
+    provision: Provision,
+    suggestion: Option<&'static str>,
+}
+
+const fn library(name: &'static str, links: &'static str, provision: Provision, suggestion: Option<&'static str>) -> SystemLibrary {
+    SystemLibrary { name, links, provision, suggestion }
+}
+
+const SYSTEM_LIBRARIES: &[SystemLibrary] = &[
+    library("openssl-sys", "openssl", Provision::Freedesktop, Some("or enable the `vendored` feature to build it from source")),
+    library("libz-sys", "z", Provision::Freedesktop, None),
+    library("bzip2-sys", "bzip2", Provision::Freedesktop, None),
+    library("lzma-sys", "lzma", Provision::Freedesktop, None),
+    library("zstd-sys", "zstd", Provision::Freedesktop, None),
+    library("libsqlite3-sys", "sqlite3", Provision::Freedesktop, Some("or enable the `bundled` feature")),
+    library("curl-sys", "curl", Provision::Freedesktop, None),
+    library("alsa-sys", "alsa", Provision::Freedesktop, Some("and give the app `--socket=pulseaudio` to play sound")),
+    library("libpulse-sys", "pulse", Provision::Freedesktop, None),
+    library("libdbus-sys", "dbus", Provision::Freedesktop, None),
+    library("libudev-sys", "udev", Provision::Freedesktop, None),
+    library("wayland-sys", "wayland", Provision::Freedesktop, None),
+    library("freetype-sys", "freetype", Provision::Freedesktop, None),
+    library("servo-fontconfig-sys", "fontconfig", Provision::Freedesktop, None),
+    library("expat-sys", "expat", Provision::Freedesktop, None),
+    library("glib-sys", "glib-2.0", Provision::Freedesktop, None),
+    library("gobject-sys", "gobject-2.0", Provision::Freedesktop, None),
+    library("gio-sys", "gio-2.0", Provision::Freedesktop, None),
+    library("cairo-sys-rs", "cairo", Provision::Freedesktop, None),
+    library("pango-sys", "pango", Provision::Freedesktop, None),
+    library("gdk-pixbuf-sys", "gdk_pixbuf-2.0", Provision::Freedesktop, None),
+    library("gtk-sys", "gtk-3", Provision::Freedesktop, None),
+    library("gstreamer-sys", "gstreamer-1.0", Provision::Freedesktop, None),
+    library("gtk4-sys", "gtk4", Provision::Gnome, Some("other runtimes need a gtk4 module")),
+    library("libadwaita-sys", "adwaita-1", Provision::Gnome, Some("other runtimes need a libadwaita module")),
+    library("libgit2-sys", "git2", Provision::Module, Some("it builds its bundled copy unless a libgit2 module is added")),
+    library("libssh2-sys", "ssh2", Provision::Module, Some("it builds its bundled copy unless a libssh2 module is added")),
+    library("librocksdb-sys", "rocksdb", Provision::Module, Some("it builds its bundled copy, which needs clang from the llvm SDK extension")),
+    library("pq-sys", "pq", Provision::Module, Some("add a postgresql module, or enable the `bundled` feature")),
+    library("mysqlclient-sys", "mysqlclient", Provision::Module, Some("add a mariadb module")),
+];
+
+/// A vendored `-sys` or `links` crate and what its native library needs
+#[derive(Debug, PartialEq, serde::Serialize)]
+pub struct SystemDependency {
+    #[serde(rename = "crate")]
+    pub name: String,
+    pub version: String,
+    pub links: Option<String>,
+    pub provision: Provision,
+    pub suggestion: Option<&'static str>,
+}
+
+/// Looks the `-sys` and `links` crates of `crates` up in the table of known
+/// native libraries, by name and then by the library they link
+pub fn system_dependencies(crates: &[BuildScriptCrate]) -> Vec<SystemDependency> {
+    crates
+        .iter()
+        .filter(|c| c.sys || c.links.is_some())
+        .map(|c| {
+            let known = SYSTEM_LIBRARIES
+                .iter()
+                .find(|l| l.name == c.name)
+                .or_else(|| SYSTEM_LIBRARIES.iter().find(|l| Some(l.links) == c.links.as_deref()));
+            SystemDependency {
+                name: c.name.clone(),
+                version: c.version.clone(),
+                links: c.links.clone(),
+                provision: known.map_or(Provision::Unknown, |l| l.provision),
+                suggestion: known.and_then(|l| l.suggestion),
+            }
+        })
+        .collect()
+}
+
+pub fn system_dependencies_report(dependencies: &[SystemDependency]) -> String {
+    let mut report = String::new();
+    for d in dependencies {
+        report += &format!("{} {}", d.name, d.version);
+        if let Some(links) = &d.links {
+            report += &format!(" (links {links})");
+        }
+        report += match d.provision {
+            Provision::Freedesktop => ": provided by the Freedesktop runtime and the ones built on it",
+            Provision::Gnome => ": provided by the GNOME runtime",
+            Provision::Module => ": needs a module",
+            Provision::Unknown => ": unknown, verify manually",
+        };
+        if let Some(suggestion) = d.suggestion {
+            report += &format!(", {suggestion}");
+        }
+        report.push('\n');
+    }
+    if report.is_empty() {
+        report = "no vendored crate links a native library\n".into();
+    }
+    report
+}
+
+#[test]
+fn audit_links_crate() {
+    let tmp = tempfile::tempdir().unwrap();
+    let files = [
+        (
+            "Cargo.toml",
+            "[package]\nname = \"app\"\nversion = \"0.1.0\"\n\n[dependencies]\nfoo-sys = { path = \"deps/foo-sys\" }\ncodegen = { path = \"deps/codegen\" }\nplain = { path = \"deps/plain\" }\n",
+        ),
+        ("src/main.rs", "fn main() {}\n"),
+        ("deps/foo-sys/Cargo.toml", "[package]\nname = \"foo-sys\"\nversion = \"0.2.0\"\nlinks = \"foo\"\n"),
+        ("deps/foo-sys/build.rs", "fn main() {}\n"),
+        ("deps/foo-sys/src/lib.rs", ""),
+        ("deps/codegen/Cargo.toml", "[package]\nname = \"codegen\"\nversion = \"1.0.0\"\nbuild = \"gen.rs\"\n"),
+        ("deps/codegen/gen.rs", "fn main() {}\n"),
+        ("deps/codegen/src/lib.rs", ""),
+        ("deps/plain/Cargo.toml", "[package]\nname = \"plain\"\nversion = \"1.0.0\"\n"),
+        ("deps/plain/src/lib.rs", ""),
+    ];
+    crate::sources::write_fixture(tmp.path(), &files);
+    let metadata = cargo_metadata::MetadataCommand::new()
+        .manifest_path(tmp.path().join("Cargo.toml"))
+        .exec()
+        .unwrap();
+
+    let crates = build_script_crates(&metadata);
+    assert_eq!(
+        crates,
+        [
+            BuildScriptCrate {
+                name: "foo-sys".into(),
+                version: "0.2.0".into(),
+                build_script: true,
+                links: Some("foo".into()),
+                sys: true,
+            },
+            BuildScriptCrate {
+                name: "codegen".into(),
+                version: "1.0.0".into(),
+                build_script: true,
+                links: None,
+                sys: false,
+            },
+        ]
+    );
+    let table = table(&crates);
+    assert!(table.starts_with("-sys crates (1):\n"));
+    assert!(table.contains("foo-sys 0.2.0  yes       foo\n"));
+    assert!(table.contains("other crates (1):\n"));
+}
+
+#[test]
+fn system_dependency_suggestions() {
+    let c = |name: &str, links: Option<&str>, build_script| BuildScriptCrate {
+        name: name.into(),
+        version: "1.0.0".into(),
+        build_script,
+        links: links.map(String::from),
+        sys: name.ends_with("-sys"),
+    };
+    let crates = [
+        c("openssl-sys", Some("openssl"), true),
+        c("gtk4-sys", Some("gtk4"), true),
+        c("libgit2-sys", Some("git2"), true),
+        // A fork found by the library it links
+        c("my-sqlite", Some("sqlite3"), true),
+        c("foo-sys", None, true),
+        c("codegen", None, true),
+    ];
+    let dependencies = system_dependencies(&crates);
+    let provisions: Vec<_> = dependencies.iter().map(|d| (d.name.as_str(), d.provision)).collect();
+    assert_eq!(
+        provisions,
+        [
+            ("openssl-sys", Provision::Freedesktop),
+            ("gtk4-sys", Provision::Gnome),
+            ("libgit2-sys", Provision::Module),
+            ("my-sqlite", Provision::Freedesktop),
+            ("foo-sys", Provision::Unknown),
+        ]
+    );
+    assert_eq!(
+        system_dependencies_report(&dependencies),
+        "openssl-sys 1.0.0 (links openssl): provided by the Freedesktop runtime and the ones built on it, or enable \
+         the `vendored` feature to build it from source\n\
+         gtk4-sys 1.0.0 (links gtk4): provided by the GNOME runtime, other runtimes need a gtk4 module\n\
+         libgit2-sys 1.0.0 (links git2): needs a module, it builds its bundled copy unless a libgit2 module is added\n\
+         my-sqlite 1.0.0 (links sqlite3): provided by the Freedesktop runtime and the ones built on it, or enable the \
+         `bundled` feature\n\
+         foo-sys 1.0.0: unknown, verify manually\n"
+    );
+    assert_eq!(system_dependencies_report(&[]), "no vendored crate links a native library\n");
+
+    // Each crate and library is in the table once
+    for (i, library) in SYSTEM_LIBRARIES.iter().enumerate() {
+        assert!(SYSTEM_LIBRARIES[i + 1..].iter().all(|l| l.name != library.name && l.links != library.links), "{}", library.name);
+    }
+}
@@ -0,0 +1,271 @@
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use cargo_metadata::CargoOpt;
+use serde_json::Value;
+
+use crate::checkout::{self, GitRef};
+use crate::diagnostics::Annotated;
+use crate::hash::CommitHash;
+use crate::{generate, settings, sources, COMMIT_LEN};
+
+/// What `bump` moves the application's source to
+#[derive(Debug, Clone, Copy)]
+pub enum Revision<'a> {
+    Tag(&'a str),
+    Commit(&'a str),
+}
+
+/// What `bump` changed
+#[derive(Debug)]
+pub struct Bumped {
+    pub url: String,
+    /// The tag or commit the source was at, and is at now
+    pub from: String,
+    pub to: String,
+    /// The regenerated sources file, and what changed in it
+    pub output: PathBuf,
+    pub changes: String,
+}
+
+/// The JSON pointer of the application's git source in `manifest`. Its module
+/// is the one listing `sources_file`, or else the last one, and the source is
+/// the `source_index`th of the module or its first git source.
+fn app_source_pointer(manifest: &Value, sources_file: &str, source_index: Option<usize>) -> anyhow::Result<String> {
+    fn modules<'a>(parent: &'a Value, pointer: String, found: &mut Vec<(String, &'a Value)>) {
+        for (i, module) in parent["modules"].as_array().into_iter().flatten().enumerate() {
+            let pointer = format!("{pointer}/modules/{i}");
+            if module.is_object() {
+                found.push((pointer.clone(), module));
+                modules(module, pointer, found);
+            }
+        }
+    }
+    let mut found = Vec::new();
+    modules(manifest, String::new(), &mut found);
+    let file_name = Path::new(sources_file).file_name();
+    let lists_sources = |module: &Value| {
+        let sources = module["sources"].as_array().into_iter().flatten();
+        sources.filter_map(Value::as_str).any(|s| s == sources_file || Path::new(s).file_name() == file_name)
+    };
+    let top_level = |pointer: &str| pointer.matches("/modules/").count() == 1;
+    let Some((pointer, module)) = found
+        .iter()
+        .find(|(_, module)| lists_sources(module))
+        .or_else(|| found.iter().rev().find(|(pointer, _)| top_level(pointer)))
+    else {
+        anyhow::bail!("the manifest has no modules");
+    };
+    let name = module["name"].as_str().unwrap_or("?");
+    let sources = module["sources"].as_array().map(Vec::as_slice).unwrap_or_default();
+    let is_git = |source: &Value| source["type"] == "git";
+    let index = match source_index {
+        Some(index) => match sources.get(index) {
+            Some(source) if is_git(source) => index,
+            Some(_) => anyhow::bail!("source {index} of the module {name} is not a git source"),
+            None => anyhow::bail!("the module {name} has {} sources, there's no source {index}", sources.len()),
+        },
+        None => sources.iter().position(is_git).ok_or_else(|| {
+            Annotated::new(anyhow::anyhow!("the module {name} has no git source"), "pick the application's source with --source-index")
+        })?,
+    };
+    Ok(format!("{pointer}/sources/{index}"))
+}
+
+/// Indents `value` the way `contents`, the JSON it was read from, is indented
+fn to_json_like(value: &Value, contents: &str) -> anyhow::Result<String> {
+    use serde::Serialize;
+
+    let indent = contents
+        .lines()
+        .nth(1)
+        .map(|line| &line[..line.len() - line.trim_start().len()])
+        .filter(|indent| !indent.is_empty())
+        .unwrap_or("    ");
+    let mut json = Vec::new();
+    let mut serializer = serde_json::Serializer::with_formatter(&mut json, serde_json::ser::PrettyFormatter::with_indent(indent.as_bytes()));
+    value.serialize(&mut serializer)?;
+    json.push(b'\n');
+    Ok(String::from_utf8(json)?)
+}
+
+/// Moves the application's git source of the manifest at `manifest_path` to
+/// `revision`, checked out in `cache`, and regenerates the sources of that
+/// revision's Cargo.lock next to the manifest. The module listing
+/// `sources_file` is the application's. `argv` is the command line, which
+/// settings of the application apply underneath. The manifest is only written
+/// once the sources are.
+pub fn bump(
+    manifest_path: &Path,
+    sources_file: &str,
+    revision: Revision,
+    source_index: Option<usize>,
+    argv: &[OsString],
+    cache: &Path,
+) -> anyhow::Result<Bumped> {
+    if manifest_path.extension().is_some_and(|e| e == "yml" || e == "yaml") {
+        anyhow::bail!("{} is a YAML manifest, bump only edits JSON ones", manifest_path.display());
+    }
+    let contents = std::fs::read_to_string(manifest_path).with_context(|| format!("failed to read {}", manifest_path.display()))?;
+    let mut manifest: Value = serde_json::from_str(&contents)
+        .with_context(|| format!("failed to parse {}, bump reads JSON manifests", manifest_path.display()))?;
+    let pointer = app_source_pointer(&manifest, sources_file, source_index)?;
+    let source = manifest.pointer(&pointer).unwrap();
+    let Some(url) = source["url"].as_str().map(String::from) else {
+        anyhow::bail!("the application's git source has no url");
+    };
+    let from = match (source["tag"].as_str(), source["commit"].as_str()) {
+        (Some(tag), _) => tag.to_string(),
+        (None, Some(commit)) => CommitHash::try_from(commit).map_or(commit.to_string(), |c| c.abbrev(COMMIT_LEN).to_string()),
+        (None, None) => source["branch"].as_str().unwrap_or("?").to_string(),
+    };
+
+    let reference = match revision {
+        Revision::Tag(tag) => GitRef::Tag(tag),
+        Revision::Commit(commit) => GitRef::Rev(commit),
+    };
+    let checkout = checkout::checkout(&url, reference, cache)?;
+    let commit = checkout::head_commit(&checkout)?;
+    let to = match revision {
+        Revision::Tag(tag) => tag.to_string(),
+        Revision::Commit(_) => commit.abbrev(COMMIT_LEN).to_string(),
+    };
+    if !checkout.join("Cargo.lock").is_file() {
+        let error = anyhow::anyhow!("{url} has no Cargo.lock at {to}, the sources of its dependencies can't be generated");
+        return Err(Annotated::new(error, "the application has to commit its Cargo.lock, the manifest is left as it is").into());
+    }
+
+    let mut metadata_command = crate::toolchain::metadata_command(&crate::toolchain::cargo());
+    metadata_command.manifest_path(checkout.join("Cargo.toml")).features(CargoOpt::AllFeatures);
+    let (metadata, stale) = generate::locked_metadata(&metadata_command)?;
+    let mut args = settings::resolve(argv, &metadata)?.args;
+    args.fetch = true;
+    let cargo_lock = match stale {
+        Some(stale) => stale.cargo_lock,
+        None => std::fs::read_to_string(checkout.join("Cargo.lock"))?,
+    };
+    let lock_hash = sources::lockfile_hash(&cargo_lock, &args);
+    let output = manifest_path.parent().unwrap().join(&args.output);
+    let git_output = args.separate_git.as_ref().map(|path| manifest_path.parent().unwrap().join(path));
+    let previous = generate::read_sources(&output, git_output.as_deref()).ok();
+    generate::ensure_git_checkouts(&args, &metadata, &cargo_lock)?;
+    let generated = generate::generate(&args, &metadata, &cargo_lock, lock_hash, &output)?;
+    let toolchain = match args.ignore_toolchain {
+        true => None,
+        false => crate::toolchain::pinned(&checkout)?,
+    };
+    // The application's source is the manifest's, which no module or sources file repeats
+    generate::write_outputs(&args, &metadata, &generated, None, toolchain.as_ref(), manifest_path.parent().unwrap(), &output)?;
+    let changes = match previous {
+        Some(previous) => previous.diff(&generated).summary(),
+        None => "written".into(),
+    };
+
+    let source = manifest.pointer_mut(&pointer).unwrap().as_object_mut().unwrap();
+    source.insert("commit".into(), commit.as_str().into());
+    match revision {
+        Revision::Tag(tag) => {
+            source.insert("tag".into(), tag.into());
+        }
+        Revision::Commit(_) => {
+            source.remove("tag");
+        }
+    }
+    // A fixed revision replaces the branch, which would move on
+    source.remove("branch");
+    generate::write_output(manifest_path, to_json_like(&manifest, &contents)?.as_bytes(), false)?;
+    Ok(Bumped { url, from, to, output, changes })
+}
+
+#[test]
+fn bump_local_app() {
+    let tmp = tempfile::tempdir().unwrap();
+    let (project, cache) = (tmp.path().join("project"), tmp.path().join("cache"));
+    let run = |args: &[&str]| {
+        let output = std::process::Command::new("git")
+            .args(["-c", "user.name=test", "-c", "user.email=test@example.com", "-c", "init.defaultBranch=main"])
+            .args(args)
+            .current_dir(&project)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap().trim_end().to_string()
+    };
+    let release = |version: &str, lockfile: bool| {
+        let manifest = format!("[package]\nname = \"app\"\nversion = \"{version}\"\nedition = \"2021\"\n");
+        std::fs::write(project.join("Cargo.toml"), manifest).unwrap();
+        match lockfile {
+            true => std::fs::write(project.join("Cargo.lock"), format!("version = 4\n\n[[package]]\nname = \"app\"\nversion = \"{version}\"\n")).unwrap(),
+            false => std::fs::remove_file(project.join("Cargo.lock")).unwrap(),
+        }
+        run(&["add", "-A"]);
+        run(&["commit", "-q", "-m", version]);
+        run(&["tag", &format!("v{version}")]);
+    };
+    std::fs::create_dir_all(project.join("src")).unwrap();
+    std::fs::write(project.join("src/main.rs"), "fn main() {}\n").unwrap();
+    run(&["init", "-q"]);
+    release("1.0.0", true);
+    let v1 = run(&["rev-parse", "HEAD"]);
+    release("1.1.0", true);
+    let v1_1 = run(&["rev-parse", "HEAD"]);
+    release("2.0.0", false);
+
+    let flatpak = tmp.path().join("flatpak");
+    std::fs::create_dir_all(&flatpak).unwrap();
+    let manifest_path = flatpak.join("org.example.App.json");
+    let url = project.to_str().unwrap();
+    let manifest = serde_json::json!({
+        "id": "org.example.App",
+        "modules": [
+            {"name": "libfoo", "sources": [{"type": "git", "url": "https://example.com/libfoo.git", "tag": "v3"}]},
+            {"name": "app", "sources": [
+                {"type": "git", "url": url, "tag": "v1.0.0", "commit": v1},
+                "cargo-sources.json",
+            ]},
+        ],
+    });
+    let contents = format!("{}\n", serde_json::to_string_pretty(&manifest).unwrap());
+    std::fs::write(&manifest_path, &contents).unwrap();
+    assert_eq!(app_source_pointer(&manifest, "cargo-sources.json", None).unwrap(), "/modules/1/sources/0");
+    assert_eq!(app_source_pointer(&manifest, "other.json", None).unwrap(), "/modules/1/sources/0");
+    let err = app_source_pointer(&manifest, "cargo-sources.json", Some(1)).unwrap_err();
+    assert_eq!(err.to_string(), "source 1 of the module app is not a git source");
+
+    let argv = |tag: &str| -> Vec<OsString> {
+        ["cargo", "flatpak", "bump", manifest_path.to_str().unwrap(), "--tag", tag].map(OsString::from).to_vec()
+    };
+    let err = bump(&manifest_path, "cargo-sources.json", Revision::Tag("v2.0.0"), None, &argv("v2.0.0"), &cache).unwrap_err();
+    assert_eq!(err.to_string(), format!("{url} has no Cargo.lock at v2.0.0, the sources of its dependencies can't be generated"));
+    assert_eq!(std::fs::read_to_string(&manifest_path).unwrap(), contents);
+    assert!(!flatpak.join("cargo-sources.json").exists());
+
+    let bumped = bump(&manifest_path, "cargo-sources.json", Revision::Tag("v1.1.0"), None, &argv("v1.1.0"), &cache).unwrap();
+    assert_eq!((bumped.from.as_str(), bumped.to.as_str(), bumped.changes.as_str()), ("v1.0.0", "v1.1.0", "written"));
+    let mut expected = manifest.clone();
+    expected["modules"][1]["sources"][0]["tag"] = "v1.1.0".into();
+    expected["modules"][1]["sources"][0]["commit"] = v1_1.clone().into();
+    let written = std::fs::read_to_string(&manifest_path).unwrap();
+    assert_eq!(written, format!("{}\n", serde_json::to_string_pretty(&expected).unwrap()));
+    assert!(generate::read_sources(&flatpak.join("cargo-sources.json"), None).is_ok());
+
+    // A commit replaces the tag
+    let argv = ["cargo", "flatpak", "bump", manifest_path.to_str().unwrap(), "--commit", &v1].map(OsString::from);
+    let bumped = bump(&manifest_path, "cargo-sources.json", Revision::Commit(&v1), None, &argv, &cache).unwrap();
+    assert_eq!((bumped.from.as_str(), bumped.to.as_str()), ("v1.1.0", &v1[..COMMIT_LEN]));
+    let written: Value = serde_json::from_str(&std::fs::read_to_string(&manifest_path).unwrap()).unwrap();
+    assert_eq!(written["modules"][1]["sources"][0], serde_json::json!({"type": "git", "url": url, "commit": v1}));
+
+    // The sources are written as generate writes them, --no-clobber included
+    let contents = std::fs::read_to_string(&manifest_path).unwrap();
+    let argv = ["cargo", "flatpak", "--no-clobber", "bump", manifest_path.to_str().unwrap(), "--tag", "v1.1.0"].map(OsString::from);
+    let err = bump(&manifest_path, "cargo-sources.json", Revision::Tag("v1.1.0"), None, &argv, &cache).unwrap_err();
+    assert!(err.to_string().contains("cargo-sources.json"), "{err}");
+    assert_eq!(std::fs::read_to_string(&manifest_path).unwrap(), contents);
+
+    let yaml_path = flatpak.join("org.example.App.yml");
+    std::fs::write(&yaml_path, "id: org.example.App\n").unwrap();
+    let err = bump(&yaml_path, "cargo-sources.json", Revision::Tag("v1.1.0"), None, &argv, &cache).unwrap_err();
+    assert_eq!(err.to_string(), format!("{} is a YAML manifest, bump only edits JSON ones", yaml_path.display()));
+}
@@ -0,0 +1,233 @@
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::cli::CacheAction;
+use crate::net::CACHE_VERSION;
+use crate::size::human;
+
+/// The caches of a layout, by their path in its directory, and what keeps them
+const CACHES: [(&str, &str); 5] = [
+    ("index", "crates.io index files, for the checksums Cargo.lock lacks"),
+    ("checkouts", "git clones of --from-git, --fetch and bump, and the crates their builds fetch"),
+    ("advisory-db", "the RustSec advisory database of audit"),
+    ("sizes.json", "download sizes of --estimate-size"),
+    ("archives.json", "archive checksums of --git-as-archive"),
+];
+
+/// The size and number of files of `path`, a file or a directory, not following symlinks
+fn disk_usage(path: &Path) -> (u64, usize) {
+    let Ok(metadata) = std::fs::symlink_metadata(path) else {
+        return (0, 0);
+    };
+    if !metadata.is_dir() {
+        return (metadata.len(), 1);
+    }
+    std::fs::read_dir(path)
+        .into_iter()
+        .flatten()
+        .filter_map(Result::ok)
+        .map(|entry| disk_usage(&entry.path()))
+        .fold((0, 0), |(size, files), (s, f)| (size + s, files + f))
+}
+
+/// The directories in `root` of layouts older than the current one, `v<N>` with
+/// a lower `N`. A newer version belongs to a newer cargo-flatpak sharing the
+/// cache, and nothing else in `root` is touched, which may be shared with other tools.
+fn stale_layouts(root: &Path) -> Vec<PathBuf> {
+    let mut stale: Vec<_> = std::fs::read_dir(root)
+        .into_iter()
+        .flatten()
+        .filter_map(Result::ok)
+        .filter(|entry| {
+            let name = entry.file_name().to_string_lossy().to_string();
+            name.strip_prefix('v')
+                .filter(|n| n.bytes().all(|b| b.is_ascii_digit()))
+                .and_then(|n| n.parse::<u32>().ok())
+                .is_some_and(|version| version < CACHE_VERSION)
+        })
+        .map(|entry| entry.path())
+        .collect();
+    stale.sort();
+    stale
+}
+
+/// How much a cache holds
+#[derive(Debug, PartialEq)]
+pub struct Usage {
+    pub name: String,
+    pub size: u64,
+    pub files: usize,
+}
+
+/// The usage of each cache of the current layout in `root`, then of the older
+/// layouts if there are any
+pub fn info(root: &Path) -> Vec<Usage> {
+    let dir = root.join(format!("v{CACHE_VERSION}"));
+    let mut usage: Vec<_> = CACHES
+        .iter()
+        .map(|(name, _)| {
+            let (size, files) = disk_usage(&dir.join(name));
+            Usage { name: name.to_string(), size, files }
+        })
+        .collect();
+    let stale = stale_layouts(root);
+    if !stale.is_empty() {
+        let (size, files) = stale.iter().map(|path| disk_usage(path)).fold((0, 0), |(size, files), (s, f)| (size + s, files + f));
+        usage.push(Usage { name: "older layouts".into(), size, files });
+    }
+    usage
+}
+
+/// When a file was last written, or a git clone last fetched or checked out
+fn last_written(path: &Path) -> Option<SystemTime> {
+    let modified = |path: &Path| std::fs::symlink_metadata(path).and_then(|m| m.modified()).ok();
+    let git = path.join(".git");
+    if !git.is_dir() {
+        return modified(path);
+    }
+    let entries = std::fs::read_dir(&git).into_iter().flatten().filter_map(Result::ok);
+    entries.filter_map(|entry| modified(&entry.path())).chain(modified(&git)).max()
+}
+
+/// What a prune or clear removed
+#[derive(Debug, Default, PartialEq)]
+pub struct Removed {
+    pub entries: usize,
+    pub size: u64,
+}
+
+impl Removed {
+    fn remove(&mut self, path: &Path) -> anyhow::Result<()> {
+        let (size, _) = disk_usage(path);
+        match path.is_dir() && !path.is_symlink() {
+            true => std::fs::remove_dir_all(path),
+            false => std::fs::remove_file(path),
+        }
+        .map_err(|e| anyhow::anyhow!("failed to remove {}: {e}", path.display()))?;
+        self.entries += 1;
+        self.size += size;
+        Ok(())
+    }
+}
+
+/// Removes what in `dir` was last written before `cutoff`, git clones as a
+/// whole, and the directories left empty
+fn prune_dir(dir: &Path, cutoff: SystemTime, removed: &mut Removed) -> anyhow::Result<()> {
+    for entry in std::fs::read_dir(dir)?.filter_map(Result::ok) {
+        let path = entry.path();
+        if path.is_dir() && !path.is_symlink() && !path.join(".git").is_dir() {
+            prune_dir(&path, cutoff, removed)?;
+            if std::fs::read_dir(&path)?.next().is_none() {
+                std::fs::remove_dir(&path)?;
+            }
+        } else if last_written(&path).is_some_and(|written| written < cutoff) {
+            removed.remove(&path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Removes the caches of older layouts in `root`, and what the current one
+/// hasn't written since `older_than` before `now`
+pub fn prune(root: &Path, older_than: Duration, now: SystemTime) -> anyhow::Result<Removed> {
+    let mut removed = Removed::default();
+    for stale in stale_layouts(root) {
+        removed.remove(&stale)?;
+    }
+    let dir = root.join(format!("v{CACHE_VERSION}"));
+    if dir.is_dir() {
+        prune_dir(&dir, now.checked_sub(older_than).unwrap_or(SystemTime::UNIX_EPOCH), &mut removed)?;
+    }
+    Ok(removed)
+}
+
+/// Removes every cache in `root`, of every layout
+pub fn clear(root: &Path) -> anyhow::Result<Removed> {
+    let mut removed = Removed::default();
+    let current = root.join(format!("v{CACHE_VERSION}"));
+    for path in stale_layouts(root).into_iter().chain(current.exists().then_some(current)) {
+        removed.remove(&path)?;
+    }
+    Ok(removed)
+}
+
+/// Runs `cargo flatpak cache`, on the caches of --cache-dir or the XDG cache directory
+pub fn cache(action: &CacheAction) -> anyhow::Result<()> {
+    let root = crate::net::cache_root().ok_or_else(|| anyhow::anyhow!("no cache directory, pass --cache-dir"))?;
+    match action {
+        CacheAction::Info => {
+            println!("{}", root.join(format!("v{CACHE_VERSION}")).display());
+            let usage = info(&root);
+            let width = usage.iter().map(|usage| usage.name.len()).max().unwrap_or(0);
+            for usage in &usage {
+                let about = CACHES.iter().find(|(name, _)| *name == usage.name).map_or("", |(_, about)| about);
+                println!("  {:width$}  {:>9}  {}", usage.name, human(usage.size), about);
+            }
+            println!("total: {}", human(usage.iter().map(|usage| usage.size).sum()));
+        }
+        CacheAction::Prune { older_than } => {
+            let removed = prune(&root, Duration::from_secs(older_than.saturating_mul(24 * 60 * 60)), SystemTime::now())?;
+            println!("pruned {} entries, {}", removed.entries, human(removed.size));
+        }
+        CacheAction::Clear => {
+            let removed = clear(&root)?;
+            println!("removed {}", human(removed.size));
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn pruned_and_cleared() {
+    let tmp = tempfile::tempdir().unwrap();
+    let root = tmp.path();
+    let dir = root.join(format!("v{CACHE_VERSION}"));
+    let now = SystemTime::now();
+    let days_ago = |days: u64| now - Duration::from_secs(days * 24 * 60 * 60);
+    let write = |path: &str, contents: &str, written: SystemTime| {
+        let path = root.join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, contents).unwrap();
+        std::fs::File::options().write(true).open(&path).unwrap().set_modified(written).unwrap();
+    };
+    let current = |path: &str| format!("v{CACHE_VERSION}/{path}");
+    write(&current("index/index.crates.io/3/s/syn"), "old", days_ago(40));
+    write(&current("index/index.crates.io/se/rd/serde"), "fresh", days_ago(1));
+    // A clone goes as a whole, by when it was last fetched, however old its files
+    write(&current("checkouts/repos/github.com/a/stale/src/lib.rs"), "", days_ago(1));
+    write(&current("checkouts/repos/github.com/a/stale/.git/HEAD"), "", days_ago(40));
+    std::fs::File::open(dir.join("checkouts/repos/github.com/a/stale/.git")).unwrap().set_modified(days_ago(40)).unwrap();
+    write(&current("checkouts/repos/github.com/a/fetched/src/lib.rs"), "", days_ago(40));
+    write(&current("checkouts/repos/github.com/a/fetched/.git/FETCH_HEAD"), "", days_ago(2));
+    write(&current("sizes.json"), "{}", days_ago(3));
+    write("v0/index/old", "v0", now);
+    // Not a layout, if named like a cache of one
+    write("index/unrelated", "not ours", now);
+    write("unrelated/file", "not ours", days_ago(100));
+    let newer = format!("v{}", CACHE_VERSION + 1);
+    write(&format!("{newer}/index/new"), "newer", days_ago(100));
+
+    let usage = info(root);
+    assert_eq!(usage[0], Usage { name: "index".into(), size: 8, files: 2 });
+    assert_eq!(usage[1].files, 4);
+    assert_eq!(usage[3], Usage { name: "sizes.json".into(), size: 2, files: 1 });
+    assert_eq!(usage.last().unwrap(), &Usage { name: "older layouts".into(), size: 2, files: 1 });
+
+    let removed = prune(root, Duration::from_secs(30 * 24 * 60 * 60), now).unwrap();
+    assert_eq!(removed, Removed { entries: 3, size: 5 });
+    assert!(dir.join("index/index.crates.io/se/rd/serde").is_file());
+    assert!(!dir.join("index/index.crates.io/3").exists());
+    assert!(!dir.join("checkouts/repos/github.com/a/stale").exists());
+    assert!(dir.join("checkouts/repos/github.com/a/fetched/src/lib.rs").is_file());
+    assert!(dir.join("sizes.json").is_file());
+    assert!(!root.join("v0").exists() && root.join("index/unrelated").is_file());
+    assert!(root.join(&newer).join("index/new").is_file());
+    assert!(root.join("unrelated/file").is_file());
+    assert_eq!(info(root).len(), CACHES.len());
+
+    let removed = clear(root).unwrap();
+    assert_eq!(removed, Removed { entries: 1, size: 7 });
+    assert!(!dir.exists());
+    assert!(root.join("unrelated/file").is_file() && root.join("index/unrelated").is_file() && root.join(&newer).is_dir());
+    assert_eq!(clear(root).unwrap(), Removed::default());
+}
@@ -0,0 +1,454 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+
+use crate::cli::AppSource;
+use crate::diagnostics::{self, Diagnostic};
+use crate::hash::{CommitHash, Sha256};
+use crate::net;
+use crate::sources::{Archive, Git, Source};
+
+/// What of a --from-git repository is checked out
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GitRef<'a> {
+    Tag(&'a str),
+    Branch(&'a str),
+    Rev(&'a str),
+    /// The default branch of the remote
+    Head,
+}
+
+impl GitRef<'_> {
+    /// What `git checkout` takes for the reference in a clone of the remote
+    fn target(&self) -> String {
+        match self {
+            GitRef::Tag(tag) => format!("refs/tags/{tag}"),
+            GitRef::Branch(branch) => format!("refs/remotes/origin/{branch}"),
+            GitRef::Rev(rev) => rev.to_string(),
+            GitRef::Head => "refs/remotes/origin/HEAD".into(),
+        }
+    }
+}
+
+/// The `checkouts` directory of the cache, the clones of --from-git by
+/// host and path, along with the CARGO_HOME their dependencies are fetched into
+pub fn default_cache() -> Option<PathBuf> {
+    Some(net::cache_dir()?.join("checkouts"))
+}
+
+/// The CARGO_HOME of the checkouts in `cache`, kept apart from the user's
+pub fn cargo_home(cache: &Path) -> PathBuf {
+    cache.join("cargo-home")
+}
+
+/// Where the clone of `url` is kept in `cache`
+fn clone_path(cache: &Path, url: &str) -> PathBuf {
+    let path = url.split_once("://").map_or(url, |(_, path)| path);
+    let path = path.trim_end_matches('/').trim_end_matches(".git");
+    cache.join("repos").join(path.trim_start_matches('/').replace(':', "_"))
+}
+
+/// Runs git in `dir`, failing with what it printed when it fails
+fn git(dir: &Path, args: &[&str]) -> anyhow::Result<()> {
+    git_output(dir, args).map(drop)
+}
+
+/// Runs git in `dir` like [`git`], returning its trimmed output. It's killed
+/// after the network timeout, as a clone or fetch may hang on the remote.
+fn git_output(dir: &Path, args: &[&str]) -> anyhow::Result<String> {
+    let output = net::output_with_timeout(net::git().arg("-C").arg(dir).args(args), net::client().retry().timeout)
+        .with_context(|| format!("`git {}` failed", args.join(" ")))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        anyhow::bail!("`git {}` failed with {}:\n{}", args.join(" "), output.status, stderr.trim_end());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim_end().to_string())
+}
+
+/// Checks `reference` of the repository at `url` out in `cache`, cloning it
+/// the first time. Later runs reuse the clone, fetching only for branches, and
+/// for tags and revisions it doesn't have yet. Returns the checkout.
+pub fn checkout(url: &str, reference: GitRef, cache: &Path) -> anyhow::Result<PathBuf> {
+    let dir = clone_path(cache, url);
+    let target = reference.target();
+    if !dir.join(".git").exists() {
+        std::fs::create_dir_all(&dir)?;
+        git(&dir, &["clone", "--quiet", "--no-checkout", url, "."])?;
+    } else {
+        let commit = format!("{target}^{{commit}}");
+        let known = git(&dir, &["rev-parse", "--quiet", "--verify", &commit]).is_ok();
+        if !known || matches!(reference, GitRef::Branch(_) | GitRef::Head) {
+            git(&dir, &["fetch", "--quiet", "--tags", "--force", "--prune", "origin", "+refs/heads/*:refs/remotes/origin/*"])?;
+        }
+    }
+    git(&dir, &["checkout", "--quiet", "--force", "--detach", &target])
+        .map_err(|e| e.context(format!("{url} has no {}", target.trim_start_matches("refs/remotes/origin/"))))?;
+    // Leftovers of other references, or of running cargo in the checkout
+    git(&dir, &["clean", "--quiet", "-ffdx", "--exclude", "/target"])?;
+    Ok(dir)
+}
+
+/// The commit checked out in `dir`
+pub fn head_commit(dir: &Path) -> anyhow::Result<CommitHash> {
+    Ok(CommitHash::try_from(git_output(dir, &["rev-parse", "HEAD"])?)?)
+}
+
+/// A published crate unpacked into a temporary directory, removed when dropped
+#[derive(Debug)]
+pub struct UnpackedCrate {
+    /// The package, with its Cargo.lock
+    pub dir: PathBuf,
+    pub url: String,
+    pub sha256: Sha256,
+    temp: PathBuf,
+}
+
+impl UnpackedCrate {
+    /// The crate archive the application builds from, at `dest`
+    pub fn source(&self, dest: String) -> Source {
+        Source::Archive(Archive {
+            archive_type: "tar-gzip".into(),
+            url: Some(self.url.clone()),
+            path: None,
+            sha256: self.sha256.clone(),
+            dest,
+            dest_filename: None,
+        })
+    }
+}
+
+impl Drop for UnpackedCrate {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.temp);
+    }
+}
+
+/// Downloads `name` `version` from `crate_url_template`, checking it against
+/// the checksum in the sparse index at `index`, and unpacks it. Applications
+/// publish their Cargo.lock: one is only resolved now, with a warning, when
+/// `allow_missing_lockfile` is set.
+pub fn unpack_crate(
+    name: &str,
+    version: &str,
+    crate_url_template: &str,
+    index: &str,
+    index_cache: Option<&Path>,
+    allow_missing_lockfile: bool,
+) -> anyhow::Result<UnpackedCrate> {
+    let sha256 = crate::index::checksum(index, name, version, index_cache)?;
+    let url = crate::sources::crate_url(crate_url_template, name, version, sha256.as_str());
+    let temp = crate::test_build::temp_dir("from-crate")?;
+    let unpacked = UnpackedCrate { dir: temp.join(format!("{name}-{version}")), url, sha256, temp };
+
+    let archive = unpacked.temp.join(format!("{name}-{version}.crate"));
+    let mut response = net::client().get(&unpacked.url).with_context(|| format!("failed to download {}", unpacked.url))?.into_reader();
+    std::io::copy(&mut response, &mut std::fs::File::create(&archive)?)?;
+    let downloaded = crate::sources::sha256_file(&archive)?;
+    if downloaded != unpacked.sha256.as_str() {
+        anyhow::bail!("{} has the checksum {downloaded}, the index says {}", unpacked.url, unpacked.sha256.as_str());
+    }
+    let status = std::process::Command::new("tar").arg("-xzf").arg(&archive).arg("-C").arg(&unpacked.temp).status()?;
+    if !status.success() || !unpacked.dir.join("Cargo.toml").is_file() {
+        anyhow::bail!("failed to unpack {}", unpacked.url);
+    }
+
+    if !unpacked.dir.join("Cargo.lock").exists() {
+        if !allow_missing_lockfile {
+            let error = anyhow::anyhow!("{name} {version} was published without a Cargo.lock");
+            return Err(diagnostics::Annotated::new(
+                error,
+                "pass --allow-missing-lockfile to resolve one now, which may pick newer dependencies than upstream builds with",
+            )
+            .into());
+        }
+        let status = std::process::Command::new(crate::toolchain::cargo()).arg("generate-lockfile").current_dir(&unpacked.dir).status()?;
+        if !status.success() {
+            anyhow::bail!("`cargo generate-lockfile` failed with {status}");
+        }
+        diagnostics::warn(
+            "generated-lockfile",
+            format!("{name} {version} was published without a Cargo.lock, the sources are of one resolved now"),
+        );
+    }
+    Ok(unpacked)
+}
+
+/// The origin remote of the repository of `workspace`, as flatpak-builder can
+/// clone it, and its HEAD commit
+fn origin(workspace: &Path) -> anyhow::Result<(String, CommitHash)> {
+    let url = git_output(workspace, &["remote", "get-url", "origin"])
+        .with_context(|| format!("--app-source git needs the repository of {} to have an origin remote", workspace.display()))?;
+    Ok((https_remote(&url)?, head_commit(workspace)?))
+}
+
+/// `url` over https when it's an SSH remote, `ssh://[user@]host/path` or
+/// `[user@]host:path`, which a flatpak build has no keys for
+fn https_remote(url: &str) -> anyhow::Result<String> {
+    let https = match url.split_once("://") {
+        Some(("ssh" | "git+ssh", rest)) => {
+            let (host, path) = rest.split_once('/').unwrap_or((rest, ""));
+            let host = host.rsplit('@').next().unwrap();
+            format!("https://{}/{path}", host.split(':').next().unwrap())
+        }
+        Some(_) => return Ok(url.to_string()),
+        None => match url.split_once(':') {
+            // A path of the remote has no colon before its first slash
+            Some((host, path)) if !host.contains('/') => format!("https://{}/{}", host.rsplit('@').next().unwrap(), path.trim_start_matches('/')),
+            _ => anyhow::bail!("the origin remote {url} is a local path, which a flatpak build can't clone"),
+        },
+    };
+    diagnostics::emit(
+        Diagnostic::warning("ssh-remote", format!("the origin remote {url} is SSH, which a flatpak build has no keys for, the app source clones {https}"))
+            .with_suggestion("check that the repository is public, or use --app-source archive:URL"),
+    );
+    Ok(https)
+}
+
+/// The --app-source of the application in `workspace`, at `dest`. A git
+/// source is of the origin remote at the HEAD commit, with a warning when
+/// tracked files have changes that aren't committed. An archive is downloaded to compute
+/// its checksum, unless `sha256` is given.
+pub fn app_source(app: &AppSource, sha256: Option<&Sha256>, workspace: &Path, dest: String) -> anyhow::Result<Source> {
+    match app {
+        AppSource::Git => {
+            let (url, commit) = origin(workspace)?;
+            // Untracked files are left alone, like the sources written next to the manifest
+            if !git_output(workspace, &["status", "--porcelain", "--untracked-files=no"])?.is_empty() {
+                diagnostics::emit(
+                    Diagnostic::warning(
+                        "dirty-tree",
+                        format!("{} has uncommitted changes, the app source is commit {}", workspace.display(), commit.abbrev(7)),
+                    )
+                    .with_suggestion("commit them, or they're left out of the flatpak build"),
+                );
+            }
+            Ok(Source::Git(Git { url, commit, dest, x_checker_data: None }))
+        }
+        AppSource::Archive(url) => {
+            let sha256 = match sha256 {
+                Some(sha256) => sha256.clone(),
+                // A release URL may be replaced, so its checksum isn't cached
+                None => crate::sources::archive_sha256(url, None).with_context(|| format!("failed to download {url}"))?,
+            };
+            Ok(Source::Archive(Archive {
+                archive_type: archive_type(url)?.into(),
+                url: Some(url.clone()),
+                path: None,
+                sha256,
+                dest,
+                dest_filename: None,
+            }))
+        }
+    }
+}
+
+/// The flatpak-builder archive type of `url`, by its extension
+fn archive_type(url: &str) -> anyhow::Result<&'static str> {
+    let name = url.split(['?', '#']).next().unwrap_or(url);
+    let types = [
+        (".tar.gz", "tar-gzip"),
+        (".tgz", "tar-gzip"),
+        (".tar.xz", "tar-xz"),
+        (".tar.bz2", "tar-bzip2"),
+        (".tar.zst", "tar-zst"),
+        (".tar", "tar"),
+        (".zip", "zip"),
+    ];
+    match types.iter().find(|(extension, _)| name.ends_with(extension)) {
+        Some((_, archive_type)) => Ok(archive_type),
+        None => anyhow::bail!("--app-source can't tell the archive type of {url}, expected a .tar.gz, .tar.xz, .tar.bz2, .tar.zst, .tar or .zip"),
+    }
+}
+
+#[test]
+fn checkouts_of_a_local_remote() {
+    let tmp = tempfile::tempdir().unwrap();
+    let (project, remote, cache) = (tmp.path().join("project"), tmp.path().join("remote.git"), tmp.path().join("cache"));
+    let run = |dir: &Path, args: &[&str]| {
+        let status = std::process::Command::new("git")
+            .args(["-c", "user.name=test", "-c", "user.email=test@example.com", "-c", "init.defaultBranch=main"])
+            .args(args)
+            .current_dir(dir)
+            .status()
+            .unwrap();
+        assert!(status.success());
+    };
+    let release = |version: &str| {
+        let manifest = format!("[package]\nname = \"app\"\nversion = \"{version}\"\nedition = \"2021\"\n");
+        let cargo_lock = format!("version = 4\n\n[[package]]\nname = \"app\"\nversion = \"{version}\"\n");
+        std::fs::write(project.join("Cargo.toml"), manifest).unwrap();
+        std::fs::write(project.join("Cargo.lock"), cargo_lock).unwrap();
+        run(&project, &["add", "."]);
+        run(&project, &["commit", "-q", "-m", version]);
+    };
+    std::fs::create_dir_all(project.join("src")).unwrap();
+    std::fs::write(project.join("src/main.rs"), "fn main() {}\n").unwrap();
+    run(&project, &["init", "-q"]);
+    release("1.0.0");
+    run(&project, &["tag", "v1.0.0"]);
+    release("1.1.0");
+    run(tmp.path(), &["clone", "-q", "--bare", "project", "remote.git"]);
+
+    let url = remote.to_str().unwrap();
+    let version = |checkout: &Path| std::fs::read_to_string(checkout.join("Cargo.toml")).unwrap().lines().nth(2).unwrap().to_string();
+    let tagged = checkout(url, GitRef::Tag("v1.0.0"), &cache).unwrap();
+    assert!(tagged.starts_with(cache.join("repos")));
+    assert_eq!(version(&tagged), "version = \"1.0.0\"");
+    assert_eq!(version(&checkout(url, GitRef::Head, &cache).unwrap()), "version = \"1.1.0\"");
+    assert_eq!(version(&checkout(url, GitRef::Branch("main"), &cache).unwrap()), "version = \"1.1.0\"");
+
+    // The sources of the checkout are generated as usual
+    let metadata = cargo_metadata::MetadataCommand::new().manifest_path(tagged.join("Cargo.toml")).exec().unwrap();
+    let cargo_lock = std::fs::read_to_string(tagged.join("Cargo.lock")).unwrap();
+    let args = <crate::cli::Args as clap::Parser>::parse_from(["flatpak"]);
+    let generated = crate::generate::generate(&args, &metadata, &cargo_lock, "hash".into(), &tmp.path().join("cargo-sources.json"));
+    assert!(generated.is_ok());
+
+    let err = checkout(url, GitRef::Tag("v9.9.9"), &cache).unwrap_err();
+    assert_eq!(err.to_string(), format!("{url} has no refs/tags/v9.9.9"));
+
+    // A new branch commit is fetched, what the clone has is checked out without the remote
+    release("1.2.0");
+    run(&project, &["push", "-q", remote.to_str().unwrap(), "main"]);
+    assert_eq!(version(&checkout(url, GitRef::Branch("main"), &cache).unwrap()), "version = \"1.2.0\"");
+    std::fs::remove_dir_all(&remote).unwrap();
+    std::fs::write(tagged.join("stray"), "").unwrap();
+    assert_eq!(version(&checkout(url, GitRef::Tag("v1.0.0"), &cache).unwrap()), "version = \"1.0.0\"");
+    assert!(!tagged.join("stray").exists());
+}
+
+#[test]
+fn unpacked_crates() {
+    use std::collections::HashMap;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let crate_file = |name: &str, lockfile: bool| {
+        let dir = tmp.path().join(format!("pkg/{name}-1.0.0"));
+        std::fs::create_dir_all(dir.join("src")).unwrap();
+        std::fs::write(dir.join("Cargo.toml"), format!("[package]\nname = \"{name}\"\nversion = \"1.0.0\"\nedition = \"2021\"\n")).unwrap();
+        std::fs::write(dir.join("src/main.rs"), "fn main() {}\n").unwrap();
+        if lockfile {
+            std::fs::write(dir.join("Cargo.lock"), format!("version = 3\n\n[[package]]\nname = \"{name}\"\nversion = \"1.0.0\"\n")).unwrap();
+        }
+        let file = tmp.path().join(format!("{name}-1.0.0.crate"));
+        let status = std::process::Command::new("tar")
+            .arg("-czf")
+            .arg(&file)
+            .arg("-C")
+            .arg(tmp.path().join("pkg"))
+            .arg(format!("{name}-1.0.0"))
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let body = std::fs::read(&file).unwrap();
+        let checksum = crate::sources::sha256_file(&file).unwrap();
+        let index = format!("{{\"name\":\"{name}\",\"vers\":\"1.0.0\",\"deps\":[],\"cksum\":\"{checksum}\",\"features\":{{}},\"yanked\":false}}\n");
+        (body, checksum, index)
+    };
+    let (tool, tool_checksum, tool_index) = crate_file("tool", true);
+    let (bare, _, bare_index) = crate_file("bare", false);
+    let files = HashMap::from([
+        ("/to/ol/tool", tool_index.into()),
+        ("/ba/re/bare", bare_index.into()),
+        ("/crates/tool-1.0.0.crate", tool),
+        ("/crates/bare-1.0.0.crate", bare),
+    ]);
+    let (server, _) = crate::index::serve(files);
+    let template = format!("{server}crates/{{name}}-{{version}}.crate");
+
+    let unpacked = unpack_crate("tool", "1.0.0", &template, &server, None, false).unwrap();
+    assert_eq!(unpacked.url, format!("{server}crates/tool-1.0.0.crate"));
+    assert_eq!(unpacked.sha256.as_str(), tool_checksum);
+    assert!(unpacked.dir.join("Cargo.lock").is_file());
+    let temp = unpacked.temp.clone();
+    drop(unpacked);
+    assert!(!temp.exists());
+
+    let err = unpack_crate("bare", "1.0.0", &template, &server, None, false).unwrap_err();
+    assert_eq!(err.to_string(), "bare 1.0.0 was published without a Cargo.lock");
+    let (unpacked, warnings) = diagnostics::capture(diagnostics::ErrorFormat::Human, || {
+        unpack_crate("bare", "1.0.0", &template, &server, None, true).unwrap()
+    });
+    assert!(unpacked.dir.join("Cargo.lock").is_file());
+    assert!(warnings.starts_with("warning: bare 1.0.0 was published without a Cargo.lock"));
+
+    let err = unpack_crate("tool", "2.0.0", &template, &server, None, false).unwrap_err();
+    assert_eq!(err.to_string(), format!("tool 2.0.0 is not in the index {server}"));
+}
+
+#[test]
+fn app_sources() {
+    use std::collections::HashMap;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let run = |args: &[&str]| {
+        let output = std::process::Command::new("git")
+            .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+            .args(args)
+            .current_dir(tmp.path())
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap().trim_end().to_string()
+    };
+    std::fs::write(tmp.path().join("Cargo.toml"), "[package]\nname = \"app\"\n").unwrap();
+    run(&["init", "-q"]);
+    run(&["add", "."]);
+    run(&["commit", "-q", "-m", "app"]);
+    let err = app_source(&AppSource::Git, None, tmp.path(), ".".into()).unwrap_err();
+    assert!(err.to_string().ends_with("to have an origin remote"));
+
+    run(&["remote", "add", "origin", "https://example.com/app.git"]);
+    let commit = CommitHash::try_from(run(&["rev-parse", "HEAD"])).unwrap();
+    let expected = Source::Git(Git { url: "https://example.com/app.git".into(), commit: commit.clone(), dest: ".".into(), x_checker_data: None });
+    std::fs::write(tmp.path().join("cargo-sources.json"), "[]").unwrap();
+    let (source, warnings) = diagnostics::capture(diagnostics::ErrorFormat::Human, || {
+        app_source(&AppSource::Git, None, tmp.path(), ".".into()).unwrap()
+    });
+    assert_eq!(source, expected);
+    assert_eq!(warnings, "");
+    std::fs::write(tmp.path().join("Cargo.toml"), "[package]\nname = \"changed\"\n").unwrap();
+    let (source, warnings) = diagnostics::capture(diagnostics::ErrorFormat::Human, || {
+        app_source(&AppSource::Git, None, tmp.path(), ".".into()).unwrap()
+    });
+    assert_eq!(source, expected);
+    assert!(warnings.starts_with(&format!("warning: {} has uncommitted changes, the app source is commit {}", tmp.path().display(), commit.abbrev(7))));
+
+    // SSH remotes are cloned over https
+    for remote in ["git@example.com:owner/app.git", "ssh://git@example.com:2222/owner/app.git"] {
+        run(&["remote", "set-url", "origin", remote]);
+        let ((url, _), warnings) = diagnostics::capture(diagnostics::ErrorFormat::Human, || origin(tmp.path()).unwrap());
+        assert_eq!(url, "https://example.com/owner/app.git");
+        assert!(warnings.starts_with(&format!("warning: the origin remote {remote} is SSH")), "{warnings}");
+    }
+    run(&["remote", "set-url", "origin", "/srv/git/app.git"]);
+    let err = origin(tmp.path()).unwrap_err().to_string();
+    assert_eq!(err, "the origin remote /srv/git/app.git is a local path, which a flatpak build can't clone");
+    run(&["remote", "set-url", "origin", "https://example.com/app.git"]);
+
+    let (server, _) = crate::index::serve(HashMap::from([("/app-1.0.tar.xz", b"release".to_vec())]));
+    let url = format!("{server}app-1.0.tar.xz");
+    let Source::Archive(archive) = app_source(&AppSource::Archive(url.clone()), None, tmp.path(), "app".into()).unwrap() else {
+        panic!("not an archive");
+    };
+    assert_eq!(archive.archive_type, "tar-xz");
+    assert_eq!(archive.url, Some(url));
+    assert_eq!(archive.dest, "app");
+    let file = tmp.path().join("release");
+    std::fs::write(&file, "release").unwrap();
+    assert_eq!(archive.sha256.as_str(), crate::sources::sha256_file(&file).unwrap());
+
+    // Given the checksum, the archive isn't downloaded
+    let sha256 = Sha256::try_from("0".repeat(64)).unwrap();
+    let url = "https://unreachable.invalid/app-1.0.zip?download=1".to_string();
+    let Source::Archive(archive) = app_source(&AppSource::Archive(url), Some(&sha256), tmp.path(), ".".into()).unwrap() else {
+        panic!("not an archive");
+    };
+    assert_eq!((archive.archive_type.as_str(), archive.sha256), ("zip", sha256.clone()));
+    let err = app_source(&AppSource::Archive("https://example.com/app".into()), Some(&sha256), tmp.path(), ".".into()).unwrap_err();
+    assert!(err.to_string().starts_with("--app-source can't tell the archive type of https://example.com/app"));
+
+    assert_eq!(crate::cli::parse_app_source("git"), Ok(AppSource::Git));
+    assert_eq!(crate::cli::parse_app_source("archive:https://example.com/a.tar.gz"), Ok(AppSource::Archive("https://example.com/a.tar.gz".into())));
+    assert!(crate::cli::parse_app_source("archive:").is_err());
+}
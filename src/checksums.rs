@@ -0,0 +1,59 @@
+use std::collections::BTreeSet;
+
+use crate::sources::Source;
+
+/// What the builder downloads for `sources`, as a `sha256sum` list: a line
+/// `<sha256>  <url>` per archive and file, then after a blank line a line
+/// `<commit>  <url>` per git repository. Both are sorted by URL, and given
+/// the same sources the list is the same, whatever their order.
+pub fn sha256sums(sources: &[&Source]) -> String {
+    let mut downloads = BTreeSet::new();
+    let mut clones = BTreeSet::new();
+    for source in sources {
+        match source {
+            Source::Archive(archive) => {
+                if let Some(url) = &archive.url {
+                    downloads.insert((url.as_str(), archive.sha256.as_str()));
+                }
+            }
+            Source::File(file) => {
+                if let (Some(url), Some(sha256)) = (&file.url, &file.sha256) {
+                    downloads.insert((url.as_str(), sha256.as_str()));
+                }
+            }
+            Source::Git(git) => {
+                clones.insert((git.url.as_str(), git.commit.as_str()));
+            }
+            _ => {}
+        }
+    }
+    let lines = |entries: BTreeSet<(&str, &str)>| entries.into_iter().map(|(url, hash)| format!("{hash}  {url}\n")).collect::<String>();
+    let mut sums = lines(downloads);
+    if !clones.is_empty() {
+        sums += "\n";
+        sums += &lines(clones);
+    }
+    sums
+}
+
+#[test]
+fn mixed_sources() {
+    let sha256 = |c: char| c.to_string().repeat(64);
+    let sources: Vec<Source> = serde_json::from_value(serde_json::json!([
+        {"type": "archive", "archive-type": "tar-gzip", "url": "https://static.crates.io/crates/url/url-2.5.0.crate", "sha256": sha256('b'), "dest": "cargo/vendor/url-2.5.0"},
+        {"type": "git", "url": "https://github.com/gtk-rs/gtk4-rs", "commit": "89abcdef0123456789abcdef0123456789abcdef", "dest": "flatpak-cargo/git/gtk4-rs-89abcde"},
+        {"type": "inline", "contents": "{}", "dest": "cargo/vendor/url-2.5.0", "dest-filename": ".cargo-checksum.json"},
+        {"type": "file", "url": "https://mirror.example/anstream-0.6.15.crate", "sha256": sha256('a'), "dest": "cargo/local-registry", "dest-filename": "anstream-0.6.15.crate"},
+        {"type": "file", "path": "patches/fix.patch", "sha256": sha256('c'), "dest": "patches"},
+        {"type": "archive", "archive-type": "tar-gzip", "url": "https://static.crates.io/crates/url/url-2.5.0.crate", "sha256": sha256('b'), "dest": "elsewhere"},
+        {"type": "git", "url": "https://github.com/gtk-rs/gtk-rs-core", "commit": "0123456789abcdef0123456789abcdef01234567", "dest": "flatpak-cargo/git/gtk-rs-core-0123456"},
+        {"type": "git", "url": "https://github.com/gtk-rs/gtk4-rs", "commit": "0123456789abcdef0123456789abcdef01234567", "dest": "flatpak-cargo/git/gtk4-rs-0123456"},
+        {"type": "shell", "commands": ["true"]},
+    ]))
+    .unwrap();
+    let sources: Vec<_> = sources.iter().collect();
+    assert_eq!(sha256sums(&sources), include_str!("testdata/sha256sums"));
+    let reversed: Vec<_> = sources.iter().rev().copied().collect();
+    assert_eq!(sha256sums(&reversed), sha256sums(&sources));
+    assert_eq!(sha256sums(&sources[2..3]), "");
+}
@@ -1,11 +1,712 @@
-use clap::Parser;
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand, ValueEnum};
+
+use crate::diagnostics::ErrorFormat;
+use crate::hash::{CommitHash, Sha256};
+use crate::policy::{parse_forbid_rule, ForbidRule, SourceKind};
+use crate::sources::SourcesFormat;
+use crate::{CARGO_HOME, CRATES_IO, CRATES_IO_INDEX, LOCAL_REGISTRY_DIR, VENDOR_DIR};
 
 /// Simple program to greet a person
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 pub struct Args {
+    #[clap(subcommand)]
+    pub command: Option<SubCommand>,
+    /// The sources file, written as YAML when it ends in .yml or .yaml
     #[clap(short, long, default_value = "cargo-sources.json")]
     pub output: String,
+    /// What the sources files are written as [default: by the extension of --output]
+    #[clap(long, value_enum, value_name = "FORMAT")]
+    pub format: Option<SourcesFormat>,
+    /// Cargo.lock to generate from, `-` to read it from stdin [default: the workspace's]
+    #[clap(long, value_name = "PATH", conflicts_with = "watch")]
+    pub lockfile: Option<PathBuf>,
+    /// Generate from the lockfile alone, without running cargo metadata on a
+    /// project, which needn't be on disk. Git dependencies can't be vendored then.
+    #[clap(long, conflicts_with_all = ["watch", "from_git", "from_crate"])]
+    pub lockfile_only: bool,
+    /// Fail when Cargo.lock is out of date with the manifests instead of warning
+    #[clap(long)]
+    pub strict: bool,
+    /// Fail instead of overwriting files that already exist
+    #[clap(long)]
+    pub no_clobber: bool,
+    /// Bundle path dependencies that live outside of the workspace as `dir` sources,
+    /// where they are relative to the workspace
+    #[clap(long)]
+    pub bundle_path_deps: bool,
+    /// Set `dest-filename` on archive sources to `{name}-{version}.crate`
+    #[clap(long)]
+    pub archive_dest_filename: bool,
+    /// Download URL of registry crates, with `{name}`, `{version}`, `{prefix}`
+    /// (the index directory, e.g. `se/rd`) and `{checksum}` placeholders
+    #[clap(long, default_value_t = format!("{CRATES_IO}/{{name}}/{{name}}-{{version}}.crate"), value_parser = parse_crate_url_template)]
+    pub crate_url_template: String,
+    /// Directory of pre-downloaded `{name}-{version}.crate` files to use instead of crates.io
+    #[clap(long)]
+    pub local_crates_dir: Option<PathBuf>,
+    /// Fail when a crate is missing from --local-crates-dir instead of downloading it
+    #[clap(long, requires = "local_crates_dir")]
+    pub require_local: bool,
+    /// Add `x-checker-data` to git sources that track a branch or a tag
+    #[clap(long)]
+    pub x_checker_data: bool,
+    /// Add `[net] offline = true` to the generated cargo config
+    #[clap(long)]
+    pub config_offline: bool,
+    /// Merge the project's .cargo/config.toml, except `[source]`, into the generated config
+    #[clap(long)]
+    pub merge_project_config: bool,
+    /// Write the cargo config to this file and reference it instead of inlining it
+    #[clap(long)]
+    pub write_config: Option<PathBuf>,
+    /// Leave the cargo config out of the sources, for projects maintaining their own
+    #[clap(long, conflicts_with = "write_config")]
+    pub no_config: bool,
+    /// Keep running, regenerating whenever Cargo.lock or a workspace manifest changes
+    #[clap(long, conflicts_with = "no_clobber")]
+    pub watch: bool,
+    /// Print the generated cargo config
+    #[clap(long)]
+    pub print_config: bool,
+    /// Print errors, warnings and notes as they are, or as a JSON object per line
+    #[clap(long, value_enum, value_name = "FORMAT", default_value = "human")]
+    pub error_format: ErrorFormat,
+    /// Write the crates added, removed and updated since the last sources
+    /// file to FILE, as a Markdown comment for merge requests
+    #[clap(long, value_name = "FILE")]
+    pub summary_markdown: Option<PathBuf>,
+    /// The cargo to run, instead of the one of the CARGO environment variable or
+    /// else PATH
+    #[clap(long, value_name = "BIN", value_parser = parse_cargo_path)]
+    pub cargo_path: Option<PathBuf>,
+    /// Report the cargo that runs, and its version
+    #[clap(long, short)]
+    pub verbose: bool,
+    /// Where to keep the index files, git clones and downloads looked up on the
+    /// network [default: $XDG_CACHE_HOME/cargo-flatpak]
+    #[clap(long, value_name = "DIR")]
+    pub cache_dir: Option<PathBuf>,
+    /// Write where the run spends its time to FILE, as Chrome trace events
+    /// for about://tracing, with a span per crate
+    #[clap(long, value_name = "FILE")]
+    pub trace: Option<PathBuf>,
+    /// Print the resolved options and whether each comes from the command line, a
+    /// `CARGO_FLATPAK_*` variable, the metadata settings or the defaults, then exit
+    #[clap(long, value_enum, value_name = "FORMAT", num_args = 0..=1, default_missing_value = "toml")]
+    pub print_options: Option<ConfigFormat>,
+    /// Directory of the flatpak manifest, file sources are relative to it
+    /// [default: the directory of the output file]
+    #[clap(long)]
+    pub manifest_dir: Option<PathBuf>,
+    /// Ship the Cargo.lock the sources were generated from
+    #[clap(long)]
+    pub include_lockfile: bool,
+    /// Reference local files, such as the lockfile, with file sources instead of inlining them
+    #[clap(long)]
+    pub no_inline: bool,
+    /// Where CARGO_HOME is staged in the build directory, the config and the
+    /// vendored crates are placed under it
+    #[clap(long, default_value = CARGO_HOME)]
+    pub cargo_home: String,
+    /// Directory of the vendored crates, relative to the build directory.
+    /// Takes precedence over --cargo-home, which only moves the config then
+    /// [default: <cargo-home>/vendor]
+    #[clap(long)]
+    pub vendor_dir: Option<String>,
+    /// How the registry crates are vendored, git crates are always extracted
+    /// into the vendor directory
+    #[clap(long, value_enum, default_value = "directory")]
+    pub vendor_strategy: VendorStrategy,
+    /// Write the sources of the packages that could be generated when others
+    /// fail, listing what's missing and exiting with status 2
+    #[clap(long)]
+    pub keep_going: bool,
+    /// Leave out the registry crates no flatpak architecture of --arches builds,
+    /// like windows-sys, vendoring stubs cargo resolves the lockfile with instead
+    #[clap(long, conflicts_with = "lockfile_only")]
+    pub prune_foreign_targets: bool,
+    /// The flatpak architectures --prune-foreign-targets keeps the crates of,
+    /// and the advisories of audit are matched for
+    #[clap(long, value_delimiter = ',', default_value = "x86_64,aarch64")]
+    pub arches: Vec<FlatpakArch>,
+    /// Look the checksums Cargo.lock lacks up in the registry's sparse index
+    /// instead of failing on the packages without one
+    #[clap(long)]
+    pub resolve_missing_checksums: bool,
+    /// Sparse index crates.io checksums are looked up in, for --resolve-missing-checksums
+    /// and --add-crate
+    #[clap(long, value_name = "URL", default_value = CRATES_IO_INDEX)]
+    pub crates_io_index: String,
+    /// Order of the sources: each crate's sources together, in Cargo.lock order,
+    /// or grouped by source type. The cargo config always comes last.
+    #[clap(long, value_enum, default_value = "crate")]
+    pub group_by: GroupBy,
+    /// Write the sources across files of at most N entries, `<output stem>-1.json`
+    /// and so on, each crate's sources in one file
+    #[clap(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    pub split: Option<u64>,
+    /// Write the sources of git crates to PATH instead, relative to the
+    /// workspace like --output, leaving the other crates and the cargo config
+    /// in the output
+    #[clap(long, value_name = "PATH", conflicts_with = "split")]
+    pub separate_git: Option<String>,
+    /// Download git dependencies hosted on GitHub, GitLab or Gitea as commit
+    /// archives, with checksums, instead of cloning them
+    #[clap(long)]
+    pub git_as_archive: bool,
+    /// Move the last crate out of each git clone instead of copying it, and
+    /// remove the clone, so the build directory doesn't hold the repository twice
+    #[clap(long)]
+    pub move_git_sources: bool,
+    /// Clone each git crate straight into the vendor directory, once per crate,
+    /// instead of copying it out of one clone of its repository with `shell`
+    /// sources, which some builders don't allow
+    #[clap(long, conflicts_with_all = ["move_git_sources", "vendor_exclude"])]
+    pub no_shell_sources: bool,
+    /// Remove the paths matching GLOB from the copy of git crate CRATE, e.g.
+    /// `mylib=tests/fixtures`
+    #[clap(long, value_name = "CRATE=GLOB", value_parser = parse_vendor_exclude)]
+    pub vendor_exclude: Vec<(String, String)>,
+    /// Vendor git crate CRATE at COMMIT instead of the commit of Cargo.lock,
+    /// e.g. to backport a fix without touching the lockfile
+    #[clap(long, value_name = "CRATE=COMMIT", value_parser = parse_override_commit)]
+    pub override_commit: Vec<(String, CommitHash)>,
+    /// Prepended to every dest, for modules building from a subdirectory
+    #[clap(long)]
+    pub dest_prefix: Option<String>,
+    /// Generate the sources of the repository at URL instead, cloned into the
+    /// cache, writing them to the current directory rather than the checkout
+    #[clap(long, value_name = "URL", conflicts_with = "watch")]
+    pub from_git: Option<String>,
+    /// Tag of the --from-git repository to check out [default: its default branch]
+    #[clap(long, requires = "from_git", conflicts_with_all = ["rev", "branch"])]
+    pub tag: Option<String>,
+    /// Commit of the --from-git repository to check out
+    #[clap(long, requires = "from_git", conflicts_with = "branch")]
+    pub rev: Option<String>,
+    /// Branch of the --from-git repository to check out
+    #[clap(long, requires = "from_git")]
+    pub branch: Option<String>,
+    /// Generate the sources of the application NAME@VERSION published on crates.io
+    /// instead, writing them to the current directory
+    #[clap(long, value_name = "NAME@VERSION", value_parser = parse_crate_spec, conflicts_with_all = ["watch", "from_git"])]
+    pub from_crate: Option<(String, String)>,
+    /// Resolve a Cargo.lock for a --from-crate crate published without one
+    #[clap(long, requires = "from_crate")]
+    pub allow_missing_lockfile: bool,
+    /// Add the application itself first, in the module or else the sources:
+    /// `git` for the workspace's origin at its HEAD commit, or `archive:URL`
+    #[clap(long, value_name = "git|archive:URL", value_parser = parse_app_source, conflicts_with = "from_crate")]
+    pub app_source: Option<AppSource>,
+    /// sha256 of the --app-source archive, instead of downloading it once to compute it
+    #[clap(long, requires = "app_source", value_parser = |s: &str| Sha256::try_from(s).map_err(|e| e.to_string()))]
+    pub sha256: Option<Sha256>,
+    /// The origin remote and HEAD commit of --app-source git, looked up once
+    /// the workspace is known
+    #[clap(skip)]
+    pub app_origin: Option<(String, CommitHash)>,
+    /// Also vendor the crates.io crate NAME@VERSION, e.g. for a tool the build
+    /// commands `cargo install`. Its checksum is looked up in the sparse index,
+    /// or given as `NAME@VERSION=SHA256`. Only the crate itself is added, not
+    /// its dependencies: vendor those with --add-lockfile.
+    #[clap(long, value_name = "NAME@VERSION[=SHA256]", value_parser = parse_added_crate)]
+    pub add_crate: Vec<(String, String, Option<Sha256>)>,
+    /// Also vendor the registry crates of the lockfile at PATH, e.g. of a tool
+    /// the build commands `cargo install --locked`
+    #[clap(long, value_name = "PATH")]
+    pub add_lockfile: Vec<PathBuf>,
+    /// Run `cargo fetch --locked` when git dependencies aren't checked out yet
+    #[clap(long)]
+    pub fetch: bool,
+    /// Proxy of every HTTP request, git and cargo included, over cargo's
+    /// `http.proxy` and the `https_proxy`/`http_proxy` environment variables
+    #[clap(long, value_name = "URL")]
+    pub proxy: Option<String>,
+    /// Retries of a download, index lookup or URL check that timed out, lost
+    /// its connection or got a 429 or 5xx, with a backoff doubling from a second
+    #[clap(long, value_name = "N", default_value = "3")]
+    pub network_retries: u32,
+    /// Timeout of each network request and `git ls-remote`, in seconds
+    #[clap(long, value_name = "SECS", default_value = "30")]
+    pub network_timeout: u64,
+    /// Check that the rewritten manifest of every git crate still means the same to cargo
+    #[clap(long)]
+    pub verify_manifests: bool,
+    /// How many path dependencies deep the crates of a git checkout are followed
+    #[clap(long, value_name = "N", default_value_t = 32)]
+    pub max_path_depth: usize,
+    /// Also write a flatpak-builder module building and installing the binaries
+    #[clap(long)]
+    pub module: bool,
+    #[clap(long, default_value = "cargo-module.json", requires = "module")]
+    pub module_output: String,
+    /// Don't warn about the toolchain rust-toolchain.toml pins, nor adjust the
+    /// module and init manifests for it
+    #[clap(long)]
+    pub ignore_toolchain: bool,
+    /// Write a list of modules instead, one for each selected package with a
+    /// binary, all building from the same sources
+    #[clap(long, requires = "module")]
+    pub per_package_modules: bool,
+    /// Name of the module of a package, with a `{package}` placeholder
+    #[clap(long, default_value = "{package}", value_parser = parse_module_name_template, requires = "module")]
+    pub module_name_template: String,
+    /// Also write a bash script laying the sources out like flatpak-builder,
+    /// for debugging without it
+    #[clap(long, value_name = "PATH")]
+    pub emit_vendor_script: Option<PathBuf>,
+    /// Also write the checksums of what the builder downloads, `<sha256>  <url>`
+    /// lines for the archives and files, then `<commit>  <url>` lines for the
+    /// git repositories
+    #[clap(long, value_name = "PATH")]
+    pub emit_checksums: Option<PathBuf>,
+    /// Workspace packages to build in the module [default: all members]
+    #[clap(short, long)]
+    pub package: Vec<String>,
+    /// Binaries to install in the module [default: all of the selected packages]
+    #[clap(long)]
+    pub bin: Vec<String>,
+    /// Cargo profile the module builds with
+    #[clap(long, default_value = "release")]
+    pub profile: String,
+    /// Argument appended to the cargo build of the module, e.g. `--locked`
+    #[clap(long, value_name = "ARG", allow_hyphen_values = true)]
+    pub cargo_arg: Vec<String>,
+    /// Check that the output was generated from the current Cargo.lock and options
+    #[clap(long)]
+    pub verify_hash: bool,
+    /// List the vendored crates with a build script or a `links` key instead of generating
+    #[clap(long, value_enum, num_args = 0..=1, default_missing_value = "table")]
+    pub audit_build_scripts: Option<OutputFormat>,
+    /// Tell which runtime or module provides the native libraries of the vendored
+    /// `-sys` crates instead of generating
+    #[clap(long, value_enum, num_args = 0..=1, default_missing_value = "table")]
+    pub suggest_system_deps: Option<OutputFormat>,
+    /// Report how much flatpak-builder will download instead of generating
+    #[clap(long)]
+    pub estimate_size: bool,
+    /// Fail if a crate matching `name[@version-req]` would be vendored, the name may be a glob
+    #[clap(long, value_name = "CRATE", value_parser = parse_forbid_rule)]
+    pub forbid: Vec<ForbidRule>,
+    /// Fail if a crate from this kind of source would be vendored
+    #[clap(long, value_enum)]
+    pub forbid_source: Vec<SourceKind>,
+    /// Fail if a git dependency would be vendored, `--forbid-source git`
+    #[clap(long)]
+    pub deny_git_sources: bool,
+    /// Exempt a git dependency from --deny-git-sources, by its repository URL or crate name
+    #[clap(long, value_name = "URL|CRATE")]
+    pub allow_git: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum VendorStrategy {
+    /// Extracted into the vendor directory
+    Directory,
+    /// As `.crate` files with an index, in `<cargo-home>/local-registry`
+    LocalRegistry,
+    /// Into cargo's own download cache, without replacing crates.io. Needs
+    /// cargo 1.70 or later, the cache is named after the sparse index the way
+    /// the cargo on PATH, or --cargo-path, names it.
+    RegistryCache,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum FlatpakArch {
+    #[value(name = "x86_64")]
+    X86_64,
+    Aarch64,
+    I386,
+    Arm,
+}
+
+impl FlatpakArch {
+    /// The rust target flatpak builds for on the architecture
+    pub fn target(self) -> &'static str {
+        match self {
+            FlatpakArch::X86_64 => "x86_64-unknown-linux-gnu",
+            FlatpakArch::Aarch64 => "aarch64-unknown-linux-gnu",
+            FlatpakArch::I386 => "i686-unknown-linux-gnu",
+            FlatpakArch::Arm => "armv7-unknown-linux-gnueabihf",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum GroupBy {
+    Crate,
+    Type,
+}
+
+/// What fails `cargo flatpak audit`
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum Deny {
+    /// Informational advisories, such as unmaintained crates, and vulnerabilities
+    Warnings,
+    Vulnerabilities,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum ConfigFormat {
+    Toml,
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum OutputFormat {
+    Table,
+    Json,
+}
+
+impl Args {
+    /// What the sources file at `path` is written as, --format or its extension
+    pub fn sources_format(&self, path: &std::path::Path) -> SourcesFormat {
+        self.format.unwrap_or_else(|| SourcesFormat::of(path))
+    }
+
+    /// Where Cargo.lock is read from, `None` for stdin
+    pub fn lockfile_path(&self, workspace: &std::path::Path) -> Option<PathBuf> {
+        match &self.lockfile {
+            Some(path) if path.as_os_str() == "-" => None,
+            Some(path) => Some(path.clone()),
+            None => Some(workspace.join("Cargo.lock")),
+        }
+    }
+
+    /// What of the --from-git repository is checked out
+    pub fn git_ref(&self) -> crate::checkout::GitRef<'_> {
+        use crate::checkout::GitRef;
+        match (&self.tag, &self.rev, &self.branch) {
+            (Some(tag), _, _) => GitRef::Tag(tag),
+            (_, Some(rev), _) => GitRef::Rev(rev),
+            (_, _, Some(branch)) => GitRef::Branch(branch),
+            _ => GitRef::Head,
+        }
+    }
+
+    /// The retries and timeout of --network-retries and --network-timeout
+    pub fn network_retry(&self) -> crate::net::Retry {
+        crate::net::Retry {
+            retries: self.network_retries,
+            timeout: std::time::Duration::from_secs(self.network_timeout),
+            ..Default::default()
+        }
+    }
+
+    /// The options that change the generated sources, part of the lockfile hash
+    pub fn generation_options(&self) -> String {
+        let options: &[&dyn std::fmt::Debug] = &[
+            &self.bundle_path_deps,
+            &self.archive_dest_filename,
+            &self.crate_url_template,
+            &self.local_crates_dir,
+            &self.require_local,
+            &self.x_checker_data,
+            &self.config_offline,
+            &self.merge_project_config,
+            &self.write_config,
+            &self.no_config,
+            &self.include_lockfile,
+            &self.no_inline,
+            &self.cargo_home,
+            &self.vendor_dir(),
+            &self.vendor_strategy,
+            &self.resolve_missing_checksums,
+            &self.prune_foreign_targets,
+            &self.arches,
+            &self.dest_prefix,
+            &self.group_by,
+            &self.split,
+            &self.separate_git,
+            &self.vendor_exclude,
+            &self.override_commit,
+            &self.add_crate,
+            &self.add_lockfile,
+            &self.git_as_archive,
+            &self.move_git_sources,
+            &self.no_shell_sources,
+            &self.app_source,
+            &self.sha256,
+            &self.app_origin,
+            &self.keep_going,
+        ];
+        format!("{options:?}")
+    }
+
+    /// The source kinds of --forbid-source, and git for --deny-git-sources
+    pub fn forbidden_sources(&self) -> Vec<SourceKind> {
+        let mut kinds = self.forbid_source.clone();
+        if self.deny_git_sources && !kinds.contains(&SourceKind::Git) {
+            kinds.push(SourceKind::Git);
+        }
+        kinds
+    }
+
+    /// The vendored crates directory, including the dest prefix
+    pub fn vendor_dir(&self) -> String {
+        match &self.vendor_dir {
+            Some(dir) => self.dest(dir),
+            None => self.dest(&format!("{}/{VENDOR_DIR}", self.cargo_home)),
+        }
+    }
+
+    /// The directory of the git crates replacing others through `[replace]`, next to the vendor directory
+    pub fn replaced_dir(&self) -> String {
+        format!("{}-replaced", self.vendor_dir())
+    }
+
+    /// The local registry of --vendor-strategy local-registry, including the dest prefix
+    pub fn local_registry_dir(&self) -> String {
+        self.dest(&format!("{}/{LOCAL_REGISTRY_DIR}", self.cargo_home))
+    }
+
+    /// The cargo home directory, including the dest prefix
+    pub fn cargo_home_dir(&self) -> String {
+        self.dest(&self.cargo_home)
+    }
+
+    /// Places `path`, relative to the module build directory, under --dest-prefix
+    pub fn dest(&self, path: &str) -> String {
+        let Some(prefix) = self.dest_prefix.as_deref().map(|p| p.trim_end_matches('/')) else {
+            return path.to_string();
+        };
+        match path {
+            "." | "" => prefix.to_string(),
+            path => format!("{prefix}/{path}"),
+        }
+    }
+}
+
+pub fn parse_crate_url_template(template: &str) -> Result<String, String> {
+    for placeholder in ["{name}", "{version}"] {
+        if !template.contains(placeholder) {
+            return Err(format!("the template must contain {placeholder}"));
+        }
+    }
+    Ok(template.to_string())
+}
+
+pub fn parse_module_name_template(template: &str) -> Result<String, String> {
+    if !template.contains("{package}") {
+        return Err("the template must contain {package}".into());
+    }
+    Ok(template.to_string())
+}
+
+/// Where --app-source takes the application's source from
+#[derive(Debug, Clone, PartialEq)]
+pub enum AppSource {
+    /// The origin remote of the workspace's repository, at its HEAD commit
+    Git,
+    /// An archive of the application, like a release tarball
+    Archive(String),
+}
+
+pub fn parse_app_source(spec: &str) -> Result<AppSource, String> {
+    match spec.split_once(':') {
+        _ if spec == "git" => Ok(AppSource::Git),
+        Some(("archive", url)) if !url.is_empty() => Ok(AppSource::Archive(url.into())),
+        _ => Err("expected `git` or `archive:URL`".into()),
+    }
+}
+
+pub fn parse_crate_spec(spec: &str) -> Result<(String, String), String> {
+    match spec.split_once('@') {
+        Some((name, version)) if !name.is_empty() && !version.is_empty() => Ok((name.into(), version.into())),
+        _ => Err("expected NAME@VERSION, e.g. ripgrep@14.1.0".into()),
+    }
+}
+
+pub fn parse_vendor_exclude(exclude: &str) -> Result<(String, String), String> {
+    let Some((name, glob)) = exclude.split_once('=') else {
+        return Err("expected CRATE=GLOB".into());
+    };
+    if glob.is_empty() || glob.starts_with('/') || glob.split('/').any(|c| c == "..") {
+        return Err(format!("`{glob}` must be a relative path inside of the crate"));
+    }
+    glob::Pattern::new(glob).map_err(|e| format!("invalid glob `{glob}`: {e}"))?;
+    Ok((name.to_string(), glob.to_string()))
+}
+
+pub fn parse_added_crate(spec: &str) -> Result<(String, String, Option<Sha256>), String> {
+    let (spec, sha256) = match spec.split_once('=') {
+        Some((spec, sha256)) => (spec, Some(Sha256::try_from(sha256).map_err(|e| e.to_string())?)),
+        None => (spec, None),
+    };
+    let (name, version) = parse_crate_spec(spec)?;
+    Ok((name, version, sha256))
+}
+
+/// A --cargo-path made absolute, as cargo runs in other directories than
+/// the current one. A bare name is looked up on PATH instead.
+pub fn parse_cargo_path(path: &str) -> Result<PathBuf, String> {
+    let path = PathBuf::from(path);
+    if path.is_absolute() || path.components().count() == 1 {
+        return Ok(path);
+    }
+    std::fs::canonicalize(&path).map_err(|e| format!("{}: {e}", path.display()))
+}
+
+pub fn parse_override_commit(spec: &str) -> Result<(String, CommitHash), String> {
+    match spec.split_once('=') {
+        Some((name, commit)) if !name.is_empty() => Ok((name.to_string(), CommitHash::try_from(commit).map_err(|e| e.to_string())?)),
+        _ => Err("expected CRATE=COMMIT".into()),
+    }
+}
+
+#[derive(Debug, Subcommand)]
+pub enum SubCommand {
+    /// Check that every archive and git repository of the sources is reachable
+    VerifyUrls {
+        /// Sources file to check [default: generate the sources in memory]
+        file: Option<PathBuf>,
+        /// Number of concurrent checks
+        #[clap(long, default_value = "8")]
+        jobs: usize,
+    },
+    /// Check the crates staged in a flatpak-builder build directory against
+    /// the sources, failing on any that are missing, differ or don't belong
+    VerifyVendored {
+        /// The build directory, or the flatpak-builder state directory with
+        /// the module build directories under it
+        builddir: PathBuf,
+    },
+    /// Trace how the sources of a package are derived
+    Explain {
+        /// The package, as `name` or `name@version`
+        #[clap(value_name = "CRATE")]
+        package: String,
+    },
+    /// Print the vendored packages, the same ones the sources are generated for
+    List {
+        #[clap(long, value_enum, default_value = "table")]
+        format: OutputFormat,
+        /// Only list the packages from this kind of source
+        #[clap(long, value_enum)]
+        only: Option<SourceKind>,
+    },
+    /// Match the RustSec advisories against the vendored crates, the same ones
+    /// the sources are generated for
+    Audit {
+        /// Checkout of the advisory database [default: a clone in the cache]
+        #[clap(long, value_name = "PATH")]
+        db: Option<PathBuf>,
+        /// Use the cached clone as is, without updating it
+        #[clap(long, conflicts_with = "db")]
+        offline: bool,
+        #[clap(long, value_enum, default_value = "vulnerabilities")]
+        deny: Deny,
+        #[clap(long, value_enum, default_value = "table")]
+        format: OutputFormat,
+    },
+    /// Check the sources against Flathub's policies, failing on denied rules
+    Lint {
+        /// Sources file to lint [default: generate the sources in memory]
+        file: Option<PathBuf>,
+        /// Fail on findings of this rule
+        #[clap(long, value_enum, value_name = "RULE")]
+        deny: Vec<crate::lint::Rule>,
+        /// Don't report findings of this rule
+        #[clap(long, value_enum, value_name = "RULE")]
+        allow: Vec<crate::lint::Rule>,
+        #[clap(long, value_enum, default_value = "table")]
+        format: OutputFormat,
+    },
+    /// Convert a sources file of flatpak-cargo-generator to this tool's
+    /// format, writing it to the output
+    Import {
+        /// The sources file to convert
+        file: PathBuf,
+    },
+    /// Write a flatpak manifest for the package from a template, to
+    /// `<app id>.json` in the workspace
+    Init {
+        /// Application ID of the manifest, e.g. `org.example.App`
+        #[clap(long)]
+        app_id: String,
+        /// A built-in template, `gnome` or `cli`, or the path of a JSON or YAML
+        /// template with `{{placeholders}}`
+        #[clap(long, default_value = "gnome")]
+        template: String,
+        /// Runtime of the application, e.g. `org.freedesktop.Platform`, `org.gnome.Platform`
+        /// or `org.kde.Platform` [default: the built-in template's]
+        #[clap(long)]
+        runtime: Option<String>,
+        /// SDK to build with [default: the runtime's]
+        #[clap(long)]
+        sdk: Option<String>,
+        /// Version of the runtime [default: its latest]
+        #[clap(long)]
+        runtime_version: Option<String>,
+        /// Binary the application runs [default: the package's first]
+        #[clap(long)]
+        command: Option<String>,
+    },
+    /// Lay the sources out in a temporary directory the way flatpak-builder
+    /// would, and build the project against them offline
+    TestBuild {
+        /// Keep the build directory instead of removing it
+        #[clap(long)]
+        keep: bool,
+        /// Only resolve the dependencies with `cargo metadata` instead of `cargo check`
+        #[clap(long)]
+        quick: bool,
+    },
+    /// Move the application's git source in a flatpak manifest to another tag
+    /// or commit, and regenerate the sources of that revision next to it
+    Bump {
+        /// The JSON manifest
+        manifest: PathBuf,
+        /// Tag to move to, recorded along with its commit
+        #[clap(long, required_unless_present = "commit", conflicts_with = "commit")]
+        tag: Option<String>,
+        /// Commit to move to
+        #[clap(long)]
+        commit: Option<String>,
+        /// Index of the application's source among the sources of its module
+        /// [default: its first git source]
+        #[clap(long, value_name = "N")]
+        source_index: Option<usize>,
+    },
+    /// Rewrite sources files as cargo flatpak writes them: its key order and
+    /// indentation, the registry crates sorted, grouped by --group-by
+    Fmt {
+        /// The sources files
+        #[clap(required = true)]
+        files: Vec<PathBuf>,
+        /// Only report the files that aren't formatted, failing if any
+        #[clap(long)]
+        check: bool,
+    },
+    /// Check the environment for what commonly keeps the sources from being
+    /// generated or built, failing only on what they can't be generated without
+    Doctor {
+        /// Skip the checks that need the network
+        #[clap(long)]
+        offline: bool,
+    },
+    /// Show, prune or clear the caches of what's looked up on the network
+    Cache {
+        #[clap(subcommand)]
+        action: CacheAction,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum CacheAction {
+    /// Print where the caches are, and the size of each
+    Info,
+    /// Remove what hasn't been written in a number of days, and the caches of
+    /// older layouts
+    Prune {
+        #[clap(long, value_name = "DAYS")]
+        older_than: u64,
+    },
+    /// Remove every cache
+    Clear,
 }
 
 #[derive(Debug, Parser)]
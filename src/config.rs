@@ -0,0 +1,223 @@
+use std::path::Path;
+
+use toml::{map::Map, Value};
+
+use crate::sources::{File, Source};
+use crate::{VENDORED_REGISTRY, VENDORED_SOURCES};
+
+/// The cargo config installed into CARGO_HOME by the generated sources
+pub struct CargoConfig {
+    doc: Map<String, Value>,
+}
+
+impl CargoConfig {
+    /// A config with the `vendored-sources` directory replacement at `vendor_dir`
+    pub fn new(vendor_dir: &str) -> Self {
+        let mut config = CargoConfig { doc: Map::new() };
+        let mut vendored = Map::new();
+        vendored.insert("directory".into(), vendor_dir.into());
+        config.section_mut("source").insert(VENDORED_SOURCES.into(), vendored.into());
+        config
+    }
+
+    /// A config read back from its TOML
+    pub fn from_toml(contents: &str) -> Result<Self, toml::de::Error> {
+        Ok(CargoConfig { doc: toml::from_str(contents)? })
+    }
+
+    /// Adds the `vendored-registry` local registry at `registry_dir`
+    pub fn add_local_registry(&mut self, registry_dir: &str) {
+        let mut registry = Map::new();
+        registry.insert("local-registry".into(), registry_dir.into());
+        self.section_mut("source").insert(VENDORED_REGISTRY.into(), registry.into());
+    }
+
+    /// Returns the table for `section`, creating it if needed
+    pub fn section_mut(&mut self, section: &str) -> &mut Map<String, Value> {
+        let value = self
+            .doc
+            .entry(section)
+            .or_insert_with(|| Value::Table(Map::new()));
+        if !value.is_table() {
+            *value = Value::Table(Map::new());
+        }
+        value.as_table_mut().unwrap()
+    }
+
+    /// Adds the `[source]` entries produced for a package. Cargo reads all the
+    /// crates of a source from the one it's replaced with, a source replaced
+    /// with another one already is an error.
+    pub fn add_sources(&mut self, entries: Map<String, Value>) -> anyhow::Result<()> {
+        let sources = self.section_mut("source");
+        for (key, value) in entries {
+            if let Some(existing) = sources.get(&key).filter(|existing| existing.get("replace-with") != value.get("replace-with")) {
+                let (ours, theirs) = (existing.get("replace-with"), value.get("replace-with"));
+                anyhow::bail!(
+                    "{key} would be replaced with both {} and {}, cargo reads all of its crates from one source",
+                    ours.and_then(Value::as_str).unwrap_or("nothing"),
+                    theirs.and_then(Value::as_str).unwrap_or("nothing"),
+                );
+            }
+            sources.insert(key, value);
+        }
+        Ok(())
+    }
+
+    /// Adds the `[source]` entries of `other` that the config doesn't have
+    pub fn merge_sources(&mut self, other: &CargoConfig) {
+        let Some(Value::Table(theirs)) = other.doc.get("source") else {
+            return;
+        };
+        let ours = self.section_mut("source");
+        for (key, value) in theirs {
+            if !ours.contains_key(key) {
+                ours.insert(key.clone(), value.clone());
+            }
+        }
+    }
+
+    /// Forbids network access for any cargo invocation inside the build
+    pub fn set_offline(&mut self) {
+        let net = self.section_mut("net");
+        net.insert("offline".into(), true.into());
+        net.insert("retry".into(), 0.into());
+    }
+
+    /// Merges the project's own `.cargo/config.toml` underneath the generated
+    /// settings. Its `[source]` table is dropped, and on conflicting keys the
+    /// generated value is kept. Returns a warning per conflict.
+    pub fn merge_project(&mut self, project: &Map<String, Value>) -> Vec<String> {
+        fn merge(ours: &mut Map<String, Value>, theirs: &Map<String, Value>, path: &str, warnings: &mut Vec<String>) {
+            for (key, value) in theirs {
+                let key_path = format!("{path}{key}");
+                match (ours.get_mut(key), value) {
+                    (None, value) => {
+                        ours.insert(key.clone(), value.clone());
+                    }
+                    (Some(Value::Table(ours)), Value::Table(theirs)) => {
+                        merge(ours, theirs, &format!("{key_path}."), warnings)
+                    }
+                    (Some(ours), theirs) if ours != theirs => {
+                        warnings.push(format!("{key_path} is overridden by the generated config"))
+                    }
+                    _ => {}
+                }
+            }
+        }
+        let mut warnings = Vec::new();
+        let mut project = project.clone();
+        if project.remove("source").is_some() {
+            warnings.push("[source] of the project config is replaced by the vendored sources".into());
+        }
+        merge(&mut self.doc, &project, "", &mut warnings);
+        warnings
+    }
+
+    pub fn to_toml(&self) -> Result<String, toml::ser::Error> {
+        toml::to_string(&self.doc)
+    }
+
+    /// Writes the config to `path` and returns a file source installing it,
+    /// with a path relative to `manifest_dir`, where the flatpak manifest lives
+    pub fn write_file(
+        &self,
+        path: &Path,
+        manifest_dir: &Path,
+        cargo_home: &str,
+        lock_hash: Option<String>,
+        no_clobber: bool,
+    ) -> anyhow::Result<Source> {
+        crate::generate::write_output(path, self.to_toml()?.as_bytes(), no_clobber)?;
+        let path = pathdiff::diff_paths(path.canonicalize()?, manifest_dir.canonicalize()?)
+            .ok_or_else(|| anyhow::anyhow!("cannot reference {} from {}", path.display(), manifest_dir.display()))?;
+        Ok(Source::File(File {
+            url: None,
+            path: Some(crate::sources::utf8_path(&path)?.to_string()),
+            sha256: None,
+            dest: cargo_home.into(),
+            dest_filename: Some("config.toml".into()),
+            x_cargo_lock_hash: lock_hash,
+        }))
+    }
+}
+
+#[test]
+fn offline_config() {
+    let mut config = CargoConfig::new("cargo/vendor");
+    let mut crates_io = Map::new();
+    crates_io.insert("replace-with".into(), VENDORED_SOURCES.into());
+    let mut entries = Map::new();
+    entries.insert("crates-io".into(), crates_io.into());
+    config.add_sources(entries.clone()).unwrap();
+    // Once more is fine, replacing it with something else isn't
+    config.add_sources(entries.clone()).unwrap();
+    entries["crates-io"]["replace-with"] = crate::REPLACED_SOURCES.into();
+    let err = config.add_sources(entries).unwrap_err().to_string();
+    assert_eq!(err, "crates-io would be replaced with both vendored-sources and replaced-sources, cargo reads all of its crates from one source");
+
+    let default = config.to_toml().unwrap();
+    assert!(!default.contains("[net]"));
+
+    config.set_offline();
+    let parsed: Value = toml::from_str(&config.to_toml().unwrap()).unwrap();
+    assert_eq!(parsed["source"]["vendored-sources"]["directory"].as_str(), Some("cargo/vendor"));
+    assert_eq!(parsed["source"]["crates-io"]["replace-with"].as_str(), Some(VENDORED_SOURCES));
+    assert_eq!(parsed["net"]["offline"].as_bool(), Some(true));
+    assert_eq!(parsed["net"]["retry"].as_integer(), Some(0));
+}
+
+#[test]
+fn write_config_file() {
+    let tmp = tempfile::tempdir().unwrap();
+    std::fs::create_dir(tmp.path().join("cargo")).unwrap();
+    let config = CargoConfig::new("cargo/vendor");
+
+    let source = config.write_file(&tmp.path().join("cargo/config.toml"), tmp.path(), "cargo", None, false).unwrap();
+
+    let written = std::fs::read_to_string(tmp.path().join("cargo/config.toml")).unwrap();
+    assert_eq!(written, config.to_toml().unwrap());
+    assert_eq!(
+        serde_json::to_value(&source).unwrap(),
+        serde_json::json!({
+            "type": "file",
+            "path": "cargo/config.toml",
+            "dest": "cargo",
+            "dest-filename": "config.toml",
+        })
+    );
+}
+
+#[test]
+fn merge_project_config() {
+    let project: Map<String, Value> = toml::from_str(
+        r#"
+        [build]
+        rustflags = ["-C", "target-cpu=native"]
+
+        [target.x86_64-unknown-linux-gnu]
+        linker = "clang"
+
+        [net]
+        offline = false
+        git-fetch-with-cli = true
+
+        [source.crates-io]
+        replace-with = "mirror"
+        "#,
+    )
+    .unwrap();
+    let mut config = CargoConfig::new("cargo/vendor");
+    config.set_offline();
+
+    let warnings = config.merge_project(&project);
+
+    let merged: Value = toml::from_str(&config.to_toml().unwrap()).unwrap();
+    assert_eq!(merged["build"]["rustflags"], Value::from(vec!["-C", "target-cpu=native"]));
+    assert_eq!(merged["target"]["x86_64-unknown-linux-gnu"]["linker"].as_str(), Some("clang"));
+    assert_eq!(merged["net"]["offline"].as_bool(), Some(true));
+    assert_eq!(merged["net"]["git-fetch-with-cli"].as_bool(), Some(true));
+    assert!(merged["source"].get("crates-io").is_none());
+    assert_eq!(merged["source"]["vendored-sources"]["directory"].as_str(), Some("cargo/vendor"));
+    assert_eq!(warnings.len(), 2);
+    assert!(warnings[1].contains("net.offline"));
+}
@@ -0,0 +1,289 @@
+use std::cell::RefCell;
+use std::io::Write;
+use std::ops::Range;
+use std::sync::RwLock;
+
+use clap::ValueEnum;
+
+use crate::generate::{Incomplete, PackageErrors};
+
+/// How diagnostics are printed on stderr
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum ErrorFormat {
+    /// `warning: message`, for people
+    Human,
+    /// A JSON object per line, for tools
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    Error,
+    Warning,
+    /// What's being done, not something wrong
+    Note,
+}
+
+/// The package a diagnostic is about
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct Crate {
+    pub name: String,
+    pub version: String,
+}
+
+/// The lines of a file a diagnostic points at
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct Snippet {
+    pub file: String,
+    /// Where the span starts, both counted from 1
+    pub line: usize,
+    pub column: usize,
+    /// How many characters of the first line the span covers
+    pub length: usize,
+    /// The lines the span covers, from the start of the first
+    pub lines: Vec<String>,
+}
+
+/// What to do about a lockfile cargo-flatpak can't use
+pub const REGENERATE_LOCKFILE: &str = "regenerate the lockfile with `cargo generate-lockfile`";
+/// What to do about a git checkout that's missing or incomplete
+pub const FETCH_CHECKOUTS: &str = "run `cargo fetch` to check the git dependencies out, or pass --fetch";
+
+/// The most lines of a snippet shown, a long `[[package]]` block is cut short
+const MAX_LINES: usize = 8;
+
+impl Snippet {
+    /// The bytes `span` of `source`, the contents of `file`
+    pub fn new(file: impl Into<String>, source: &str, span: Range<usize>) -> Snippet {
+        let start = span.start.min(source.len());
+        let line_start = source[..start].rfind('\n').map_or(0, |i| i + 1);
+        let end = span.end.clamp(start, source.len());
+        let line_end = source[end..].find('\n').map_or(source.len(), |i| end + i);
+        let lines: Vec<String> = source[line_start..line_end].trim_end().lines().map(str::to_string).collect();
+        let first_end = source[start..].find('\n').map_or(source.len(), |i| start + i).min(end);
+        Snippet {
+            file: file.into(),
+            line: source[..start].matches('\n').count() + 1,
+            column: source[line_start..start].chars().count() + 1,
+            length: source[start..first_end].trim_end().chars().count().max(1),
+            lines,
+        }
+    }
+
+    /// The snippet as rustc shows it, under the message
+    fn render(&self) -> String {
+        let shown = self.lines.len().min(MAX_LINES);
+        let width = (self.line + shown.saturating_sub(1)).to_string().len();
+        let gutter = " ".repeat(width);
+        let mut out = format!("{gutter}--> {}:{}:{}\n{gutter} |\n", self.file, self.line, self.column);
+        for (i, line) in self.lines.iter().take(shown).enumerate() {
+            out.push_str(&format!("{:>width$} | {line}\n", self.line + i));
+            if i == 0 {
+                out.push_str(&format!("{gutter} | {}{}\n", " ".repeat(self.column - 1), "^".repeat(self.length)));
+            }
+        }
+        if self.lines.len() > shown {
+            out.push_str(&format!("{gutter} | ...\n"));
+        }
+        out.push_str(&format!("{gutter} |\n"));
+        out
+    }
+}
+
+/// An error with what to show along with it: the part of a file it's about,
+/// and what to do about it
+#[derive(Debug)]
+pub struct Annotated {
+    pub error: anyhow::Error,
+    pub snippet: Option<Snippet>,
+    pub suggestion: String,
+}
+
+impl Annotated {
+    pub fn new(error: impl Into<anyhow::Error>, suggestion: impl Into<String>) -> Annotated {
+        Annotated { error: error.into(), snippet: None, suggestion: suggestion.into() }
+    }
+
+    pub fn with_snippet(mut self, snippet: Option<Snippet>) -> Annotated {
+        self.snippet = snippet;
+        self
+    }
+
+    /// The annotation somewhere in the chain of `error`
+    pub fn find(error: &anyhow::Error) -> Option<&Annotated> {
+        error.chain().find_map(|e| e.downcast_ref::<Annotated>())
+    }
+}
+
+impl std::fmt::Display for Annotated {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:#}", self.error)
+    }
+}
+
+impl std::error::Error for Annotated {}
+
+/// An error or warning, rendered the same way whatever the format
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct Diagnostic {
+    pub level: Level,
+    /// What went wrong, e.g. `duplicate-package`
+    pub code: &'static str,
+    pub message: String,
+    #[serde(rename = "crate")]
+    pub package: Option<Crate>,
+    pub snippet: Option<Snippet>,
+    pub suggestion: Option<String>,
+}
+
+impl Diagnostic {
+    pub fn warning(code: &'static str, message: impl Into<String>) -> Diagnostic {
+        Diagnostic { level: Level::Warning, code, message: message.into(), package: None, snippet: None, suggestion: None }
+    }
+
+    pub fn error(code: &'static str, message: impl Into<String>) -> Diagnostic {
+        Diagnostic { level: Level::Error, code, message: message.into(), package: None, snippet: None, suggestion: None }
+    }
+
+    pub fn note(code: &'static str, message: impl Into<String>) -> Diagnostic {
+        Diagnostic { level: Level::Note, code, message: message.into(), package: None, snippet: None, suggestion: None }
+    }
+
+    pub fn with_crate(mut self, name: &str, version: &str) -> Diagnostic {
+        self.package = Some(Crate { name: name.into(), version: version.into() });
+        self
+    }
+
+    pub fn with_suggestion(mut self, suggestion: impl Into<String>) -> Diagnostic {
+        self.suggestion = Some(suggestion.into());
+        self
+    }
+
+    /// The snippet and suggestion of `error`, when it's annotated
+    fn annotated(mut self, error: &anyhow::Error) -> Diagnostic {
+        if let Some(annotated) = Annotated::find(error) {
+            self.snippet = annotated.snippet.clone().or(self.snippet);
+            self.suggestion = Some(annotated.suggestion.clone());
+        }
+        self
+    }
+
+    /// The diagnostic as a line of `format`, newline included
+    pub fn render(&self, format: ErrorFormat) -> String {
+        match format {
+            ErrorFormat::Human => {
+                let level = match self.level {
+                    Level::Error => "error",
+                    Level::Warning => "warning",
+                    Level::Note => "note",
+                };
+                let mut out = format!("{level}: {}\n", self.message);
+                if let Some(snippet) = &self.snippet {
+                    out.push_str(&snippet.render());
+                }
+                if let Some(suggestion) = &self.suggestion {
+                    out.push_str(&format!("  help: {suggestion}\n"));
+                }
+                out
+            }
+            ErrorFormat::Json => format!("{}\n", serde_json::to_string(self).unwrap()),
+        }
+    }
+
+    /// The diagnostics of a failed run, one for each package that failed
+    pub fn from_error(error: &anyhow::Error) -> Vec<Diagnostic> {
+        let (PackageErrors(errors), summary) = if let Some(incomplete) = error.downcast_ref::<Incomplete>() {
+            let summary = Diagnostic::error("incomplete-output", incomplete.summary())
+                .with_suggestion("add their sources by hand, or fix them and generate again");
+            (&incomplete.errors, summary)
+        } else if let Some(errors) = error.downcast_ref::<PackageErrors>() {
+            (errors, Diagnostic::error("error", PackageErrors::summary(errors.0.len())))
+        } else {
+            return vec![Diagnostic::error("error", format!("{error:#}")).annotated(error)];
+        };
+        let mut diagnostics: Vec<_> = errors
+            .iter()
+            .map(|e| {
+                let diagnostic = Diagnostic::error("package", e.message()).with_crate(&e.name, &e.version);
+                Diagnostic { snippet: e.snippet.clone(), ..diagnostic }.annotated(&e.error)
+            })
+            .collect();
+        diagnostics.push(summary);
+        diagnostics
+    }
+}
+
+static FORMAT: RwLock<ErrorFormat> = RwLock::new(ErrorFormat::Human);
+
+thread_local! {
+    /// Where the diagnostics go instead of stderr, while captured
+    static CAPTURE: RefCell<Option<(ErrorFormat, String)>> = const { RefCell::new(None) };
+}
+
+/// Sets the format of the diagnostics from then on, human-readable until set
+pub fn set_format(format: ErrorFormat) {
+    *FORMAT.write().unwrap() = format;
+}
+
+/// Prints `diagnostic` on stderr
+pub fn emit(diagnostic: Diagnostic) {
+    let captured = CAPTURE.with_borrow_mut(|capture| match capture {
+        Some((format, out)) => {
+            out.push_str(&diagnostic.render(*format));
+            true
+        }
+        None => false,
+    });
+    if !captured {
+        let format = *FORMAT.read().unwrap();
+        let _ = std::io::stderr().write_all(diagnostic.render(format).as_bytes());
+    }
+}
+
+pub fn warn(code: &'static str, message: impl Into<String>) {
+    emit(Diagnostic::warning(code, message));
+}
+
+pub fn note(code: &'static str, message: impl Into<String>) {
+    emit(Diagnostic::note(code, message));
+}
+
+/// Prints the diagnostics of a failed run
+pub fn report(error: &anyhow::Error) {
+    for diagnostic in Diagnostic::from_error(error) {
+        emit(diagnostic);
+    }
+}
+
+/// Runs `f`, returning what it emitted in `format` instead of printing it
+#[cfg(test)]
+pub fn capture<T>(format: ErrorFormat, f: impl FnOnce() -> T) -> (T, String) {
+    CAPTURE.with_borrow_mut(|capture| *capture = Some((format, String::new())));
+    let result = f();
+    let (_, out) = CAPTURE.with_borrow_mut(Option::take).unwrap();
+    (result, out)
+}
+
+#[test]
+fn human_and_json() {
+    let warning = Diagnostic::warning("cache", "could not write the size cache").with_suggestion("check the permissions");
+    assert_eq!(warning.render(ErrorFormat::Human), "warning: could not write the size cache\n  help: check the permissions\n");
+    let json: serde_json::Value = serde_json::from_str(&warning.render(ErrorFormat::Json)).unwrap();
+    assert_eq!(
+        json,
+        serde_json::json!({
+            "level": "warning",
+            "code": "cache",
+            "message": "could not write the size cache",
+            "crate": null,
+            "snippet": null,
+            "suggestion": "check the permissions",
+        })
+    );
+
+    let note = Diagnostic::note("fetch", "fetching 2 missing git checkouts");
+    assert_eq!(note.render(ErrorFormat::Human), "note: fetching 2 missing git checkouts\n");
+    let json: serde_json::Value = serde_json::from_str(&note.render(ErrorFormat::Json)).unwrap();
+    assert_eq!(json["level"], "note");
+}
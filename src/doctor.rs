@@ -0,0 +1,273 @@
+use std::ffi::OsStr;
+use std::path::Path;
+
+use cargo_metadata::semver::Version;
+
+use crate::cli::Args;
+use crate::diagnostics::{FETCH_CHECKOUTS, REGENERATE_LOCKFILE};
+
+/// How a probe of the environment went
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Pass,
+    /// Something that may go wrong, or that cargo flatpak works around
+    Warn,
+    /// Something the sources can't be generated without
+    Fail,
+    /// Not probed, like the network under --offline
+    Skip,
+}
+
+impl Status {
+    fn label(self) -> &'static str {
+        match self {
+            Status::Pass => "pass",
+            Status::Warn => "warn",
+            Status::Fail => "fail",
+            Status::Skip => "skip",
+        }
+    }
+}
+
+/// The outcome of a probe, with what to do about it unless it passed
+#[derive(Debug, PartialEq)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: Status,
+    pub message: String,
+    pub suggestion: Option<String>,
+}
+
+impl Check {
+    fn new(name: &'static str, status: Status, message: impl Into<String>) -> Self {
+        Check { name, status, message: message.into(), suggestion: None }
+    }
+
+    fn with_suggestion(mut self, suggestion: impl Into<String>) -> Self {
+        self.suggestion = Some(suggestion.into());
+        self
+    }
+}
+
+/// The cargo of `described`, the output of `toolchain::describe_cargo`, is
+/// recent enough to read crates.io through its sparse index, which cargo
+/// supports from 1.68 and defaults to from 1.70
+pub fn cargo_version(described: Result<String, String>) -> Check {
+    const NAME: &str = "cargo";
+    let described = match described {
+        Ok(described) => described,
+        Err(e) => return Check::new(NAME, Status::Fail, e).with_suggestion("install cargo, or pass --cargo-path"),
+    };
+    let version = described
+        .split_once("(cargo ")
+        .and_then(|(_, version)| Version::parse(version.split([' ', ')']).next()?).ok());
+    let Some(version) = version else {
+        return Check::new(NAME, Status::Warn, format!("can't tell the version of {described}"));
+    };
+    let upgrade = "update the toolchain with `rustup update`";
+    match (version.major, version.minor) {
+        (1, ..=67) => Check::new(NAME, Status::Fail, format!("cargo {version} doesn't support sparse indexes")).with_suggestion(upgrade),
+        (1, 68 | 69) => Check::new(NAME, Status::Warn, format!("cargo {version} only reads crates.io's sparse index when configured to"))
+            .with_suggestion(upgrade),
+        _ => Check::new(NAME, Status::Pass, described),
+    }
+}
+
+/// The workspace at `workspace` has a Cargo.lock
+pub fn lockfile(workspace: &Path) -> Check {
+    let lockfile = workspace.join("Cargo.lock");
+    match lockfile.is_file() {
+        true => Check::new("lockfile", Status::Pass, lockfile.display().to_string()),
+        false => Check::new("lockfile", Status::Fail, format!("{} has no Cargo.lock", workspace.display()))
+            .with_suggestion(REGENERATE_LOCKFILE),
+    }
+}
+
+/// The git dependencies are checked out: `missing` are the repositories that
+/// aren't, or the error of `cargo metadata --offline` when it couldn't tell
+pub fn git_checkouts(missing: Result<Vec<String>, String>) -> Check {
+    const NAME: &str = "git checkouts";
+    match missing {
+        Ok(missing) if missing.is_empty() => Check::new(NAME, Status::Pass, "every git dependency is checked out"),
+        Ok(missing) => Check::new(NAME, Status::Warn, format!("not checked out: {}", missing.join(", "))).with_suggestion(FETCH_CHECKOUTS),
+        Err(e) => Check::new(NAME, Status::Warn, format!("cargo metadata --offline failed: {e}")).with_suggestion(FETCH_CHECKOUTS),
+    }
+}
+
+/// flatpak-builder is on `path`, the PATH to search
+pub fn flatpak_builder(path: Option<&OsStr>) -> Check {
+    const NAME: &str = "flatpak-builder";
+    let found = std::env::split_paths(path.unwrap_or_default()).map(|dir| dir.join(NAME)).find(|path| path.is_file());
+    match found {
+        Some(found) => Check::new(NAME, Status::Pass, found.display().to_string()),
+        None => Check::new(NAME, Status::Warn, "flatpak-builder is not on PATH, the sources can't be built here")
+            .with_suggestion("install flatpak-builder, or `flatpak install flathub org.flatpak.Builder`"),
+    }
+}
+
+/// A flatpak manifest in `dir` lists `output`, the sources file
+pub fn output_referenced(dir: &Path, output: &str) -> Check {
+    const NAME: &str = "output";
+    let mut manifests: Vec<_> = std::fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|e| e == "json" || e == "yml" || e == "yaml"))
+        .filter_map(|path| Some((std::fs::read_to_string(&path).ok()?, path)))
+        .filter(|(contents, _)| contents.contains("modules"))
+        .collect();
+    manifests.sort_by(|a, b| a.1.cmp(&b.1));
+    let listed = format!("Cargo sources: add \"{output}\" to the sources of the application's module");
+    match manifests.iter().find(|(contents, _)| contents.contains(output)) {
+        Some((_, manifest)) => Check::new(NAME, Status::Pass, format!("{} lists {output}", manifest.display())),
+        None if manifests.is_empty() => Check::new(NAME, Status::Warn, format!("no flatpak manifest in {}", dir.display()))
+            .with_suggestion("write one with `cargo flatpak init`"),
+        None => Check::new(NAME, Status::Warn, format!("no flatpak manifest in {} lists {output}", dir.display())).with_suggestion(listed),
+    }
+}
+
+/// The sparse index at `index` answers, unless `offline`
+pub fn index_reachable(index: &str, offline: bool) -> Check {
+    const NAME: &str = "crates.io index";
+    if offline {
+        return Check::new(NAME, Status::Skip, "--offline");
+    }
+    let url = format!("{index}config.json");
+    match crate::net::client().get(&url) {
+        Ok(_) => Check::new(NAME, Status::Pass, format!("{index} is reachable")),
+        Err(e) => Check::new(NAME, Status::Warn, format!("{url}: {e}"))
+            .with_suggestion("check the connection and --proxy, checksums Cargo.lock lacks can't be looked up"),
+    }
+}
+
+/// The checks as a table, one per line with the suggestions under them, and
+/// a count of each outcome
+pub fn table(checks: &[Check]) -> String {
+    let width = checks.iter().map(|check| check.name.len()).max().unwrap_or(0);
+    let mut table = String::new();
+    for check in checks {
+        table += &format!("{:width$}  {}  {}\n", check.name, check.status.label(), check.message);
+        if let Some(suggestion) = &check.suggestion {
+            table += &format!("{:width$}        help: {suggestion}\n", "");
+        }
+    }
+    let count = |status: Status| checks.iter().filter(|check| check.status == status).count();
+    table += &format!(
+        "{} passed, {} warnings, {} failed, {} skipped\n",
+        count(Status::Pass),
+        count(Status::Warn),
+        count(Status::Fail),
+        count(Status::Skip)
+    );
+    table
+}
+
+/// The first error cargo metadata printed
+fn metadata_error(e: cargo_metadata::Error) -> String {
+    match e {
+        cargo_metadata::Error::CargoMetadata { stderr } => {
+            stderr.lines().find(|line| line.starts_with("error")).unwrap_or(stderr.trim()).to_string()
+        }
+        e => e.to_string(),
+    }
+}
+
+/// Runs every probe on the workspace of the current directory, printing the
+/// table and failing on the checks that failed
+pub fn doctor(args: &Args, offline: bool) -> anyhow::Result<()> {
+    let cargo = crate::toolchain::cargo();
+    let mut checks = vec![cargo_version(crate::toolchain::describe_cargo(&cargo).map_err(|e| e.to_string()))];
+    let metadata_command = crate::toolchain::metadata_command(&cargo);
+    match metadata_command.clone().no_deps().exec() {
+        Ok(metadata) => {
+            let workspace = metadata.workspace_root.as_std_path();
+            checks.push(lockfile(workspace));
+            if workspace.join("Cargo.lock").is_file() {
+                let missing = metadata_command
+                    .clone()
+                    .other_options(["--locked".to_string(), "--offline".to_string()])
+                    .exec()
+                    .map_err(metadata_error)
+                    .and_then(|metadata| {
+                        let cargo_lock = std::fs::read_to_string(workspace.join("Cargo.lock")).map_err(|e| e.to_string())?;
+                        crate::generate::missing_locked_checkouts(&cargo_lock, &metadata).map_err(|e| e.to_string())
+                    });
+                checks.push(git_checkouts(missing));
+            }
+            checks.push(output_referenced(workspace, &args.output));
+        }
+        Err(e) => checks.push(
+            Check::new("workspace", Status::Fail, format!("cargo metadata failed: {}", metadata_error(e)))
+                .with_suggestion("run cargo flatpak in a cargo workspace"),
+        ),
+    }
+    checks.push(flatpak_builder(std::env::var_os("PATH").as_deref()));
+    checks.push(index_reachable(&args.crates_io_index, offline));
+    print!("{}", table(&checks));
+    let failed = checks.iter().filter(|check| check.status == Status::Fail).count();
+    if failed > 0 {
+        anyhow::bail!("{failed} doctor checks failed");
+    }
+    Ok(())
+}
+
+#[test]
+fn probes() {
+    use std::collections::HashMap;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let version = |described: &str| cargo_version(Ok(described.into())).status;
+    assert_eq!(version("/usr/bin/cargo (cargo 1.80.0 (376290515 2024-07-16))"), Status::Pass);
+    assert_eq!(version("cargo (cargo 1.69.0 (6e9a83356 2023-04-12))"), Status::Warn);
+    assert_eq!(version("cargo (cargo 1.60.0-nightly (abc 2022-01-01))"), Status::Fail);
+    assert_eq!(version("cargo (rustc 1.80.0)"), Status::Warn);
+    let missing = cargo_version(Err("failed to run cargo: No such file or directory".into()));
+    assert_eq!((missing.status, missing.suggestion.as_deref()), (Status::Fail, Some("install cargo, or pass --cargo-path")));
+
+    assert_eq!(lockfile(tmp.path()).status, Status::Fail);
+    std::fs::write(tmp.path().join("Cargo.lock"), "version = 3\n").unwrap();
+    assert_eq!(lockfile(tmp.path()).status, Status::Pass);
+
+    assert_eq!(git_checkouts(Ok(Vec::new())).status, Status::Pass);
+    let missing = git_checkouts(Ok(vec!["https://github.com/gtk-rs/gtk4-rs#0123456".into()]));
+    assert_eq!(missing.message, "not checked out: https://github.com/gtk-rs/gtk4-rs#0123456");
+    assert_eq!(missing.suggestion.as_deref(), Some(FETCH_CHECKOUTS));
+
+    let bin = tmp.path().join("bin");
+    std::fs::create_dir(&bin).unwrap();
+    assert_eq!(flatpak_builder(Some(bin.as_os_str())).status, Status::Warn);
+    assert_eq!(flatpak_builder(None).status, Status::Warn);
+    std::fs::write(bin.join("flatpak-builder"), "").unwrap();
+    let path = std::env::join_paths([tmp.path().join("nowhere"), bin.clone()]).unwrap();
+    assert_eq!(flatpak_builder(Some(&path)).message, bin.join("flatpak-builder").display().to_string());
+
+    assert_eq!(output_referenced(tmp.path(), "cargo-sources.json").message, format!("no flatpak manifest in {}", tmp.path().display()));
+    std::fs::write(tmp.path().join("org.example.App.yml"), "modules:\n  - name: app\n    sources:\n      - other.json\n").unwrap();
+    let unlisted = output_referenced(tmp.path(), "cargo-sources.json");
+    assert_eq!(unlisted.status, Status::Warn);
+    assert!(unlisted.suggestion.unwrap().contains("add \"cargo-sources.json\""));
+    std::fs::write(tmp.path().join("org.example.App.json"), r#"{"modules": [{"sources": ["cargo-sources.json"]}]}"#).unwrap();
+    assert_eq!(output_referenced(tmp.path(), "cargo-sources.json").status, Status::Pass);
+
+    assert_eq!(index_reachable("http://127.0.0.1:1/", true).status, Status::Skip);
+    let (index, requests) = crate::index::serve(HashMap::from([("/config.json", b"{}".to_vec())]));
+    assert_eq!(index_reachable(&index, false).status, Status::Pass);
+    let (empty, _) = crate::index::serve(HashMap::new());
+    assert_eq!(index_reachable(&empty, false).message, format!("{empty}config.json: HTTP 404"));
+    assert_eq!(requests.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+    let checks = [
+        Check::new("lockfile", Status::Pass, "Cargo.lock"),
+        Check::new("crates.io index", Status::Skip, "--offline"),
+        Check::new("cargo", Status::Fail, "cargo 1.60.0 doesn't support sparse indexes").with_suggestion("update"),
+    ];
+    assert_eq!(
+        table(&checks),
+        "lockfile         pass  Cargo.lock\n\
+         crates.io index  skip  --offline\n\
+         cargo            fail  cargo 1.60.0 doesn't support sparse indexes\n\
+         \x20                      help: update\n\
+         1 passed, 0 warnings, 1 failed, 1 skipped\n"
+    );
+}
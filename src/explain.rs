@@ -0,0 +1,202 @@
+use cargo_metadata::Metadata;
+
+use crate::cli::Args;
+use crate::generate::{manifest_key, package_manifests};
+use crate::policy::dependency_path;
+use crate::sources::{get_package_sources, git_checkout_roots, git_reference, LockFile, Package, Source};
+
+/// Levenshtein distance, for suggesting crate names
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, a) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, b) in b.iter().enumerate() {
+            let substitution = diagonal + usize::from(a != *b);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// Finds the lockfile entry for `name[@version]`
+fn find_package<'a>(cargo_lock: &'a LockFile, query: &str) -> anyhow::Result<&'a Package> {
+    let (name, version) = match query.split_once('@') {
+        Some((name, version)) => (name, Some(version)),
+        None => (query, None),
+    };
+    let candidates: Vec<_> = cargo_lock.package.iter().filter(|p| p.name == name).collect();
+    if candidates.is_empty() {
+        let mut names: Vec<_> = cargo_lock.package.iter().map(|p| p.name.as_str()).collect();
+        names.sort();
+        names.dedup();
+        let close: Vec<_> = names
+            .into_iter()
+            .filter(|n| edit_distance(n, name) <= 2 || n.contains(name) || name.contains(n))
+            .collect();
+        match close.as_slice() {
+            [] => anyhow::bail!("no package named `{name}` in Cargo.lock"),
+            close => anyhow::bail!("no package named `{name}` in Cargo.lock, did you mean {}?", close.join(", ")),
+        }
+    }
+    match (version, candidates.as_slice()) {
+        (Some(version), candidates) => candidates.iter().find(|p| p.version == version).copied().ok_or_else(|| {
+            let versions: Vec<_> = candidates.iter().map(|p| p.version.as_str()).collect();
+            anyhow::anyhow!("no version {version} of `{name}` in Cargo.lock, it has {}", versions.join(", "))
+        }),
+        (None, [package]) => Ok(package),
+        (None, candidates) => {
+            let versions: Vec<_> = candidates.iter().map(|p| p.version.as_str()).collect();
+            anyhow::bail!("`{name}` is locked at several versions ({}), pick one with {name}@<version>", versions.join(", "))
+        }
+    }
+}
+
+/// Traces how the sources of one package are derived, as labelled lines
+pub fn explain(
+    query: &str,
+    cargo_lock: &str,
+    cargo_metadata: &Metadata,
+    args: &Args,
+) -> anyhow::Result<Vec<(&'static str, String)>> {
+    let cargo_lock: LockFile = toml::from_str(cargo_lock)?;
+    let package = find_package(&cargo_lock, query)?;
+    let manifests = package_manifests(cargo_metadata);
+    let manifest = manifests.get(&manifest_key(package)).map(String::as_str);
+
+    let mut trace = vec![
+        ("package", format!("{} {}", package.name, package.version)),
+        ("source", package.source.clone().unwrap_or_else(|| "path".into())),
+    ];
+    trace.extend(package.checksum.as_ref().map(|checksum| ("checksum", checksum.to_string())));
+    if let Some((repository, commit)) = package.source.as_deref().and_then(git_reference) {
+        trace.push(("canonical url", repository));
+        trace.push(("commit", commit.to_string()));
+        if let Some(manifest) = manifest {
+            let (workspace_root, repository_root) = git_checkout_roots(manifest)?;
+            let manifest_dir = std::path::Path::new(manifest).parent().unwrap().canonicalize()?;
+            let package_path = pathdiff::diff_paths(&manifest_dir, &repository_root).unwrap_or(manifest_dir);
+            trace.push(("manifest", manifest.to_string()));
+            trace.push(("repository root", repository_root.display().to_string()));
+            trace.push(("workspace root", workspace_root.display().to_string()));
+            trace.push(("package path", package_path.display().to_string()));
+        }
+    }
+
+    match get_package_sources(package, manifest, args)? {
+        Some((sources, config)) => {
+            trace.extend(config.keys().map(|key| ("config key", key.clone())));
+            for source in sources {
+                let line = match &source {
+                    Source::Archive(archive) => {
+                        let from = archive.url.as_ref().or(archive.path.as_ref()).unwrap();
+                        format!("archive {} <- {from}", archive.dest)
+                    }
+                    Source::Inline(inline) => format!("inline {}/{}", inline.dest, inline.dest_filename),
+                    Source::Git(git) => format!("git {} <- {}", git.dest, git.url),
+                    Source::Shell(shell) => format!("shell {}", shell.commands.join("; ")),
+                    Source::Dir(dir) => format!("dir {} <- {}", dir.dest, dir.path),
+                    Source::File(file) => format!("file {}", file.dest),
+                    Source::Other(other) => format!("other {other}"),
+                };
+                trace.push(("dest", line));
+            }
+        }
+        None => trace.push(("dest", "not vendored, it's part of the workspace".into())),
+    }
+
+    let path = dependency_path(cargo_metadata, &package.name, &package.version)
+        .map(|path| path.join(" -> "))
+        .unwrap_or_else(|| "not in the dependency graph of cargo metadata".into());
+    trace.push(("dependency path", path));
+    Ok(trace)
+}
+
+pub fn render(trace: &[(&str, String)]) -> String {
+    let width = trace.iter().map(|(label, _)| label.len()).max().unwrap_or(0) + 1;
+    trace
+        .iter()
+        .map(|(label, value)| format!("{:width$} {value}\n", format!("{label}:")))
+        .collect()
+}
+
+#[test]
+fn explain_registry_and_git_crates() {
+    use clap::Parser;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let files = [
+        ("app/Cargo.toml", "[package]\nname = \"app\"\nversion = \"0.1.0\"\n\n[dependencies]\ngtk4 = { path = \"../gtk4-rs/gtk4\" }\n"),
+        ("app/src/main.rs", "fn main() {}\n"),
+        ("gtk4-rs/.git/HEAD", ""),
+        ("gtk4-rs/Cargo.toml", "[workspace]\nmembers = [\"gtk4\"]\n"),
+        ("gtk4-rs/gtk4/Cargo.toml", "[package]\nname = \"gtk4\"\nversion = \"0.9.0\"\n"),
+        ("gtk4-rs/gtk4/src/lib.rs", ""),
+    ];
+    crate::sources::write_fixture(tmp.path(), &files);
+    let metadata = cargo_metadata::MetadataCommand::new()
+        .manifest_path(tmp.path().join("app/Cargo.toml"))
+        .exec()
+        .unwrap();
+    // gtk4 as cargo metadata reports a git package, from the checkout it points at
+    let git = "git+https://github.com/gtk-rs/gtk4-rs.git?branch=main#0123456789abcdef0123456789abcdef01234567";
+    let mut metadata = serde_json::to_value(metadata).unwrap();
+    for package in metadata["packages"].as_array_mut().unwrap().iter_mut().filter(|p| p["name"] == "gtk4") {
+        package["source"] = git.into();
+    }
+    let metadata: cargo_metadata::Metadata = serde_json::from_value(metadata).unwrap();
+    let cargo_lock = &format!(
+        r#"
+        version = 3
+
+        [[package]]
+        name = "app"
+        version = "0.1.0"
+
+        [[package]]
+        name = "anstream"
+        version = "0.6.15"
+        source = "registry+https://github.com/rust-lang/crates.io-index"
+        checksum = "{}"
+
+        [[package]]
+        name = "gtk4"
+        version = "0.9.0"
+        source = "git+https://github.com/gtk-rs/gtk4-rs.git?branch=main#0123456789abcdef0123456789abcdef01234567"
+    "#,
+        crate::sources::FIXTURE_CHECKSUM
+    );
+    let args = Args::parse_from(["flatpak"]);
+    let field = |trace: &[(&str, String)], label: &str| -> Vec<String> {
+        trace.iter().filter(|(l, _)| *l == label).map(|(_, v)| v.clone()).collect()
+    };
+
+    let trace = explain("anstream", cargo_lock, &metadata, &args).unwrap();
+    assert_eq!(field(&trace, "package"), ["anstream 0.6.15"]);
+    assert_eq!(field(&trace, "config key"), ["crates-io"]);
+    assert_eq!(
+        field(&trace, "dest"),
+        [
+            "archive cargo/vendor/anstream-0.6.15 <- https://static.crates.io/crates/anstream/anstream-0.6.15.crate",
+            "inline cargo/vendor/anstream-0.6.15/.cargo-checksum.json",
+        ]
+    );
+    assert_eq!(field(&trace, "dependency path"), ["not in the dependency graph of cargo metadata"]);
+
+    let trace = explain("gtk4@0.9.0", cargo_lock, &metadata, &args).unwrap();
+    let checkout = tmp.path().join("gtk4-rs").canonicalize().unwrap();
+    assert_eq!(field(&trace, "canonical url"), ["https://github.com/gtk-rs/gtk4-rs"]);
+    assert_eq!(field(&trace, "config key"), ["https://github.com/gtk-rs/gtk4-rs?branch=main"]);
+    assert_eq!(field(&trace, "repository root"), [checkout.display().to_string()]);
+    assert_eq!(field(&trace, "workspace root"), [checkout.display().to_string()]);
+    assert_eq!(field(&trace, "package path"), ["gtk4"]);
+    assert_eq!(field(&trace, "dest")[0], "git flatpak-cargo/git/gtk-rs-gtk4-rs-8cd6d500-0123456 <- https://github.com/gtk-rs/gtk4-rs");
+    assert_eq!(field(&trace, "dependency path"), ["app 0.1.0 -> gtk4 0.9.0"]);
+
+    let err = explain("anstrem", cargo_lock, &metadata, &args).unwrap_err().to_string();
+    assert_eq!(err, "no package named `anstrem` in Cargo.lock, did you mean anstream?");
+    let err = explain("gtk4@0.8.0", cargo_lock, &metadata, &args).unwrap_err().to_string();
+    assert_eq!(err, "no version 0.8.0 of `gtk4` in Cargo.lock, it has 0.9.0");
+}
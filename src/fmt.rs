@@ -0,0 +1,85 @@
+use std::path::PathBuf;
+
+use crate::cli::GroupBy;
+use crate::sources::{Source, SourceSet};
+
+/// `contents`, a sources file, as cargo flatpak writes one. Entries it doesn't
+/// know of, or with fields it doesn't, are kept as they are.
+pub fn format(contents: &str, group_by: GroupBy) -> anyhow::Result<String> {
+    let sources: Vec<Source> = serde_json::from_str(contents)?;
+    let mut sources = SourceSet::from_sources(sources)?;
+    sources.sort_registry_crates();
+    sources.group_by(group_by);
+    let mut formatted = Vec::new();
+    sources.write_json(&mut formatted)?;
+    Ok(String::from_utf8(formatted).unwrap())
+}
+
+/// Formats `files` in place, or with `check` prints those that aren't
+/// formatted and fails if there are any
+pub fn fmt(files: &[PathBuf], check: bool, group_by: GroupBy) -> anyhow::Result<()> {
+    let mut unformatted = 0;
+    for file in files {
+        let contents = std::fs::read_to_string(file).map_err(|e| anyhow::anyhow!("failed to read {}: {e}", file.display()))?;
+        let formatted = format(&contents, group_by).map_err(|e| e.context(format!("failed to parse {}", file.display())))?;
+        if formatted == contents {
+            continue;
+        }
+        unformatted += 1;
+        match check {
+            true => println!("{} is not formatted", file.display()),
+            false => {
+                std::fs::write(file, formatted)?;
+                println!("formatted {}", file.display());
+            }
+        }
+    }
+    if check && unformatted > 0 {
+        anyhow::bail!("{unformatted} of {} sources files are not formatted", files.len());
+    }
+    Ok(())
+}
+
+#[test]
+fn scrambled_sources() {
+    use clap::Parser;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let (metadata, cargo_lock) = crate::generate::fixture_workspace(tmp.path());
+    let args = crate::cli::Args::parse_from(["flatpak"]);
+    let generated = crate::generate::generate(&args, &metadata, &cargo_lock, "hash".into(), &tmp.path().join("cargo-sources.json")).unwrap();
+    let mut entries: Vec<serde_json::Value> = serde_json::to_value(generated.sources()).unwrap().as_array().unwrap().clone();
+    // Not one of ours, the keys of which stay in their order
+    let extra = serde_json::json!({"type": "extra-data", "url": "https://example.com/a", "sha256": "00", "size": 1, "filename": "a"});
+    entries.insert(4, extra);
+    let canonical = format!("{:#}", serde_json::Value::Array(entries.clone()));
+
+    // Compact, keys reversed, url before anstream
+    let reordered = |value: &serde_json::Value| {
+        let mut object = serde_json::Map::new();
+        for (key, value) in value.as_object().unwrap().iter().rev() {
+            object.insert(key.clone(), value.clone());
+        }
+        serde_json::Value::Object(object)
+    };
+    let (crates, rest) = entries.split_at(4);
+    let mut scrambled: Vec<_> = [&crates[2..], &crates[..2], rest].concat();
+    for entry in scrambled.iter_mut().filter(|entry| entry["type"] != "extra-data") {
+        *entry = reordered(entry);
+    }
+    let scrambled = serde_json::to_string(&scrambled).unwrap();
+    assert_ne!(scrambled, canonical);
+
+    let formatted = format(&scrambled, GroupBy::Crate).unwrap();
+    assert_eq!(formatted, canonical);
+    assert_eq!(format(&formatted, GroupBy::Crate).unwrap(), formatted);
+
+    let file = tmp.path().join("scrambled.json");
+    std::fs::write(&file, &scrambled).unwrap();
+    let err = fmt(std::slice::from_ref(&file), true, GroupBy::Crate).unwrap_err();
+    assert_eq!(err.to_string(), "1 of 1 sources files are not formatted");
+    assert_eq!(std::fs::read_to_string(&file).unwrap(), scrambled);
+    fmt(std::slice::from_ref(&file), false, GroupBy::Crate).unwrap();
+    assert_eq!(std::fs::read_to_string(&file).unwrap(), canonical);
+    fmt(std::slice::from_ref(&file), true, GroupBy::Crate).unwrap();
+}
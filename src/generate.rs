@@ -0,0 +1,2002 @@
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use cargo_metadata::{Metadata, MetadataCommand, PackageId};
+
+use crate::cli::{Args, FlatpakArch, GroupBy, VendorStrategy};
+use crate::diagnostics::{self, Annotated, Diagnostic, Snippet, FETCH_CHECKOUTS, REGENERATE_LOCKFILE};
+use crate::index;
+use crate::policy::{check_forbidden, SourceKind};
+use crate::sources::{
+    artifact_dependencies, lockfile_package_span, lockfile_source, registry_index, get_path_dependency_sources, Inline, LockFile, Package, PathDependency,
+    Source, SourceSet, CONFIG_OWNER, CRATES_IO_SOURCE, LOCKFILE_OWNER, stub_package_sources,
+};
+
+/// A package whose sources couldn't be generated
+#[derive(Debug)]
+pub struct PackageError {
+    pub name: String,
+    pub version: String,
+    pub error: anyhow::Error,
+    /// The package's `[[package]]` block in Cargo.lock
+    pub snippet: Option<Snippet>,
+}
+
+impl PackageError {
+    /// The error, starting with the package
+    pub fn message(&self) -> String {
+        let package = format!("{} {}", self.name, self.version);
+        // Most errors already start with the package
+        match format!("{:#}", self.error) {
+            error if error.starts_with(&package) => error,
+            error => format!("{package}: {error}"),
+        }
+    }
+}
+
+/// Every package that failed, so they can all be fixed before the next run
+#[derive(Debug)]
+pub struct PackageErrors(pub Vec<PackageError>);
+
+impl PackageErrors {
+    pub fn summary(failed: usize) -> String {
+        format!("failed to generate the sources of {failed} packages")
+    }
+}
+
+impl std::fmt::Display for PackageErrors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:", Self::summary(self.0.len()))?;
+        for error in &self.0 {
+            write!(f, "\n  {}", error.message())?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for PackageErrors {}
+
+/// The exit status of a --keep-going run that left packages out
+pub const INCOMPLETE_EXIT_CODE: u8 = 2;
+
+/// What a --keep-going run that left packages out fails with, after writing
+/// the sources of the others to `output`
+#[derive(Debug)]
+pub struct Incomplete {
+    pub output: PathBuf,
+    pub errors: PackageErrors,
+}
+
+impl Incomplete {
+    /// The packages missing from the output, one per line
+    pub fn summary(&self) -> String {
+        let mut summary = format!(
+            "wrote {} without the sources of {} packages:",
+            self.output.display(),
+            self.errors.0.len()
+        );
+        for error in &self.errors.0 {
+            summary.push_str(&format!("\n  {} {}", error.name, error.version));
+        }
+        summary
+    }
+}
+
+impl std::fmt::Display for Incomplete {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.summary())
+    }
+}
+
+impl std::error::Error for Incomplete {}
+
+/// Generates the sources for the workspace described by `cargo_metadata` from
+/// the contents of its Cargo.lock, with the cargo config as the last entry
+/// unless --no-config is given.
+/// `output` is where the sources will be written, relative paths are based on it.
+pub fn generate(
+    args: &Args,
+    cargo_metadata: &Metadata,
+    cargo_lock: &str,
+    lock_hash: String,
+    output: &Path,
+) -> anyhow::Result<SourceSet> {
+    Ok(generate_sources(args, cargo_metadata, cargo_lock, lock_hash, output, false)?.0)
+}
+
+/// Like `generate`, but leaves the packages that fail out of the sources
+/// instead of failing, and returns their errors along with them
+pub fn generate_keep_going(
+    args: &Args,
+    cargo_metadata: &Metadata,
+    cargo_lock: &str,
+    lock_hash: String,
+    output: &Path,
+) -> anyhow::Result<(SourceSet, Vec<PackageError>)> {
+    generate_sources(args, cargo_metadata, cargo_lock, lock_hash, output, true)
+}
+
+fn generate_sources(
+    args: &Args,
+    cargo_metadata: &Metadata,
+    cargo_lock: &str,
+    lock_hash: String,
+    output: &Path,
+    keep_going: bool,
+) -> anyhow::Result<(SourceSet, Vec<PackageError>)> {
+    let workspace = cargo_metadata.workspace_root.as_std_path();
+    let cargo_lock_contents = cargo_lock;
+    let lockfile = args.lockfile_path(workspace).map_or("<stdin>".into(), |path| path.display().to_string());
+    let span = tracing::info_span!("parse lockfile").entered();
+    let mut cargo_lock: LockFile = toml::de::from_str(cargo_lock).map_err(|e| {
+        let snippet = e.span().map(|span| Snippet::new(&lockfile, cargo_lock_contents, span));
+        Annotated::new(anyhow::anyhow!("failed to parse {lockfile}: {}", e.message()), REGENERATE_LOCKFILE).with_snippet(snippet)
+    })?;
+    if args.lockfile_only {
+        check_lockfile_only(&cargo_lock, args)?;
+    }
+    let mut unresolved = HashMap::new();
+    if args.resolve_missing_checksums {
+        if args.vendor_strategy != VendorStrategy::Directory {
+            anyhow::bail!("--resolve-missing-checksums needs --vendor-strategy directory, the other strategies check the crates against Cargo.lock");
+        }
+        unresolved = index::resolve_missing_checksums(&mut cargo_lock.package, &args.crates_io_index, index::default_cache().as_deref());
+    }
+    let mut manifests = package_manifests(cargo_metadata);
+    override_commits(args, &mut cargo_lock.package, &mut manifests)?;
+    let external_path_deps = external_path_dependencies(cargo_metadata)?;
+    span.exit();
+
+    let path_dep_sources = get_path_dependency_sources(
+        &external_path_deps,
+        workspace,
+        output.parent().unwrap(),
+        args,
+    )?;
+
+    let mut sources = SourceSet::new(&args.vendor_dir());
+    if args.config_offline {
+        sources.config.set_offline();
+    }
+    if args.merge_project_config {
+        let project_config = [".cargo/config.toml", ".cargo/config"]
+            .iter()
+            .map(|path| workspace.join(path))
+            .find(|path| path.is_file());
+        match project_config {
+            Some(path) => {
+                let project: toml::Table = toml::from_str(&std::fs::read_to_string(&path)?)?;
+                for warning in sources.config.merge_project(&project) {
+                    diagnostics::warn("project-config", format!("{}: {warning}", path.display()));
+                }
+            }
+            None => diagnostics::warn("project-config", "--merge-project-config: the project has no .cargo/config.toml"),
+        }
+    }
+
+    let mut artifact_deps = Vec::new();
+    for member in cargo_metadata.workspace_packages() {
+        let manifest: toml::Value = toml::from_str(&std::fs::read_to_string(&member.manifest_path)?)?;
+        artifact_deps.extend(artifact_dependencies(&manifest));
+    }
+
+    let added = added_packages(args, &cargo_lock)?;
+    let span = tracing::info_span!("packages").entered();
+    let replace = replace_section(workspace)?;
+    if !replace.is_empty() {
+        diagnostics::warn("replace", "Cargo.toml: [replace] is deprecated, [patch] replaces crates the same way");
+        if args.vendor_strategy != VendorStrategy::Directory {
+            anyhow::bail!("[replace] needs --vendor-strategy directory, the index of the other strategies lacks the replaced crates");
+        }
+    }
+    let mut locked = vendored_packages(&cargo_lock, cargo_metadata)?;
+    let (replacements, replaced) = replaced_packages(&cargo_lock, &locked, &replace);
+    locked.extend(replaced);
+    let foreign = match args.prune_foreign_targets {
+        true => foreign_packages(&locked, cargo_metadata, args)?,
+        false => Vec::new(),
+    };
+    // What ships in the sources, bundled path dependencies included
+    let bundled = cargo_lock.package.iter().filter(|p| {
+        p.source.is_none() && external_path_deps.iter().any(|dep| dep.name == p.name && dep.version == p.version)
+    });
+    let packages: Vec<_> = locked.iter().copied().chain(&added).collect();
+    let shipped: Vec<_> = packages.iter().copied().chain(bundled).collect();
+    check_forbidden(&shipped, cargo_metadata, &args.forbid, &args.forbidden_sources(), &args.allow_git)?;
+    let mut errors = Vec::new();
+    for &package in &packages {
+        let _span = tracing::info_span!("package", "crate" = %format_args!("{} {}", package.name, package.version)).entered();
+        if foreign.iter().any(|&p| std::ptr::eq(p, package)) {
+            let stub = stub_package_sources(package, metadata_package(package, cargo_metadata), args)?;
+            for source in stub {
+                sources.push(Some(SourceKind::Registry), format!("{}-{}", package.name, package.version), source);
+            }
+            continue;
+        }
+        let manifest = manifests.get(&manifest_key(package)).map(String::as_str);
+        if let Some(error) = unresolved.remove(&(package.name.clone(), package.version.clone())) {
+            errors.push(PackageError { name: package.name.clone(), version: package.version.clone(), error, snippet: None });
+            continue;
+        }
+        let pushed = match replacements.iter().any(|&p| std::ptr::eq(p, package)) {
+            true => sources.push_replacement(package, manifest, args),
+            false => sources.push_package(package, manifest, args),
+        };
+        if let Err(e) = pushed {
+            let error = if artifact_deps.contains(&package.name) {
+                e.context(format!(
+                    "{} is an artifact dependency, which cargo metadata doesn't resolve without -Z bindeps",
+                    package.name
+                ))
+            } else {
+                e
+            };
+            let error = match Annotated::find(&error) {
+                Some(_) => error,
+                None if package.source.as_deref().is_some_and(|s| s.starts_with("git+")) => Annotated::new(error, FETCH_CHECKOUTS).into(),
+                None => Annotated::new(error, REGENERATE_LOCKFILE).into(),
+            };
+            errors.push(PackageError { name: package.name.clone(), version: package.version.clone(), error, snippet: None });
+        }
+    }
+    span.exit();
+    if !errors.is_empty() {
+        if let Ok(document) = toml_edit::ImDocument::parse(cargo_lock_contents) {
+            for error in &mut errors {
+                let span = lockfile_package_span(&document, &error.name, &error.version);
+                error.snippet = span.map(|span| Snippet::new(&lockfile, cargo_lock_contents, span));
+            }
+        }
+        if !keep_going {
+            return Err(PackageErrors(errors).into());
+        }
+    }
+    match args.vendor_strategy {
+        VendorStrategy::Directory => {}
+        VendorStrategy::LocalRegistry => sources.config.add_local_registry(&args.local_registry_dir()),
+        // Without a replacement nothing else keeps cargo from updating the index
+        VendorStrategy::RegistryCache => sources.config.set_offline(),
+    }
+    for index in registry_index(&locked, cargo_metadata, args)? {
+        sources.push(Some(SourceKind::Registry), index.dest_filename.clone(), Source::Inline(index));
+    }
+
+    for source in path_dep_sources {
+        // A bundled path dependency is a dir where the manifests point
+        let Source::Dir(dir) = &source else { unreachable!("path dependencies are dir sources") };
+        let owner = dir.dest.rsplit('/').next().unwrap().to_string();
+        sources.push(Some(SourceKind::Path), owner, source);
+    }
+    sources.group_by(args.group_by);
+    if args.move_git_sources {
+        sources.move_git_sources();
+    }
+
+    let manifest_dir = manifest_dir(args, workspace, output);
+
+    if args.include_lockfile {
+        let lockfile = match args.no_inline {
+            true => Some(args.lockfile_path(workspace).ok_or_else(|| {
+                anyhow::anyhow!("--no-inline references the lockfile by its path, and one read from stdin has none")
+            })?),
+            false => None,
+        };
+        let lockfile = lockfile_source(cargo_lock_contents, lockfile.as_deref(), &manifest_dir, &args.dest("."))?;
+        sources.push(None, LOCKFILE_OWNER, lockfile);
+    }
+
+    // Without the sources of every package, the output isn't up to date with anything
+    let lock_hash = errors.is_empty().then_some(lock_hash);
+    if !args.no_config {
+        let _span = tracing::info_span!("config").entered();
+        let cargo_vendored_sources = match &args.write_config {
+            Some(config_path) => sources.config.write_file(
+                &workspace.join(config_path),
+                &manifest_dir,
+                &args.cargo_home_dir(),
+                lock_hash,
+                args.no_clobber,
+            )?,
+            None => Source::Inline(Inline {
+                contents: sources.config.to_toml()?,
+                base64: false,
+                dest: args.cargo_home_dir(),
+                dest_filename: "config".into(),
+                x_cargo_lock_hash: lock_hash,
+            }),
+        };
+        sources.push(None, CONFIG_OWNER, cargo_vendored_sources);
+    }
+
+    Ok((sources, errors))
+}
+
+/// The directory of the flatpak manifest, which source paths are relative to
+pub fn manifest_dir(args: &Args, out_dir: &Path, output: &Path) -> PathBuf {
+    match &args.manifest_dir {
+        Some(dir) => out_dir.join(dir),
+        None => output.parent().unwrap().to_path_buf(),
+    }
+}
+
+/// `cargo-sources.json` split in n files is `cargo-sources-1.json` to `cargo-sources-n.json`
+pub fn split_path(output: &Path, index: usize) -> PathBuf {
+    let mut name = output.file_stem().unwrap_or_default().to_os_string();
+    name.push(format!("-{index}"));
+    if let Some(extension) = output.extension() {
+        name.push(".");
+        name.push(extension);
+    }
+    output.with_file_name(name)
+}
+
+/// Removes the split files of `output` from the `from`th on, those a split
+/// into more files left behind
+pub fn remove_split_files(output: &Path, from: usize) -> anyhow::Result<()> {
+    for path in (from..).map(|i| split_path(output, i)).take_while(|path| path.exists()) {
+        std::fs::remove_file(&path).with_context(|| format!("failed to remove {}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// Writes `contents` to `path` through a temporary file renamed over it, so
+/// an interrupted run never leaves a partial file. Missing parent directories
+/// are created, and with `no_clobber` an existing file is an error.
+pub fn write_output(path: &Path, contents: &[u8], no_clobber: bool) -> anyhow::Result<()> {
+    write_output_with(path, no_clobber, |out| Ok(out.write_all(contents)?))
+}
+
+/// Like [`write_output`], with the contents streamed by `write` into a buffered file
+pub fn write_output_with(
+    path: &Path,
+    no_clobber: bool,
+    write: impl FnOnce(&mut dyn Write) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    use anyhow::Context;
+
+    let parent = path.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    std::fs::create_dir_all(parent).with_context(|| format!("failed to create {}", parent.display()))?;
+    let file_name = path.file_name().ok_or_else(|| anyhow::anyhow!("{} is not a file path", path.display()))?;
+    let mut tmp_name = std::ffi::OsString::from(".");
+    tmp_name.push(file_name);
+    tmp_name.push(format!(".{}.tmp", std::process::id()));
+    let tmp = parent.join(tmp_name);
+    let written = (|| {
+        let mut file = std::io::BufWriter::new(std::fs::File::create(&tmp)?);
+        write(&mut file)?;
+        file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        anyhow::Ok(())
+    })()
+    .with_context(|| format!("failed to write {}", tmp.display()));
+    let moved = written.and_then(|_| match no_clobber {
+        // Linking fails if the file exists, where a rename would replace it
+        true => std::fs::hard_link(&tmp, path).map_err(|e| match e.kind() {
+            std::io::ErrorKind::AlreadyExists => {
+                anyhow::anyhow!("{} already exists and --no-clobber is set", path.display())
+            }
+            _ => anyhow::Error::new(e).context(format!("failed to write {}", path.display())),
+        }),
+        false => std::fs::rename(&tmp, path).with_context(|| format!("failed to write {}", path.display())),
+    });
+    let _ = std::fs::remove_file(&tmp);
+    moved
+}
+
+/// Writes the sources, and the module, vendor script and checksums if asked for
+pub fn write_outputs(
+    args: &Args,
+    cargo_metadata: &cargo_metadata::Metadata,
+    generated: &SourceSet,
+    app_source: Option<&Source>,
+    toolchain: Option<&crate::toolchain::Toolchain>,
+    out_dir: &Path,
+    output: &Path,
+) -> anyhow::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let _span = tracing::info_span!("write").entered();
+    let workspace = cargo_metadata.workspace_root.as_std_path();
+    // Without a module the application's source leads the (first) sources file
+    let write_sources = |path: &Path, sources: &[&Source], first: bool| {
+        let mut sources = sources.to_vec();
+        if let Some(app_source) = app_source.filter(|_| first && !args.module) {
+            sources.insert(0, app_source);
+        }
+        write_output_with(path, args.no_clobber, |out| crate::sources::write_sources(out, &sources, args.sources_format(path)))
+    };
+    if let Some(script) = &args.emit_vendor_script {
+        let path = out_dir.join(script);
+        let manifest_dir = manifest_dir(args, out_dir, output);
+        let script = crate::script::vendor_script(generated, &manifest_dir)?;
+        write_output(&path, script.as_bytes(), args.no_clobber)?;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755))?;
+    }
+    if let Some(checksums) = &args.emit_checksums {
+        let sources: Vec<_> = app_source.into_iter().chain(generated.sources()).collect();
+        write_output(&out_dir.join(checksums), crate::checksums::sha256sums(&sources).as_bytes(), args.no_clobber)?;
+    }
+    let outputs = match (args.split, &args.separate_git) {
+        (_, Some(git_output)) => {
+            let (git, others) = generated.partition_git();
+            let git_output = out_dir.join(git_output);
+            write_sources(output, &others, true)?;
+            write_output_with(&git_output, args.no_clobber, |out| crate::sources::write_sources(out, &git, args.sources_format(&git_output)))?;
+            for path in [output, &git_output] {
+                println!("{}", path.strip_prefix(out_dir).unwrap_or(path).display());
+            }
+            vec![output.to_path_buf(), git_output]
+        }
+        (Some(max), None) => {
+            if args.group_by != GroupBy::Crate {
+                anyhow::bail!("--split keeps the sources of a crate together, it needs --group-by crate");
+            }
+            let mut outputs = Vec::new();
+            for (i, chunk) in generated.split(max as usize)?.iter().enumerate() {
+                let path = split_path(output, i + 1);
+                write_sources(&path, chunk, i == 0)?;
+                println!("{}", path.strip_prefix(out_dir).unwrap_or(&path).display());
+                outputs.push(path);
+            }
+            remove_split_files(output, outputs.len() + 1)?;
+            outputs
+        }
+        (None, None) => {
+            write_sources(output, &generated.sources(), true)?;
+            vec![output.to_path_buf()]
+        }
+    };
+
+    if args.module {
+        let bins = crate::module::binary_targets(cargo_metadata, &args.package)?;
+        let name = match (args.package.as_slice(), cargo_metadata.root_package()) {
+            ([package], _) => crate::module::module_name(package, args),
+            (_, Some(root)) => crate::module::module_name(&root.name, args),
+            _ => crate::sources::utf8_path(Path::new(workspace.file_name().unwrap()))?.to_string(),
+        };
+        let module_output = out_dir.join(&args.module_output);
+        let sources_files = outputs
+            .iter()
+            .map(|output| {
+                let path = pathdiff::diff_paths(output, module_output.parent().unwrap()).unwrap();
+                Ok(crate::sources::utf8_path(&path)?.to_string())
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let mut modules = match args.per_package_modules {
+            true => crate::module::package_modules(&bins, &sources_files, args)?,
+            false => vec![crate::module::module(&name, &bins, &sources_files, args)?],
+        };
+        if let Some(toolchain) = toolchain {
+            for module in &mut modules {
+                module.pin_toolchain(toolchain);
+            }
+        }
+        if let Some(app_source) = app_source {
+            for module in &mut modules {
+                module.sources.insert(0, crate::module::ModuleSource::Source(app_source.clone()));
+            }
+        }
+        let module = match args.per_package_modules {
+            true => serde_json::to_string_pretty(&modules)?,
+            false => serde_json::to_string_pretty(&modules[0])?,
+        };
+        write_output(&module_output, module.as_bytes(), args.no_clobber)?;
+    }
+    Ok(())
+}
+
+/// Reads the sources of `output`, or of its split files when they're newer or
+/// there's no `output`, after those of the git crates in `git_output` with
+/// --separate-git
+pub fn read_sources(output: &Path, git_output: Option<&Path>) -> anyhow::Result<SourceSet> {
+    let parse = |path: &Path| -> anyhow::Result<Vec<Source>> {
+        if path.extension().is_some_and(|e| e == "yml" || e == "yaml") {
+            anyhow::bail!("{} is YAML, which cargo flatpak writes but doesn't read back", path.display());
+        }
+        let contents = std::fs::read_to_string(path).with_context(|| format!("failed to read {}", path.display()))?;
+        Ok(serde_json::from_str(&contents)?)
+    };
+    // The git crates come first, the config stays last
+    let mut sources: Vec<Source> = match git_output {
+        Some(path) => parse(path)?,
+        None => Vec::new(),
+    };
+    let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    let split = match (modified(output), modified(&split_path(output, 1))) {
+        (Some(single), Some(split)) => split > single,
+        (single, split) => single.is_none() && split.is_some(),
+    };
+    if !split {
+        sources.extend(parse(output)?);
+        return SourceSet::from_sources(sources);
+    }
+    for path in (1..).map(|i| split_path(output, i)).take_while(|path| path.exists()) {
+        sources.extend(parse(&path)?);
+    }
+    SourceSet::from_sources(sources)
+}
+
+/// A Cargo.lock out of date with the manifests
+#[derive(Debug)]
+pub struct StaleLockfile {
+    /// What of the manifests the lockfile doesn't match, which may be nothing
+    /// cargo metadata tells of
+    pub mismatches: Vec<String>,
+    /// The lockfile cargo resolved instead, which the metadata is of
+    pub cargo_lock: String,
+}
+
+/// Runs `command` with `--locked`, telling whether Cargo.lock is out of date
+/// with the manifests. Cargo then resolves again without it, and the sources
+/// are to be generated from the lockfile it resolved, which the metadata
+/// matches. The lockfile on disk is put back as it was.
+pub fn locked_metadata(command: &MetadataCommand) -> anyhow::Result<(Metadata, Option<StaleLockfile>)> {
+    let failed = |e: cargo_metadata::Error| anyhow::anyhow!(e).context("failed to get metadata");
+    match command.clone().other_options(["--locked".to_string()]).exec() {
+        Err(cargo_metadata::Error::CargoMetadata { stderr }) if stderr.contains("--locked was passed") => {}
+        locked => return Ok((locked.map_err(failed)?, None)),
+    }
+    let workspace = command.clone().no_deps().exec().map_err(failed)?.workspace_root;
+    let lockfile = workspace.join("Cargo.lock");
+    // Without a lockfile there's nothing to be out of date, cargo writes one
+    let Ok(cargo_lock) = std::fs::read_to_string(&lockfile) else {
+        return Ok((command.exec().map_err(failed)?, None));
+    };
+    let metadata = command.exec().map_err(failed);
+    let resolved = std::fs::read_to_string(&lockfile);
+    write_output(lockfile.as_std_path(), cargo_lock.as_bytes(), false).with_context(|| format!("failed to restore {lockfile}"))?;
+    let (metadata, resolved) = (metadata?, resolved.with_context(|| format!("failed to read {lockfile}"))?);
+    let mismatches = lockfile_mismatches(&metadata, &toml::from_str(&cargo_lock)?);
+    Ok((metadata, Some(StaleLockfile { mismatches, cargo_lock: resolved })))
+}
+
+/// The workspace members, and the requirements of their dependencies, that
+/// no package of `cargo_lock` satisfies
+fn lockfile_mismatches(metadata: &Metadata, cargo_lock: &LockFile) -> Vec<String> {
+    fn source(package: &Package) -> Option<&str> {
+        package.source.as_deref().map(|s| s.split('#').next().unwrap())
+    }
+    let mut mismatches = Vec::new();
+    let locked = |name: &str| cargo_lock.package.iter().filter(|p| p.name == name).collect::<Vec<_>>();
+    for member in metadata.workspace_packages() {
+        if !locked(&member.name).iter().any(|p| p.source.is_none() && p.version == member.version.to_string()) {
+            mismatches.push(format!("{} {} is not in Cargo.lock", member.name, member.version));
+        }
+        for dep in &member.dependencies {
+            // Path dependencies have no source, git ones are locked to a commit
+            let candidates: Vec<_> = locked(&dep.name).into_iter().filter(|p| source(p) == dep.source.as_deref()).collect();
+            let satisfied = candidates.iter().any(|p| {
+                cargo_metadata::semver::Version::parse(&p.version).is_ok_and(|version| dep.req.matches(&version))
+            });
+            match (satisfied, candidates.as_slice()) {
+                (true, _) => {}
+                (false, []) => mismatches.push(format!("{} depends on {} {}, which is not in Cargo.lock", member.name, dep.name, dep.req)),
+                (false, candidates) => {
+                    let versions: Vec<_> = candidates.iter().map(|p| p.version.as_str()).collect();
+                    mismatches.push(format!(
+                        "{} depends on {} {}, Cargo.lock has {}",
+                        member.name,
+                        dep.name,
+                        dep.req,
+                        versions.join(", ")
+                    ));
+                }
+            }
+        }
+    }
+    mismatches.dedup();
+    mismatches
+}
+
+/// The warning, or with --strict the error, of a Cargo.lock out of date with the manifests
+pub fn stale_lockfile_message(mismatches: &[String]) -> String {
+    let mut message =
+        "Cargo.lock is out of date with the manifests, the sources are generated from what cargo resolves instead".to_string();
+    for mismatch in mismatches {
+        message += &format!("\n  {mismatch}");
+    }
+    message
+}
+
+pub const UPDATE_LOCKFILE: &str = "bring Cargo.lock up to date with `cargo update --workspace`, and commit it";
+
+/// Reads the Cargo.lock of --lockfile, from `stdin` for `-`, or the one of the workspace
+pub fn read_lockfile(args: &Args, workspace: &Path, mut stdin: impl std::io::Read) -> anyhow::Result<String> {
+    let Some(path) = args.lockfile_path(workspace) else {
+        let mut cargo_lock = String::new();
+        stdin.read_to_string(&mut cargo_lock).context("failed to read Cargo.lock from stdin")?;
+        return Ok(cargo_lock);
+    };
+    std::fs::read_to_string(&path).with_context(|| format!("failed to read {}", path.display()))
+}
+
+/// Metadata for --lockfile-only, a workspace at `dir` that cargo knows
+/// nothing of, so that every package of the lockfile is vendored
+pub fn lockfile_only_metadata(dir: &Path) -> anyhow::Result<Metadata> {
+    Ok(serde_json::from_value(serde_json::json!({
+        "packages": [],
+        "workspace_members": [],
+        "resolve": null,
+        "workspace_root": dir,
+        "target_directory": dir.join("target"),
+        "version": 1,
+    }))?)
+}
+
+/// Fails on what --lockfile-only can't generate
+fn check_lockfile_only(cargo_lock: &LockFile, args: &Args) -> anyhow::Result<()> {
+    if args.vendor_strategy != VendorStrategy::Directory {
+        anyhow::bail!("--lockfile-only needs --vendor-strategy directory, the registry index entries of the others come from cargo metadata");
+    }
+    let git: Vec<_> = cargo_lock
+        .package
+        .iter()
+        .filter(|p| p.source.as_deref().is_some_and(|s| s.starts_with("git+")))
+        .map(|p| format!("{} {}", p.name, p.version))
+        .collect();
+    if !git.is_empty() {
+        let error = anyhow::anyhow!(
+            "--lockfile-only can't vendor the git dependencies:\n  {}\n\
+             A vendored git crate gets its Cargo.toml rewritten from the checkout, and the lockfile only \
+             names the commit: the checkout is found by cargo metadata on the project",
+            git.join("\n  ")
+        );
+        return Err(Annotated::new(error, "run in the project rather than on its lockfile alone").into());
+    }
+    Ok(())
+}
+
+/// Manifest paths by `(name, version, source)`, as a git package may have the
+/// name and version of a registry one
+pub type Manifests = HashMap<(String, String, Option<String>), String>;
+
+/// The key of a package of Cargo.lock in [`Manifests`]
+pub fn manifest_key(package: &Package) -> (String, String, Option<String>) {
+    (package.name.clone(), package.version.clone(), package.source.clone())
+}
+
+/// The manifest path of every package cargo metadata knows of
+pub fn package_manifests(cargo_metadata: &Metadata) -> Manifests {
+    cargo_metadata
+        .packages
+        .iter()
+        .map(|p| ((p.name.clone(), p.version.to_string(), p.source.as_ref().map(|s| s.repr.clone())), p.manifest_path.to_string()))
+        .collect()
+}
+
+/// The packages reachable from the workspace members in the resolve graph of
+/// cargo metadata, as `(name, version, source)`, or `None` without one
+fn resolved_packages(cargo_metadata: &Metadata) -> Option<HashSet<(&str, String, Option<&str>)>> {
+    let resolve = cargo_metadata.resolve.as_ref()?;
+    let nodes: HashMap<&PackageId, _> = resolve.nodes.iter().map(|n| (&n.id, n)).collect();
+    let mut reached: HashSet<&PackageId> = cargo_metadata.workspace_members.iter().collect();
+    let mut queue: Vec<&PackageId> = reached.iter().copied().collect();
+    while let Some(id) = queue.pop() {
+        for dep in nodes.get(id).map(|n| n.deps.as_slice()).unwrap_or_default() {
+            if reached.insert(&dep.pkg) {
+                queue.push(&dep.pkg);
+            }
+        }
+    }
+    let packages = cargo_metadata.packages.iter().filter(|p| reached.contains(&p.id));
+    Some(packages.map(|p| (p.name.as_str(), p.version.to_string(), p.source.as_ref().map(|s| s.repr.as_str()))).collect())
+}
+
+/// The packages some flatpak architecture of `arches` builds, as
+/// `(name, version, source)`, by the resolve graph of cargo metadata filtered
+/// to each one's target
+fn platform_packages(cargo_metadata: &Metadata, arches: &[FlatpakArch]) -> anyhow::Result<HashSet<(String, String, Option<String>)>> {
+    let mut built = HashSet::new();
+    for arch in arches {
+        let metadata = crate::toolchain::metadata_command(&crate::toolchain::cargo())
+            .manifest_path(cargo_metadata.workspace_root.join("Cargo.toml"))
+            .features(cargo_metadata::CargoOpt::AllFeatures)
+            .other_options(["--locked".to_string(), "--filter-platform".to_string(), arch.target().to_string()])
+            .exec()
+            .with_context(|| format!("failed to resolve the dependencies for {}", arch.target()))?;
+        let resolved = resolved_packages(&metadata).unwrap_or_default();
+        built.extend(resolved.into_iter().map(|(name, version, source)| (name.to_string(), version, source.map(String::from))));
+    }
+    Ok(built)
+}
+
+/// The registry packages of `packages` none of the flatpak architectures of
+/// --arches builds. Git packages are kept, the config replaces their sources anyway.
+fn foreign_packages<'a>(packages: &[&'a Package], cargo_metadata: &Metadata, args: &Args) -> anyhow::Result<Vec<&'a Package>> {
+    if args.vendor_strategy != VendorStrategy::Directory {
+        anyhow::bail!("--prune-foreign-targets needs --vendor-strategy directory, the stubs it vendors are directories");
+    }
+    let built = platform_packages(cargo_metadata, &args.arches)?;
+    let foreign: Vec<&Package> = packages
+        .iter()
+        .copied()
+        .filter(|p| p.checksum.is_some() && !p.source.as_deref().is_some_and(|s| s.starts_with("git+")))
+        .filter(|p| !built.contains(&(p.name.clone(), p.version.clone(), p.source.clone())))
+        .collect();
+    if !foreign.is_empty() {
+        let names: Vec<_> = foreign.iter().map(|p| format!("{} {}", p.name, p.version)).collect();
+        let message = format!("pruned {} crates no flatpak architecture builds, vendoring stubs of them: {}", foreign.len(), names.join(", "));
+        diagnostics::emit(Diagnostic::warning("foreign-targets", message).with_suggestion("add the architectures they're built for to --arches to vendor them"));
+    }
+    Ok(foreign)
+}
+
+/// The package of cargo metadata `package` is
+fn metadata_package<'a>(package: &Package, cargo_metadata: &'a Metadata) -> Option<&'a cargo_metadata::Package> {
+    cargo_metadata.packages.iter().find(|p| {
+        p.name == package.name && p.version.to_string() == package.version && p.source.as_ref().map(|s| s.repr.as_str()) == package.source.as_deref()
+    })
+}
+
+/// Drops the repeated entries of a package, as a botched merge of Cargo.lock
+/// leaves them, failing when they disagree on the checksum.
+/// The same name and version from two sources are two packages.
+fn dedup_packages(packages: Vec<&Package>) -> anyhow::Result<Vec<&Package>> {
+    let mut seen: HashMap<(&str, &str, Option<&str>), &Package> = HashMap::new();
+    let mut deduped = Vec::new();
+    for package in packages {
+        let key = (package.name.as_str(), package.version.as_str(), package.source.as_deref());
+        let Some(first) = seen.get(&key) else {
+            seen.insert(key, package);
+            deduped.push(package);
+            continue;
+        };
+        if first.checksum == package.checksum {
+            let message = format!("Cargo.lock lists {} {} twice, vendoring it once", package.name, package.version);
+            diagnostics::emit(
+                Diagnostic::warning("duplicate-package", message)
+                    .with_crate(&package.name, &package.version)
+                    .with_suggestion("remove the repeated entry from Cargo.lock"),
+            );
+            continue;
+        }
+        let entry = |p: &Package| {
+            let checksum = p.checksum.as_ref().map_or("none".into(), |c| c.to_string());
+            format!("  source {}, checksum {checksum}", p.source.as_deref().unwrap_or("none"))
+        };
+        anyhow::bail!(
+            "Cargo.lock lists {name} {version} twice, differently:\n{}\n{}\n\
+             resolve the merge conflict, e.g. with `cargo update -p {name}@{version}`",
+            entry(first),
+            entry(package),
+            name = package.name,
+            version = package.version,
+        );
+    }
+    Ok(deduped)
+}
+
+/// The lockfile packages that are vendored from a registry, a local mirror of
+/// one or a git repository.
+/// The resolve graph decides which are needed, the whole lockfile is vendored
+/// when metadata has no resolve.
+pub fn vendored_packages<'a>(cargo_lock: &'a LockFile, cargo_metadata: &Metadata) -> anyhow::Result<Vec<&'a Package>> {
+    dedup_packages(vendorable_packages(cargo_lock, cargo_metadata))
+}
+
+/// The packages of Cargo.lock to vendor, duplicates included
+fn vendorable_packages<'a>(cargo_lock: &'a LockFile, cargo_metadata: &Metadata) -> Vec<&'a Package> {
+    let resolved = resolved_packages(cargo_metadata);
+    cargo_lock
+        .package
+        .iter()
+        // Registry packages without a checksum too, so they are reported rather than left out
+        .filter(|p| p.source.is_some())
+        .filter(|p| {
+            resolved.as_ref().is_none_or(|resolved| {
+                resolved.contains(&(p.name.as_str(), p.version.clone(), p.source.as_deref()))
+            })
+        })
+        .collect()
+}
+
+/// The crates the `[replace]` section of the workspace manifest at `workspace`
+/// replaces, by name and version, from keys like `foo:1.0.0` or `foo@1.0.0`.
+/// With --lockfile-only there may be no manifest, and nothing is replaced.
+fn replace_section(workspace: &Path) -> anyhow::Result<Vec<(String, String)>> {
+    let path = workspace.join("Cargo.toml");
+    if !path.is_file() {
+        return Ok(Vec::new());
+    }
+    let manifest: toml::Table = toml::from_str(&std::fs::read_to_string(&path)?)
+        .map_err(|e| anyhow::anyhow!("failed to parse {}: {e}", path.display()))?;
+    let Some(replace) = manifest.get("replace").and_then(toml::Value::as_table) else {
+        return Ok(Vec::new());
+    };
+    let mut specs = Vec::new();
+    for key in replace.keys() {
+        let spec = key.rsplit_once('#').map_or(key.as_str(), |(_, spec)| spec);
+        let Some((name, version)) = spec.split_once(['@', ':']) else {
+            anyhow::bail!("{}: the [replace] key {key} names no version", path.display());
+        };
+        specs.push((name.to_string(), version.to_string()));
+    }
+    Ok(specs)
+}
+
+/// The git crates of `locked` replacing others through `[replace]`, and the
+/// registry crates they replace, which cargo metadata leaves out but cargo
+/// still reads from the vendored sources
+fn replaced_packages<'a>(
+    cargo_lock: &'a LockFile,
+    locked: &[&'a Package],
+    replace: &[(String, String)],
+) -> (Vec<&'a Package>, Vec<&'a Package>) {
+    let replaced = |package: &Package| {
+        replace.iter().any(|(name, version)| {
+            *name == package.name && (package.version == *version || package.version.starts_with(&format!("{version}.")))
+        })
+    };
+    let replacements = locked
+        .iter()
+        .copied()
+        .filter(|p| SourceKind::of(p) == SourceKind::Git && replaced(p))
+        .collect();
+    let originals = cargo_lock
+        .package
+        .iter()
+        .filter(|p| SourceKind::of(p) == SourceKind::Registry && replaced(p))
+        .filter(|p| !locked.iter().any(|&l| std::ptr::eq(l, *p)))
+        .collect();
+    (replacements, originals)
+}
+
+/// The git repositories, as `url#commit`, of the vendored crates whose
+/// checkout is missing, as before the first `cargo fetch`
+pub fn missing_git_checkouts(packages: &[&Package], manifests: &Manifests) -> Vec<String> {
+    let mut missing: Vec<String> = Vec::new();
+    for package in packages {
+        let Some(source) = package.source.as_deref().and_then(|s| s.strip_prefix("git+")) else {
+            continue;
+        };
+        if manifests.get(&manifest_key(package)).is_some_and(|manifest| Path::new(manifest).is_file()) {
+            continue;
+        }
+        let (url, commit) = source.split_once('#').unwrap_or((source, ""));
+        let repository = format!("{}#{commit}", url.split('?').next().unwrap());
+        if !missing.contains(&repository) {
+            missing.push(repository);
+        }
+    }
+    missing
+}
+
+/// The git repositories of the vendored crates of `cargo_lock` whose checkout is missing
+pub fn missing_locked_checkouts(cargo_lock: &str, cargo_metadata: &Metadata) -> anyhow::Result<Vec<String>> {
+    let cargo_lock: LockFile = toml::from_str(cargo_lock)?;
+    Ok(missing_git_checkouts(&vendorable_packages(&cargo_lock, cargo_metadata), &package_manifests(cargo_metadata)))
+}
+
+/// Fails naming the git repositories that aren't checked out yet, or with
+/// --fetch, runs `cargo fetch` to check them out
+pub fn ensure_git_checkouts(args: &Args, cargo_metadata: &Metadata, cargo_lock: &str) -> anyhow::Result<()> {
+    let missing = missing_locked_checkouts(cargo_lock, cargo_metadata)?;
+    if missing.is_empty() {
+        return Ok(());
+    }
+    if !args.fetch {
+        let error = anyhow::anyhow!("the checkouts of these git repositories are missing:\n  {}", missing.join("\n  "));
+        return Err(Annotated::new(error, FETCH_CHECKOUTS).into());
+    }
+    crate::diagnostics::note("fetch", format!("fetching {} missing git checkouts", missing.len()));
+    let manifest_path = cargo_metadata.workspace_root.join("Cargo.toml");
+    let status = fetch_command(&crate::toolchain::cargo(), args, manifest_path.as_std_path())
+        .envs(crate::net::client().env())
+        .status()?;
+    if !status.success() {
+        anyhow::bail!("`cargo fetch --locked` failed with {status}");
+    }
+    let missing = missing_locked_checkouts(cargo_lock, cargo_metadata)?;
+    if !missing.is_empty() {
+        anyhow::bail!("`cargo fetch` didn't check out these git repositories:\n  {}", missing.join("\n  "));
+    }
+    Ok(())
+}
+
+/// `cargo fetch` of the workspace at `manifest_path`, for the targets of
+/// --arches when --prune-foreign-targets leaves the others out
+fn fetch_command(cargo: &Path, args: &Args, manifest_path: &Path) -> std::process::Command {
+    let mut command = std::process::Command::new(cargo);
+    command.args(["fetch", "--locked", "--manifest-path"]).arg(manifest_path);
+    if args.prune_foreign_targets {
+        for arch in &args.arches {
+            command.args(["--target", arch.target()]);
+        }
+    }
+    command
+}
+
+/// The packages of --add-crate and --add-lockfile that Cargo.lock doesn't
+/// have already. An added crate's dependencies are only vendored when an
+/// --add-lockfile has them: nothing resolves them.
+pub fn added_packages(args: &Args, cargo_lock: &LockFile) -> anyhow::Result<Vec<Package>> {
+    if (!args.add_crate.is_empty() || !args.add_lockfile.is_empty()) && args.vendor_strategy != VendorStrategy::Directory {
+        anyhow::bail!("--add-crate and --add-lockfile need --vendor-strategy directory, the index of the other strategies needs what cargo metadata reports");
+    }
+    let mut added: Vec<Package> = Vec::new();
+    let mut add = |package: Package| {
+        let same = |p: &Package| p.name == package.name && p.version == package.version && p.source == package.source;
+        if !cargo_lock.package.iter().any(same) && !added.iter().any(same) {
+            added.push(package);
+        }
+    };
+    for (name, version, sha256) in &args.add_crate {
+        let checksum = match sha256 {
+            Some(sha256) => sha256.clone(),
+            None => index::checksum(&args.crates_io_index, name, version, index::default_cache().as_deref())
+                .map_err(|e| Annotated::new(e, "pass the checksum as NAME@VERSION=SHA256 to add the crate offline"))?,
+        };
+        add(Package {
+            name: name.clone(),
+            version: version.clone(),
+            source: Some(CRATES_IO_SOURCE.into()),
+            checksum: Some(checksum),
+            // Unlike a Cargo.lock missing it, the added crate's own lockfile has the checksum
+            checksum_from_index: false,
+            dependencies: None,
+        });
+    }
+    for path in &args.add_lockfile {
+        let contents = std::fs::read_to_string(path).with_context(|| format!("failed to read {}", path.display()))?;
+        let lockfile: LockFile = toml::from_str(&contents).with_context(|| format!("failed to parse {}", path.display()))?;
+        for package in lockfile.package.into_iter().filter(|p| p.source.is_some()) {
+            match SourceKind::of(&package) {
+                SourceKind::Registry if package.checksum.is_some() => add(package),
+                _ => diagnostics::warn(
+                    "added-lockfile",
+                    format!("{}: {} {} is not a registry crate with a checksum, leaving it out", path.display(), package.name, package.version),
+                ),
+            }
+        }
+    }
+    Ok(added)
+}
+
+/// Applies --override-commit to the git packages of the lockfile, pointing
+/// their manifest at a checkout of the commit: cargo's, next to the one of the
+/// locked commit, or with --fetch a clone in the cache. Without either the
+/// manifest of the locked commit is normalized instead, with a warning.
+pub fn override_commits(args: &Args, packages: &mut [Package], manifests: &mut Manifests) -> anyhow::Result<()> {
+    for (name, commit) in &args.override_commit {
+        let mut overridden = false;
+        for package in packages.iter_mut().filter(|package| &package.name == name) {
+            let Some((source, locked)) = package.source.as_deref().filter(|s| s.starts_with("git+")).and_then(|s| s.split_once('#')) else {
+                continue;
+            };
+            let (source, locked) = (source.to_string(), locked.to_string());
+            let message = format!(
+                "{name} {} is vendored at {commit} instead of {locked}: the sources no longer match Cargo.lock",
+                package.version
+            );
+            diagnostics::emit(
+                Diagnostic::warning("override-commit", message)
+                    .with_crate(&package.name, &package.version)
+                    .with_suggestion("update Cargo.lock to the commit once upstream has it"),
+            );
+            let key = manifest_key(package);
+            package.source = Some(format!("{source}#{commit}"));
+            overridden = true;
+            // The manifest moves along with the source
+            let Some(manifest) = manifests.remove(&key) else {
+                continue;
+            };
+            let (_, repo_dir) = crate::sources::git_checkout_roots(&manifest)?;
+            let relative = Path::new(manifest.as_str()).canonicalize()?.strip_prefix(&repo_dir)?.to_path_buf();
+            let sibling = repo_dir.with_file_name(commit.abbrev(crate::COMMIT_LEN));
+            let checkout = match (sibling.join(&relative).is_file(), args.fetch) {
+                (true, _) => sibling,
+                (false, true) => {
+                    let (url, _) = crate::sources::git_reference(package.source.as_deref().unwrap()).context("invalid git source")?;
+                    let cache = crate::checkout::default_cache().context("no cache directory to clone into")?;
+                    crate::checkout::checkout(&url, crate::checkout::GitRef::Rev(commit.as_str()), &cache)?
+                }
+                (false, false) => {
+                    let message = format!("{name}: no checkout of {commit}, normalizing the manifest of {locked}");
+                    diagnostics::emit(Diagnostic::warning("override-commit", message).with_suggestion("pass --fetch to clone it"));
+                    manifests.insert(manifest_key(package), manifest);
+                    continue;
+                }
+            };
+            manifests.insert(manifest_key(package), checkout.join(relative).to_string_lossy().into_owned());
+        }
+        if !overridden {
+            anyhow::bail!("--override-commit {name}: Cargo.lock has no git package {name}");
+        }
+    }
+    Ok(())
+}
+
+/// Path dependencies that live outside of the workspace, which the build
+/// can't reach unless they're bundled
+pub fn external_path_dependencies(cargo_metadata: &Metadata) -> anyhow::Result<Vec<PathDependency>> {
+    let canonical_workspace = cargo_metadata.workspace_root.as_std_path().canonicalize()?;
+    let mut deps = Vec::new();
+    for package in &cargo_metadata.packages {
+        let manifest_dir = package.manifest_path.parent().unwrap().as_std_path();
+        let canonical_dir = manifest_dir.canonicalize().unwrap_or_else(|_| manifest_dir.to_path_buf());
+        if package.source.is_none() && !canonical_dir.starts_with(&canonical_workspace) {
+            deps.push(PathDependency {
+                name: package.name.clone(),
+                version: package.version.to_string(),
+                dir: manifest_dir.to_path_buf(),
+            });
+        }
+    }
+    Ok(deps)
+}
+
+/// Adds crates.io packages to the resolve graph, as dependencies of the first
+/// workspace member, so it agrees with a made-up lockfile
+#[cfg(test)]
+pub fn with_registry_deps(metadata: Metadata, deps: &[(&str, &str)]) -> Metadata {
+    let source = "registry+https://github.com/rust-lang/crates.io-index";
+    let deps: Vec<_> = deps.iter().map(|(name, version)| (*name, *version, source)).collect();
+    with_deps(metadata, &deps)
+}
+
+/// Like [`with_registry_deps`], with `(name, version, source)` packages
+#[cfg(test)]
+pub fn with_deps(metadata: Metadata, deps: &[(&str, &str, &str)]) -> Metadata {
+    let mut metadata = serde_json::to_value(metadata).unwrap();
+    let member = metadata["workspace_members"][0].clone();
+    let template = metadata["packages"].as_array().unwrap().iter().find(|p| p["id"] == member).unwrap().clone();
+    for (name, version, source) in deps {
+        let id = format!("{source}#{name}@{version}");
+        let mut package = template.clone();
+        package["name"] = (*name).into();
+        package["version"] = (*version).into();
+        package["id"] = id.clone().into();
+        package["source"] = (*source).into();
+        metadata["packages"].as_array_mut().unwrap().push(package);
+        let nodes = metadata["resolve"]["nodes"].as_array_mut().unwrap();
+        nodes.push(serde_json::json!({"id": id, "dependencies": [], "deps": [], "features": []}));
+        let node = nodes.iter_mut().find(|n| n["id"] == member).unwrap();
+        node["dependencies"].as_array_mut().unwrap().push(id.clone().into());
+        node["deps"].as_array_mut().unwrap().push(serde_json::json!({
+            "name": name.replace('-', "_"),
+            "pkg": id,
+            "dep_kinds": [{"kind": null, "target": null}],
+        }));
+    }
+    serde_json::from_value(metadata).unwrap()
+}
+
+#[cfg(test)]
+pub fn fixture_workspace(root: &Path) -> (Metadata, String) {
+    let files = [
+        ("app/Cargo.toml", "[package]\nname = \"app\"\nversion = \"0.1.0\"\n"),
+        ("app/src/main.rs", "fn main() {}\n"),
+    ];
+    crate::sources::write_fixture(root, &files);
+    let metadata = cargo_metadata::MetadataCommand::new()
+        .manifest_path(root.join("app/Cargo.toml"))
+        .exec()
+        .unwrap();
+    let registry_deps = [("anstream", "0.6.15"), ("url", "2.5.0")];
+    let metadata = with_registry_deps(metadata, &registry_deps);
+    let mut cargo_lock = "version = 3\n\n[[package]]\nname = \"app\"\nversion = \"0.1.0\"\n".to_string();
+    for (name, version) in registry_deps {
+        cargo_lock += &format!(
+            "\n[[package]]\nname = \"{name}\"\nversion = \"{version}\"\n\
+             source = \"registry+https://github.com/rust-lang/crates.io-index\"\n\
+             checksum = \"{}\"\n",
+            crate::sources::FIXTURE_CHECKSUM
+        );
+    }
+    (metadata, cargo_lock)
+}
+
+#[test]
+fn no_config() {
+    use clap::Parser;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let (metadata, cargo_lock) = fixture_workspace(tmp.path());
+    let output = tmp.path().join("app/cargo-sources.json");
+
+    let args = Args::parse_from(["flatpak"]);
+    let default = generate(&args, &metadata, &cargo_lock, "hash".into(), &output).unwrap();
+    let Some(Source::Inline(inline)) = default.sources().last() else { panic!("expected the inline config last") };
+    assert_eq!((inline.dest.as_str(), inline.dest_filename.as_str()), ("cargo", "config"));
+
+    let args = Args::parse_from(["flatpak", "--no-config"]);
+    let generated = generate(&args, &metadata, &cargo_lock, "hash".into(), &output).unwrap();
+    assert_eq!(generated.sources().len(), default.sources().len() - 1);
+    assert!(!generated.sources().iter().any(|s| matches!(s, Source::Inline(i) if i.dest_filename == "config")));
+    assert_eq!(generated.config.to_toml().unwrap(), inline.contents);
+}
+
+#[test]
+fn lockfile_from_stdin() {
+    use clap::Parser;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let (metadata, cargo_lock) = fixture_workspace(tmp.path());
+    let workspace = tmp.path().join("app");
+    std::fs::write(workspace.join("Cargo.lock"), &cargo_lock).unwrap();
+    let output = workspace.join("cargo-sources.json");
+    let json = |sources: SourceSet| serde_json::to_string_pretty(&sources.sources()).unwrap();
+
+    let args = Args::parse_from(["flatpak", "--include-lockfile"]);
+    let from_file = read_lockfile(&args, &workspace, std::io::empty()).unwrap();
+    let expected = json(generate(&args, &metadata, &from_file, "hash".into(), &output).unwrap());
+
+    // No project at all, only the lockfile piped in
+    let elsewhere = tmp.path().join("elsewhere");
+    std::fs::create_dir(&elsewhere).unwrap();
+    let args = Args::parse_from(["flatpak", "--include-lockfile", "--lockfile", "-", "--lockfile-only"]);
+    let from_stdin = read_lockfile(&args, &elsewhere, cargo_lock.as_bytes()).unwrap();
+    let metadata = lockfile_only_metadata(&elsewhere).unwrap();
+    let generated = generate(&args, &metadata, &from_stdin, "hash".into(), &elsewhere.join("cargo-sources.json")).unwrap();
+    assert_eq!(json(generated), expected);
+
+    let args = Args::parse_from(["flatpak", "--include-lockfile", "--no-inline", "--lockfile", "-", "--lockfile-only"]);
+    let Err(err) = generate(&args, &metadata, &from_stdin, "hash".into(), &output) else { panic!("expected an error") };
+    assert_eq!(err.to_string(), "--no-inline references the lockfile by its path, and one read from stdin has none");
+
+    let git = "\n[[package]]\nname = \"gtk4\"\nversion = \"0.9.0\"\n\
+               source = \"git+https://github.com/gtk-rs/gtk4-rs?branch=main#0123456789abcdef0123456789abcdef01234567\"\n";
+    let args = Args::parse_from(["flatpak", "--lockfile", "-", "--lockfile-only"]);
+    let Err(err) = generate(&args, &metadata, &(cargo_lock + git), "hash".into(), &output) else { panic!("expected an error") };
+    assert_eq!(
+        err.to_string(),
+        "--lockfile-only can't vendor the git dependencies:\n  gtk4 0.9.0\n\
+         A vendored git crate gets its Cargo.toml rewritten from the checkout, and the lockfile only names the \
+         commit: the checkout is found by cargo metadata on the project"
+    );
+    assert!(Annotated::find(&err).is_some());
+}
+
+#[test]
+fn stale_lockfile() {
+    let tmp = tempfile::tempdir().unwrap();
+    let manifest = |lib_req: &str| format!("[package]\nname = \"app\"\nversion = \"0.1.0\"\n\n[dependencies]\nlib = {{ path = \"lib\", version = \"{lib_req}\" }}\n");
+    let lib = |version: &str| format!("[package]\nname = \"lib\"\nversion = \"{version}\"\n");
+    crate::sources::write_fixture(
+        tmp.path(),
+        &[("Cargo.toml", manifest("0.1").as_str()), ("src/main.rs", "fn main() {}\n"), ("lib/Cargo.toml", &lib("0.1.0")), ("lib/src/lib.rs", "")],
+    );
+    let mut command = MetadataCommand::new();
+    command.manifest_path(tmp.path().join("Cargo.toml"));
+    let cargo = std::env::var_os("CARGO").unwrap_or_else(|| "cargo".into());
+    let status = std::process::Command::new(cargo).args(["generate-lockfile", "--offline"]).current_dir(tmp.path()).status();
+    assert!(status.unwrap().success());
+    let cargo_lock = std::fs::read_to_string(tmp.path().join("Cargo.lock")).unwrap();
+    assert!(locked_metadata(&command).unwrap().1.is_none());
+
+    // The dependency bumped without committing the lockfile
+    std::fs::write(tmp.path().join("Cargo.toml"), manifest("0.2")).unwrap();
+    std::fs::write(tmp.path().join("lib/Cargo.toml"), lib("0.2.0")).unwrap();
+    let (metadata, stale) = locked_metadata(&command).unwrap();
+    assert_eq!(metadata.workspace_packages().len(), 1);
+    let stale = stale.unwrap();
+    assert_eq!(
+        stale_lockfile_message(&stale.mismatches),
+        "Cargo.lock is out of date with the manifests, the sources are generated from what cargo resolves instead\n  \
+         app depends on lib ^0.2, Cargo.lock has 0.1.0"
+    );
+    // The sources follow the metadata, the lockfile on disk is left alone
+    let resolved: LockFile = toml::from_str(&stale.cargo_lock).unwrap();
+    assert!(resolved.package.iter().any(|p| p.name == "lib" && p.version == "0.2.0"));
+    assert!(lockfile_mismatches(&metadata, &resolved).is_empty());
+    assert_eq!(std::fs::read_to_string(tmp.path().join("Cargo.lock")).unwrap(), cargo_lock);
+}
+
+#[test]
+fn split_sources() {
+    use clap::Parser;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let (metadata, cargo_lock) = fixture_workspace(tmp.path());
+    let output = tmp.path().join("app/cargo-sources.json");
+    let args = Args::parse_from(["flatpak"]);
+    let generated = generate(&args, &metadata, &cargo_lock, "hash".into(), &output).unwrap();
+    assert_eq!(generated.sources().len(), 5);
+
+    let chunks = generated.split(3).unwrap();
+    let lengths: Vec<_> = chunks.iter().map(Vec::len).collect();
+    assert_eq!(lengths, [2, 3]);
+    for (i, chunk) in chunks.iter().enumerate() {
+        std::fs::write(split_path(&output, i + 1), serde_json::to_string(chunk).unwrap()).unwrap();
+    }
+    assert!(split_path(&output, 2).ends_with("app/cargo-sources-2.json"));
+    assert_eq!(read_sources(&output, None).unwrap().entries(), generated.entries());
+
+    // Of an older single file and split files, the newest layout is read
+    let age = |path: &Path, secs: u64| {
+        let time = std::time::SystemTime::now() - std::time::Duration::from_secs(secs);
+        std::fs::File::options().write(true).open(path).unwrap().set_modified(time).unwrap();
+    };
+    std::fs::write(&output, "[]").unwrap();
+    age(&output, 60);
+    assert_eq!(read_sources(&output, None).unwrap().entries(), generated.entries());
+    age(&split_path(&output, 1), 120);
+    assert!(read_sources(&output, None).unwrap().entries().is_empty());
+    std::fs::remove_file(&output).unwrap();
+
+    // A split into fewer files removes the rest
+    std::fs::write(split_path(&output, 3), "[]").unwrap();
+    remove_split_files(&output, 2).unwrap();
+    assert!(split_path(&output, 1).exists());
+    assert!(!split_path(&output, 2).exists() && !split_path(&output, 3).exists());
+    let Some(Source::Inline(config)) = chunks.last().unwrap().last() else { panic!("expected the config last") };
+    assert_eq!(config.dest_filename, "config");
+
+    let err = generated.split(1).unwrap_err().to_string();
+    assert_eq!(err, "anstream-0.6.15 has 2 sources, more than --split 1 allows in a file");
+}
+
+#[test]
+fn resolved_packages_only() {
+    use clap::Parser;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let (metadata, mut cargo_lock) = fixture_workspace(tmp.path());
+    let output = tmp.path().join("app/cargo-sources.json");
+    let args = Args::parse_from(["flatpak"]);
+    let json = |generated: SourceSet| generated.to_json().unwrap();
+
+    // A plain workspace vendors its whole lockfile either way
+    let mut unresolved = metadata.clone();
+    unresolved.resolve = None;
+    let resolved = json(generate(&args, &metadata, &cargo_lock, "hash".into(), &output).unwrap());
+    assert_eq!(resolved, json(generate(&args, &unresolved, &cargo_lock, "hash".into(), &output).unwrap()));
+    assert!(resolved.contains("cargo/vendor/anstream-0.6.15") && resolved.contains("cargo/vendor/url-2.5.0"));
+
+    // A package nothing depends on is left out, unless there's no resolve
+    cargo_lock += &format!(
+        "\n[[package]]\nname = \"stale\"\nversion = \"1.0.0\"\n\
+         source = \"registry+https://github.com/rust-lang/crates.io-index\"\nchecksum = \"{}\"\n",
+        crate::sources::FIXTURE_CHECKSUM
+    );
+    let lock: LockFile = toml::from_str(&cargo_lock).unwrap();
+    let names = |metadata: &Metadata| -> Vec<String> {
+        vendored_packages(&lock, metadata).unwrap().iter().map(|p| p.name.clone()).collect()
+    };
+    assert_eq!(names(&metadata), ["anstream", "url"]);
+    assert_eq!(names(&unresolved), ["anstream", "url", "stale"]);
+}
+
+#[test]
+fn forbid_vendored_packages() {
+    use clap::Parser;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let (metadata, mut cargo_lock) = fixture_workspace(tmp.path());
+    let output = tmp.path().join("cargo-sources.json");
+    // A locked package outside the dependency graph isn't vendored
+    cargo_lock += &format!(
+        "\n[[package]]\nname = \"openssl-sys\"\nversion = \"0.9.0\"\nsource = \"{CRATES_IO_SOURCE}\"\nchecksum = \"{}\"\n",
+        crate::sources::FIXTURE_CHECKSUM
+    );
+    let generate_with = |flags: &[&str]| {
+        let args = Args::parse_from([&["flatpak"], flags].concat());
+        generate(&args, &metadata, &cargo_lock, "hash".into(), &output)
+    };
+    assert!(generate_with(&["--forbid", "openssl-sys"]).is_ok());
+
+    let added = format!("log@0.4.22={}", "c".repeat(64));
+    let Err(err) = generate_with(&["--add-crate", &added, "--forbid", "log"]) else { panic!("expected an error") };
+    assert!(err.to_string().starts_with("1 forbidden packages are vendored:\nlog 0.4.22: forbidden by `log`"), "{err}");
+}
+
+#[test]
+fn duplicate_lockfile_entries() {
+    let tmp = tempfile::tempdir().unwrap();
+    let (mut metadata, _) = fixture_workspace(tmp.path());
+    metadata.resolve = None;
+    let entry = |name: &str, checksum: &str| {
+        format!(
+            "\n[[package]]\nname = \"{name}\"\nversion = \"1.0.0\"\n\
+             source = \"registry+https://github.com/rust-lang/crates.io-index\"\nchecksum = \"{checksum}\"\n"
+        )
+    };
+    let (a, b) = ("a".repeat(64), "b".repeat(64));
+
+    let lock = format!("version = 3\n{}{}{}", entry("foo", &a), entry("bar", &a), entry("foo", &a));
+    let lock: LockFile = toml::from_str(&lock).unwrap();
+    let names: Vec<_> = vendored_packages(&lock, &metadata).unwrap().iter().map(|p| p.name.as_str()).collect();
+    assert_eq!(names, ["foo", "bar"]);
+
+    // foo 1.0.0 from a registry and from git is legal
+    let git = "\n[[package]]\nname = \"foo\"\nversion = \"1.0.0\"\n\
+               source = \"git+https://github.com/example/foo#0123456789abcdef0123456789abcdef01234567\"\n";
+    let lock: LockFile = toml::from_str(&format!("version = 3\n{}{git}", entry("foo", &a))).unwrap();
+    let sources: Vec<_> = vendored_packages(&lock, &metadata).unwrap().iter().map(|p| p.source.clone().unwrap()).collect();
+    assert_eq!(sources.len(), 2, "{sources:?}");
+    assert_ne!(sources[0], sources[1]);
+
+    let lock: LockFile = toml::from_str(&format!("version = 3\n{}{}", entry("foo", &a), entry("foo", &b))).unwrap();
+    let err = vendored_packages(&lock, &metadata).unwrap_err().to_string();
+    assert_eq!(
+        err,
+        format!(
+            "Cargo.lock lists foo 1.0.0 twice, differently:\n\
+             \x20 source registry+https://github.com/rust-lang/crates.io-index, checksum {a}\n\
+             \x20 source registry+https://github.com/rust-lang/crates.io-index, checksum {b}\n\
+             resolve the merge conflict, e.g. with `cargo update -p foo@1.0.0`"
+        )
+    );
+}
+
+#[test]
+fn git_and_registry_package_of_one_name() {
+    use clap::Parser;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let (metadata, mut cargo_lock) = fixture_workspace(tmp.path());
+    let commit = "0123456789abcdef0123456789abcdef01234567";
+    let source = format!("git+https://github.com/servo/rust-url?branch=main#{commit}");
+    let checkout = tmp.path().join("cargo-home/git/checkouts/rust-url-1d1a5b9f2c3e4f5a").join(&commit[..7]);
+    let files = [
+        (".cargo-ok", ""),
+        ("Cargo.toml", "[workspace]\nmembers = [\"url\"]\n"),
+        ("url/Cargo.toml", "[package]\nname = \"url\"\nversion = \"2.5.0\"\nedition = \"2021\"\n"),
+        ("url/src/lib.rs", ""),
+    ];
+    crate::sources::write_fixture(&checkout, &files);
+    let mut metadata = with_deps(metadata, &[("url", "2.5.0", &source)]);
+    for package in metadata.packages.iter_mut().filter(|p| p.source.as_ref().is_some_and(|s| s.repr == source)) {
+        package.manifest_path = checkout.join("url/Cargo.toml").try_into().unwrap();
+    }
+    // The registry url last, where a lookup by name would find it for both
+    metadata.packages.sort_by_key(|p| p.source.as_ref().is_some_and(|s| s.repr.starts_with("registry+")));
+    cargo_lock += &format!("\n[[package]]\nname = \"url\"\nversion = \"2.5.0\"\nsource = \"{source}\"\n");
+
+    let args = Args::parse_from(["flatpak"]);
+    let output = tmp.path().join("app/cargo-sources.json");
+    let generated = generate(&args, &metadata, &cargo_lock, "hash".into(), &output).unwrap();
+    let sources = generated.sources();
+    let git = sources.iter().find_map(|s| match s {
+        Source::Git(git) => Some(git),
+        _ => None,
+    });
+    assert_eq!(git.unwrap().commit.as_str(), commit);
+    let vendored = sources.iter().any(|s| matches!(s, Source::Inline(i) if i.dest_filename == "Cargo.toml" && i.contents.contains("edition = \"2021\"")));
+    assert!(vendored, "{}", serde_json::to_string_pretty(&sources).unwrap());
+}
+
+#[test]
+fn output_writes() {
+    let tmp = tempfile::tempdir().unwrap();
+    let path = tmp.path().join("flatpak/sources/cargo-sources.json");
+
+    write_output(&path, b"first", false).unwrap();
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "first");
+    write_output(&path, b"second", false).unwrap();
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "second");
+
+    let err = write_output(&path, b"third", true).unwrap_err().to_string();
+    assert_eq!(err, format!("{} already exists and --no-clobber is set", path.display()));
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "second");
+    let new = tmp.path().join("flatpak/new.json");
+    write_output(&new, b"new", true).unwrap();
+    assert_eq!(std::fs::read_to_string(&new).unwrap(), "new");
+
+    // No temporary files are left behind
+    let list = |dir: &Path| std::fs::read_dir(dir).unwrap().map(|e| e.unwrap().file_name()).collect::<Vec<_>>();
+    let mut files = list(path.parent().unwrap());
+    files.extend(list(new.parent().unwrap()));
+    files.sort();
+    assert_eq!(files, ["cargo-sources.json", "new.json", "sources"]);
+}
+
+#[test]
+fn missing_checkouts() {
+    let cargo_home = tempfile::tempdir().unwrap();
+    let checkout = cargo_home.path().join("git/checkouts/gtk4-rs-1d1a5b9f2c3e4f5a/0123456");
+    let package = |name: &str, source: &str| Package {
+        name: name.into(),
+        version: "0.9.0".into(),
+        source: Some(source.into()),
+        checksum: None,
+        checksum_from_index: false,
+        dependencies: None,
+    };
+    let git = "git+https://github.com/gtk-rs/gtk4-rs?branch=main#0123456789abcdef0123456789abcdef01234567";
+    let registry = "registry+https://github.com/rust-lang/crates.io-index";
+    let (gtk4, gdk4, url) = (package("gtk4", git), package("gdk4", git), package("url", registry));
+    let packages = [&gtk4, &gdk4, &url];
+    let manifests: Manifests = [&gtk4, &gdk4]
+        .iter()
+        .map(|package| (manifest_key(package), checkout.join(&package.name).join("Cargo.toml").to_str().unwrap().to_string()))
+        .collect();
+
+    assert_eq!(
+        missing_git_checkouts(&packages, &manifests),
+        ["https://github.com/gtk-rs/gtk4-rs#0123456789abcdef0123456789abcdef01234567"]
+    );
+    for name in ["gtk4", "gdk4"] {
+        std::fs::create_dir_all(checkout.join(name)).unwrap();
+        std::fs::write(checkout.join(name).join("Cargo.toml"), "").unwrap();
+    }
+    assert!(missing_git_checkouts(&packages, &manifests).is_empty());
+    assert_eq!(missing_git_checkouts(&packages, &HashMap::new()).len(), 1);
+}
+
+#[test]
+fn fetch_checkouts() {
+    use clap::Parser;
+
+    let args = |extra: &[&str]| Args::parse_from(["flatpak", "--fetch"].iter().chain(extra));
+    let command_args = |args: &Args| {
+        let command = fetch_command(Path::new("cargo"), args, Path::new("/app/Cargo.toml"));
+        command.get_args().map(|arg| arg.to_str().unwrap().to_string()).collect::<Vec<_>>()
+    };
+
+    assert_eq!(command_args(&args(&[])), ["fetch", "--locked", "--manifest-path", "/app/Cargo.toml"]);
+    assert_eq!(
+        command_args(&args(&["--prune-foreign-targets", "--arches", "x86_64,aarch64"])),
+        [
+            "fetch",
+            "--locked",
+            "--manifest-path",
+            "/app/Cargo.toml",
+            "--target",
+            "x86_64-unknown-linux-gnu",
+            "--target",
+            "aarch64-unknown-linux-gnu",
+        ]
+    );
+}
+
+#[test]
+fn overridden_commits() {
+    use clap::Parser;
+
+    let cargo_home = tempfile::tempdir().unwrap();
+    let (locked, hotfix) = ("0123456789abcdef0123456789abcdef01234567", "c0ffeec0ffeec0ffeec0ffeec0ffeec0ffeec0ff");
+    let checkouts = cargo_home.path().join("git/checkouts/gtk4-rs-1d1a5b9f2c3e4f5a");
+    for (commit, edition) in [(locked, "2018"), (hotfix, "2021")] {
+        let checkout = checkouts.join(&commit[..7]);
+        std::fs::create_dir_all(checkout.join("gtk4/src")).unwrap();
+        std::fs::write(checkout.join(".cargo-ok"), "").unwrap();
+        std::fs::write(checkout.join("Cargo.toml"), "[workspace]\nmembers = [\"gtk4\"]\n").unwrap();
+        let manifest = format!("[package]\nname = \"gtk4\"\nversion = \"0.9.0\"\nedition = \"{edition}\"\n");
+        std::fs::write(checkout.join("gtk4/Cargo.toml"), manifest).unwrap();
+        std::fs::write(checkout.join("gtk4/src/lib.rs"), "").unwrap();
+    }
+    let mut packages = vec![Package {
+        name: "gtk4".into(),
+        version: "0.9.0".into(),
+        source: Some(format!("git+https://github.com/gtk-rs/gtk4-rs?branch=main#{locked}")),
+        checksum: None,
+        checksum_from_index: false,
+        dependencies: None,
+    }];
+    let manifest = checkouts.join(&locked[..7]).join("gtk4/Cargo.toml").to_str().unwrap().to_string();
+    let mut manifests = Manifests::from([(manifest_key(&packages[0]), manifest)]);
+    let args = Args::parse_from(["flatpak", "--override-commit", &format!("gtk4={hotfix}")]);
+    override_commits(&args, &mut packages, &mut manifests).unwrap();
+    let manifest = &manifests[&manifest_key(&packages[0])];
+    assert!(manifest.contains(&format!("/{}/gtk4/", &hotfix[..7])), "{manifest}");
+
+    let mut sources = SourceSet::new(&args.vendor_dir());
+    sources.push_package(&packages[0], Some(manifest), &args).unwrap();
+    let json = serde_json::to_string(&sources.sources()).unwrap();
+    assert!(!json.contains(&locked[..7]), "{json}");
+    let listed = sources.sources();
+    let [Source::Git(git), Source::Shell(shell), Source::Inline(cargo_toml), _] = listed.as_slice() else {
+        panic!("expected the clone, the copy and the vendored files");
+    };
+    assert_eq!(git.commit.as_str(), hotfix);
+    assert!(git.dest.ends_with(&format!("gtk-rs-gtk4-rs-8cd6d500-{}", &hotfix[..7])), "{}", git.dest);
+    assert!(shell.commands[1].contains(&git.dest));
+    assert!(cargo_toml.contents.contains("edition = \"2021\""), "{}", cargo_toml.contents);
+    // The dependency still names the branch, so the config replaces the same source
+    let config = sources.config.to_toml().unwrap();
+    assert!(config.contains("[source.\"https://github.com/gtk-rs/gtk4-rs?branch=main\"]") && !config.contains(locked), "{config}");
+
+    // Without a checkout of the commit the locked manifest is normalized
+    std::fs::remove_dir_all(checkouts.join(&hotfix[..7])).unwrap();
+    let hotfix2 = "fedcba9876543210fedcba9876543210fedcba98";
+    let args = Args::parse_from(["flatpak", "--override-commit", &format!("gtk4={hotfix2}")]);
+    let manifest = checkouts.join(&locked[..7]).join("gtk4/Cargo.toml").to_str().unwrap().to_string();
+    let mut manifests = Manifests::from([(manifest_key(&packages[0]), manifest)]);
+    override_commits(&args, &mut packages, &mut manifests).unwrap();
+    assert!(packages[0].source.as_deref().unwrap().ends_with(hotfix2));
+    assert!(manifests[&manifest_key(&packages[0])].contains(&locked[..7]));
+
+    let args = Args::parse_from(["flatpak", "--override-commit", &format!("gdk4={hotfix}")]);
+    let err = override_commits(&args, &mut packages, &mut manifests).unwrap_err();
+    assert_eq!(err.to_string(), "--override-commit gdk4: Cargo.lock has no git package gdk4");
+    assert!(Args::try_parse_from(["flatpak", "--override-commit", "gtk4=main"]).is_err());
+}
+
+#[test]
+fn added_crates() {
+    use clap::Parser;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let (metadata, cargo_lock) = fixture_workspace(tmp.path());
+    let output = tmp.path().join("cargo-sources.json");
+    let sha256 = "c".repeat(64);
+    let log = format!("{{\"name\":\"log\",\"vers\":\"0.4.22\",\"deps\":[],\"cksum\":\"{sha256}\",\"features\":{{}},\"yanked\":false}}\n");
+    let (index, _) = index::serve(HashMap::from([("/3/l/log", log.into())]));
+    let archives = |args: &[&str]| -> Vec<(String, String)> {
+        let args = Args::parse_from([&["flatpak"], args].concat());
+        let generated = generate(&args, &metadata, &cargo_lock, "hash".into(), &output).unwrap();
+        generated
+            .sources()
+            .into_iter()
+            .filter_map(|source| match source {
+                Source::Archive(archive) => Some((archive.dest.clone(), archive.sha256.to_string())),
+                Source::Inline(inline) if inline.dest == "cargo/vendor/log-0.4.22" => Some((inline.dest_filename.clone(), inline.contents.clone())),
+                _ => None,
+            })
+            .collect()
+    };
+    let log = ("cargo/vendor/log-0.4.22".to_string(), sha256.clone());
+    // cargo checks the crate against the checksum of the lockfile it's installed with
+    let log_checksum = (".cargo-checksum.json".to_string(), format!("{{\"package\": \"{sha256}\", \"files\": {{}}}}"));
+
+    let found = archives(&["--add-crate", "log@0.4.22", "--crates-io-index", &index]);
+    assert_eq!(found.len(), 4);
+    assert_eq!(found[2..], [log.clone(), log_checksum.clone()]);
+    // Offline, with the checksum given
+    let found = archives(&["--add-crate", &format!("log@0.4.22={sha256}"), "--crates-io-index", "http://127.0.0.1:1/"]);
+    assert_eq!(found[2..], [log, log_checksum]);
+
+    // Crates Cargo.lock already has are vendored once
+    let lockfile = tmp.path().join("tool.lock");
+    let checksum = "d".repeat(64);
+    let tool = format!(
+        "version = 3\n\n[[package]]\nname = \"tool\"\nversion = \"1.0.0\"\n{}",
+        ["url@2.5.0", "idna@0.5.0"]
+            .map(|spec| spec.split_once('@').unwrap())
+            .map(|(name, version)| format!("\n[[package]]\nname = \"{name}\"\nversion = \"{version}\"\nsource = \"{CRATES_IO_SOURCE}\"\nchecksum = \"{checksum}\"\n"))
+            .concat()
+    );
+    std::fs::write(&lockfile, tool).unwrap();
+    let found = archives(&["--add-lockfile", lockfile.to_str().unwrap(), "--add-crate", &format!("url@2.5.0={sha256}")]);
+    let dests: Vec<_> = found.iter().map(|(dest, _)| dest.as_str()).collect();
+    assert_eq!(dests, ["cargo/vendor/anstream-0.6.15", "cargo/vendor/url-2.5.0", "cargo/vendor/idna-0.5.0"]);
+
+    let args = Args::parse_from(["flatpak", "--add-crate", "log@0.4.22", "--crates-io-index", "http://127.0.0.1:1/"]);
+    let Err(err) = generate(&args, &metadata, &cargo_lock, "hash".into(), &output) else { panic!("expected an error") };
+    assert_eq!(Annotated::find(&err).unwrap().suggestion, "pass the checksum as NAME@VERSION=SHA256 to add the crate offline");
+    let args = Args::parse_from(["flatpak", "--add-crate", "log@0.4.22", "--vendor-strategy", "local-registry"]);
+    assert!(generate(&args, &metadata, &cargo_lock, "hash".into(), &output).is_err());
+}
+
+#[test]
+fn every_package_error_reported() {
+    use clap::Parser;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let (metadata, mut cargo_lock) = fixture_workspace(tmp.path());
+    let git = "git+https://github.com/example/ghost?rev=0123456#0123456789abcdef0123456789abcdef01234567";
+    let registry = "registry+https://github.com/rust-lang/crates.io-index";
+    // The checkout of ghost is the app, which has no package ghost, and unsummed has no checksum
+    let metadata = with_deps(metadata, &[("ghost", "0.1.0", git), ("unsummed", "1.0.0", registry)]);
+    for (name, version, source) in [("ghost", "0.1.0", git), ("unsummed", "1.0.0", registry)] {
+        cargo_lock += &format!("\n[[package]]\nname = \"{name}\"\nversion = \"{version}\"\nsource = \"{source}\"\n");
+    }
+    let args = Args::parse_from(["cargo-flatpak"]);
+    let output = tmp.path().join("app/cargo-sources.json");
+    let Err(err) = generate(&args, &metadata, &cargo_lock, String::new(), &output) else {
+        panic!("expected an error");
+    };
+    let errors = &err.downcast_ref::<PackageErrors>().unwrap().0;
+    let packages: Vec<_> = errors.iter().map(|e| (e.name.as_str(), e.version.as_str())).collect();
+    assert_eq!(packages, [("ghost", "0.1.0"), ("unsummed", "1.0.0")]);
+    let err = err.to_string();
+    assert!(err.starts_with("failed to generate the sources of 2 packages:\n  ghost 0.1.0: "), "{err}");
+    assert!(err.contains("has no package ghost"), "{err}");
+    assert!(err.ends_with("\n  unsummed 1.0.0 has no checksum in Cargo.lock"), "{err}");
+    assert!(!output.exists());
+}
+
+#[test]
+fn keep_going_leaves_failed_packages_out() {
+    use crate::diagnostics::ErrorFormat;
+    use clap::Parser;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let (metadata, mut cargo_lock) = fixture_workspace(tmp.path());
+    // The checkout of ghost is the app, which has no package ghost
+    let git = "git+https://github.com/example/ghost?rev=0123456#0123456789abcdef0123456789abcdef01234567";
+    let metadata = with_deps(metadata, &[("ghost", "0.1.0", git)]);
+    cargo_lock += &format!("\n[[package]]\nname = \"ghost\"\nversion = \"0.1.0\"\nsource = \"{git}\"\n");
+    let output = tmp.path().join("app/cargo-sources.json");
+
+    let args = Args::parse_from(["cargo-flatpak"]);
+    assert!(generate(&args, &metadata, &cargo_lock, String::new(), &output).is_err());
+    let args = Args::parse_from(["cargo-flatpak", "--keep-going"]);
+    let (sources, failed) = generate_keep_going(&args, &metadata, &cargo_lock, "hash".into(), &output).unwrap();
+    // A partial output is never up to date
+    assert_eq!(crate::sources::find_lockfile_hash(&sources), None);
+    assert_ne!(args.generation_options(), Args::parse_from(["cargo-flatpak"]).generation_options());
+    let owners: Vec<_> = sources.entries().iter().map(|e| e.owner.as_str()).collect();
+    assert_eq!(owners, ["anstream-0.6.15", "anstream-0.6.15", "url-2.5.0", "url-2.5.0", CONFIG_OWNER]);
+    assert!(sources.config.to_toml().unwrap().contains("[source.crates-io]"));
+
+    let incomplete = Incomplete { output: "cargo-sources.json".into(), errors: PackageErrors(failed) };
+    let diagnostics = Diagnostic::from_error(&incomplete.into());
+    let rendered: Vec<_> = diagnostics.iter().map(|d| d.render(ErrorFormat::Human)).collect();
+    assert_eq!(rendered.len(), 2);
+    assert!(rendered[0].starts_with("error: ghost 0.1.0: "), "{}", rendered[0]);
+    assert_eq!(
+        rendered[1],
+        "error: wrote cargo-sources.json without the sources of 1 packages:\n  ghost 0.1.0\n  \
+         help: add their sources by hand, or fix them and generate again\n"
+    );
+}
+
+#[test]
+fn json_diagnostics() {
+    use crate::diagnostics::{capture, ErrorFormat};
+    use clap::Parser;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let (metadata, mut cargo_lock) = fixture_workspace(tmp.path());
+    let registry = "registry+https://github.com/rust-lang/crates.io-index";
+    let metadata = with_deps(metadata, &[("unsummed", "1.0.0", registry)]);
+    let duplicate = cargo_lock.split("\n[[package]]").find(|p| p.contains("\"url\"")).unwrap().to_string();
+    cargo_lock += &format!("\n[[package]]{duplicate}");
+    cargo_lock += &format!("\n[[package]]\nname = \"unsummed\"\nversion = \"1.0.0\"\nsource = \"{registry}\"\n");
+    let args = Args::parse_from(["cargo-flatpak", "--error-format", "json"]);
+    let output = tmp.path().join("app/cargo-sources.json");
+    let ((), stream) = capture(ErrorFormat::Json, || {
+        let Err(err) = generate(&args, &metadata, &cargo_lock, String::new(), &output) else {
+            panic!("expected an error");
+        };
+        diagnostics::report(&err);
+    });
+    let diagnostics: Vec<serde_json::Value> = stream.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+    let summary: Vec<_> = diagnostics.iter().map(|d| (d["level"].as_str().unwrap(), d["code"].as_str().unwrap())).collect();
+    assert_eq!(summary, [("warning", "duplicate-package"), ("error", "package"), ("error", "error")]);
+    assert_eq!(diagnostics[0]["crate"], serde_json::json!({"name": "url", "version": "2.5.0"}));
+    assert_eq!(diagnostics[1]["crate"], serde_json::json!({"name": "unsummed", "version": "1.0.0"}));
+    assert!(diagnostics[1]["message"].as_str().unwrap().contains("has no checksum"));
+    assert_eq!(diagnostics[2]["message"], "failed to generate the sources of 1 packages");
+}
+
+#[test]
+fn missing_checksum_rendered() {
+    use crate::diagnostics::ErrorFormat;
+    use clap::Parser;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let (metadata, mut cargo_lock) = fixture_workspace(tmp.path());
+    let registry = "registry+https://github.com/rust-lang/crates.io-index";
+    let metadata = with_deps(metadata, &[("unsummed", "1.0.0", registry)]);
+    cargo_lock += &format!("\n[[package]]\nname = \"unsummed\"\nversion = \"1.0.0\"\nsource = \"{registry}\"\n");
+    let args = Args::parse_from(["cargo-flatpak"]);
+    let Err(err) = generate(&args, &metadata, &cargo_lock, String::new(), &tmp.path().join("app/cargo-sources.json")) else {
+        panic!("expected an error");
+    };
+    let rendered: String = Diagnostic::from_error(&err).iter().map(|d| d.render(ErrorFormat::Human)).collect();
+    let expected = [
+        "error: unsummed 1.0.0 has no checksum in Cargo.lock",
+        "  --> $TMP/app/Cargo.lock:19:1",
+        "   |",
+        "19 | [[package]]",
+        "   | ^^^^^^^^^^^",
+        "20 | name = \"unsummed\"",
+        "21 | version = \"1.0.0\"",
+        "22 | source = \"registry+https://github.com/rust-lang/crates.io-index\"",
+        "   |",
+        "  help: regenerate the lockfile with `cargo generate-lockfile`, or pass --resolve-missing-checksums to look \
+         the checksum up in the index",
+        "error: failed to generate the sources of 1 packages",
+        "",
+    ];
+    assert_eq!(rendered.replace(tmp.path().to_str().unwrap(), "$TMP"), expected.join("\n"));
+}
+
+#[test]
+fn malformed_lockfile_rendered() {
+    use crate::diagnostics::ErrorFormat;
+    use clap::Parser;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let (metadata, cargo_lock) = fixture_workspace(tmp.path());
+    let cargo_lock = cargo_lock.replacen("version = \"2.5.0\"", "version = 2.5.0", 1);
+    let args = Args::parse_from(["cargo-flatpak"]);
+    let Err(err) = generate(&args, &metadata, &cargo_lock, String::new(), &tmp.path().join("app/cargo-sources.json")) else {
+        panic!("expected an error");
+    };
+    let rendered: String = Diagnostic::from_error(&err).iter().map(|d| d.render(ErrorFormat::Human)).collect();
+    let expected = [
+        "error: failed to parse $TMP/app/Cargo.lock: expected newline, `#`",
+        "  --> $TMP/app/Cargo.lock:15:14",
+        "   |",
+        "15 | version = 2.5.0",
+        "   |              ^",
+        "   |",
+        "  help: regenerate the lockfile with `cargo generate-lockfile`",
+        "",
+    ];
+    assert_eq!(rendered.replace(tmp.path().to_str().unwrap(), "$TMP"), expected.join("\n"));
+}
+
+#[test]
+fn large_lockfile_streamed() {
+    use clap::Parser;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let (mut metadata, mut cargo_lock) = fixture_workspace(tmp.path());
+    // Without a resolve graph the whole lockfile is vendored
+    metadata.resolve = None;
+    for i in 0..4500 {
+        cargo_lock += &format!(
+            "\n[[package]]\nname = \"synthetic-{i}\"\nversion = \"1.0.{i}\"\n\
+             source = \"registry+https://github.com/rust-lang/crates.io-index\"\n\
+             checksum = \"{:064x}\"\n",
+            i + 1
+        );
+    }
+    let args = Args::parse_from(["flatpak"]);
+    let output = tmp.path().join("app/cargo-sources.json");
+    let generated = generate(&args, &metadata, &cargo_lock, "hash".into(), &output).unwrap();
+    assert_eq!(generated.sources().len(), 2 * 4502 + 1);
+    write_output_with(&output, false, |out| Ok(generated.write_json(out)?)).unwrap();
+    let streamed = std::fs::read_to_string(&output).unwrap();
+    assert_eq!(streamed, serde_json::to_string_pretty(&generated.sources()).unwrap());
+}
+
+/// A project depending on registry crates served from `file://` URLs, and
+/// the URL template reaching them: bar, which turns on a feature of qux, so
+/// the index has to tell cargo about both. Returns the metadata, the lockfile,
+/// the project directory and the template.
+#[cfg(test)]
+pub(crate) fn packed_crates_fixture(root: &Path) -> (Metadata, String, PathBuf, String) {
+    use std::process::Command;
+
+    use crate::sources::sha256_file;
+
+    let write = |path: &Path, contents: &str| {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, contents).unwrap();
+    };
+    let crates = root.join("crates");
+    std::fs::create_dir(&crates).unwrap();
+    let pack = |name: &str, manifest: &str, lib: &str| {
+        let dir = root.join(format!("pkg/{name}-0.1.0"));
+        write(&dir.join("Cargo.toml"), &format!("[package]\nname = \"{name}\"\nversion = \"0.1.0\"\n{manifest}"));
+        write(&dir.join("src/lib.rs"), lib);
+        let crate_file = crates.join(format!("{name}-0.1.0.crate"));
+        let mut tar = Command::new("tar");
+        tar.arg("-czf").arg(&crate_file).arg("-C").arg(root.join("pkg")).arg(format!("{name}-0.1.0"));
+        assert!(tar.status().unwrap().success());
+        sha256_file(&crate_file).unwrap()
+    };
+    let qux = pack("qux", "\n[features]\nextra = []\n", "#[cfg(feature = \"extra\")]\npub const QUX: u32 = 1;\n");
+    let bar = pack("bar", "\n[dependencies]\nqux = { version = \"0.1\", features = [\"extra\"] }\n", "pub const BAR: u32 = qux::QUX;\n");
+
+    let registry = "registry+https://github.com/rust-lang/crates.io-index";
+    let (metadata, _) = fixture_workspace(root);
+    let metadata = with_deps(metadata, &[("bar", "0.1.0", registry), ("qux", "0.1.0", registry)]);
+    let mut metadata = serde_json::to_value(metadata).unwrap();
+    for package in metadata["packages"].as_array_mut().unwrap() {
+        match package["name"].as_str() {
+            Some("bar") => {
+                package["dependencies"] = serde_json::json!([{
+                    "name": "qux", "source": registry, "req": "^0.1", "kind": null, "rename": null,
+                    "optional": false, "uses_default_features": true, "features": ["extra"],
+                    "target": null, "registry": null,
+                }])
+            }
+            Some("qux") => package["features"] = serde_json::json!({"extra": []}),
+            _ => {}
+        }
+    }
+    let cargo_lock = format!(
+        "version = 3\n\n\
+         [[package]]\nname = \"app\"\nversion = \"0.1.0\"\ndependencies = [\n \"bar\",\n]\n\n\
+         [[package]]\nname = \"bar\"\nversion = \"0.1.0\"\nsource = \"{registry}\"\nchecksum = \"{bar}\"\n\
+         dependencies = [\n \"qux\",\n]\n\n\
+         [[package]]\nname = \"qux\"\nversion = \"0.1.0\"\nsource = \"{registry}\"\nchecksum = \"{qux}\"\n"
+    );
+
+    let build = root.join("build");
+    write(&build.join("Cargo.toml"), "[package]\nname = \"app\"\nversion = \"0.1.0\"\n\n[dependencies]\nbar = \"0.1\"\n");
+    write(&build.join("src/main.rs"), "fn main() {\n    let _ = bar::BAR;\n}\n");
+    let template = format!("{}{{name}}-{{version}}.crate", url::Url::from_directory_path(&crates).unwrap());
+    (serde_json::from_value(metadata).unwrap(), cargo_lock, build, template)
+}
+
+/// Lays `sources` out in `build` with the vendor script, then checks the
+/// project offline with the staged cargo home
+#[cfg(test)]
+pub(crate) fn check_vendored(sources: &SourceSet, build: &Path, target: &Path) {
+    use std::process::Command;
+
+    let run = |command: &mut Command| {
+        let output = command.output().unwrap();
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    };
+    let script = build.join("vendor.sh");
+    std::fs::write(&script, crate::script::vendor_script(sources, build).unwrap()).unwrap();
+    run(Command::new("bash").arg(&script).current_dir(build));
+    run(Command::new(std::env::var("CARGO").unwrap_or_else(|_| "cargo".into()))
+        .args(["check", "--offline", "--locked", "--quiet"])
+        .current_dir(build)
+        .env("CARGO_HOME", build.join("cargo"))
+        .env("CARGO_TARGET_DIR", target));
+}
+
+#[test]
+fn local_registry_builds() {
+    use clap::Parser;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let (metadata, cargo_lock, build, template) = packed_crates_fixture(tmp.path());
+    let args = Args::parse_from([
+        "flatpak",
+        "--vendor-strategy",
+        "local-registry",
+        "--crate-url-template",
+        &template,
+        "--include-lockfile",
+    ]);
+    let generated = generate(&args, &metadata, &cargo_lock, "hash".into(), &build.join("cargo-sources.json")).unwrap();
+    let config = generated.config.to_toml().unwrap();
+    assert!(config.contains("[source.crates-io]\nreplace-with = \"vendored-registry\""), "{config}");
+    assert!(config.contains("[source.vendored-registry]\nlocal-registry = \"cargo/local-registry\""), "{config}");
+
+    check_vendored(&generated, &build, &tmp.path().join("target"));
+    // The crates stay packed, next to their index
+    assert!(build.join("cargo/local-registry/bar-0.1.0.crate").is_file());
+    assert!(build.join("cargo/local-registry/index/3/q/qux").is_file());
+    assert!(!build.join("cargo/vendor").exists());
+}
+
+#[test]
+fn registry_cache_builds() {
+    use clap::Parser;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let (metadata, cargo_lock, build, template) = packed_crates_fixture(tmp.path());
+    let args = Args::parse_from([
+        "flatpak",
+        "--vendor-strategy",
+        "registry-cache",
+        "--crate-url-template",
+        &template,
+        "--include-lockfile",
+    ]);
+    let generated = generate(&args, &metadata, &cargo_lock, "hash".into(), &build.join("cargo-sources.json")).unwrap();
+    let config = generated.config.to_toml().unwrap();
+    assert!(!config.contains("crates-io"), "{config}");
+    assert!(config.contains("offline = true"), "{config}");
+
+    check_vendored(&generated, &build, &tmp.path().join("target"));
+    // Cargo unpacked the crates from its cache, under the name it gives crates.io
+    let version = crate::toolchain::cargo_version(&crate::toolchain::cargo()).unwrap();
+    let index_dir = crate::sources::crates_io_index_dir(&version).unwrap();
+    let cache = build.join("cargo/registry/cache").join(index_dir);
+    assert!(cache.join("bar-0.1.0.crate").is_file());
+    let unpacked = build.join("cargo/registry/src").join(index_dir);
+    assert!(unpacked.join("qux-0.1.0/Cargo.toml").is_file());
+    assert_eq!(std::fs::read_dir(build.join("cargo/registry/src")).unwrap().count(), 1);
+}
+
+#[test]
+fn prune_foreign_targets() {
+    use clap::Parser;
+
+    let tmp = tempfile::tempdir().unwrap();
+    crate::sources::write_fixture(
+        tmp.path(),
+        &[
+            (
+                "Cargo.toml",
+                "[package]\nname = \"app\"\nversion = \"0.1.0\"\n\n[dependencies]\ncommon = { path = \"common\" }\n\n\
+                 [target.'cfg(windows)'.dependencies]\nwinshim = { path = \"winshim\" }\n\n\
+                 [target.'cfg(target_os = \"macos\")'.dependencies]\nmacshim = { path = \"macshim\" }\n\n\
+                 [target.'cfg(target_arch = \"aarch64\")'.dependencies]\narmshim = { path = \"armshim\" }\n",
+            ),
+            ("src/main.rs", "fn main() {}\n"),
+        ],
+    );
+    for name in ["common", "winshim", "macshim", "armshim"] {
+        let manifest = format!("[package]\nname = \"{name}\"\nversion = \"0.1.0\"\n");
+        crate::sources::write_fixture(&tmp.path().join(name), &[("Cargo.toml", manifest.as_str()), ("src/lib.rs", "")]);
+    }
+    let cargo = std::env::var_os("CARGO").unwrap_or_else(|| "cargo".into());
+    let status = std::process::Command::new(cargo).args(["generate-lockfile", "--offline"]).current_dir(tmp.path()).status().unwrap();
+    assert!(status.success());
+    let metadata = MetadataCommand::new().manifest_path(tmp.path().join("Cargo.toml")).exec().unwrap();
+
+    let names = |arches: &[FlatpakArch]| {
+        let mut names: Vec<_> = platform_packages(&metadata, arches).unwrap().into_iter().map(|(name, _, _)| name).collect();
+        names.sort();
+        names
+    };
+    assert_eq!(names(&[FlatpakArch::X86_64, FlatpakArch::Aarch64]), ["app", "armshim", "common"]);
+    assert_eq!(names(&[FlatpakArch::X86_64]), ["app", "common"]);
+
+    // A windows-only registry crate the unfiltered resolve has is replaced by a stub
+    let metadata = with_registry_deps(metadata, &[("windows-sys", "0.52.0")]);
+    let mut cargo_lock = std::fs::read_to_string(tmp.path().join("Cargo.lock")).unwrap();
+    cargo_lock += &format!(
+        "\n[[package]]\nname = \"windows-sys\"\nversion = \"0.52.0\"\n\
+         source = \"registry+https://github.com/rust-lang/crates.io-index\"\nchecksum = \"{}\"\n",
+        crate::sources::FIXTURE_CHECKSUM
+    );
+    let output = tmp.path().join("cargo-sources.json");
+    let args = Args::parse_from(["flatpak", "--prune-foreign-targets"]);
+    let (generated, warnings) =
+        diagnostics::capture(diagnostics::ErrorFormat::Human, || generate(&args, &metadata, &cargo_lock, "hash".into(), &output));
+    let generated = generated.unwrap();
+    assert!(warnings.starts_with("warning: pruned 1 crates no flatpak architecture builds, vendoring stubs of them: windows-sys 0.52.0\n"), "{warnings}");
+    let stub: Vec<_> = generated
+        .sources()
+        .into_iter()
+        .filter_map(|source| match source {
+            Source::Inline(inline) if inline.dest == "cargo/vendor/windows-sys-0.52.0" => Some(inline.dest_filename.as_str()),
+            Source::Archive(_) => panic!("a pruned crate is downloaded"),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(stub, ["Cargo.toml", "lib.rs", ".cargo-checksum.json"]);
+
+    let args = Args::parse_from(["flatpak", "--prune-foreign-targets", "--vendor-strategy", "local-registry"]);
+    let Err(err) = generate(&args, &metadata, &cargo_lock, "hash".into(), &output) else { panic!("stubs need directories") };
+    assert_eq!(err.to_string(), "--prune-foreign-targets needs --vendor-strategy directory, the stubs it vendors are directories");
+}
+
+#[test]
+fn replace_section_builds() {
+    use std::process::Command;
+
+    use clap::Parser;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let root = tmp.path();
+    let (metadata, cargo_lock, build, template) = packed_crates_fixture(root);
+    // A fork of qux, which bar builds against in its place
+    let fork = root.join("fork");
+    std::fs::create_dir_all(fork.join("src")).unwrap();
+    std::fs::write(fork.join("Cargo.toml"), "[package]\nname = \"qux\"\nversion = \"0.1.0\"\n\n[features]\nextra = []\n").unwrap();
+    std::fs::write(fork.join("src/lib.rs"), "pub const QUX: u32 = 2;\n").unwrap();
+    let git = |args: &[&str]| {
+        let output = Command::new("git").args(args).current_dir(&fork).output().unwrap();
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        String::from_utf8(output.stdout).unwrap()
+    };
+    git(&["init", "-q"]);
+    git(&["add", "."]);
+    git(&["-c", "user.name=test", "-c", "user.email=test@example.com", "commit", "-q", "-m", "fork"]);
+    let commit = git(&["rev-parse", "HEAD"]).trim().to_string();
+    let fork_url = url::Url::from_directory_path(&fork).unwrap().to_string();
+    let fork_url = fork_url.trim_end_matches('/');
+
+    let replace = format!("\n[replace]\n\"qux:0.1.0\" = {{ git = \"{fork_url}\" }}\n");
+    for manifest in [root.join("app/Cargo.toml"), build.join("Cargo.toml")] {
+        let contents = std::fs::read_to_string(&manifest).unwrap();
+        std::fs::write(&manifest, contents + &replace).unwrap();
+    }
+    std::fs::write(build.join("src/main.rs"), "const _: () = assert!(bar::BAR == 2);\n\nfn main() {}\n").unwrap();
+    // Cargo resolves the fork, and keeps the replaced crate in the lockfile
+    let registry = "registry+https://github.com/rust-lang/crates.io-index";
+    let source = format!("git+{fork_url}#{commit}");
+    let metadata = serde_json::to_string(&metadata).unwrap().replace(&format!("{registry}#qux@0.1.0"), &format!("{source}#qux@0.1.0"));
+    let mut metadata: serde_json::Value = serde_json::from_str(&metadata).unwrap();
+    for package in metadata["packages"].as_array_mut().unwrap() {
+        if package["name"] == "qux" {
+            package["source"] = source.clone().into();
+            package["manifest_path"] = fork.join("Cargo.toml").to_str().unwrap().into();
+        }
+    }
+    let metadata: Metadata = serde_json::from_value(metadata).unwrap();
+    let cargo_lock = cargo_lock.replace(" \"qux\",\n", &format!(" \"qux 0.1.0 ({registry})\",\n"))
+        + &format!("replace = \"qux 0.1.0 (git+{fork_url})\"\n\n[[package]]\nname = \"qux\"\nversion = \"0.1.0\"\nsource = \"{source}\"\n");
+
+    let args = Args::parse_from(["flatpak", "--crate-url-template", &template, "--include-lockfile"]);
+    let generated = generate(&args, &metadata, &cargo_lock, "hash".into(), &build.join("cargo-sources.json")).unwrap();
+    let config = generated.config.to_toml().unwrap();
+    assert!(config.contains("[source.replaced-sources]\ndirectory = \"cargo/vendor-replaced\""), "{config}");
+    assert!(config.contains(&format!("git = \"{fork_url}\"\nreplace-with = \"replaced-sources\"")), "{config}");
+
+    check_vendored(&generated, &build, &root.join("target"));
+    assert!(build.join("cargo/vendor/qux-0.1.0/Cargo.toml").is_file());
+    assert!(build.join("cargo/vendor-replaced/qux-0.1.0/Cargo.toml").is_file());
+
+    let args = Args::parse_from(["flatpak", "--crate-url-template", &template, "--vendor-strategy", "local-registry"]);
+    let Err(err) = generate(&args, &metadata, &cargo_lock, "hash".into(), &build.join("cargo-sources.json")) else { panic!("expected an error") };
+    assert_eq!(err.to_string(), "[replace] needs --vendor-strategy directory, the index of the other strategies lacks the replaced crates");
+}
@@ -0,0 +1,156 @@
+use std::fmt;
+
+/// A checksum or commit that isn't well-formed
+#[derive(Debug, Clone, PartialEq)]
+pub struct InvalidHash {
+    kind: &'static str,
+    value: String,
+    expected: &'static str,
+}
+
+impl fmt::Display for InvalidHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid {} `{}`, expected {}", self.kind, self.value, self.expected)
+    }
+}
+
+impl std::error::Error for InvalidHash {}
+
+fn validate(kind: &'static str, value: &str, lengths: &[usize], expected: &'static str) -> Result<String, InvalidHash> {
+    if lengths.contains(&value.len()) && value.bytes().all(|b| b.is_ascii_hexdigit()) {
+        Ok(value.to_string())
+    } else {
+        Err(InvalidHash { kind, value: value.to_string(), expected })
+    }
+}
+
+/// The sha256 of a file, as found in Cargo.lock `checksum`s
+#[derive(Debug, Clone, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Sha256(String);
+
+impl Sha256 {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl TryFrom<&str> for Sha256 {
+    type Error = InvalidHash;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        validate("sha256 checksum", value, &[64], "64 hex digits").map(Sha256)
+    }
+}
+
+impl TryFrom<String> for Sha256 {
+    type Error = InvalidHash;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        Sha256::try_from(value.as_str())
+    }
+}
+
+impl From<Sha256> for String {
+    fn from(sha256: Sha256) -> String {
+        sha256.0
+    }
+}
+
+impl fmt::Display for Sha256 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// A full git commit id, sha1 or sha256
+#[derive(Debug, Clone, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct CommitHash(String);
+
+impl CommitHash {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// The first `len` digits, like `git rev-parse --short`
+    pub fn abbrev(&self, len: usize) -> &str {
+        &self.0[..len.min(self.0.len())]
+    }
+}
+
+impl TryFrom<&str> for CommitHash {
+    type Error = InvalidHash;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        validate("git commit", value, &[40, 64], "40 or 64 hex digits").map(CommitHash)
+    }
+}
+
+impl TryFrom<String> for CommitHash {
+    type Error = InvalidHash;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        CommitHash::try_from(value.as_str())
+    }
+}
+
+impl From<CommitHash> for String {
+    fn from(commit: CommitHash) -> String {
+        commit.0
+    }
+}
+
+impl fmt::Display for CommitHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[test]
+fn hash_validation() {
+    let checksum = crate::sources::FIXTURE_CHECKSUM;
+    let commit = "0123456789abcdef0123456789abcdef01234567";
+    assert_eq!(Sha256::try_from(checksum).unwrap().to_string(), checksum);
+    assert_eq!(CommitHash::try_from(commit).unwrap().abbrev(7), "0123456");
+    assert_eq!(CommitHash::try_from(commit).unwrap().abbrev(100), commit);
+    assert!(CommitHash::try_from(checksum).is_ok());
+
+    assert_eq!(
+        Sha256::try_from(commit).unwrap_err().to_string(),
+        format!("invalid sha256 checksum `{commit}`, expected 64 hex digits")
+    );
+    assert!(Sha256::try_from(&checksum.replace('e', "g") as &str).is_err());
+    assert_eq!(
+        CommitHash::try_from("main").unwrap_err().to_string(),
+        "invalid git commit `main`, expected 40 or 64 hex digits"
+    );
+    assert!(CommitHash::try_from("").is_err());
+
+    let json = serde_json::to_string(&Sha256::try_from(checksum).unwrap()).unwrap();
+    assert_eq!(json, format!("\"{checksum}\""));
+    assert_eq!(serde_json::from_str::<Sha256>(&json).unwrap().as_str(), checksum);
+    assert!(serde_json::from_str::<CommitHash>("\"0123\"").is_err());
+}
+
+/// Fails to compile if `$to: From<$from>`, since both impls apply then and
+/// the call is ambiguous
+#[cfg(test)]
+macro_rules! assert_not_from {
+    ($to:ty, $from:ty) => {{
+        trait AmbiguousIfFrom<A> {
+            fn check() {}
+        }
+        impl<T: ?Sized> AmbiguousIfFrom<()> for T {}
+        impl<T: ?Sized + From<$from>> AmbiguousIfFrom<u8> for T {}
+        <$to as AmbiguousIfFrom<_>>::check();
+    }};
+}
+
+#[test]
+fn hashes_do_not_convert() {
+    assert_not_from!(Sha256, CommitHash);
+    assert_not_from!(CommitHash, Sha256);
+    assert_not_from!(Sha256, String);
+    assert_not_from!(CommitHash, &'static str);
+}
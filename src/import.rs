@@ -0,0 +1,268 @@
+use std::collections::HashMap;
+
+use crate::cli::Args;
+use crate::config::CargoConfig;
+use crate::sources::{get_package_sources, git_cache_dir, Git, Inline, LockFile, Package, Shell, Source, SourceSet};
+use crate::VENDORED_SOURCES;
+
+/// An imported sources file
+pub struct Imported {
+    pub sources: SourceSet,
+    /// The entries with no native equivalent, and why
+    pub unmapped: Vec<(serde_json::Value, String)>,
+}
+
+/// The `cp -r` of a git crate out of its checkout, as both generators write it
+fn parse_copy(command: &str) -> Option<(&str, &str)> {
+    let rest = command.strip_prefix("cp -r --reflink=auto \"")?;
+    let (from, rest) = rest.split_once("\" \"")?;
+    Some((from, rest.strip_suffix('"')?))
+}
+
+fn is_config(inline: &Inline) -> bool {
+    matches!(inline.dest_filename.as_str(), "config" | "config.toml")
+}
+
+/// Maps the entries of a sources file written by flatpak-cargo-generator
+/// onto the sources this tool generates for `cargo_lock`. Crates are
+/// recognized by their checksum or git commit, and moved to the dests `args`
+/// call for. The cargo config is rebuilt, it comes last.
+pub fn import(old: &[serde_json::Value], cargo_lock: &str, args: &Args) -> anyhow::Result<Imported> {
+    let cargo_lock: LockFile = toml::from_str(cargo_lock)?;
+    let parsed: Vec<Source> = old.iter().map(|entry| serde_json::from_value(entry.clone())).collect::<Result<_, _>>()?;
+
+    let old_config = parsed.iter().find_map(|source| match source {
+        Source::Inline(inline) if is_config(inline) => toml::from_str::<toml::Table>(&inline.contents).ok(),
+        _ => None,
+    });
+    let old_vendor_dir = old_config
+        .as_ref()
+        .and_then(|config| config.get("source")?.get(VENDORED_SOURCES)?.get("directory")?.as_str())
+        .unwrap_or("cargo/vendor")
+        .trim_end_matches('/')
+        .to_string();
+    let vendor_dir = args.vendor_dir();
+    // Read off the raw entry, some versions of the generator leave out `archive-type`
+    let archive_package = |entry: &serde_json::Value| {
+        let field = |key: &str| entry.get(key).and_then(|v| v.as_str());
+        if field("type") != Some("archive") {
+            return None;
+        }
+        let sha256 = field("sha256")?;
+        let candidates: Vec<_> = cargo_lock
+            .package
+            .iter()
+            .filter(|p| p.checksum.as_ref().is_some_and(|c| c.as_str() == sha256))
+            .collect();
+        // Crates with the same contents only differ by name
+        let named = |p: &&Package| {
+            let crate_name = format!("{}-{}", p.name, p.version);
+            let last = |path: &str| path.trim_end_matches('/').rsplit('/').next().map(String::from);
+            field("dest").and_then(last).as_ref() == Some(&crate_name)
+                || field("url").and_then(last) == Some(format!("{crate_name}.crate"))
+        };
+        match candidates.as_slice() {
+            [package] => Some(*package),
+            candidates => candidates.iter().copied().find(named),
+        }
+    };
+
+    // Where the crates and checkouts move to
+    let mut dests: HashMap<String, String> = HashMap::new();
+    for (entry, source) in old.iter().zip(&parsed) {
+        if let (Some(package), Some(dest)) = (archive_package(entry), entry.get("dest").and_then(|d| d.as_str())) {
+            dests.insert(dest.to_string(), format!("{vendor_dir}/{}-{}", package.name, package.version));
+        }
+        if let Source::Git(git) = source {
+            dests.insert(git.dest.clone(), git_cache_dir(&git.url, &git.commit, args)?.to_string());
+        }
+    }
+    let move_dest = |dest: &str| -> Option<String> {
+        if let Some(dest) = dests.get(dest) {
+            return Some(dest.clone());
+        }
+        let under = |old: &str, new: &str| {
+            let rest = dest.strip_prefix(old).filter(|rest| rest.starts_with('/'))?;
+            Some(format!("{new}{rest}"))
+        };
+        dests
+            .iter()
+            .find_map(|(old, new)| under(old, new))
+            .or_else(|| under(&old_vendor_dir, &vendor_dir))
+    };
+
+    let mut sources = Vec::new();
+    let mut unmapped = Vec::new();
+    let mut has_config = false;
+    for (entry, source) in old.iter().zip(parsed) {
+        if let Some(package) = archive_package(entry) {
+            let (native, _) = get_package_sources(package, None, args)?.unwrap();
+            sources.push(native.into_iter().next().unwrap());
+            continue;
+        }
+        let mapped = match source {
+            Source::Archive(_) => Err("the archive is of no crate in Cargo.lock".to_string()),
+            Source::Git(git) => Ok(Source::Git(Git {
+                dest: dests[&git.dest].clone(),
+                x_checker_data: None,
+                ..git
+            })),
+            Source::Shell(shell) => shell
+                .commands
+                .iter()
+                .map(|command| {
+                    let (from, to) = parse_copy(command)?;
+                    Some(format!(r#"cp -r --reflink=auto "{}" "{}""#, move_dest(from)?, move_dest(to)?))
+                })
+                .collect::<Option<Vec<_>>>()
+                .map(|commands| Source::Shell(Shell { commands }))
+                .ok_or_else(|| "the shell commands do more than copy a git crate".to_string()),
+            Source::Inline(inline) if is_config(&inline) => {
+                has_config = true;
+                continue;
+            }
+            Source::Inline(inline) if inline.dest_filename == "Cargo.lock" => Ok(Source::Inline(Inline {
+                dest: args.dest("."),
+                ..inline
+            })),
+            Source::Inline(inline) => match move_dest(&inline.dest) {
+                Some(dest) => Ok(Source::Inline(Inline { dest, ..inline })),
+                None => Err("the inline file isn't part of a vendored crate".to_string()),
+            },
+            Source::Dir(_) | Source::File(_) => Err("path sources aren't generated from Cargo.lock".to_string()),
+            Source::Other(_) => Err("the source type isn't generated by cargo flatpak".to_string()),
+        };
+        match mapped {
+            Ok(source) => sources.push(source),
+            Err(reason) => unmapped.push((entry.clone(), reason)),
+        }
+    }
+
+    if has_config {
+        let mut cargo_config = CargoConfig::new(&vendor_dir);
+        for (section, table) in old_config.into_iter().flatten() {
+            let Some(mut table) = table.as_table().cloned() else {
+                continue;
+            };
+            if section == "source" {
+                table.remove(VENDORED_SOURCES);
+            }
+            cargo_config.section_mut(&section).extend(table);
+        }
+        sources.push(Source::Inline(Inline {
+            contents: cargo_config.to_toml()?,
+            base64: false,
+            dest: args.cargo_home_dir(),
+            dest_filename: "config".into(),
+            x_cargo_lock_hash: None,
+        }));
+    }
+    Ok(Imported { sources: SourceSet::from_sources(sources)?, unmapped })
+}
+
+#[test]
+fn import_python_generated_sources() {
+    use clap::Parser;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let (metadata, mut cargo_lock) = crate::generate::fixture_workspace(tmp.path());
+    cargo_lock += "\n[[package]]\nname = \"gtk4\"\nversion = \"0.9.0\"\n\
+                   source = \"git+https://github.com/gtk-rs/gtk4-rs.git?branch=main#0123456789abcdef0123456789abcdef01234567\"\n";
+    let checksum = crate::sources::FIXTURE_CHECKSUM;
+    // Written by flatpak-cargo-generator.py, the url crate's archive by an older version without `archive-type`
+    let python = format!(
+        r#"[
+    {{
+        "type": "archive",
+        "archive-type": "tar-gzip",
+        "url": "https://static.crates.io/crates/anstream/anstream-0.6.15.crate",
+        "sha256": "{checksum}",
+        "dest": "cargo/vendor/anstream-0.6.15"
+    }},
+    {{
+        "type": "inline",
+        "contents": "{{\"package\": \"{checksum}\", \"files\": {{}}}}",
+        "dest": "cargo/vendor/anstream-0.6.15",
+        "dest-filename": ".cargo-checksum.json"
+    }},
+    {{
+        "type": "git",
+        "url": "https://github.com/gtk-rs/gtk4-rs",
+        "commit": "0123456789abcdef0123456789abcdef01234567",
+        "dest": "flatpak-cargo/git/gtk4-rs-0123456"
+    }},
+    {{
+        "type": "shell",
+        "commands": [
+            "cp -r --reflink=auto \"flatpak-cargo/git/gtk4-rs-0123456/gtk4\" \"cargo/vendor/gtk4\""
+        ]
+    }},
+    {{
+        "type": "inline",
+        "contents": "[package]\nname = \"gtk4\"\nversion = \"0.9.0\"\n",
+        "dest": "cargo/vendor/gtk4",
+        "dest-filename": "Cargo.toml"
+    }},
+    {{
+        "type": "inline",
+        "contents": "{{\"package\": null, \"files\": {{}}}}",
+        "dest": "cargo/vendor/gtk4",
+        "dest-filename": ".cargo-checksum.json"
+    }},
+    {{
+        "type": "shell",
+        "commands": [
+            "sed -i 's/foo/bar/' cargo/vendor/gtk4/build.rs"
+        ]
+    }},
+    {{
+        "type": "archive",
+        "url": "https://static.crates.io/crates/url/url-2.5.0.crate",
+        "sha256": "{checksum}",
+        "dest": "cargo/vendor/url-2.5.0"
+    }},
+    {{
+        "type": "inline",
+        "contents": "{{\"package\": \"{checksum}\", \"files\": {{}}}}",
+        "dest": "cargo/vendor/url-2.5.0",
+        "dest-filename": ".cargo-checksum.json"
+    }},
+    {{
+        "type": "inline",
+        "contents": "[source.vendored-sources]\ndirectory = \"cargo/vendor\"\n\n[source.crates-io]\nreplace-with = \"vendored-sources\"\n\n[source.\"https://github.com/gtk-rs/gtk4-rs?branch=main\"]\ngit = \"https://github.com/gtk-rs/gtk4-rs\"\nreplace-with = \"vendored-sources\"\nbranch = \"main\"\n",
+        "dest": "cargo",
+        "dest-filename": "config"
+    }}
+]"#
+    );
+    let old: Vec<serde_json::Value> = serde_json::from_str(&python).unwrap();
+    let args = Args::parse_from(["flatpak", "--vendor-dir", "vendor"]);
+
+    let imported = import(&old, &cargo_lock, &args).unwrap();
+    assert_eq!(imported.unmapped.len(), 1);
+    assert_eq!(imported.unmapped[0].0, old[6]);
+    assert_eq!(imported.unmapped[0].1, "the shell commands do more than copy a git crate");
+    let imported = serde_json::to_value(imported.sources.sources()).unwrap();
+
+    // The crates.io entries are what generation makes of the same lockfile
+    let output = tmp.path().join("app/cargo-sources.json");
+    let generated = crate::generate::generate(&args, &metadata, &cargo_lock, "hash".into(), &output).unwrap();
+    let mut generated = serde_json::to_value(generated.sources()).unwrap();
+    let generated = generated.as_array_mut().unwrap();
+    let config = generated.pop().unwrap();
+    let registry: Vec<_> = [0, 1, 6, 7].iter().map(|i| imported[i].clone()).collect();
+    assert_eq!(&registry, generated);
+
+    assert_eq!(imported[2]["dest"], "flatpak-cargo/git/gtk-rs-gtk4-rs-8cd6d500-0123456");
+    assert_eq!(imported[3]["commands"][0], r#"cp -r --reflink=auto "flatpak-cargo/git/gtk-rs-gtk4-rs-8cd6d500-0123456/gtk4" "vendor/gtk4""#);
+    assert_eq!(imported[4]["dest"], "vendor/gtk4");
+    assert_eq!(imported[5]["dest"], "vendor/gtk4");
+
+    let imported_config: toml::Table = toml::from_str(imported[8]["contents"].as_str().unwrap()).unwrap();
+    let generated_config: toml::Table = toml::from_str(config["contents"].as_str().unwrap()).unwrap();
+    assert_eq!(imported[8]["dest"], config["dest"]);
+    assert_eq!(imported_config["source"]["vendored-sources"], generated_config["source"]["vendored-sources"]);
+    assert_eq!(imported_config["source"]["crates-io"], generated_config["source"]["crates-io"]);
+    assert_eq!(imported_config["source"]["https://github.com/gtk-rs/gtk4-rs?branch=main"]["branch"].as_str(), Some("main"));
+    assert_eq!(imported.as_array().unwrap().len(), 9);
+}
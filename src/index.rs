@@ -0,0 +1,245 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+
+use crate::diagnostics::{self, Annotated, Diagnostic, REGENERATE_LOCKFILE};
+use crate::hash::Sha256;
+use crate::sources::{crate_prefix, Package, CRATES_IO_SOURCE};
+
+/// A line of a sparse index file, as much of it as checksums need
+#[derive(serde::Deserialize)]
+struct IndexVersion {
+    vers: String,
+    cksum: String,
+}
+
+/// The `index` directory of the cache, the index files by host and path
+pub fn default_cache() -> Option<PathBuf> {
+    Some(crate::net::cache_dir()?.join("index"))
+}
+
+/// The sparse index the packages of the lockfile source `source` are in:
+/// `crates_io` for crates.io, the registry itself when it's sparse
+pub fn sparse_index<'a>(source: &'a str, crates_io: &'a str) -> Option<&'a str> {
+    match source {
+        CRATES_IO_SOURCE => Some(crates_io),
+        source => source.strip_prefix("sparse+"),
+    }
+}
+
+/// The URL of the file of `name` in the sparse index at `index`
+fn index_url(index: &str, name: &str) -> String {
+    let name = name.to_lowercase();
+    format!("{}/{}/{name}", index.trim_end_matches('/'), crate_prefix(&name))
+}
+
+/// Where the file at `url` is kept in `cache`
+fn cache_path(cache: &Path, url: &str) -> PathBuf {
+    let path = url.split_once("://").map_or(url, |(_, path)| path);
+    cache.join(path.replace(':', "_"))
+}
+
+/// The checksum of `version` in the index file `file`, if it lists the version
+fn find_checksum(file: &str, version: &str) -> anyhow::Result<Option<Sha256>> {
+    for line in file.lines().filter(|line| !line.trim().is_empty()) {
+        let entry: IndexVersion = serde_json::from_str(line)?;
+        if entry.vers == version {
+            return Ok(Some(Sha256::try_from(entry.cksum)?));
+        }
+    }
+    Ok(None)
+}
+
+/// The checksum of `name` `version` in the sparse index at `index`. The index
+/// file is kept in `cache`, and downloaded again only when it predates the
+/// version: published versions don't change.
+pub fn checksum(index: &str, name: &str, version: &str, cache: Option<&Path>) -> anyhow::Result<Sha256> {
+    let url = index_url(index, name);
+    let cached = cache.map(|cache| cache_path(cache, &url));
+    if let Some(file) = cached.as_ref().and_then(|path| std::fs::read_to_string(path).ok()) {
+        if let Ok(Some(checksum)) = find_checksum(&file, version) {
+            return Ok(checksum);
+        }
+    }
+    let file = match crate::net::client().get(&url) {
+        Ok(response) => response.into_string()?,
+        Err(crate::net::RequestError::Status(404)) => anyhow::bail!("{name} is not in the index {index}"),
+        Err(e) => return Err(anyhow::Error::new(e).context(format!("failed to download {url}"))),
+    };
+    let checksum = find_checksum(&file, version).with_context(|| format!("{url} is not a sparse index file"))?;
+    if let Some(path) = cached {
+        let written = std::fs::create_dir_all(path.parent().unwrap()).and_then(|_| std::fs::write(&path, &file));
+        if let Err(e) = written {
+            diagnostics::warn("cache", format!("could not write the index cache {}: {e}", path.display()));
+        }
+    }
+    checksum.ok_or_else(|| anyhow::anyhow!("{name} {version} is not in the index {index}"))
+}
+
+/// Fills in the checksums of the registry packages Cargo.lock has none for
+/// from their sparse index, with a warning for each. Returns the errors of the
+/// packages that couldn't be looked up, by name and version.
+pub fn resolve_missing_checksums(
+    packages: &mut [Package],
+    crates_io: &str,
+    cache: Option<&Path>,
+) -> HashMap<(String, String), anyhow::Error> {
+    let mut errors = HashMap::new();
+    for package in packages.iter_mut().filter(|p| p.checksum.is_none()) {
+        let Some(source) = package.source.as_deref().filter(|s| s.starts_with("registry+") || s.starts_with("sparse+")) else {
+            continue;
+        };
+        let (name, version) = (&package.name, &package.version);
+        let checksum = match sparse_index(source, crates_io) {
+            Some(index) => checksum(index, name, version, cache).map(|checksum| (index, checksum)),
+            None => Err(anyhow::anyhow!("{name} {version} has no checksum in Cargo.lock, and {source} is not a sparse index to look it up in")),
+        };
+        match checksum {
+            Ok((index, checksum)) => {
+                diagnostics::emit(
+                    Diagnostic::warning("resolved-checksum", format!("{name} {version} has no checksum in Cargo.lock, using the one in {index}"))
+                        .with_crate(name, version)
+                        .with_suggestion(REGENERATE_LOCKFILE),
+                );
+                package.checksum = Some(checksum);
+                package.checksum_from_index = true;
+            }
+            Err(e) => {
+                errors.insert((name.clone(), version.clone()), Annotated::new(e, REGENERATE_LOCKFILE).into());
+            }
+        }
+    }
+    errors
+}
+
+/// Serves `files` by path on a local port, counting the requests
+#[cfg(test)]
+pub(crate) fn serve(files: HashMap<&'static str, Vec<u8>>) -> (String, &'static std::sync::atomic::AtomicUsize) {
+    serve_with(move |_, path| match files.get(path) {
+        Some(body) => (200, body.clone()),
+        None => (404, Vec::new()),
+    })
+}
+
+/// Serves what `respond` answers a method and path with, its status and
+/// body, on a local port, counting the requests. HEAD requests get the
+/// length of the body without it.
+#[cfg(test)]
+pub(crate) fn serve_with(
+    mut respond: impl FnMut(&str, &str) -> (u16, Vec<u8>) + Send + 'static,
+) -> (String, &'static std::sync::atomic::AtomicUsize) {
+    use std::io::{BufRead, BufReader, Write};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let index = format!("http://{}/", listener.local_addr().unwrap());
+    let requests: &'static AtomicUsize = Box::leak(Box::new(AtomicUsize::new(0)));
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(&stream);
+            let mut request = String::new();
+            reader.read_line(&mut request).unwrap();
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                line.clear();
+            }
+            requests.fetch_add(1, Ordering::SeqCst);
+            let mut request = request.split(' ');
+            let (method, path) = (request.next().unwrap_or_default(), request.next().unwrap_or_default());
+            let (status, body) = respond(method, path);
+            let reason = match status {
+                200 => "OK",
+                206 => "Partial Content",
+                404 => "Not Found",
+                405 => "Method Not Allowed",
+                _ => "Status",
+            };
+            // The client may have timed out and left already
+            let _ = write!(stream, "HTTP/1.1 {status} {reason}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len())
+                .and_then(|_| if method == "HEAD" { Ok(()) } else { stream.write_all(&body) });
+        }
+    });
+    (index, requests)
+}
+
+#[test]
+fn checksums_from_the_index() {
+    use std::sync::atomic::Ordering;
+
+    let (a, b) = ("a".repeat(64), "b".repeat(64));
+    let bar = format!(
+        "{{\"name\":\"bar\",\"vers\":\"0.1.0\",\"deps\":[],\"cksum\":\"{a}\",\"features\":{{}},\"yanked\":false}}\n\
+         {{\"name\":\"bar\",\"vers\":\"0.2.0\",\"deps\":[],\"cksum\":\"{b}\",\"features\":{{}},\"yanked\":false}}\n"
+    );
+    let (index, requests) = serve(HashMap::from([("/3/b/bar", bar.into())]));
+    let tmp = tempfile::tempdir().unwrap();
+    let cache = tmp.path().join("index");
+
+    assert_eq!(checksum(&index, "bar", "0.1.0", Some(&cache)).unwrap().as_str(), a);
+    assert_eq!(checksum(&index, "Bar", "0.2.0", Some(&cache)).unwrap().as_str(), b);
+    assert_eq!(requests.load(Ordering::SeqCst), 1);
+    assert!(cache_path(&cache, &index_url(&index, "bar")).is_file());
+    // A version the cached file predates is looked up again
+    let err = checksum(&index, "bar", "0.3.0", Some(&cache)).unwrap_err().to_string();
+    assert_eq!(err, format!("bar 0.3.0 is not in the index {index}"));
+    assert_eq!(requests.load(Ordering::SeqCst), 2);
+    let err = checksum(&index, "quux", "1.0.0", None).unwrap_err().to_string();
+    assert_eq!(err, format!("quux is not in the index {index}"));
+
+    assert_eq!(sparse_index(CRATES_IO_SOURCE, &index), Some(index.as_str()));
+    assert_eq!(sparse_index("sparse+https://example.com/index/", &index), Some("https://example.com/index/"));
+    assert_eq!(sparse_index("registry+https://example.com/git-index", &index), None);
+}
+
+#[test]
+fn resolved_checksums_build() {
+    use clap::Parser;
+
+    use crate::cli::Args;
+    use crate::diagnostics::{capture, ErrorFormat};
+    use crate::sources::{lockfile_source, sha256_file, Inline, LockFile, Source, SourceSet};
+
+    let tmp = tempfile::tempdir().unwrap();
+    let (_, cargo_lock, build, template) = crate::generate::packed_crates_fixture(tmp.path());
+    let bar = sha256_file(&tmp.path().join("crates/bar-0.1.0.crate")).unwrap();
+    let cargo_lock = cargo_lock.replace(&format!("checksum = \"{bar}\"\n"), "");
+    let (index, _) = serve(HashMap::from([(
+        "/3/b/bar",
+        format!("{{\"name\":\"bar\",\"vers\":\"0.1.0\",\"deps\":[],\"cksum\":\"{bar}\",\"features\":{{}},\"yanked\":false}}\n").into(),
+    )]));
+
+    let mut lock: LockFile = toml::from_str(&cargo_lock).unwrap();
+    let (errors, warnings) = capture(ErrorFormat::Human, || resolve_missing_checksums(&mut lock.package, &index, None));
+    assert!(errors.is_empty());
+    assert_eq!(
+        warnings,
+        format!(
+            "warning: bar 0.1.0 has no checksum in Cargo.lock, using the one in {index}\n  \
+             help: regenerate the lockfile with `cargo generate-lockfile`\n"
+        )
+    );
+
+    let args = Args::parse_from(["flatpak", "--crate-url-template", &template]);
+    let mut sources = SourceSet::new(&args.vendor_dir());
+    for package in &lock.package {
+        sources.push_package(package, None, &args).unwrap();
+    }
+    let Source::Inline(checksum) = &sources.sources()[1] else { panic!("expected bar's checksum") };
+    assert_eq!(checksum.contents, r#"{"package": null, "files": {}}"#);
+    sources.push(None, "Cargo.lock", lockfile_source(&cargo_lock, None, &build, ".").unwrap());
+    let config = sources.config.to_toml().unwrap();
+    sources.push(
+        None,
+        "config",
+        Source::Inline(Inline {
+            contents: config,
+            base64: false,
+            dest: args.cargo_home_dir(),
+            dest_filename: "config".into(),
+            x_cargo_lock_hash: None,
+        }),
+    );
+    crate::generate::check_vendored(&sources, &build, &tmp.path().join("target"));
+}
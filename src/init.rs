@@ -0,0 +1,403 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use cargo_metadata::Metadata;
+
+use crate::cli::Args;
+use crate::diagnostics;
+use crate::toolchain::{Toolchain, RUST_STABLE};
+
+/// A manifest template shipped with cargo-flatpak, with its runtime
+struct Builtin {
+    name: &'static str,
+    contents: &'static str,
+    runtime: &'static str,
+}
+
+const BUILTINS: [Builtin; 2] = [
+    Builtin { name: "gnome", contents: include_str!("templates/gnome.json"), runtime: "org.gnome.Platform" },
+    Builtin { name: "cli", contents: include_str!("templates/cli.json"), runtime: "org.freedesktop.Platform" },
+];
+
+/// A runtime with its SDK, its latest version, and the Freedesktop SDK branch
+/// a version is based on, which is the branch of the rust extension it takes
+struct Runtime {
+    runtime: &'static str,
+    sdk: &'static str,
+    latest: &'static str,
+    base: fn(&str) -> Option<&str>,
+}
+
+const RUNTIMES: [Runtime; 3] = [
+    Runtime {
+        runtime: "org.freedesktop.Platform",
+        sdk: "org.freedesktop.Sdk",
+        latest: "25.08",
+        base: |version| Some(version).filter(|v| v.ends_with(".08")),
+    },
+    Runtime {
+        runtime: "org.gnome.Platform",
+        sdk: "org.gnome.Sdk",
+        latest: "49",
+        base: |version| match version {
+            "45" | "46" => Some("23.08"),
+            "47" | "48" => Some("24.08"),
+            "49" => Some("25.08"),
+            _ => None,
+        },
+    },
+    Runtime {
+        runtime: "org.kde.Platform",
+        sdk: "org.kde.Sdk",
+        latest: "6.9",
+        base: |version| match version {
+            // Qt 5 branches name their base, as in `5.15-24.08`
+            _ if version.starts_with("5.15-") => Some(&version[5..]),
+            "6.6" | "6.7" => Some("23.08"),
+            "6.8" | "6.9" => Some("24.08"),
+            "6.10" => Some("25.08"),
+            _ => None,
+        },
+    },
+];
+
+/// The runtime, SDK and rust extension branch of a manifest
+#[derive(Debug, PartialEq)]
+pub struct Platform {
+    pub runtime: String,
+    pub sdk: String,
+    pub runtime_version: Option<String>,
+    pub rust_extension_branch: Option<String>,
+}
+
+impl Platform {
+    /// The SDK and rust extension branch of `runtime`, and its latest version
+    /// unless `runtime_version` is given. Runtimes cargo-flatpak doesn't know
+    /// are taken as given, with a warning.
+    pub fn new(runtime: &str, sdk: Option<&str>, runtime_version: Option<&str>) -> Platform {
+        let Some(known) = RUNTIMES.iter().find(|r| r.runtime == runtime) else {
+            let sdk = sdk.map_or_else(|| runtime.strip_suffix(".Platform").map_or(runtime.into(), |r| format!("{r}.Sdk")), String::from);
+            diagnostics::warn(
+                "unknown-runtime",
+                format!("{runtime} is not a runtime cargo-flatpak knows, using it with the SDK {sdk} as given: check the rust extension works with them"),
+            );
+            return Platform {
+                runtime: runtime.into(),
+                sdk,
+                runtime_version: runtime_version.map(String::from),
+                rust_extension_branch: None,
+            };
+        };
+        let version = runtime_version.unwrap_or(known.latest);
+        let branch = (known.base)(version);
+        if branch.is_none() {
+            diagnostics::warn(
+                "unknown-runtime",
+                format!("cargo-flatpak doesn't know the Freedesktop SDK {runtime} {version} is based on, nor the rust extension branch it takes"),
+            );
+        }
+        Platform {
+            runtime: runtime.into(),
+            sdk: sdk.unwrap_or(known.sdk).into(),
+            runtime_version: Some(version.into()),
+            rust_extension_branch: branch.map(String::from),
+        }
+    }
+
+    fn values(&self) -> impl Iterator<Item = (&'static str, String)> {
+        [
+            ("runtime", Some(self.runtime.clone())),
+            ("sdk", Some(self.sdk.clone())),
+            ("runtime_version", self.runtime_version.clone()),
+            ("rust_extension_branch", self.rust_extension_branch.clone()),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| Some((name, value?)))
+    }
+}
+
+/// The placeholders a template can use, with the option giving each its value
+pub const PLACEHOLDERS: [(&str, &str); 10] = [
+    ("app_id", "--app-id"),
+    ("name", "-p"),
+    ("command", "--command"),
+    ("sources_file", "--output"),
+    ("runtime", "--runtime"),
+    ("sdk", "--sdk"),
+    ("runtime_version", "--runtime-version"),
+    ("rust_extension_branch", "--runtime-version"),
+    ("rust_extension", "--ignore-toolchain"),
+    ("cargo_home", "--cargo-home"),
+];
+
+/// A manifest template, built in or read from a file
+#[derive(Debug)]
+pub struct Template {
+    pub contents: String,
+    /// `json`, `yml` or `yaml`, what the manifest is written as
+    pub extension: String,
+    /// The runtime of a built-in template, when not given
+    pub runtime: Option<&'static str>,
+}
+
+impl Template {
+    /// The built-in template `template` names, or the template file at that path
+    pub fn load(template: &str) -> anyhow::Result<Template> {
+        if let Some(builtin) = BUILTINS.iter().find(|b| b.name == template) {
+            return Ok(Template {
+                contents: builtin.contents.into(),
+                extension: "json".into(),
+                runtime: Some(builtin.runtime),
+            });
+        }
+        let path = Path::new(template);
+        let extension = match path.extension().and_then(|e| e.to_str()) {
+            Some(extension @ ("json" | "yml" | "yaml")) => extension.to_string(),
+            Some(_) => anyhow::bail!("{template} is not a JSON or YAML template, the file has to end in .json, .yml or .yaml"),
+            None => {
+                let builtins: Vec<_> = BUILTINS.iter().map(|b| b.name).collect();
+                anyhow::bail!("there is no built-in template `{template}`, the built-in ones are {}", builtins.join(", "))
+            }
+        };
+        let contents = std::fs::read_to_string(path).map_err(|e| anyhow::anyhow!("failed to read {template}: {e}"))?;
+        Ok(Template { contents, extension, runtime: None })
+    }
+
+    /// The template with `values` in place of its `{{placeholders}}`, all of which
+    /// have to be known and have a value
+    pub fn render(&self, values: &BTreeMap<&str, String>) -> anyhow::Result<String> {
+        let mut out = String::with_capacity(self.contents.len());
+        let (mut unknown, mut missing) = (Vec::new(), Vec::new());
+        let mut rest = self.contents.as_str();
+        while let Some(start) = rest.find("{{") {
+            out.push_str(&rest[..start]);
+            let placeholder = &rest[start + 2..];
+            let Some(end) = placeholder.find("}}") else {
+                let line = self.contents[..self.contents.len() - rest.len() + start].matches('\n').count() + 1;
+                anyhow::bail!("the placeholder on line {line} of the template is never closed with `}}}}`");
+            };
+            let name = placeholder[..end].trim();
+            let after = placeholder.get(end + 2..).and_then(|rest| rest.chars().next());
+            let quote = out.chars().last().filter(|&c| (c == '"' || c == '\'') && after == Some(c));
+            match values.get(name) {
+                // JSON values land in strings, as do YAML ones in quotes, which
+                // escape differently
+                Some(value) if self.extension == "json" || quote == Some('"') => {
+                    let quoted = serde_json::to_string(value)?;
+                    out.push_str(&quoted[1..quoted.len() - 1]);
+                }
+                Some(value) if quote == Some('\'') => out.push_str(&value.replace('\'', "''")),
+                // Unquoted YAML values are quoted unless YAML reads them as they are
+                Some(value) if !crate::sources::yaml_plain(value) => out.push_str(&serde_json::to_string(value)?),
+                Some(value) => out.push_str(value),
+                None => match PLACEHOLDERS.iter().find(|(known, _)| *known == name) {
+                    Some(placeholder) => missing.push(placeholder),
+                    None => unknown.push(format!("{{{{{name}}}}}")),
+                },
+            }
+            rest = &placeholder[end + 2..];
+        }
+        out.push_str(rest);
+        if !unknown.is_empty() {
+            let known: Vec<_> = PLACEHOLDERS.iter().map(|(name, _)| format!("{{{{{name}}}}}")).collect();
+            anyhow::bail!("unknown placeholders in the template: {}, the known ones are {}", unknown.join(", "), known.join(", "));
+        }
+        if let Some((name, option)) = missing.first() {
+            anyhow::bail!("the template uses {{{{{name}}}}}, which has no value: pass {option}");
+        }
+        Ok(out)
+    }
+}
+
+/// Writes a manifest for the package of the workspace from `template` to
+/// `{app_id}.json`, or `.yml` and `.yaml` for the same YAML templates, in the
+/// workspace. A nightly `toolchain` builds with the rust-nightly extension.
+/// Returns the path of the manifest, and the platform it runs on.
+pub fn init(
+    metadata: &Metadata,
+    args: &Args,
+    app_id: &str,
+    template: &str,
+    platform: (Option<&str>, Option<&str>, Option<&str>),
+    command: Option<&str>,
+    toolchain: Option<&Toolchain>,
+) -> anyhow::Result<(PathBuf, Option<Platform>)> {
+    let template = Template::load(template)?;
+    let name = match (args.package.as_slice(), metadata.root_package()) {
+        ([package], _) => package.clone(),
+        ([], Some(root)) => root.name.clone(),
+        _ => anyhow::bail!("the manifest is for one package, pick it with -p"),
+    };
+    let command = match command {
+        Some(command) => Some(command.to_string()),
+        None => crate::module::binary_targets(metadata, std::slice::from_ref(&name))?.first().map(|b| b.name.clone()),
+    };
+
+    let mut values = BTreeMap::from([
+        ("app_id", app_id.to_string()),
+        ("name", name),
+        ("sources_file", args.output.clone()),
+        ("cargo_home", args.cargo_home_dir()),
+        ("rust_extension", toolchain.map_or(RUST_STABLE, Toolchain::sdk_extension).to_string()),
+    ]);
+    values.extend(command.map(|command| ("command", command)));
+    let (runtime, sdk, runtime_version) = platform;
+    let platform = runtime.or(template.runtime).map(|runtime| Platform::new(runtime, sdk, runtime_version));
+    match &platform {
+        Some(platform) => values.extend(platform.values()),
+        None => values.extend(runtime_version.map(|version| ("runtime_version", version.to_string()))),
+    }
+    let manifest = template.render(&values)?;
+
+    let path = metadata.workspace_root.as_std_path().join(format!("{app_id}.{}", template.extension));
+    // Never over a manifest that's there, however it got there since init started
+    crate::generate::write_output(&path, manifest.as_bytes(), true).map_err(|e| match path.exists() {
+        true => anyhow::anyhow!("{} already exists, remove it to start over", path.display()),
+        false => e,
+    })?;
+    Ok((path, platform))
+}
+
+#[cfg(test)]
+fn values(pairs: &[(&'static str, &str)]) -> BTreeMap<&'static str, String> {
+    pairs.iter().map(|(name, value)| (*name, value.to_string())).collect()
+}
+
+#[test]
+fn builtin_templates() {
+    let values = |platform: &Platform| {
+        let mut values = values(&[("app_id", "org.example.App"), ("name", "app"), ("command", "app"), ("sources_file", "cargo-sources.json"), ("cargo_home", "cargo"), ("rust_extension", "rust-stable")]);
+        values.extend(platform.values());
+        values
+    };
+    let module = serde_json::json!({
+        "name": "app",
+        "buildsystem": "simple",
+        "build-options": {"env": {"CARGO_HOME": "/run/build/app/cargo"}},
+        "build-commands": [
+            "cargo --offline build --release",
+            "install -Dm755 target/release/app /app/bin/app",
+        ],
+        "sources": [{"type": "dir", "path": "."}, "cargo-sources.json"],
+    });
+
+    let gnome = Template::load("gnome").unwrap();
+    assert_eq!(gnome.runtime, Some("org.gnome.Platform"));
+    let platform = Platform::new("org.gnome.Platform", None, None);
+    let manifest: serde_json::Value = serde_json::from_str(&gnome.render(&values(&platform)).unwrap()).unwrap();
+    assert_eq!(
+        manifest,
+        serde_json::json!({
+            "id": "org.example.App",
+            "runtime": "org.gnome.Platform",
+            "runtime-version": "49",
+            "sdk": "org.gnome.Sdk",
+            "sdk-extensions": ["org.freedesktop.Sdk.Extension.rust-stable"],
+            "command": "app",
+            "finish-args": ["--share=ipc", "--socket=fallback-x11", "--socket=wayland", "--device=dri"],
+            "build-options": {"append-path": "/usr/lib/sdk/rust-stable/bin", "env": {"CARGO_NET_OFFLINE": "true"}},
+            "modules": [module],
+        })
+    );
+
+    // A pinned nightly builds with the extension of its own
+    let mut nightly = values(&platform);
+    nightly.insert("rust_extension", "rust-nightly".into());
+    let manifest: serde_json::Value = serde_json::from_str(&gnome.render(&nightly).unwrap()).unwrap();
+    assert_eq!(manifest["sdk-extensions"], serde_json::json!(["org.freedesktop.Sdk.Extension.rust-nightly"]));
+    assert_eq!(manifest["build-options"]["append-path"], "/usr/lib/sdk/rust-nightly/bin");
+
+    let cli = Template::load("cli").unwrap();
+    let platform = Platform::new("org.freedesktop.Platform", None, None);
+    let manifest: serde_json::Value = serde_json::from_str(&cli.render(&values(&platform)).unwrap()).unwrap();
+    assert_eq!(manifest["runtime"], "org.freedesktop.Platform");
+    assert_eq!(manifest["runtime-version"], "25.08");
+    assert_eq!(manifest["finish-args"], serde_json::json!(["--filesystem=home"]));
+    assert_eq!(manifest["modules"], serde_json::json!([module]));
+
+    let err = Template::load("games").unwrap_err().to_string();
+    assert_eq!(err, "there is no built-in template `games`, the built-in ones are gnome, cli");
+}
+
+#[test]
+fn user_templates() {
+    let tmp = tempfile::tempdir().unwrap();
+    let path = tmp.path().join("manifest.yml");
+    std::fs::write(&path, "app-id: {{ app_id }}\nruntime-version: '{{runtime_version}}'\ncommand: {{command}}\n").unwrap();
+    let template = Template::load(path.to_str().unwrap()).unwrap();
+    assert_eq!(template.extension, "yml");
+    let rendered = template.render(&values(&[("app_id", "org.example.Tool"), ("command", "tool"), ("runtime_version", "25.08")]));
+    assert_eq!(rendered.unwrap(), "app-id: org.example.Tool\nruntime-version: '25.08'\ncommand: tool\n");
+
+    // Without a built-in's default, the runtime version has to be given
+    let err = template.render(&values(&[("app_id", "org.example.Tool"), ("command", "tool")])).unwrap_err().to_string();
+    assert_eq!(err, "the template uses {{runtime_version}}, which has no value: pass --runtime-version");
+
+    // Values are escaped for the quotes around them, and quoted without any
+    std::fs::write(&path, "app-id: {{app_id}}\nname: '{{name}}'\ncommand: \"{{command}}\"\nruntime-version: {{runtime_version}}\n").unwrap();
+    let template = Template::load(path.to_str().unwrap()).unwrap();
+    let rendered = template.render(&values(&[("app_id", "org.example.Tool"), ("name", "it's: a tool"), ("command", "say \"hi\""), ("runtime_version", "25.08")]));
+    assert_eq!(
+        rendered.unwrap(),
+        "app-id: org.example.Tool\nname: 'it''s: a tool'\ncommand: \"say \\\"hi\\\"\"\nruntime-version: \"25.08\"\n"
+    );
+
+    std::fs::write(&path, "app-id: {{app_id}}\nflavour: {{ flavour }}\nlevel: {{level}}\n").unwrap();
+    let template = Template::load(path.to_str().unwrap()).unwrap();
+    let err = template.render(&values(&[("app_id", "org.example.Tool")])).unwrap_err().to_string();
+    assert_eq!(
+        err,
+        "unknown placeholders in the template: {{flavour}}, {{level}}, the known ones are {{app_id}}, {{name}}, \
+         {{command}}, {{sources_file}}, {{runtime}}, {{sdk}}, {{runtime_version}}, {{rust_extension_branch}}, \
+         {{rust_extension}}, {{cargo_home}}"
+    );
+
+    std::fs::write(&path, "app-id: {{app_id}}\ncommand: {{command\n").unwrap();
+    let template = Template::load(path.to_str().unwrap()).unwrap();
+    let err = template.render(&values(&[("app_id", "org.example.Tool")])).unwrap_err().to_string();
+    assert_eq!(err, "the placeholder on line 2 of the template is never closed with `}}`");
+}
+
+#[test]
+fn runtimes() {
+    use crate::diagnostics::{capture, ErrorFormat};
+
+    let manifest = |runtime: &str, sdk: Option<&str>, runtime_version: &str| {
+        let (platform, warnings) = capture(ErrorFormat::Human, || Platform::new(runtime, sdk, Some(runtime_version)));
+        let mut values = values(&[("app_id", "org.example.App"), ("name", "app"), ("command", "app"), ("sources_file", "cargo-sources.json"), ("cargo_home", "cargo"), ("rust_extension", "rust-stable")]);
+        values.extend(platform.values());
+        let manifest: serde_json::Value = serde_json::from_str(&Template::load("gnome").unwrap().render(&values).unwrap()).unwrap();
+        let fields = ["runtime", "runtime-version", "sdk", "sdk-extensions"].map(|field| manifest[field].clone());
+        (fields, platform.rust_extension_branch, warnings)
+    };
+    let rust = serde_json::json!(["org.freedesktop.Sdk.Extension.rust-stable"]);
+
+    let (fields, branch, warnings) = manifest("org.gnome.Platform", None, "47");
+    assert_eq!(fields, [serde_json::json!("org.gnome.Platform"), "47".into(), "org.gnome.Sdk".into(), rust.clone()]);
+    assert_eq!((branch.as_deref(), warnings.as_str()), (Some("24.08"), ""));
+
+    let (fields, branch, _) = manifest("org.freedesktop.Platform", None, "24.08");
+    assert_eq!(fields, [serde_json::json!("org.freedesktop.Platform"), "24.08".into(), "org.freedesktop.Sdk".into(), rust.clone()]);
+    assert_eq!(branch.as_deref(), Some("24.08"));
+
+    let (fields, branch, _) = manifest("org.kde.Platform", None, "5.15-24.08");
+    assert_eq!(fields, [serde_json::json!("org.kde.Platform"), "5.15-24.08".into(), "org.kde.Sdk".into(), rust.clone()]);
+    assert_eq!(branch.as_deref(), Some("24.08"));
+
+    // Unknown runtimes go through as they are
+    let (fields, branch, warnings) = manifest("org.example.Platform", None, "1.0");
+    assert_eq!(fields, [serde_json::json!("org.example.Platform"), "1.0".into(), "org.example.Sdk".into(), rust.clone()]);
+    assert_eq!(branch, None);
+    assert_eq!(
+        warnings,
+        "warning: org.example.Platform is not a runtime cargo-flatpak knows, using it with the SDK org.example.Sdk \
+         as given: check the rust extension works with them\n"
+    );
+    let (fields, _, warnings) = manifest("org.gnome.Platform", Some("org.gnome.Sdk.Debug"), "3.38");
+    assert_eq!(fields[2], "org.gnome.Sdk.Debug");
+    assert_eq!(
+        warnings,
+        "warning: cargo-flatpak doesn't know the Freedesktop SDK org.gnome.Platform 3.38 is based on, nor the rust \
+         extension branch it takes\n"
+    );
+}
@@ -0,0 +1,47 @@
+mod app;
+pub mod sources;
+pub(crate) mod advisory;
+pub(crate) mod audit;
+pub(crate) mod bump;
+pub(crate) mod cache;
+pub(crate) mod checkout;
+pub(crate) mod checksums;
+pub(crate) mod cli;
+pub(crate) mod config;
+pub(crate) mod diagnostics;
+pub(crate) mod doctor;
+pub(crate) mod explain;
+pub(crate) mod fmt;
+pub(crate) mod generate;
+pub mod hash;
+pub(crate) mod import;
+pub(crate) mod index;
+pub(crate) mod init;
+pub(crate) mod lint;
+pub(crate) mod list;
+pub(crate) mod module;
+pub(crate) mod net;
+pub(crate) mod policy;
+pub(crate) mod script;
+pub(crate) mod settings;
+pub(crate) mod size;
+pub(crate) mod summary;
+pub(crate) mod test_build;
+pub(crate) mod toolchain;
+pub(crate) mod trace;
+pub(crate) mod vendored;
+pub(crate) mod verify;
+pub(crate) mod watch;
+
+const CRATES_IO: &str = "https://static.crates.io/crates";
+const CRATES_IO_INDEX: &str = "https://index.crates.io/";
+const CARGO_HOME: &str = "cargo";
+const VENDOR_DIR: &str = "vendor";
+const VENDORED_SOURCES: &str = "vendored-sources";
+const REPLACED_SOURCES: &str = "replaced-sources";
+const LOCAL_REGISTRY_DIR: &str = "local-registry";
+const VENDORED_REGISTRY: &str = "vendored-registry";
+const GIT_CACHE: &str = "flatpak-cargo/git";
+const COMMIT_LEN: usize = 7;
+
+pub use app::main;
@@ -0,0 +1,379 @@
+use clap::ValueEnum;
+use serde_json::Value;
+
+/// A check of `cargo flatpak lint`, by its stable ID
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Rule {
+    /// A URL downloaded over plain HTTP
+    PlainHttp,
+    /// An archive or file download without a sha256
+    MissingChecksum,
+    /// A git source following a branch or tag instead of naming a commit
+    UnpinnedGit,
+    /// A shell source running something other than the copies and removals
+    /// of vendoring
+    ShellCommand,
+    /// A cargo config that doesn't replace crates.io with the vendored crates
+    CratesIoNotReplaced,
+    /// Any git source, for builds vendoring registry crates only. Allowed
+    /// unless denied.
+    GitDependency,
+}
+
+impl Rule {
+    pub const ALL: [Rule; 6] = [
+        Rule::PlainHttp,
+        Rule::MissingChecksum,
+        Rule::UnpinnedGit,
+        Rule::ShellCommand,
+        Rule::CratesIoNotReplaced,
+        Rule::GitDependency,
+    ];
+
+    pub fn id(self) -> &'static str {
+        match self {
+            Rule::PlainHttp => "plain-http",
+            Rule::MissingChecksum => "missing-checksum",
+            Rule::UnpinnedGit => "unpinned-git",
+            Rule::ShellCommand => "shell-command",
+            Rule::CratesIoNotReplaced => "crates-io-not-replaced",
+            Rule::GitDependency => "git-dependency",
+        }
+    }
+
+    /// How a finding counts without --deny or --allow
+    fn default_severity(self) -> Option<Severity> {
+        match self {
+            Rule::ShellCommand => Some(Severity::Warning),
+            Rule::GitDependency => None,
+            _ => Some(Severity::Error),
+        }
+    }
+}
+
+/// Errors fail the lint, warnings are only reported
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct Finding {
+    pub rule: Rule,
+    pub severity: Severity,
+    pub message: String,
+    /// The offending source, as in the sources file
+    pub entry: Value,
+}
+
+/// The severity of each rule, `None` for the allowed ones: the default,
+/// with `deny` made errors and `allow` turned off, `allow` winning
+pub fn severities(deny: &[Rule], allow: &[Rule]) -> Vec<(Rule, Option<Severity>)> {
+    Rule::ALL
+        .into_iter()
+        .map(|rule| {
+            let severity = match (allow.contains(&rule), deny.contains(&rule)) {
+                (true, _) => None,
+                (false, true) => Some(Severity::Error),
+                (false, false) => rule.default_severity(),
+            };
+            (rule, severity)
+        })
+        .collect()
+}
+
+/// The words of a shell command, `None` when it has anything but words:
+/// pipes, redirections, substitutions, several commands. A word may join
+/// quoted and unquoted parts, like `"cargo/vendor/foo"/tests`.
+fn shell_words(command: &str) -> Option<Vec<String>> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut chars = command.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            ' ' | '\t' => words.extend(word.take()),
+            '\\' => word.get_or_insert_with(String::new).push(chars.next()?),
+            '"' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next()? {
+                        '"' => break,
+                        '$' | '`' | '\\' => return None,
+                        c => word.push(c),
+                    }
+                }
+            }
+            ';' | '&' | '|' | '$' | '`' | '>' | '<' | '(' | ')' | '\n' | '\'' => return None,
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    Some(words)
+}
+
+/// Whether `command` is one of what vendoring git crates runs: making the
+/// vendor directory, copying a crate or a file, moving a crate, removing
+/// paths, all of them under `roots`
+fn known_command(command: &str, roots: &[String]) -> bool {
+    let Some(words) = shell_words(command) else {
+        return false;
+    };
+    let words: Vec<_> = words.iter().map(String::as_str).collect();
+    let paths = match words.as_slice() {
+        ["cp", "-r", "--reflink=auto", from, to] | ["cp", "--reflink=auto", from, to] | ["mv", from, to] => vec![*from, *to],
+        ["rm", "-rf", path] | ["mkdir", "-p", path] => vec![*path],
+        _ => return false,
+    };
+    paths.iter().all(|path| vendoring_path(path, roots))
+}
+
+/// Whether `path` is relative, stays inside of the build directory and is
+/// one of `roots` or under one of them
+fn vendoring_path(path: &str, roots: &[String]) -> bool {
+    let path = std::path::Path::new(path);
+    let relative = path.components().all(|c| matches!(c, std::path::Component::Normal(_) | std::path::Component::CurDir));
+    relative && roots.iter().any(|root| path.starts_with(root))
+}
+
+/// Where vendoring copies, moves and removes: the git checkouts, the cargo
+/// home and the vendor directories of the config, or the default ones
+/// without a config
+fn vendoring_roots(sources: &[Value]) -> Vec<String> {
+    let mut roots = vec!["flatpak-cargo/git".to_string()];
+    let mut config = false;
+    for source in sources {
+        let field = |key: &str| source.get(key).and_then(Value::as_str);
+        match field("type").unwrap_or_default() {
+            "git" | "archive" => roots.extend(field("dest").map(String::from)),
+            "inline" if matches!(field("dest-filename"), Some("config" | "config.toml")) => {
+                config = true;
+                roots.extend(field("dest").map(String::from));
+                let contents: toml::Table = toml::from_str(field("contents").unwrap_or_default()).unwrap_or_default();
+                let directories = contents.get("source").and_then(toml::Value::as_table).into_iter().flatten();
+                roots.extend(directories.filter_map(|(_, source)| Some(source.get("directory")?.as_str()?.to_string())));
+            }
+            _ => {}
+        }
+    }
+    if !config {
+        roots.push("cargo".into());
+    }
+    roots
+}
+
+/// What the rules find in a sources file
+fn findings(sources: &[Value]) -> Vec<(Rule, String, &Value)> {
+    let roots = vendoring_roots(sources);
+    let mut findings = Vec::new();
+    let mut config = None;
+    for source in sources {
+        let field = |key: &str| source.get(key).and_then(Value::as_str);
+        let kind = field("type").unwrap_or_default();
+        if let Some(url) = field("url").filter(|url| url.starts_with("http://")) {
+            findings.push((Rule::PlainHttp, format!("{url} is downloaded over plain HTTP"), source));
+        }
+        match kind {
+            "archive" | "file" if field("url").is_some() && field("sha256").is_none() => {
+                findings.push((Rule::MissingChecksum, format!("{} has no sha256", field("url").unwrap()), source));
+            }
+            "git" => {
+                let url = field("url").unwrap_or_default();
+                if field("commit").is_none() {
+                    let reference = field("tag").or(field("branch")).map_or(String::new(), |r| format!(" at {r}"));
+                    findings.push((Rule::UnpinnedGit, format!("{url} is cloned{reference} without a commit"), source));
+                }
+                findings.push((Rule::GitDependency, format!("{url} is a git dependency"), source));
+            }
+            "shell" => {
+                let commands = source.get("commands").and_then(Value::as_array).into_iter().flatten();
+                for command in commands {
+                    let command = command.as_str().unwrap_or_default();
+                    if !known_command(command, &roots) {
+                        findings.push((Rule::ShellCommand, format!("the shell source runs `{command}`"), source));
+                    }
+                }
+            }
+            "inline" if matches!(field("dest-filename"), Some("config" | "config.toml")) => config = Some(source),
+            _ => {}
+        }
+    }
+    match config {
+        Some(source) => {
+            let contents: toml::Table = toml::from_str(source["contents"].as_str().unwrap_or_default()).unwrap_or_default();
+            let replaced = contents.get("source").and_then(|s| s.get("crates-io")).and_then(|c| c.get("replace-with")).is_some();
+            // Seeding cargo's download cache replaces nothing, it needs offline instead
+            let offline = contents.get("net").and_then(|net| net.get("offline")).and_then(toml::Value::as_bool) == Some(true);
+            if !replaced && !offline {
+                let message = "the cargo config neither replaces crates-io nor keeps cargo offline".to_string();
+                findings.push((Rule::CratesIoNotReplaced, message, source));
+            }
+        }
+        None => findings.push((Rule::CratesIoNotReplaced, "the sources have no cargo config".into(), &Value::Null)),
+    }
+    findings
+}
+
+/// Lints `sources` with the rules at `severities`
+pub fn lint(sources: &Value, severities: &[(Rule, Option<Severity>)]) -> Vec<Finding> {
+    let sources = sources.as_array().map(Vec::as_slice).unwrap_or_default();
+    let mut linted: Vec<_> = findings(sources)
+        .into_iter()
+        .filter_map(|(rule, message, entry)| {
+            let (_, severity) = severities.iter().find(|(r, _)| *r == rule)?;
+            Some(Finding { rule, severity: (*severity)?, message, entry: entry.clone() })
+        })
+        .collect();
+    linted.sort_by_key(|finding| finding.rule);
+    linted
+}
+
+pub fn table(findings: &[Finding]) -> String {
+    let mut table = String::new();
+    for finding in findings {
+        let severity = serde_json::to_value(finding.severity).unwrap();
+        table += &format!("{}[{}]: {}\n", severity.as_str().unwrap(), finding.rule.id(), finding.message);
+        if !finding.entry.is_null() {
+            table += &format!("  {}\n", finding.entry);
+        }
+    }
+    let errors = findings.iter().filter(|f| f.severity == Severity::Error).count();
+    table += &format!("{errors} errors, {} warnings\n", findings.len() - errors);
+    table
+}
+
+/// Fails if any finding is an error
+pub fn check(findings: &[Finding]) -> anyhow::Result<()> {
+    let errors = findings.iter().filter(|f| f.severity == Severity::Error).count();
+    if errors > 0 {
+        anyhow::bail!("{errors} lint errors in the sources");
+    }
+    Ok(())
+}
+
+/// A sources file of vendored crates that every default rule passes
+#[cfg(test)]
+fn clean_sources() -> Vec<Value> {
+    let mut sources: Vec<Value> = serde_json::from_str(
+        r#"[
+            {"type": "archive", "archive-type": "tar-gzip", "url": "https://static.crates.io/crates/url/url-2.5.0.crate",
+             "dest": "cargo/vendor/url-2.5.0"},
+            {"type": "git", "url": "https://github.com/gtk-rs/gtk4-rs", "commit": "0123456789abcdef0123456789abcdef01234567",
+             "dest": "flatpak-cargo/git/gtk-rs-gtk4-rs-8cd6d500-0123456"},
+            {"type": "shell", "commands": [
+                "cp -r --reflink=auto \"flatpak-cargo/git/gtk-rs-gtk4-rs-8cd6d500-0123456/gtk4\" \"cargo/vendor/gtk4\"",
+                "cp --reflink=auto \"flatpak-cargo/git/gtk-rs-gtk4-rs-8cd6d500-0123456/LICENSE\" \"cargo/vendor/gtk4/LICENSE\"",
+                "rm -rf \"cargo/vendor/gtk4\"/benches/data\\ *.bin"
+            ]},
+            {"type": "inline", "contents": "[source.crates-io]\nreplace-with = \"vendored-sources\"\n", "dest": "cargo",
+             "dest-filename": "config"}
+        ]"#,
+    )
+    .unwrap();
+    sources[0]["sha256"] = crate::sources::FIXTURE_CHECKSUM.into();
+    sources
+}
+
+/// The rules `sources` breaks with the default severities, and their severity
+#[cfg(test)]
+fn broken(sources: Vec<Value>) -> Vec<(Rule, Severity)> {
+    lint(&Value::Array(sources), &severities(&[], &[])).into_iter().map(|f| (f.rule, f.severity)).collect()
+}
+
+#[test]
+fn clean() {
+    assert_eq!(broken(clean_sources()), []);
+    // Stubs of the config kept cargo offline instead of replacing crates.io
+    let mut sources = clean_sources();
+    sources[3]["contents"] = "[net]\noffline = true\n".into();
+    assert_eq!(broken(sources), []);
+}
+
+#[test]
+fn plain_http() {
+    let mut sources = clean_sources();
+    sources[0]["url"] = "http://static.crates.io/crates/url/url-2.5.0.crate".into();
+    assert_eq!(broken(sources), [(Rule::PlainHttp, Severity::Error)]);
+}
+
+#[test]
+fn missing_checksum() {
+    let mut sources = clean_sources();
+    sources[0].as_object_mut().unwrap().remove("sha256");
+    let findings = lint(&Value::Array(sources.clone()), &severities(&[], &[]));
+    assert_eq!(findings.len(), 1);
+    assert_eq!(
+        findings[0],
+        Finding {
+            rule: Rule::MissingChecksum,
+            severity: Severity::Error,
+            message: "https://static.crates.io/crates/url/url-2.5.0.crate has no sha256".into(),
+            entry: sources[0].clone(),
+        }
+    );
+}
+
+#[test]
+fn unpinned_git() {
+    let mut sources = clean_sources();
+    sources[1].as_object_mut().unwrap().remove("commit");
+    sources[1]["branch"] = "main".into();
+    let findings = lint(&Value::Array(sources), &severities(&[], &[]));
+    assert_eq!(findings.len(), 1);
+    assert_eq!(findings[0].message, "https://github.com/gtk-rs/gtk4-rs is cloned at main without a commit");
+}
+
+#[test]
+fn shell_command() {
+    let outside = [
+        "rm -rf /",
+        "rm -rf \"/etc\"",
+        "mv /etc \"cargo/vendor/foo\"",
+        "cp --reflink=auto \"flatpak-cargo/git/x/../../../etc/passwd\" \"cargo/vendor/foo\"",
+        "rm -rf \"cargo/vendor/../../home\"",
+        "rm -rf src",
+    ];
+    let unknown = ["curl -o x https://example.com/x", "cp -r a b; rm -rf /", "mv \"$(id)\" b", "rm -rf a b", "cp a > b"];
+    for command in unknown.into_iter().chain(outside) {
+        let mut sources = clean_sources();
+        sources[2]["commands"] = serde_json::json!([command]);
+        assert_eq!(broken(sources), [(Rule::ShellCommand, Severity::Warning)], "{command}");
+    }
+    let mut sources = clean_sources();
+    sources[2]["commands"] = serde_json::json!(["mv \"flatpak-cargo/git/x/foo\" \"cargo/vendor/foo\"", "rm -rf \"flatpak-cargo/git/x\""]);
+    assert_eq!(broken(sources), []);
+}
+
+#[test]
+fn crates_io_not_replaced() {
+    let mut sources = clean_sources();
+    sources[3]["contents"] = "[source.vendored-sources]\ndirectory = \"cargo/vendor\"\n".into();
+    assert_eq!(broken(sources), [(Rule::CratesIoNotReplaced, Severity::Error)]);
+    let mut sources = clean_sources();
+    sources.pop();
+    let findings = lint(&Value::Array(sources), &severities(&[], &[]));
+    assert_eq!((findings[0].message.as_str(), &findings[0].entry), ("the sources have no cargo config", &Value::Null));
+}
+
+#[test]
+fn git_dependency() {
+    let sources = Value::Array(clean_sources());
+    let findings = lint(&sources, &severities(&[Rule::GitDependency], &[]));
+    let found: Vec<_> = findings.iter().map(|f| (f.rule, f.severity, f.message.as_str())).collect();
+    assert_eq!(found, [(Rule::GitDependency, Severity::Error, "https://github.com/gtk-rs/gtk4-rs is a git dependency")]);
+    assert!(check(&findings).is_err());
+
+    // --allow wins, and warnings don't fail
+    let mut shell = clean_sources();
+    shell[2]["commands"] = serde_json::json!(["make install"]);
+    let findings = lint(&Value::Array(shell.clone()), &severities(&[Rule::GitDependency], &[Rule::GitDependency]));
+    assert_eq!(findings.iter().map(|f| f.rule).collect::<Vec<_>>(), [Rule::ShellCommand]);
+    check(&findings).unwrap();
+    assert_eq!(
+        table(&findings),
+        format!("warning[shell-command]: the shell source runs `make install`\n  {}\n0 errors, 1 warnings\n", shell[2])
+    );
+    assert!(lint(&Value::Array(shell), &severities(&[], &[Rule::ShellCommand])).is_empty());
+}
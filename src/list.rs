@@ -0,0 +1,137 @@
+use cargo_metadata::Metadata;
+
+use crate::cli::Args;
+use crate::generate::{external_path_dependencies, vendored_packages};
+use crate::hash::CommitHash;
+use crate::policy::SourceKind;
+use crate::sources::{git_reference, LockFile};
+use crate::COMMIT_LEN;
+
+/// A package the sources vendor
+#[derive(Debug, PartialEq, serde::Serialize)]
+pub struct ListedPackage {
+    pub name: String,
+    pub version: String,
+    pub kind: SourceKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub repository: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub commit: Option<CommitHash>,
+}
+
+/// Lists the packages generation vendors, in the same order, with the bundled
+/// path dependencies last
+pub fn list(cargo_lock: &str, cargo_metadata: &Metadata, args: &Args) -> anyhow::Result<Vec<ListedPackage>> {
+    let cargo_lock: LockFile = toml::from_str(cargo_lock)?;
+    let mut packages: Vec<_> = vendored_packages(&cargo_lock, cargo_metadata)?
+        .into_iter()
+        .map(|package| {
+            let kind = SourceKind::of(package);
+            let (repository, commit) = match kind {
+                SourceKind::Git => git_reference(package.source.as_deref().unwrap()).unzip(),
+                _ => (None, None),
+            };
+            ListedPackage {
+                name: package.name.clone(),
+                version: package.version.clone(),
+                kind,
+                repository,
+                commit,
+            }
+        })
+        .collect();
+    if args.bundle_path_deps {
+        packages.extend(external_path_dependencies(cargo_metadata)?.into_iter().map(|dep| ListedPackage {
+            name: dep.name,
+            version: dep.version,
+            kind: SourceKind::Path,
+            repository: None,
+            commit: None,
+        }));
+    }
+    Ok(packages)
+}
+
+pub fn table(packages: &[ListedPackage]) -> String {
+    let name_width = packages.iter().map(|p| p.name.len()).max().unwrap_or(0);
+    let version_width = packages.iter().map(|p| p.version.len()).max().unwrap_or(0);
+    let mut table = String::new();
+    for package in packages {
+        let kind = match package.kind {
+            SourceKind::Registry => "registry",
+            SourceKind::Git => "git",
+            SourceKind::Path => "path",
+        };
+        let mut line = format!("{:name_width$}  {:version_width$}  {kind}", package.name, package.version);
+        if let (Some(repository), Some(commit)) = (&package.repository, &package.commit) {
+            line += &format!("  {repository} {}", commit.abbrev(COMMIT_LEN));
+        }
+        table += line.trim_end();
+        table.push('\n');
+    }
+    table
+}
+
+#[test]
+fn list_matches_generated_sources() {
+    use std::collections::BTreeSet;
+
+    use clap::Parser;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let files = [
+        ("app/Cargo.toml", "[package]\nname = \"app\"\nversion = \"0.1.0\"\n\n[dependencies]\nshared = { path = \"../shared\" }\n"),
+        ("app/src/main.rs", "fn main() {}\n"),
+        ("shared/Cargo.toml", "[package]\nname = \"shared\"\nversion = \"0.2.0\"\n"),
+        ("shared/src/lib.rs", ""),
+    ];
+    crate::sources::write_fixture(tmp.path(), &files);
+    let metadata = cargo_metadata::MetadataCommand::new()
+        .manifest_path(tmp.path().join("app/Cargo.toml"))
+        .exec()
+        .unwrap();
+    let metadata = crate::generate::with_registry_deps(metadata, &[("anstream", "0.6.15"), ("url", "2.5.0")]);
+    let registry = |name: &str, version: &str| {
+        format!(
+            "[[package]]\nname = \"{name}\"\nversion = \"{version}\"\n\
+             source = \"registry+https://github.com/rust-lang/crates.io-index\"\n\
+             checksum = \"{}\"\n\n",
+            crate::sources::FIXTURE_CHECKSUM
+        )
+    };
+    let cargo_lock = format!(
+        "version = 3\n\n[[package]]\nname = \"app\"\nversion = \"0.1.0\"\n\n{}{}\
+         [[package]]\nname = \"shared\"\nversion = \"0.2.0\"\n",
+        registry("anstream", "0.6.15"),
+        registry("url", "2.5.0"),
+    );
+    let args = Args::parse_from(["flatpak", "--bundle-path-deps", "--dest-prefix", "app"]);
+
+    let listed = list(&cargo_lock, &metadata, &args).unwrap();
+    assert_eq!(
+        table(&listed),
+        "anstream  0.6.15  registry\nurl       2.5.0   registry\nshared    0.2.0   path\n"
+    );
+
+    let output = tmp.path().join("app/cargo-sources.json");
+    let generated = crate::generate::generate(&args, &metadata, &cargo_lock, String::new(), &output).unwrap();
+    let vendored: BTreeSet<String> = serde_json::to_value(generated.sources())
+        .unwrap()
+        .as_array()
+        .unwrap()
+        .iter()
+        .filter_map(|s| match s["type"].as_str()? {
+            // Next to the workspace, where its manifest points
+            "dir" => s["dest"].as_str().map(String::from),
+            _ => s["dest"].as_str()?.strip_prefix("app/cargo/vendor/").map(String::from),
+        })
+        .collect();
+    let listed: BTreeSet<String> = listed
+        .iter()
+        .map(|p| match p.kind {
+            SourceKind::Path => p.name.clone(),
+            _ => format!("{}-{}", p.name, p.version),
+        })
+        .collect();
+    assert_eq!(vendored, listed);
+}
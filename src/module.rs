@@ -0,0 +1,385 @@
+use std::collections::BTreeMap;
+
+use cargo_metadata::Metadata;
+
+use crate::cli::Args;
+use crate::script::shell_word;
+use crate::toolchain::{Toolchain, RUST_STABLE};
+
+/// A flatpak-builder module building the workspace from the generated sources
+#[derive(Debug, serde::Serialize)]
+pub struct Module {
+    pub name: String,
+    pub buildsystem: String,
+    #[serde(rename = "build-options")]
+    pub build_options: BuildOptions,
+    #[serde(rename = "build-commands")]
+    pub build_commands: Vec<String>,
+    pub sources: Vec<ModuleSource>,
+    /// The toolchain the workspace pins, which the SDK extension doesn't match exactly
+    #[serde(rename = "x-rust-toolchain", skip_serializing_if = "Option::is_none")]
+    pub x_rust_toolchain: Option<String>,
+}
+
+impl Module {
+    /// Builds with the SDK extension of `toolchain`, noting the pin
+    pub fn pin_toolchain(&mut self, toolchain: &Toolchain) {
+        if toolchain.sdk_extension() != RUST_STABLE {
+            self.build_options.prepend_path = Some(format!("/usr/lib/sdk/{}/bin", toolchain.sdk_extension()));
+        }
+        self.x_rust_toolchain = Some(toolchain.channel.clone());
+    }
+}
+
+/// A source of a module: a generated sources file, or a source of its own
+#[derive(Debug, serde::Serialize)]
+#[serde(untagged)]
+pub enum ModuleSource {
+    File(String),
+    Source(crate::sources::Source),
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct BuildOptions {
+    /// Ahead of the manifest's `append-path`, for another rust extension
+    #[serde(rename = "prepend-path", skip_serializing_if = "Option::is_none")]
+    pub prepend_path: Option<String>,
+    pub env: BTreeMap<String, String>,
+}
+
+/// A binary target of a workspace member
+#[derive(Debug, Clone, PartialEq)]
+pub struct BinTarget {
+    pub package: String,
+    pub name: String,
+}
+
+/// Collects the binary targets of the workspace members, restricted to the
+/// packages selected with `-p` when there are any
+pub fn binary_targets(metadata: &Metadata, selected: &[String]) -> anyhow::Result<Vec<BinTarget>> {
+    for name in selected {
+        if !metadata.workspace_packages().iter().any(|p| &p.name == name) {
+            anyhow::bail!("package `{name}` is not a member of the workspace");
+        }
+    }
+    Ok(metadata
+        .workspace_packages()
+        .into_iter()
+        .filter(|p| selected.is_empty() || selected.contains(&p.name))
+        .flat_map(|p| {
+            p.targets
+                .iter()
+                .filter(|t| t.is_bin())
+                .map(|t| BinTarget {
+                    package: p.name.clone(),
+                    name: t.name.clone(),
+                })
+        })
+        .collect())
+}
+
+/// The cargo profile flag and its directory under `target/`
+fn profile_args(profile: &str) -> (Option<String>, &str) {
+    match profile {
+        "release" => (Some("--release".into()), "release"),
+        "dev" => (None, "debug"),
+        profile => (Some(format!("--profile {}", shell_word(profile))), profile),
+    }
+}
+
+pub fn build_commands(bins: &[BinTarget], args: &Args) -> anyhow::Result<Vec<String>> {
+    for bin in &args.bin {
+        if !bins.iter().any(|b| &b.name == bin) {
+            anyhow::bail!("no binary target named `{bin}`");
+        }
+    }
+    let bins: Vec<&BinTarget> = bins
+        .iter()
+        .filter(|b| args.bin.is_empty() || args.bin.contains(&b.name))
+        .collect();
+    if bins.is_empty() {
+        anyhow::bail!("no binary targets to install, a module needs at least one");
+    }
+    Ok(commands(&bins, &args.package, &args.bin, args))
+}
+
+/// Builds `packages` and the binaries `selected`, all of them when empty, and
+/// installs `bins`
+fn commands(bins: &[&BinTarget], packages: &[String], selected: &[String], args: &Args) -> Vec<String> {
+    let (profile_flag, profile_dir) = profile_args(&args.profile);
+    let mut build = vec!["cargo --offline build".to_string()];
+    build.extend(profile_flag);
+    build.extend(packages.iter().map(|p| format!("-p {}", shell_word(p))));
+    build.extend(selected.iter().map(|b| format!("--bin {}", shell_word(b))));
+    build.extend(args.cargo_arg.iter().map(|arg| shell_word(arg)));
+
+    let mut commands = vec![build.join(" ")];
+    commands.extend(bins.iter().map(|b| {
+        let bin = shell_word(&b.name);
+        format!("install -Dm755 {} /app/bin/{bin}", shell_word(&format!("target/{profile_dir}/{}", b.name)))
+    }));
+    commands
+}
+
+fn simple_module(name: String, build_commands: Vec<String>, sources_files: &[String], args: &Args) -> Module {
+    let mut env = BTreeMap::new();
+    env.insert("CARGO_HOME".into(), format!("/run/build/{name}/{}", args.cargo_home_dir()));
+    Module {
+        name,
+        buildsystem: "simple".into(),
+        build_options: BuildOptions { prepend_path: None, env },
+        build_commands,
+        sources: sources_files.iter().cloned().map(ModuleSource::File).collect(),
+        x_rust_toolchain: None,
+    }
+}
+
+pub fn module(name: &str, bins: &[BinTarget], sources_files: &[String], args: &Args) -> anyhow::Result<Module> {
+    Ok(simple_module(name.into(), build_commands(bins, args)?, sources_files, args))
+}
+
+/// The name of the module of `package`, from --module-name-template
+pub fn module_name(package: &str, args: &Args) -> String {
+    args.module_name_template.replace("{package}", package)
+}
+
+/// A module for each package of `bins`, in their order, building only that
+/// package and installing its binaries. They all use `sources_files`.
+pub fn package_modules(bins: &[BinTarget], sources_files: &[String], args: &Args) -> anyhow::Result<Vec<Module>> {
+    for bin in &args.bin {
+        if !bins.iter().any(|b| &b.name == bin) {
+            anyhow::bail!("no binary target named `{bin}`");
+        }
+    }
+    for package in &args.package {
+        if !bins.iter().any(|b| &b.package == package) {
+            anyhow::bail!("package `{package}` has no binary targets, it can't have a module of its own");
+        }
+    }
+    let mut packages: Vec<&str> = bins.iter().map(|b| b.package.as_str()).collect();
+    packages.dedup();
+    let mut modules = Vec::new();
+    for package in packages {
+        let package_bins: Vec<&BinTarget> = bins
+            .iter()
+            .filter(|b| b.package == package && (args.bin.is_empty() || args.bin.contains(&b.name)))
+            .collect();
+        // --bin picks the binaries of some of the packages only
+        if package_bins.is_empty() {
+            continue;
+        }
+        let selected: Vec<String> = args.bin.iter().filter(|bin| package_bins.iter().any(|b| &b.name == *bin)).cloned().collect();
+        let build_commands = commands(&package_bins, &[package.to_string()], &selected, args);
+        modules.push(simple_module(module_name(package, args), build_commands, sources_files, args));
+    }
+    if modules.is_empty() {
+        anyhow::bail!("no binary targets to install, a module needs at least one");
+    }
+    Ok(modules)
+}
+
+#[cfg(test)]
+fn fixture_metadata(files: &[(&str, &str)]) -> (tempfile::TempDir, Metadata) {
+    let tmp = tempfile::tempdir().unwrap();
+    crate::sources::write_fixture(tmp.path(), files);
+    let metadata = cargo_metadata::MetadataCommand::new()
+        .manifest_path(tmp.path().join("Cargo.toml"))
+        .no_deps()
+        .exec()
+        .unwrap();
+    (tmp, metadata)
+}
+
+#[cfg(test)]
+fn module_args(extra: &[&str]) -> Args {
+    use clap::Parser;
+    Args::parse_from(["flatpak"].iter().chain(extra))
+}
+
+#[test]
+fn single_bin_module() {
+    let (_tmp, metadata) = fixture_metadata(&[
+        ("Cargo.toml", "[package]\nname = \"app\"\nversion = \"0.1.0\"\n"),
+        ("src/main.rs", "fn main() {}\n"),
+    ]);
+    let args = module_args(&[]);
+    let bins = binary_targets(&metadata, &args.package).unwrap();
+
+    let module = module("app", &bins, &["cargo-sources.json".into()], &args).unwrap();
+    assert_eq!(
+        serde_json::to_value(&module).unwrap(),
+        serde_json::json!({
+            "name": "app",
+            "buildsystem": "simple",
+            "build-options": {"env": {"CARGO_HOME": "/run/build/app/cargo"}},
+            "build-commands": [
+                "cargo --offline build --release",
+                "install -Dm755 target/release/app /app/bin/app",
+            ],
+            "sources": ["cargo-sources.json"],
+        })
+    );
+}
+
+#[test]
+fn multi_bin_module() {
+    let package = |name: &str| format!("[package]\nname = \"{name}\"\nversion = \"0.1.0\"\n");
+    let (_tmp, metadata) = fixture_metadata(&[
+        ("Cargo.toml", "[workspace]\nmembers = [\"app\", \"tools\", \"lib\"]\n"),
+        ("app/Cargo.toml", &package("app")),
+        ("app/src/main.rs", "fn main() {}\n"),
+        ("tools/Cargo.toml", &package("tools")),
+        ("tools/src/bin/tool-a.rs", "fn main() {}\n"),
+        ("tools/src/bin/tool-b.rs", "fn main() {}\n"),
+        ("lib/Cargo.toml", &package("lib")),
+        ("lib/src/lib.rs", ""),
+    ]);
+
+    let args = module_args(&[]);
+    let bins = binary_targets(&metadata, &args.package).unwrap();
+    assert_eq!(
+        build_commands(&bins, &args).unwrap(),
+        [
+            "cargo --offline build --release",
+            "install -Dm755 target/release/app /app/bin/app",
+            "install -Dm755 target/release/tool-a /app/bin/tool-a",
+            "install -Dm755 target/release/tool-b /app/bin/tool-b",
+        ]
+    );
+
+    let args = module_args(&["-p", "tools", "--bin", "tool-b", "--profile", "dev"]);
+    let bins = binary_targets(&metadata, &args.package).unwrap();
+    assert_eq!(
+        build_commands(&bins, &args).unwrap(),
+        [
+            "cargo --offline build -p tools --bin tool-b",
+            "install -Dm755 target/debug/tool-b /app/bin/tool-b",
+        ]
+    );
+
+    let args = module_args(&["-p", "lib"]);
+    let bins = binary_targets(&metadata, &args.package).unwrap();
+    assert!(build_commands(&bins, &args).unwrap_err().to_string().contains("no binary targets"));
+}
+
+#[test]
+fn custom_profile_and_cargo_args() {
+    let (_tmp, metadata) = fixture_metadata(&[
+        ("Cargo.toml", "[package]\nname = \"app\"\nversion = \"0.1.0\"\n\n[profile.dist]\ninherits = \"release\"\n"),
+        ("src/main.rs", "fn main() {}\n"),
+    ]);
+    let args = module_args(&[
+        "--profile",
+        "dist",
+        "--cargo-arg",
+        "--locked",
+        "--cargo-arg=--features",
+        "--cargo-arg",
+        "gui wayland",
+        "--cargo-arg",
+        "--config=profile.dist.debug='full'",
+    ]);
+    let bins = binary_targets(&metadata, &args.package).unwrap();
+    assert_eq!(
+        build_commands(&bins, &args).unwrap(),
+        [
+            r#"cargo --offline build --profile dist --locked --features 'gui wayland' '--config=profile.dist.debug='\''full'\'''"#,
+            "install -Dm755 target/dist/app /app/bin/app",
+        ]
+    );
+}
+
+#[test]
+fn per_package_modules() {
+    let package = |name: &str| format!("[package]\nname = \"{name}\"\nversion = \"0.1.0\"\n");
+    let (_tmp, metadata) = fixture_metadata(&[
+        ("Cargo.toml", "[workspace]\nmembers = [\"app-a\", \"app-b\", \"common\"]\n"),
+        ("app-a/Cargo.toml", &package("app-a")),
+        ("app-a/src/main.rs", "fn main() {}\n"),
+        ("app-b/Cargo.toml", &package("app-b")),
+        ("app-b/src/main.rs", "fn main() {}\n"),
+        ("app-b/src/bin/app-b-helper.rs", "fn main() {}\n"),
+        ("common/Cargo.toml", &package("common")),
+        ("common/src/lib.rs", ""),
+    ]);
+    let module = |name: &str, commands: &[&str]| {
+        serde_json::json!({
+            "name": name,
+            "buildsystem": "simple",
+            "build-options": {"env": {"CARGO_HOME": format!("/run/build/{name}/cargo")}},
+            "build-commands": commands,
+            "sources": ["cargo-sources.json"],
+        })
+    };
+    let sources = ["cargo-sources.json".to_string()];
+
+    let args = module_args(&["--module", "-p", "app-a", "-p", "app-b", "--per-package-modules", "--module-name-template", "org.example.{package}"]);
+    let bins = binary_targets(&metadata, &args.package).unwrap();
+    assert_eq!(
+        serde_json::to_value(package_modules(&bins, &sources, &args).unwrap()).unwrap(),
+        serde_json::json!([
+            module(
+                "org.example.app-a",
+                &["cargo --offline build --release -p app-a", "install -Dm755 target/release/app-a /app/bin/app-a"],
+            ),
+            module(
+                "org.example.app-b",
+                &[
+                    "cargo --offline build --release -p app-b",
+                    "install -Dm755 target/release/app-b /app/bin/app-b",
+                    "install -Dm755 target/release/app-b-helper /app/bin/app-b-helper",
+                ],
+            ),
+        ])
+    );
+
+    // Every package with a binary by default, the others left out; --bin narrows it down
+    let args = module_args(&["--module", "--per-package-modules", "--bin", "app-b-helper"]);
+    let bins = binary_targets(&metadata, &args.package).unwrap();
+    assert_eq!(
+        serde_json::to_value(package_modules(&bins, &sources, &args).unwrap()).unwrap(),
+        serde_json::json!([module(
+            "app-b",
+            &[
+                "cargo --offline build --release -p app-b --bin app-b-helper",
+                "install -Dm755 target/release/app-b-helper /app/bin/app-b-helper",
+            ],
+        )])
+    );
+
+    let args = module_args(&["--module", "--per-package-modules", "-p", "app-a", "-p", "common"]);
+    let bins = binary_targets(&metadata, &args.package).unwrap();
+    let err = package_modules(&bins, &sources, &args).unwrap_err().to_string();
+    assert_eq!(err, "package `common` has no binary targets, it can't have a module of its own");
+}
+
+#[test]
+fn pinned_nightly_module() {
+    use crate::diagnostics::{capture, ErrorFormat};
+
+    let (tmp, metadata) = fixture_metadata(&[
+        ("Cargo.toml", "[package]\nname = \"app\"\nversion = \"0.1.0\"\n"),
+        ("src/main.rs", "fn main() {}\n"),
+        ("rust-toolchain.toml", "[toolchain]\nchannel = \"nightly-2024-05-01\"\n"),
+    ]);
+    let args = module_args(&["--module"]);
+    let bins = binary_targets(&metadata, &args.package).unwrap();
+    let toolchain = crate::toolchain::pinned(tmp.path()).unwrap().unwrap();
+    let ((), warnings) = capture(ErrorFormat::Human, || toolchain.warn());
+    assert!(warnings.starts_with("warning: rust-toolchain.toml pins the toolchain nightly-2024-05-01, the build needs the rust-nightly SDK extension"));
+
+    let mut module = module("app", &bins, &["cargo-sources.json".into()], &args).unwrap();
+    module.pin_toolchain(&toolchain);
+    let module = serde_json::to_value(&module).unwrap();
+    assert_eq!(
+        module["build-options"],
+        serde_json::json!({"prepend-path": "/usr/lib/sdk/rust-nightly/bin", "env": {"CARGO_HOME": "/run/build/app/cargo"}})
+    );
+    assert_eq!(module["x-rust-toolchain"], "nightly-2024-05-01");
+
+    // A stable version pin is only noted, there's no extension to switch to
+    let mut versioned = crate::module::module("app", &bins, &["cargo-sources.json".into()], &args).unwrap();
+    versioned.pin_toolchain(&crate::toolchain::Toolchain { file: "rust-toolchain", channel: "1.75.0".into() });
+    assert_eq!(versioned.build_options.prepend_path, None);
+    assert_eq!(versioned.x_rust_toolchain.as_deref(), Some("1.75.0"));
+}
@@ -0,0 +1,471 @@
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// Timeout of a single network request, unless --network-timeout says otherwise
+pub const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How requests are retried, by --network-retries and --network-timeout
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Retry {
+    /// Attempts after the first one
+    pub retries: u32,
+    /// Timeout of each attempt
+    pub timeout: Duration,
+    /// Wait before the first retry, doubled before each next one
+    pub backoff: Duration,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        // The tests retry without waiting on the clock
+        let backoff = if cfg!(test) { Duration::from_millis(1) } else { Duration::from_secs(1) };
+        Retry { retries: 3, timeout: TIMEOUT, backoff }
+    }
+}
+
+/// A request that failed for good
+#[derive(Debug)]
+pub enum RequestError {
+    /// An HTTP error status, which another attempt wouldn't change
+    Status(u16),
+    /// A failure another attempt wouldn't change either, like a name that
+    /// doesn't resolve or a refused connection
+    Failed { url: String, error: String },
+    /// Every attempt failed, the last one with `error`
+    GaveUp { url: String, attempts: u32, error: String },
+}
+
+impl std::fmt::Display for RequestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RequestError::Status(code) => write!(f, "HTTP {code}"),
+            RequestError::Failed { url, error } => write!(f, "{url}: {error}"),
+            RequestError::GaveUp { url, attempts, error } => write!(f, "gave up on {url} after {attempts} attempts: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for RequestError {}
+
+/// Statuses of servers overloaded or failing for now, which are retried. A
+/// 501 is for good, and tells a server without HEAD.
+fn transient(code: u16) -> bool {
+    code == 429 || (500..600).contains(&code) && code != 501
+}
+
+/// Whether a request failed on a connection that timed out or was cut, which
+/// are retried, rather than one that couldn't be made at all
+fn interrupted(transport: &ureq::Transport) -> bool {
+    use std::io::ErrorKind;
+
+    let mut source = std::error::Error::source(transport);
+    while let Some(error) = source {
+        if let Some(io) = error.downcast_ref::<std::io::Error>() {
+            return matches!(
+                io.kind(),
+                ErrorKind::TimedOut
+                    | ErrorKind::WouldBlock
+                    | ErrorKind::ConnectionReset
+                    | ErrorKind::ConnectionAborted
+                    | ErrorKind::BrokenPipe
+                    | ErrorKind::UnexpectedEof
+            );
+        }
+        source = error.source();
+    }
+    false
+}
+
+/// Where requests are sent through: `--proxy`, else cargo's `http.proxy`, else the
+/// `https_proxy`/`http_proxy`/`all_proxy` of the environment. Hosts matching
+/// `no_proxy` are always reached directly.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ProxyConfig {
+    /// --proxy or cargo's `http.proxy`, for every scheme
+    pub proxy: Option<String>,
+    pub http: Option<String>,
+    pub https: Option<String>,
+    pub no_proxy: Vec<String>,
+}
+
+impl ProxyConfig {
+    /// Reads the proxy variables through `env`, lowercase names first. Like curl, only the
+    /// lowercase `http_proxy` is used, `HTTP_PROXY` can be set by a CGI request.
+    pub fn new(flag: Option<&str>, cargo_proxy: Option<String>, env: impl Fn(&str) -> Option<String>) -> Self {
+        let var = |name: &str| env(name).or_else(|| env(&name.to_uppercase())).filter(|v| !v.is_empty());
+        let all = var("all_proxy");
+        ProxyConfig {
+            proxy: flag.map(String::from).or(cargo_proxy).filter(|p| !p.is_empty()),
+            http: env("http_proxy").filter(|v| !v.is_empty()).or_else(|| all.clone()),
+            https: var("https_proxy").or(all),
+            no_proxy: var("no_proxy")
+                .iter()
+                .flat_map(|hosts| hosts.split(','))
+                .map(|host| host.trim().to_string())
+                .filter(|host| !host.is_empty())
+                .collect(),
+        }
+    }
+
+    /// The proxy requests to `url` go through, `None` for a direct connection
+    pub fn proxy_for(&self, url: &str) -> Option<&str> {
+        let url = url::Url::parse(url).ok()?;
+        let host = url.host_str()?.trim_start_matches('[').trim_end_matches(']');
+        let bypassed = self.no_proxy.iter().any(|entry| {
+            let entry = entry.trim_start_matches('.');
+            entry == "*" || host == entry || host.strip_suffix(entry).is_some_and(|rest| rest.ends_with('.'))
+        });
+        if bypassed {
+            return None;
+        }
+        self.proxy.as_deref().or(match url.scheme() {
+            "https" => self.https.as_deref(),
+            "http" => self.http.as_deref(),
+            _ => None,
+        })
+    }
+
+    /// The environment of the git and cargo subprocesses, so they use the same proxies
+    pub fn env(&self) -> Vec<(&'static str, String)> {
+        let mut env = Vec::new();
+        if let Some(http) = self.proxy.as_ref().or(self.http.as_ref()) {
+            env.push(("http_proxy", http.clone()));
+        }
+        if let Some(https) = self.proxy.as_ref().or(self.https.as_ref()) {
+            env.push(("https_proxy", https.clone()));
+        }
+        if let Some(proxy) = &self.proxy {
+            // Cargo prefers its `http.proxy` over the environment, which --proxy overrides
+            env.push(("CARGO_HTTP_PROXY", proxy.clone()));
+        }
+        if !self.no_proxy.is_empty() {
+            env.push(("no_proxy", self.no_proxy.join(",")));
+        }
+        env
+    }
+}
+
+/// Cargo's `http.proxy`, from `CARGO_HTTP_PROXY` or the config files cargo reads in
+/// `dir`: `.cargo/config.toml` of it and its parents, then CARGO_HOME's
+pub fn cargo_http_proxy(dir: &Path) -> Option<String> {
+    if let Ok(proxy) = std::env::var("CARGO_HTTP_PROXY") {
+        return Some(proxy);
+    }
+    let cargo_home = std::env::var_os("CARGO_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".cargo")));
+    dir.ancestors()
+        .map(|dir| dir.join(".cargo"))
+        .chain(cargo_home)
+        .flat_map(|dir| [dir.join("config.toml"), dir.join("config")])
+        .find_map(|path| {
+            let config: toml::Table = toml::from_str(&std::fs::read_to_string(path).ok()?).ok()?;
+            config.get("http")?.get("proxy")?.as_str().map(String::from)
+        })
+}
+
+/// Sends requests through the proxy configured for their URL
+pub struct Client {
+    config: ProxyConfig,
+    retry: Retry,
+    // Building an agent loads the native certificates, so there's one per proxy
+    agents: Mutex<HashMap<Option<String>, ureq::Agent>>,
+}
+
+impl Client {
+    /// Fails on proxies that aren't valid URLs
+    pub fn new(config: ProxyConfig, retry: Retry) -> anyhow::Result<Self> {
+        for proxy in [&config.proxy, &config.http, &config.https].into_iter().flatten() {
+            ureq::Proxy::new(proxy).map_err(|e| anyhow::anyhow!("invalid proxy `{proxy}`: {e}"))?;
+        }
+        Ok(Client { config, retry, agents: Mutex::default() })
+    }
+
+    pub fn retry(&self) -> Retry {
+        self.retry
+    }
+
+    /// The environment of the git and cargo subprocesses, so they use the
+    /// same proxies, and cargo the same retries and timeout
+    pub fn env(&self) -> Vec<(&'static str, String)> {
+        let mut env = self.config.env();
+        env.push(("CARGO_NET_RETRY", self.retry.retries.to_string()));
+        env.push(("CARGO_HTTP_TIMEOUT", self.retry.timeout.as_secs().to_string()));
+        env
+    }
+
+    fn agent(&self, url: &str) -> ureq::Agent {
+        let proxy = self.config.proxy_for(url).map(String::from);
+        let mut agents = self.agents.lock().unwrap();
+        let agent = agents.entry(proxy).or_insert_with_key(|proxy| {
+            let builder = ureq::AgentBuilder::new().timeout(self.retry.timeout).redirects(10);
+            match proxy {
+                // Validated by `Client::new`
+                Some(proxy) => builder.proxy(ureq::Proxy::new(proxy).unwrap()),
+                None => builder,
+            }
+            .build()
+        });
+        agent.clone()
+    }
+
+    /// Sends the request `request` makes with the agent for `url`, retrying
+    /// timeouts, cut connections and transient statuses with exponential backoff
+    pub fn send(&self, url: &str, request: impl Fn(&ureq::Agent) -> ureq::Request) -> Result<ureq::Response, RequestError> {
+        let agent = self.agent(url);
+        let mut backoff = self.retry.backoff;
+        for attempt in 1.. {
+            let error = match request(&agent).call() {
+                Ok(response) => return Ok(response),
+                Err(ureq::Error::Status(code, _)) if !transient(code) => return Err(RequestError::Status(code)),
+                Err(ureq::Error::Status(code, _)) => format!("HTTP {code}"),
+                Err(ureq::Error::Transport(transport)) => {
+                    let error = match transport.message() {
+                        Some(message) => format!("{}: {message}", transport.kind()),
+                        None => transport.kind().to_string(),
+                    };
+                    if !interrupted(&transport) {
+                        return Err(RequestError::Failed { url: url.to_string(), error });
+                    }
+                    error
+                }
+            };
+            if attempt > self.retry.retries {
+                return Err(RequestError::GaveUp { url: url.to_string(), attempts: attempt, error });
+            }
+            std::thread::sleep(backoff);
+            backoff *= 2;
+        }
+        unreachable!("the attempts end in a response or an error")
+    }
+
+    pub fn get(&self, url: &str) -> Result<ureq::Response, RequestError> {
+        self.send(url, |agent| agent.get(url))
+    }
+
+    pub fn head(&self, url: &str) -> Result<ureq::Response, RequestError> {
+        self.send(url, |agent| agent.head(url))
+    }
+}
+
+static CLIENT: OnceLock<Client> = OnceLock::new();
+
+/// Sets the proxies and retries of `client()`, once before any request is made
+pub fn configure(config: ProxyConfig, retry: Retry) -> anyhow::Result<()> {
+    let client = Client::new(config, retry)?;
+    if CLIENT.set(client).is_err() {
+        anyhow::bail!("the HTTP client is already configured");
+    }
+    Ok(())
+}
+
+/// The HTTP client every network operation goes through
+pub fn client() -> &'static Client {
+    CLIENT.get_or_init(|| {
+        Client::new(ProxyConfig::new(None, None, |name| std::env::var(name).ok()), Retry::default())
+            .unwrap_or_else(|e| {
+                crate::diagnostics::warn("proxy", format!("{e}, connecting directly"));
+                Client::new(ProxyConfig::default(), Retry::default()).unwrap()
+            })
+    })
+}
+
+/// `git` with the proxies of `client()`
+pub fn git() -> Command {
+    let mut command = Command::new("git");
+    command.envs(client().env());
+    command
+}
+
+/// The version of the layout of the caches, bumped when one of them changes
+/// format so the caches of the old layout are left to `cache prune`
+pub const CACHE_VERSION: u32 = 1;
+
+static CACHE_ROOT: OnceLock<PathBuf> = OnceLock::new();
+
+/// Keeps the caches in `root`, for --cache-dir, once before any of them is used
+pub fn set_cache_root(root: PathBuf) -> anyhow::Result<()> {
+    if CACHE_ROOT.set(root).is_err() {
+        anyhow::bail!("the cache directory is already set");
+    }
+    Ok(())
+}
+
+/// --cache-dir, or else `$XDG_CACHE_HOME/cargo-flatpak`, with a directory per
+/// layout version
+pub fn cache_root() -> Option<PathBuf> {
+    // The tests share a temporary one, and never see the user's caches
+    #[cfg(test)]
+    CACHE_ROOT.get_or_init(|| std::env::temp_dir().join(format!("cargo-flatpak-tests-{}", std::process::id())));
+    if let Some(root) = CACHE_ROOT.get() {
+        return Some(root.clone());
+    }
+    let cache = std::env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))?;
+    Some(cache.join("cargo-flatpak"))
+}
+
+/// The caches of the current layout in `cache_root()`, what's looked up on the
+/// network is kept there
+pub fn cache_dir() -> Option<PathBuf> {
+    Some(cache_root()?.join(format!("v{CACHE_VERSION}")))
+}
+
+/// Maps `f` over `items` on `jobs` threads, keeping the order of `items`
+pub fn parallel<T: Sync, R: Send>(items: &[T], jobs: usize, f: impl Fn(&T) -> R + Sync) -> Vec<R> {
+    let queue = Mutex::new(items.iter().enumerate());
+    let results = Mutex::new(Vec::with_capacity(items.len()));
+    std::thread::scope(|scope| {
+        for _ in 0..jobs.clamp(1, items.len().max(1)) {
+            scope.spawn(|| loop {
+                let Some((i, item)) = queue.lock().unwrap().next() else {
+                    break;
+                };
+                let result = f(item);
+                results.lock().unwrap().push((i, result));
+            });
+        }
+    });
+    let mut results = results.into_inner().unwrap();
+    results.sort_by_key(|(i, _)| *i);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+/// Runs a command, killing it if it's still running after `timeout`
+pub fn output_with_timeout(command: &mut Command, timeout: Duration) -> anyhow::Result<Output> {
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    // Drain the pipes while waiting so a chatty child can't block on a full pipe
+    let drain = |mut pipe: Box<dyn Read + Send>| {
+        std::thread::spawn(move || {
+            let mut buf = Vec::new();
+            pipe.read_to_end(&mut buf).map(|_| buf)
+        })
+    };
+    let stdout = drain(Box::new(child.stdout.take().unwrap()));
+    let stderr = drain(Box::new(child.stderr.take().unwrap()));
+    let start = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Output {
+                status,
+                stdout: stdout.join().unwrap()?,
+                stderr: stderr.join().unwrap()?,
+            });
+        }
+        if start.elapsed() > timeout {
+            child.kill()?;
+            child.wait()?;
+            anyhow::bail!("timed out after {}s", timeout.as_secs());
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    }
+}
+
+#[test]
+fn proxy_config() {
+    let env = |vars: &'static [(&str, &str)]| {
+        move |name: &str| vars.iter().find(|(var, _)| *var == name).map(|(_, value)| value.to_string())
+    };
+    let config = ProxyConfig::new(
+        None,
+        None,
+        env(&[("HTTPS_PROXY", "http://proxy:3128"), ("HTTP_PROXY", "http://cgi"), ("no_proxy", "localhost, .corp.example")]),
+    );
+    assert_eq!(config.proxy_for("https://static.crates.io/crates/a"), Some("http://proxy:3128"));
+    assert_eq!(config.proxy_for("http://example.com"), None);
+    assert_eq!(config.proxy_for("https://git.corp.example/a.git"), None);
+    assert_eq!(config.proxy_for("https://corp.example"), None);
+    assert_eq!(config.proxy_for("https://notcorp.example"), Some("http://proxy:3128"));
+    assert_eq!(config.proxy_for("https://localhost:8080"), None);
+    assert_eq!(
+        config.env(),
+        [("https_proxy", "http://proxy:3128".to_string()), ("no_proxy", "localhost,.corp.example".to_string())]
+    );
+
+    let cargo = ProxyConfig::new(None, Some("http://cargo:8080".into()), env(&[("all_proxy", "http://all")]));
+    assert_eq!(cargo.proxy_for("http://example.com"), Some("http://cargo:8080"));
+    let flag = ProxyConfig::new(Some("http://flag"), Some("http://cargo:8080".into()), env(&[("no_proxy", "*")]));
+    assert_eq!(flag.proxy_for("https://example.com"), None);
+    assert!(flag.env().contains(&("CARGO_HTTP_PROXY", "http://flag".to_string())));
+    assert_eq!(ProxyConfig::new(None, None, env(&[("all_proxy", "http://all")])).proxy_for("http://a"), Some("http://all"));
+
+    assert!(Client::new(ProxyConfig { proxy: Some("ftp://proxy:21".into()), ..Default::default() }, Retry::default()).is_err());
+}
+
+#[test]
+fn requests_go_through_proxy() {
+    // A proxy stub answering every request itself, recording the URLs
+    let requests = std::sync::Arc::new(Mutex::new(Vec::new()));
+    let recorded = requests.clone();
+    let (proxy, _) = crate::index::serve_with(move |method, url| {
+        recorded.lock().unwrap().push(format!("{method} {url}"));
+        (200, b"ok".to_vec())
+    });
+
+    let env = |name: &str| match name {
+        "http_proxy" => Some(proxy.clone()),
+        "no_proxy" => Some("direct.invalid".into()),
+        _ => None,
+    };
+    let retry = Retry { retries: 0, ..Retry::default() };
+    let client = Client::new(ProxyConfig::new(None, None, env), retry).unwrap();
+    let body = client.get("http://crates.invalid/a/b.crate").unwrap().into_string().unwrap();
+    assert_eq!(body, "ok");
+    assert!(client.head("http://direct.invalid/").is_err());
+    assert_eq!(*requests.lock().unwrap(), ["GET http://crates.invalid/a/b.crate"]);
+}
+
+#[test]
+fn retried_requests() {
+    use std::sync::atomic::Ordering;
+
+    // Fails the first two requests of each path, except `/missing` which is a
+    // 404, and answers the first one of `/slow` late
+    let mut seen: HashMap<String, usize> = HashMap::new();
+    let (server, requests) = crate::index::serve_with(move |_, path| {
+        let count = seen.entry(path.to_string()).or_default();
+        *count += 1;
+        match (path, *count) {
+            ("/missing", _) => (404, Vec::new()),
+            ("/slow", 1) => {
+                std::thread::sleep(Duration::from_millis(200));
+                (200, b"ok".to_vec())
+            }
+            ("/slow", _) => (200, b"ok".to_vec()),
+            ("/down", _) | (_, 1 | 2) => (503, Vec::new()),
+            _ => (200, b"ok".to_vec()),
+        }
+    });
+    let retry = Retry { retries: 3, timeout: TIMEOUT, backoff: Duration::from_millis(10) };
+    let client = Client::new(ProxyConfig::default(), retry).unwrap();
+
+    assert_eq!(client.get(&format!("{server}flaky")).unwrap().into_string().unwrap(), "ok");
+    assert_eq!(requests.swap(0, Ordering::SeqCst), 3);
+    // Statuses another attempt won't change aren't retried
+    assert!(matches!(client.head(&format!("{server}missing")), Err(RequestError::Status(404))));
+    assert_eq!(requests.swap(0, Ordering::SeqCst), 1);
+
+    let client = Client::new(ProxyConfig::default(), Retry { retries: 1, ..retry }).unwrap();
+    let err = client.get(&format!("{server}down")).unwrap_err();
+    assert_eq!(err.to_string(), format!("gave up on {server}down after 2 attempts: HTTP 503"));
+    assert_eq!(requests.swap(0, Ordering::SeqCst), 2);
+    // Nor are connections that can't be made
+    let unreachable = client.get("http://127.0.0.1:1/").unwrap_err();
+    assert!(matches!(&unreachable, RequestError::Failed { url, .. } if url == "http://127.0.0.1:1/"), "{unreachable}");
+    // Timeouts are, after the server's done with the late answer
+    let retry = Retry { timeout: Duration::from_millis(100), backoff: Duration::from_millis(300), ..retry };
+    let client = Client::new(ProxyConfig::default(), retry).unwrap();
+    assert_eq!(client.get(&format!("{server}slow")).unwrap().into_string().unwrap(), "ok");
+    assert_eq!(requests.swap(0, Ordering::SeqCst), 2);
+
+    assert_eq!(client.env()[client.env().len() - 2..], [("CARGO_NET_RETRY", "3".into()), ("CARGO_HTTP_TIMEOUT", "0".into())]);
+}
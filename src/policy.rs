@@ -0,0 +1,257 @@
+use std::collections::{HashMap, VecDeque};
+
+use cargo_metadata::{semver, Metadata, PackageId};
+use clap::ValueEnum;
+
+use crate::sources::{git_reference, Package};
+
+/// A crate banned from the vendored set, `name[@version-req]` on the command
+/// line. The name is a glob pattern.
+#[derive(Debug, Clone)]
+pub struct ForbidRule {
+    pub name: glob::Pattern,
+    pub req: Option<semver::VersionReq>,
+}
+
+pub fn parse_forbid_rule(rule: &str) -> Result<ForbidRule, String> {
+    let (name, req) = match rule.split_once('@') {
+        Some((name, req)) => (name, Some(req.parse().map_err(|e| format!("invalid version requirement: {e}"))?)),
+        None => (rule, None),
+    };
+    let name = glob::Pattern::new(name).map_err(|e| format!("invalid crate pattern: {e}"))?;
+    Ok(ForbidRule { name, req })
+}
+
+impl std::fmt::Display for ForbidRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.req {
+            Some(req) => write!(f, "{}@{req}", self.name),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SourceKind {
+    Registry,
+    Git,
+    Path,
+}
+
+impl SourceKind {
+    pub fn of(package: &Package) -> SourceKind {
+        match package.source.as_deref() {
+            Some(source) if source.starts_with("git+") => SourceKind::Git,
+            Some(_) => SourceKind::Registry,
+            None => SourceKind::Path,
+        }
+    }
+}
+
+impl ForbidRule {
+    fn matches(&self, package: &Package) -> bool {
+        if !self.name.matches(&package.name) {
+            return false;
+        }
+        match (&self.req, semver::Version::parse(&package.version)) {
+            (Some(req), Ok(version)) => req.matches(&version),
+            (Some(_), Err(_)) => false,
+            (None, _) => true,
+        }
+    }
+}
+
+/// The shortest chain of dependencies from a workspace member to `name@version`
+pub fn dependency_path(metadata: &Metadata, name: &str, version: &str) -> Option<Vec<String>> {
+    let resolve = metadata.resolve.as_ref()?;
+    let packages: HashMap<&PackageId, _> = metadata.packages.iter().map(|p| (&p.id, p)).collect();
+    let nodes: HashMap<&PackageId, _> = resolve.nodes.iter().map(|n| (&n.id, n)).collect();
+    let label = |id: &PackageId| packages.get(id).map(|p| format!("{} {}", p.name, p.version));
+
+    let mut parents: HashMap<&PackageId, &PackageId> = HashMap::new();
+    let mut queue: VecDeque<&PackageId> = metadata.workspace_members.iter().collect();
+    while let Some(id) = queue.pop_front() {
+        let package = packages.get(id)?;
+        if package.name == name && package.version.to_string() == version {
+            let mut path = vec![label(id)?];
+            let mut id = id;
+            while let Some(parent) = parents.get(id) {
+                path.push(label(parent)?);
+                id = parent;
+            }
+            path.reverse();
+            return Some(path);
+        }
+        for dep in nodes.get(id).map(|n| n.dependencies.as_slice()).unwrap_or_default() {
+            if !metadata.workspace_members.contains(dep) && !parents.contains_key(dep) {
+                parents.insert(dep, id);
+                queue.push_back(dep);
+            }
+        }
+    }
+    None
+}
+
+/// Whether `--allow-git` exempts a git package, by its crate name or its repository
+fn allowed_git(package: &Package, repository: &str, allow: &[String]) -> bool {
+    let normalize = |url: &str| url.trim_end_matches('/').trim_end_matches(".git").to_lowercase();
+    allow.iter().any(|allow| match allow.contains("://") {
+        true => normalize(allow) == normalize(repository),
+        false => *allow == package.name,
+    })
+}
+
+/// Checks the vendored packages against the forbidden crates and source kinds,
+/// failing with every violation and what pulls it in. Git packages matching
+/// `allow_git` are exempt from a forbidden git source kind.
+pub fn check_forbidden(
+    packages: &[&Package],
+    metadata: &Metadata,
+    rules: &[ForbidRule],
+    kinds: &[SourceKind],
+    allow_git: &[String],
+) -> anyhow::Result<()> {
+    let members: Vec<_> = metadata
+        .workspace_packages()
+        .iter()
+        .map(|p| (p.name.clone(), p.version.to_string()))
+        .collect();
+    let mut violations = Vec::new();
+    for package in packages {
+        if members.contains(&(package.name.clone(), package.version.clone())) {
+            continue;
+        }
+        let mut reasons: Vec<String> = rules
+            .iter()
+            .filter(|rule| rule.matches(package))
+            .map(|rule| format!("forbidden by `{rule}`"))
+            .collect();
+        let kind = SourceKind::of(package);
+        let repository = match kind {
+            SourceKind::Git => package.source.as_deref().and_then(git_reference).map(|(url, _)| url),
+            _ => None,
+        };
+        match &repository {
+            Some(repository) if allowed_git(package, repository, allow_git) => {}
+            _ if !kinds.contains(&kind) => {}
+            Some(repository) => reasons.push(format!("git sources are forbidden ({repository})")),
+            None => reasons.push(format!("{} sources are forbidden", kind.to_possible_value().unwrap().get_name())),
+        }
+        if reasons.is_empty() {
+            continue;
+        }
+        let path = dependency_path(metadata, &package.name, &package.version)
+            .map(|path| path.join(" -> "))
+            .unwrap_or_else(|| "not in the dependency graph".into());
+        violations.push(format!("{} {}: {}\n    {path}", package.name, package.version, reasons.join(", ")));
+    }
+    if !violations.is_empty() {
+        anyhow::bail!("{} forbidden packages are vendored:\n{}", violations.len(), violations.join("\n"));
+    }
+    Ok(())
+}
+
+#[test]
+fn forbidden_packages() {
+    let tmp = tempfile::tempdir().unwrap();
+    let package = |name: &str, version: &str, deps: &str| {
+        format!("[package]\nname = \"{name}\"\nversion = \"{version}\"\n\n[dependencies]\n{deps}")
+    };
+    let files = [
+        ("Cargo.toml", package("app", "0.1.0", "middle = { path = \"deps/middle\" }\n")),
+        ("src/main.rs", "fn main() {}\n".into()),
+        ("deps/middle/Cargo.toml", package("middle", "1.4.0", "openssl-src = { path = \"../openssl-src\" }\n")),
+        ("deps/middle/src/lib.rs", String::new()),
+        ("deps/openssl-src/Cargo.toml", package("openssl-src", "300.0.0", "")),
+        ("deps/openssl-src/src/lib.rs", String::new()),
+    ];
+    crate::sources::write_fixture(tmp.path(), &files);
+    let metadata = cargo_metadata::MetadataCommand::new()
+        .manifest_path(tmp.path().join("Cargo.toml"))
+        .exec()
+        .unwrap();
+    let packages: Vec<Package> = metadata
+        .packages
+        .iter()
+        .map(|p| Package {
+            name: p.name.clone(),
+            version: p.version.to_string(),
+            source: None,
+            checksum: None,
+            checksum_from_index: false,
+            dependencies: None,
+        })
+        .collect();
+    let packages: Vec<_> = packages.iter().collect();
+    let rules = |rules: &[&str]| -> Vec<ForbidRule> { rules.iter().map(|r| parse_forbid_rule(r).unwrap()).collect() };
+
+    assert!(check_forbidden(&packages, &metadata, &rules(&["openssl-sys", "middle@>=2"]), &[], &[]).is_ok());
+
+    let err = check_forbidden(&packages, &metadata, &rules(&["openssl-*", "middle@>=1.2, <1.5"]), &[], &[])
+        .unwrap_err()
+        .to_string();
+    assert!(err.starts_with("2 forbidden packages are vendored:"), "{err}");
+    assert!(err.contains("middle 1.4.0: forbidden by `middle@>=1.2, <1.5`\n    app 0.1.0 -> middle 1.4.0"), "{err}");
+    assert!(
+        err.contains("openssl-src 300.0.0: forbidden by `openssl-*`\n    app 0.1.0 -> middle 1.4.0 -> openssl-src 300.0.0"),
+        "{err}"
+    );
+    assert!(!err.contains("app 0.1.0:"));
+
+    let err = check_forbidden(&packages, &metadata, &[], &[SourceKind::Path], &[]).unwrap_err().to_string();
+    assert!(err.contains("openssl-src 300.0.0: path sources are forbidden"), "{err}");
+}
+
+#[test]
+fn deny_git_sources() {
+    use clap::Parser;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let package = |name: &str, deps: &str| format!("[package]\nname = \"{name}\"\nversion = \"0.1.0\"\n\n[dependencies]\n{deps}");
+    let files = [
+        ("Cargo.toml", package("app", "middle = { path = \"deps/middle\" }\n")),
+        ("src/main.rs", "fn main() {}\n".into()),
+        ("deps/middle/Cargo.toml", package("middle", "gitdep = { path = \"../gitdep\" }\n")),
+        ("deps/middle/src/lib.rs", String::new()),
+        ("deps/gitdep/Cargo.toml", package("gitdep", "")),
+        ("deps/gitdep/src/lib.rs", String::new()),
+    ];
+    crate::sources::write_fixture(tmp.path(), &files);
+    let metadata = cargo_metadata::MetadataCommand::new()
+        .manifest_path(tmp.path().join("Cargo.toml"))
+        .exec()
+        .unwrap();
+    // The lockfile has gitdep from a repository, middle from the registry
+    let commit = "0123456789abcdef0123456789abcdef01234567";
+    let packages = [
+        ("middle", Some("registry+https://github.com/rust-lang/crates.io-index".to_string())),
+        ("gitdep", Some(format!("git+https://github.com/org/gitdep.git?branch=main#{commit}"))),
+    ]
+    .map(|(name, source)| Package {
+        name: name.into(),
+        version: "0.1.0".into(),
+        source,
+        checksum: None,
+        checksum_from_index: false,
+        dependencies: None,
+    });
+    let packages: Vec<_> = packages.iter().collect();
+    let check = |flags: &[&str]| {
+        let args = crate::cli::Args::parse_from([&["cargo-flatpak"], flags].concat());
+        check_forbidden(&packages, &metadata, &args.forbid, &args.forbidden_sources(), &args.allow_git)
+    };
+
+    assert!(check(&[]).is_ok());
+    let err = check(&["--deny-git-sources"]).unwrap_err().to_string();
+    assert_eq!(
+        err,
+        "1 forbidden packages are vendored:\n\
+         gitdep 0.1.0: git sources are forbidden (https://github.com/org/gitdep)\n    \
+         app 0.1.0 -> middle 0.1.0 -> gitdep 0.1.0"
+    );
+    assert!(check(&["--forbid-source", "git", "--deny-git-sources"]).is_err());
+    assert!(check(&["--deny-git-sources", "--allow-git", "gitdep"]).is_ok());
+    assert!(check(&["--deny-git-sources", "--allow-git", "https://github.com/org/gitdep.git/"]).is_ok());
+    assert!(check(&["--deny-git-sources", "--allow-git", "https://github.com/org/other"]).is_err());
+}
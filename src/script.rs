@@ -0,0 +1,251 @@
+use std::path::Path;
+
+use crate::sources::{Source, SourceSet};
+
+/// Quotes `value` for the shell
+fn quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+/// `value` as a single shell word, quoted only when it needs to be
+pub fn shell_word(value: &str) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "_-./=:,+@%".contains(c);
+    match !value.is_empty() && value.chars().all(plain) {
+        true => value.to_string(),
+        false => quote(value),
+    }
+}
+
+/// A source `path` as the script reaches it, relative ones are relative to the
+/// flatpak manifest in `$MANIFEST_DIR`
+fn source_path(path: &str) -> String {
+    match Path::new(path).is_absolute() {
+        true => quote(path),
+        false => format!("\"$MANIFEST_DIR\"/{}", quote(path)),
+    }
+}
+
+fn file_name(path: &str) -> &str {
+    path.trim_end_matches('/').rsplit('/').next().unwrap_or(path)
+}
+
+/// Writes `contents` to `path` byte for byte with a here-document, trimming
+/// the newline it adds
+fn write_inline(path: &str, contents: &str) -> String {
+    let mut delimiter = "CARGO_FLATPAK_EOF".to_string();
+    while contents.lines().any(|line| line == delimiter) {
+        delimiter.push('_');
+    }
+    let mut commands = format!("cat > {path} <<'{delimiter}'\n{contents}");
+    match contents.ends_with('\n') {
+        true => commands += &format!("{delimiter}\n"),
+        false => commands += &format!("\n{delimiter}\ntruncate -s -1 {path}\n"),
+    }
+    commands
+}
+
+/// A bash script doing what flatpak-builder does with `sources`, in the
+/// current directory, for trying the sources out without flatpak-builder.
+/// Entries it doesn't know how to replay are skipped with a warning.
+pub fn vendor_script(sources: &SourceSet, manifest_dir: &Path) -> anyhow::Result<String> {
+    let mut script = format!(
+        r#"#!/usr/bin/env bash
+# Lays out the sources generated by cargo-flatpak in the current directory,
+# the way flatpak-builder would
+set -euo pipefail
+
+MANIFEST_DIR=${{MANIFEST_DIR:-{}}}
+
+fetch() {{
+    curl --fail --silent --show-error --location --output "$2" "$1"
+}}
+
+verify() {{
+    echo "$1  $2" | sha256sum --check --quiet -
+}}
+"#,
+        quote(crate::sources::utf8_path(manifest_dir)?)
+    );
+    for source in sources.sources() {
+        script.push('\n');
+        match source {
+            Source::Archive(archive) => {
+                let dest = quote(&archive.dest);
+                script += &format!("mkdir -p {dest}\n");
+                let file = match (&archive.url, &archive.path) {
+                    (Some(url), _) => {
+                        script += &format!("archive=$(mktemp)\nfetch {} \"$archive\"\n", quote(url));
+                        "\"$archive\"".to_string()
+                    }
+                    (None, Some(path)) => source_path(path),
+                    (None, None) => unreachable!("archives have a url or a path"),
+                };
+                script += &format!("verify {} {file}\n", archive.sha256);
+                script += &format!("tar -xf {file} -C {dest} --strip-components=1\n");
+                if archive.url.is_some() {
+                    script += "rm \"$archive\"\n";
+                }
+            }
+            Source::File(file) => {
+                let from = file.url.as_ref().or(file.path.as_ref()).unwrap();
+                let name = file.dest_filename.as_deref().unwrap_or_else(|| file_name(from));
+                let path = quote(&format!("{}/{name}", file.dest));
+                script += &format!("mkdir -p {}\n", quote(&file.dest));
+                match (&file.url, &file.path) {
+                    (Some(url), _) => script += &format!("fetch {} {path}\n", quote(url)),
+                    (None, Some(source)) => script += &format!("cp {} {path}\n", source_path(source)),
+                    (None, None) => unreachable!("files have a url or a path"),
+                }
+                if let Some(sha256) = &file.sha256 {
+                    script += &format!("verify {sha256} {path}\n");
+                }
+            }
+            Source::Inline(inline) => {
+                script += &format!("mkdir -p {}\n", quote(&inline.dest));
+                let path = quote(&format!("{}/{}", inline.dest, inline.dest_filename));
+                match inline.base64 {
+                    true => script += &format!("base64 -d > {path} <<'CARGO_FLATPAK_EOF'\n{}\nCARGO_FLATPAK_EOF\n", inline.contents),
+                    false => script += &write_inline(&path, &inline.contents),
+                }
+            }
+            Source::Git(git) => {
+                let dest = quote(&git.dest);
+                script += &format!("git clone --quiet --no-checkout {} {dest}\n", quote(&git.url));
+                script += &format!("git -C {dest} checkout --quiet {}\n", git.commit);
+                script += &format!("git -C {dest} submodule update --quiet --init --recursive\n");
+            }
+            Source::Dir(dir) => {
+                let dest = quote(&dir.dest);
+                script += &format!("mkdir -p {dest}\ncp -r {}/. {dest}\n", source_path(&dir.path));
+            }
+            Source::Shell(shell) => {
+                for command in &shell.commands {
+                    script += command;
+                    script.push('\n');
+                }
+            }
+            Source::Other(other) => {
+                let kind = other.get("type").and_then(|t| t.as_str()).unwrap_or("untyped");
+                let warning = format!("warning: skipping a {kind} source, it can't be replayed");
+                script += &format!("echo {} >&2\n", quote(&warning));
+            }
+        }
+    }
+    Ok(script)
+}
+
+#[test]
+fn inline_files_are_exact() {
+    let tmp = tempfile::tempdir().unwrap();
+    let cases = ["", "no newline", "one\nnewline\n", "it's $HOME\nCARGO_FLATPAK_EOF\n\n", "\\ `x` \"y\"\n\n"];
+    let mut script = String::new();
+    for (i, contents) in cases.iter().enumerate() {
+        script += &write_inline(&quote(&format!("{i}.txt")), contents);
+    }
+    let status = std::process::Command::new("bash")
+        .args(["-euc", &script])
+        .current_dir(tmp.path())
+        .status()
+        .unwrap();
+    assert!(status.success());
+    for (i, contents) in cases.iter().enumerate() {
+        assert_eq!(std::fs::read_to_string(tmp.path().join(format!("{i}.txt"))).unwrap(), *contents);
+    }
+}
+
+#[test]
+fn vendor_script_builds() {
+    use clap::Parser;
+    use std::process::Command;
+
+    use crate::cli::Args;
+    use crate::config::CargoConfig;
+    use crate::sources::{get_package_sources, lockfile_source, sha256_file, Inline, LockFile};
+
+    let tmp = tempfile::tempdir().unwrap();
+    let write = |path: &Path, contents: &str| {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, contents).unwrap();
+    };
+    let run = |command: &mut Command| {
+        let output = command.output().unwrap();
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        String::from_utf8(output.stdout).unwrap()
+    };
+
+    // A registry crate served from a file:// URL
+    let bar = tmp.path().join("pkg/bar-0.1.0");
+    write(&bar.join("Cargo.toml"), "[package]\nname = \"bar\"\nversion = \"0.1.0\"\n");
+    write(&bar.join("src/lib.rs"), "pub const BAR: u32 = 1;\n");
+    std::fs::create_dir(tmp.path().join("crates")).unwrap();
+    let crate_file = tmp.path().join("crates/bar-0.1.0.crate");
+    run(Command::new("tar").arg("-czf").arg(&crate_file).arg("-C").arg(tmp.path().join("pkg")).arg("bar-0.1.0"));
+    let checksum = sha256_file(&crate_file).unwrap();
+
+    // A git crate from a local remote
+    let baz = tmp.path().join("baz");
+    write(&baz.join("Cargo.toml"), "[package]\nname = \"baz\"\nversion = \"0.1.0\"\n");
+    write(&baz.join("src/lib.rs"), "pub const BAZ: u32 = 2;\n");
+    let git = |args: &[&str]| run(Command::new("git").args(args).current_dir(&baz));
+    git(&["init", "-q"]);
+    git(&["add", "."]);
+    git(&["-c", "user.name=test", "-c", "user.email=test@example.com", "commit", "-q", "-m", "init"]);
+    let commit = git(&["rev-parse", "HEAD"]).trim().to_string();
+    let baz_url = url::Url::from_directory_path(&baz).unwrap().to_string();
+    let baz_url = baz_url.trim_end_matches('/');
+
+    let cargo_lock = format!(
+        "version = 3\n\n\
+         [[package]]\nname = \"app\"\nversion = \"0.1.0\"\ndependencies = [\n \"bar\",\n \"baz\",\n]\n\n\
+         [[package]]\nname = \"bar\"\nversion = \"0.1.0\"\n\
+         source = \"registry+https://github.com/rust-lang/crates.io-index\"\nchecksum = \"{checksum}\"\n\n\
+         [[package]]\nname = \"baz\"\nversion = \"0.1.0\"\nsource = \"git+{baz_url}#{commit}\"\n"
+    );
+    let template = format!("{}{{name}}-{{version}}.crate", url::Url::from_directory_path(tmp.path().join("crates")).unwrap());
+    let args = Args::parse_from(["flatpak", "--crate-url-template", &template]);
+    let lock: LockFile = toml::from_str(&cargo_lock).unwrap();
+    let baz_manifest = baz.join("Cargo.toml");
+    let mut sources = Vec::new();
+    let mut config = CargoConfig::new(&args.vendor_dir());
+    for (package, manifest) in lock.package[1..].iter().zip([None, baz_manifest.to_str()]) {
+        let (package_sources, entries) = get_package_sources(package, manifest, &args).unwrap().unwrap();
+        sources.extend(package_sources);
+        config.add_sources(entries).unwrap();
+    }
+    sources.push(lockfile_source(&cargo_lock, None, tmp.path(), ".").unwrap());
+    sources.push(Source::Inline(Inline {
+        contents: config.to_toml().unwrap(),
+        base64: false,
+        dest: args.cargo_home_dir(),
+        dest_filename: "config".into(),
+        x_cargo_lock_hash: None,
+    }));
+
+    let build = tmp.path().join("build");
+    write(
+        &build.join("Cargo.toml"),
+        &format!(
+            "[package]\nname = \"app\"\nversion = \"0.1.0\"\n\n\
+             [dependencies]\nbar = \"0.1\"\nbaz = {{ git = \"{baz_url}\" }}\n"
+        ),
+    );
+    write(&build.join("src/main.rs"), "fn main() {\n    let _ = bar::BAR + baz::BAZ;\n}\n");
+    let script = tmp.path().join("vendor.sh");
+    std::fs::write(&script, vendor_script(&SourceSet::from_sources(sources).unwrap(), tmp.path()).unwrap()).unwrap();
+    run(Command::new("bash").arg(&script).current_dir(&build));
+    assert_eq!(std::fs::read_to_string(build.join("Cargo.lock")).unwrap(), cargo_lock);
+
+    run(Command::new(std::env::var("CARGO").unwrap_or_else(|_| "cargo".into()))
+        .args(["check", "--offline", "--locked", "--quiet"])
+        .current_dir(&build)
+        .env("CARGO_HOME", build.join("cargo"))
+        .env("CARGO_TARGET_DIR", tmp.path().join("target")));
+
+    // The checksum is checked
+    let corrupt = tmp.path().join("corrupt");
+    std::fs::create_dir(&corrupt).unwrap();
+    std::fs::write(&crate_file, "not the crate").unwrap();
+    let output = Command::new("bash").arg(&script).current_dir(&corrupt).output().unwrap();
+    assert!(!output.status.success());
+    assert!(!corrupt.join("cargo/vendor/bar-0.1.0/Cargo.toml").exists());
+}
@@ -0,0 +1,240 @@
+use std::ffi::OsString;
+
+use cargo_metadata::Metadata;
+use clap::{parser::ValueSource, ArgAction, CommandFactory, FromArgMatches};
+use serde_json::Value;
+
+use crate::cli::{Args, Command};
+
+/// The `[workspace.metadata.flatpak]` table, or `[package.metadata.flatpak]`
+/// of the root package, along with the path it was read from
+pub fn metadata_settings(metadata: &Metadata) -> Option<(&'static str, &Value)> {
+    if let Some(settings) = metadata.workspace_metadata.get("flatpak") {
+        return Some(("workspace.metadata.flatpak", settings));
+    }
+    let settings = metadata.root_package()?.metadata.get("flatpak")?;
+    Some(("package.metadata.flatpak", settings))
+}
+
+/// Where the value of an option comes from, the command line winning over the
+/// environment, which wins over the metadata settings
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Origin {
+    Cli,
+    Env,
+    File,
+    Default,
+}
+
+/// An option of the resolved command line
+#[derive(Debug, PartialEq, serde::Serialize)]
+pub struct ResolvedOption {
+    /// The values, one for a single-valued option, absent for unset ones
+    #[serde(skip_serializing_if = "Value::is_null")]
+    pub value: Value,
+    pub origin: Origin,
+}
+
+pub struct Settings {
+    pub args: Args,
+    pub warnings: Vec<String>,
+    /// Every option by its long name, in the order of `--help`
+    pub options: Vec<(String, ResolvedOption)>,
+}
+
+impl Settings {
+    /// The options as a table of `{ value, origin }`, for --print-options
+    pub fn options_value(&self) -> Value {
+        let options = self.options.iter().map(|(name, option)| (name.clone(), serde_json::to_value(option).unwrap()));
+        Value::Object(options.collect())
+    }
+}
+
+/// The environment variable setting an option, `CARGO_FLATPAK_CARGO_HOME` for `--cargo-home`
+pub fn env_var(long: &str) -> String {
+    format!("CARGO_FLATPAK_{}", long.to_uppercase().replace('-', "_"))
+}
+
+/// Turns `CARGO_FLATPAK_*` variables into command line arguments, skipping the options
+/// for which `is_set` is true. Flags are set by `1` or `true`, and every other option
+/// takes a single value. Returns the ids of the options set.
+fn env_args(env: impl Fn(&str) -> Option<String>, is_set: impl Fn(&str) -> bool) -> (Vec<OsString>, Vec<String>) {
+    let mut args = Vec::new();
+    let mut ids = Vec::new();
+    for arg in Args::command().get_arguments() {
+        let (Some(long), id) = (arg.get_long(), arg.get_id().as_str()) else {
+            continue;
+        };
+        let Some(value) = env(&env_var(long)).filter(|_| !is_set(id)) else {
+            continue;
+        };
+        match arg.get_action() {
+            ArgAction::SetTrue if matches!(value.as_str(), "1" | "true") => args.push(format!("--{long}").into()),
+            ArgAction::SetTrue => continue,
+            _ => args.push(format!("--{long}={value}").into()),
+        }
+        ids.push(id.to_string());
+    }
+    (args, ids)
+}
+
+/// Turns metadata settings into command line arguments, skipping the options
+/// for which `is_set` is true. Keys use the long flag names, e.g.
+/// `cargo-home = "build/cargo"`. Returns warnings for keys that aren't options.
+fn settings_args(path: &str, settings: &Value, is_set: impl Fn(&str) -> bool) -> (Vec<OsString>, Vec<String>) {
+    let command = Args::command();
+    let mut args = Vec::new();
+    let mut warnings = Vec::new();
+    let Some(settings) = settings.as_object() else {
+        warnings.push(format!("{path} should be a table"));
+        return (args, warnings);
+    };
+    for (key, value) in settings {
+        let Some(arg) = command.get_arguments().find(|a| a.get_long() == Some(key)) else {
+            warnings.push(format!("unknown key {path}.{key}"));
+            continue;
+        };
+        if is_set(arg.get_id().as_str()) {
+            continue;
+        }
+        let values = match value {
+            Value::Array(values) => values.iter().collect(),
+            value => vec![value],
+        };
+        for value in values {
+            match value {
+                Value::Bool(true) => args.push(format!("--{key}").into()),
+                Value::Bool(false) => {}
+                Value::String(s) => args.push(format!("--{key}={s}").into()),
+                Value::Number(n) => args.push(format!("--{key}={n}").into()),
+                _ => warnings.push(format!("unsupported value for {path}.{key}")),
+            }
+        }
+    }
+    (args, warnings)
+}
+
+/// Applies the `CARGO_FLATPAK_*` environment and the project's metadata settings
+/// underneath the command line: anything given on the command line wins over
+/// the environment, which wins over the metadata.
+pub fn resolve(argv: &[OsString], metadata: &Metadata) -> anyhow::Result<Settings> {
+    merge_settings(argv, metadata_settings(metadata), |name| std::env::var(name).ok())
+}
+
+fn merge_settings(
+    argv: &[OsString],
+    settings: Option<(&str, &Value)>,
+    env: impl Fn(&str) -> Option<String>,
+) -> anyhow::Result<Settings> {
+    let matches = Command::command().try_get_matches_from(argv)?;
+    let cli = matches.subcommand_matches("flatpak").unwrap();
+    let on_cli = |id: &str| cli.value_source(id) == Some(ValueSource::CommandLine);
+    let (env_argv, env_ids) = env_args(env, on_cli);
+    let (settings_argv, warnings) = match settings {
+        Some((path, settings)) => settings_args(path, settings, |id| on_cli(id) || env_ids.iter().any(|e| e == id)),
+        None => Default::default(),
+    };
+
+    let mut merged = argv[..2].to_vec();
+    merged.extend(settings_argv);
+    merged.extend(env_argv);
+    merged.extend_from_slice(&argv[2..]);
+    let matches = Command::command().try_get_matches_from(merged)?;
+    let resolved = matches.subcommand_matches("flatpak").unwrap();
+    let mut options = Vec::new();
+    for arg in Args::command().get_arguments() {
+        let (Some(long), id) = (arg.get_long(), arg.get_id().as_str()) else {
+            continue;
+        };
+        if matches!(id, "help" | "version") {
+            continue;
+        }
+        let origin = match resolved.value_source(id) {
+            _ if on_cli(id) => Origin::Cli,
+            _ if env_ids.iter().any(|e| e == id) => Origin::Env,
+            Some(ValueSource::CommandLine) => Origin::File,
+            _ => Origin::Default,
+        };
+        let mut values: Vec<Value> = resolved
+            .get_raw(id)
+            .into_iter()
+            .flatten()
+            .map(|value| value.to_string_lossy().into_owned().into())
+            .collect();
+        let value = match arg.get_action() {
+            ArgAction::Append => Value::Array(values),
+            _ => values.pop().unwrap_or_default(),
+        };
+        options.push((long.to_string(), ResolvedOption { value, origin }));
+    }
+    let Command::Flatpak(args) = Command::from_arg_matches(&matches)?;
+    Ok(Settings { args, warnings, options })
+}
+
+#[test]
+fn settings_precedence() {
+    let manifest: toml::Value = toml::from_str(
+        r#"
+        [workspace.metadata.flatpak]
+        output = "flatpak/cargo-sources.json"
+        cargo-home = "build/cargo"
+        config-offline = true
+        package = ["app", "cli"]
+        format = "yaml"
+        vendor-directory = "vendor"
+        "#,
+    )
+    .unwrap();
+    let settings = serde_json::to_value(&manifest["workspace"]["metadata"]["flatpak"]).unwrap();
+    let argv = |args: &[&str]| -> Vec<OsString> {
+        ["cargo-flatpak", "flatpak"].iter().chain(args).map(OsString::from).collect()
+    };
+
+    let settings = Some(("workspace.metadata.flatpak", &settings));
+    let no_env = |_: &str| None;
+    let Settings { args, warnings, .. } = merge_settings(&argv(&[]), settings, no_env).unwrap();
+    assert_eq!(args.output, "flatpak/cargo-sources.json");
+    assert_eq!(args.cargo_home, "build/cargo");
+    assert!(args.config_offline);
+    assert_eq!(args.package, ["app", "cli"]);
+    assert_eq!(args.format, Some(crate::sources::SourcesFormat::Yaml));
+    assert_eq!(warnings, ["unknown key workspace.metadata.flatpak.vendor-directory"]);
+
+    let cli = argv(&["-o", "sources.json", "-p", "app", "--format", "json"]);
+    let Settings { args, .. } = merge_settings(&cli, settings, no_env).unwrap();
+    assert_eq!(args.output, "sources.json");
+    assert_eq!(args.format, Some(crate::sources::SourcesFormat::Json));
+    assert_eq!(args.package, ["app"]);
+    assert_eq!(args.cargo_home, "build/cargo");
+}
+
+#[test]
+fn option_origins() {
+    let settings = serde_json::json!({"cargo-home": "build/cargo", "output": "metadata.json", "package": ["app"]});
+    let settings = Some(("workspace.metadata.flatpak", &settings));
+    let env = |name: &str| match name {
+        "CARGO_FLATPAK_OUTPUT" => Some("env.json".to_string()),
+        "CARGO_FLATPAK_CARGO_HOME" => Some("env/cargo".to_string()),
+        "CARGO_FLATPAK_CONFIG_OFFLINE" => Some("1".to_string()),
+        "CARGO_FLATPAK_FETCH" => Some("0".to_string()),
+        _ => None,
+    };
+    let argv: Vec<OsString> = ["cargo-flatpak", "flatpak", "-o", "cli.json"].map(OsString::from).to_vec();
+    let resolved = merge_settings(&argv, settings, env).unwrap();
+    assert_eq!(resolved.args.output, "cli.json");
+    assert_eq!(resolved.args.cargo_home, "env/cargo");
+    assert!(resolved.args.config_offline);
+    assert!(!resolved.args.fetch);
+
+    let options = resolved.options_value();
+    let option = |name: &str| (options[name]["value"].clone(), options[name]["origin"].as_str().unwrap().to_string());
+    assert_eq!(option("output"), ("cli.json".into(), "cli".into()));
+    assert_eq!(option("cargo-home"), ("env/cargo".into(), "env".into()));
+    assert_eq!(option("config-offline"), ("true".into(), "env".into()));
+    assert_eq!(option("package"), (serde_json::json!(["app"]), "file".into()));
+    assert_eq!(option("fetch"), ("false".into(), "default".into()));
+    assert_eq!(option("group-by"), ("crate".into(), "default".into()));
+    assert_eq!(option("split"), (Value::Null, "default".into()));
+    assert!(toml::to_string(&options).unwrap().contains("[output]\nvalue = \"cli.json\"\norigin = \"cli\"\n"));
+}
@@ -0,0 +1,226 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::net;
+
+/// Download size of a source
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Size {
+    Exact(u64),
+    /// Reported by a forge API, only approximates the download
+    Estimate(u64),
+    Unknown,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct SourceSize {
+    /// The source type, `archive`, `file` or `git`
+    pub kind: String,
+    /// The crate, file or repository name
+    pub name: String,
+    pub size: Size,
+}
+
+enum Download {
+    Url(String),
+    Git(String),
+}
+
+/// `$XDG_CACHE_HOME/cargo-flatpak/sizes.json`, archive sizes by URL
+pub fn default_cache() -> Option<PathBuf> {
+    Some(net::cache_dir()?.join("sizes.json"))
+}
+
+fn content_length(client: &net::Client, url: &str) -> Option<u64> {
+    client.head(url).ok()?.header("Content-Length")?.parse().ok()
+}
+
+/// The repository size reported by GitHub or GitLab, for the hosts they serve
+fn forge_size(client: &net::Client, url: &str) -> Option<u64> {
+    let url = url::Url::parse(url).ok()?;
+    let path = url.path().trim_matches('/').trim_end_matches(".git");
+    let api = match url.host_str()? {
+        "github.com" => format!("https://api.github.com/repos/{path}"),
+        "gitlab.com" => format!("https://gitlab.com/api/v4/projects/{}?statistics=true", path.replace('/', "%2F")),
+        _ => return None,
+    };
+    let response: serde_json::Value = serde_json::from_str(&client.get(&api).ok()?.into_string().ok()?).ok()?;
+    match response.get("statistics") {
+        Some(statistics) => statistics["repository_size"].as_u64(),
+        // GitHub reports kilobytes
+        None => response["size"].as_u64().map(|kb| kb * 1024),
+    }
+}
+
+/// Looks up the download size of every archive, file and git source, on
+/// `jobs` threads. Archive and file sizes are cached in `cache` across runs,
+/// failures are reported as unknown sizes.
+pub fn estimate(sources: &serde_json::Value, jobs: usize, cache: Option<&Path>) -> Vec<SourceSize> {
+    let mut cached: HashMap<String, u64> = cache
+        .and_then(|cache| std::fs::read_to_string(cache).ok())
+        .and_then(|cache| serde_json::from_str(&cache).ok())
+        .unwrap_or_default();
+
+    let mut downloads = Vec::new();
+    for source in sources.as_array().into_iter().flatten() {
+        let field = |key: &str| source.get(key).and_then(|v| v.as_str()).map(String::from);
+        let (Some(kind), Some(url)) = (field("type"), field("url")) else {
+            continue;
+        };
+        let last_component = |path: &str| path.trim_end_matches('/').rsplit('/').next().map(String::from);
+        let name = match kind.as_str() {
+            "archive" => field("dest").and_then(|dest| last_component(&dest)),
+            "file" => field("dest-filename"),
+            "git" => last_component(&url),
+            _ => continue,
+        };
+        let name = name.unwrap_or_else(|| url.clone());
+        let download = match kind.as_str() {
+            "git" => Download::Git(url),
+            _ => Download::Url(url),
+        };
+        downloads.push((kind, name, download));
+    }
+
+    let client = net::client();
+    let sizes = net::parallel(&downloads, jobs, |(_, _, download)| match download {
+        Download::Url(url) => match cached.get(url) {
+            Some(size) => Size::Exact(*size),
+            None => content_length(client, url).map_or(Size::Unknown, Size::Exact),
+        },
+        Download::Git(url) => forge_size(client, url).map_or(Size::Unknown, Size::Estimate),
+    });
+
+    let mut changed = false;
+    for ((_, _, download), size) in downloads.iter().zip(&sizes) {
+        if let (Download::Url(url), Size::Exact(size)) = (download, size) {
+            changed |= cached.insert(url.clone(), *size).is_none();
+        }
+    }
+    if let Some(cache) = cache.filter(|_| changed) {
+        let written = std::fs::create_dir_all(cache.parent().unwrap())
+            .and_then(|_| std::fs::write(cache, serde_json::to_string(&cached).unwrap()));
+        if let Err(e) = written {
+            crate::diagnostics::warn("cache", format!("could not write the size cache {}: {e}", cache.display()));
+        }
+    }
+
+    downloads
+        .into_iter()
+        .zip(sizes)
+        .map(|((kind, name, _), size)| SourceSize { kind, name, size })
+        .collect()
+}
+
+pub fn human(bytes: u64) -> String {
+    let mut size = bytes as f64;
+    for unit in ["B", "KiB", "MiB", "GiB"] {
+        if size < 1024.0 || unit == "GiB" {
+            return match unit {
+                "B" => format!("{bytes} B"),
+                unit => format!("{size:.1} {unit}"),
+            };
+        }
+        size /= 1024.0;
+    }
+    unreachable!()
+}
+
+/// Totals per source type, estimates marked with `~`, and the largest crates
+pub fn report(sizes: &[SourceSize]) -> String {
+    let mut report = String::new();
+    let mut kinds: Vec<&str> = sizes.iter().map(|s| s.kind.as_str()).collect();
+    kinds.sort();
+    kinds.dedup();
+    let mut total = 0;
+    let mut approximate = false;
+    for kind in kinds {
+        let of_kind: Vec<_> = sizes.iter().filter(|s| s.kind == kind).collect();
+        let (mut sum, mut estimated, mut unknown) = (0, false, 0);
+        for size in &of_kind {
+            match size.size {
+                Size::Exact(size) => sum += size,
+                Size::Estimate(size) => {
+                    sum += size;
+                    estimated = true;
+                }
+                Size::Unknown => unknown += 1,
+            }
+        }
+        total += sum;
+        approximate |= estimated || unknown > 0;
+        let marker = if estimated { "~" } else { "" };
+        report += &format!("{kind}: {} sources, {marker}{}", of_kind.len(), human(sum));
+        if unknown > 0 {
+            report += &format!(" ({unknown} unknown)");
+        }
+        report.push('\n');
+    }
+    let marker = if approximate { "at least " } else { "" };
+    report += &format!("total: {marker}{}\n", human(total));
+
+    let mut crates: Vec<_> = sizes
+        .iter()
+        .filter(|s| s.kind == "archive")
+        .filter_map(|s| match s.size {
+            Size::Exact(size) => Some((size, &s.name)),
+            _ => None,
+        })
+        .collect();
+    crates.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(b.1)));
+    if !crates.is_empty() {
+        report += "largest crates:\n";
+        for (size, name) in crates.iter().take(5) {
+            report += &format!("  {name} {}\n", human(*size));
+        }
+    }
+    report
+}
+
+/// Serves HEAD requests for `/<n>` with a Content-Length of n, anything else
+/// is a 404
+#[cfg(test)]
+fn test_server() -> String {
+    let (server, _) = crate::index::serve_with(|method, path| match (method, path[1..].parse::<usize>()) {
+        ("HEAD", Ok(length)) => (200, vec![0; length]),
+        _ => (404, Vec::new()),
+    });
+    server.trim_end_matches('/').to_string()
+}
+
+#[test]
+fn estimate_sizes() {
+    let server = test_server();
+    let tmp = tempfile::tempdir().unwrap();
+    let cache = tmp.path().join("sizes.json");
+    let archive = |name: &str, url: String| {
+        serde_json::json!({"type": "archive", "url": url, "sha256": "", "dest": format!("cargo/vendor/{name}")})
+    };
+    let sources = serde_json::json!([
+        archive("small-1.0.0", format!("{server}/1000")),
+        archive("big-2.0.0", format!("{server}/3145728")),
+        archive("missing-0.1.0", format!("{server}/missing")),
+        {"type": "inline", "contents": "", "dest": "cargo/vendor/small-1.0.0", "dest-filename": ".cargo-checksum.json"},
+        {"type": "git", "url": format!("{server}/example/repo"), "commit": "0123", "dest": "flatpak-cargo/git/repo-0123"},
+    ]);
+
+    let sizes = estimate(&sources, 2, Some(&cache));
+    assert_eq!(sizes.len(), 4);
+    assert_eq!(sizes[0], SourceSize { kind: "archive".into(), name: "small-1.0.0".into(), size: Size::Exact(1000) });
+    assert_eq!(sizes[1].size, Size::Exact(3145728));
+    assert_eq!(sizes[2].size, Size::Unknown);
+    assert_eq!(sizes[3], SourceSize { kind: "git".into(), name: "repo".into(), size: Size::Unknown });
+    assert_eq!(
+        report(&sizes),
+        "archive: 3 sources, 3.0 MiB (1 unknown)\n\
+         git: 1 sources, 0 B (1 unknown)\n\
+         total: at least 3.0 MiB\n\
+         largest crates:\n  big-2.0.0 3.0 MiB\n  small-1.0.0 1000 B\n"
+    );
+
+    // Cached sizes don't go to the network
+    let cached: HashMap<String, u64> = serde_json::from_str(&std::fs::read_to_string(&cache).unwrap()).unwrap();
+    assert_eq!(cached.len(), 2);
+    std::fs::write(&cache, serde_json::json!({format!("{server}/1000"): 42}).to_string()).unwrap();
+    assert_eq!(estimate(&sources, 1, Some(&cache))[0].size, Size::Exact(42));
+}
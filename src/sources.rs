@@ -1,42 +1,135 @@
 use std::{
-    collections::HashMap,
-    path::{Path, PathBuf},
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    ops::Range,
+    path::{Component, Path, PathBuf},
+    rc::Rc,
 };
 
-use toml::{map::Map, Value};
+use anyhow::Context;
+use cargo_metadata::camino::{Utf8Path, Utf8PathBuf};
+use cargo_metadata::{DependencyKind, Metadata};
+use toml::map::Map;
+use toml_edit::{DocumentMut, Item, TableLike};
 use url::Url;
-use crate::{CARGO_CRATES, COMMIT_LEN, CRATES_IO, GIT_CACHE, VENDORED_SOURCES};
+use crate::cli::{Args, GroupBy, VendorStrategy};
+use crate::config::CargoConfig;
+use crate::diagnostics::{Annotated, Snippet};
+use crate::hash::{CommitHash, Sha256};
+use crate::policy::SourceKind;
+use crate::{COMMIT_LEN, CRATES_IO, GIT_CACHE, REPLACED_SOURCES, VENDORED_REGISTRY, VENDORED_SOURCES};
 
-#[derive(Debug, Clone, serde::Serialize)]
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Archive {
     #[serde(rename = "archive-type")]
     pub archive_type: String,
-    pub url: String,
-    pub sha256: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    pub sha256: Sha256,
     pub dest: String,
+    #[serde(rename = "dest-filename", skip_serializing_if = "Option::is_none")]
+    pub dest_filename: Option<String>,
 }
 
-#[derive(Debug, Clone, serde::Serialize)]
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Inline {
     pub contents: String,
+    /// The contents are base64-encoded, for binary files
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub base64: bool,
     pub dest: String,
     #[serde(rename = "dest-filename")]
     pub dest_filename: String,
+    #[serde(rename = "x-cargo-lock-hash", skip_serializing_if = "Option::is_none")]
+    pub x_cargo_lock_hash: Option<String>,
 }
 
-#[derive(Debug, Clone, serde::Serialize)]
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Git {
     pub url: String,
-    pub commit: String,
+    pub commit: CommitHash,
     pub dest: String,
+    #[serde(rename = "x-checker-data", skip_serializing_if = "Option::is_none")]
+    pub x_checker_data: Option<GitChecker>,
+}
+
+/// flatpak-external-data-checker settings for git sources
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GitChecker {
+    #[serde(rename = "type")]
+    pub kind: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub branch: Option<String>,
+    #[serde(rename = "tag-pattern", skip_serializing_if = "Option::is_none")]
+    pub tag_pattern: Option<String>,
+}
+
+impl GitChecker {
+    /// Builds the checker from the `branch`/`tag` of a vendored source entry,
+    /// rev-pinned dependencies have nothing to track
+    fn from_vendored(vendored: &HashMap<String, String>) -> Option<Self> {
+        if let Some(branch) = vendored.get("branch") {
+            return Some(GitChecker {
+                kind: "git".into(),
+                branch: Some(branch.clone()),
+                tag_pattern: None,
+            });
+        }
+        let tag = vendored.get("tag")?;
+        // Keep the tag's prefix (e.g. `v`) and match any version after it
+        let prefix = &tag[..tag.find(|c: char| c.is_ascii_digit()).unwrap_or(tag.len())];
+        let prefix: String = prefix
+            .chars()
+            .flat_map(|c| match c.is_ascii_alphanumeric() {
+                true => vec![c],
+                false => vec!['\\', c],
+            })
+            .collect();
+        Some(GitChecker {
+            kind: "git".into(),
+            branch: None,
+            tag_pattern: Some(format!("^{prefix}([\\d.]+)$")),
+        })
+    }
 }
 
-#[derive(Debug, Clone, serde::Serialize)]
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Shell {
     pub commands: Vec<String>,
 }
 
-#[derive(Debug, Clone, serde::Serialize)]
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct File {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<Sha256>,
+    pub dest: String,
+    #[serde(rename = "dest-filename", skip_serializing_if = "Option::is_none")]
+    pub dest_filename: Option<String>,
+    #[serde(rename = "x-cargo-lock-hash", skip_serializing_if = "Option::is_none")]
+    pub x_cargo_lock_hash: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Dir {
+    pub path: String,
+    pub dest: String,
+}
+
+/// A flatpak-builder source. Reading back entries of types or with fields this
+/// doesn't generate gives `Other`, so they're written out unchanged.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
 #[serde(tag = "type")]
 pub enum Source {
     #[serde(rename = "archive")]
@@ -47,6 +140,12 @@ pub enum Source {
     Git(Git),
     #[serde(rename = "shell")]
     Shell(Shell),
+    #[serde(rename = "dir")]
+    Dir(Dir),
+    #[serde(rename = "file")]
+    File(File),
+    #[serde(untagged)]
+    Other(serde_json::Value),
 }
 
 #[derive(Debug, serde::Deserialize)]
@@ -57,22 +156,55 @@ pub struct LockFile {
 }
 
 #[derive(Debug, serde::Deserialize)]
+#[serde(try_from = "LockPackage")]
 pub struct Package {
     pub name: String,
     pub version: String,
     pub source: Option<String>,
-    pub checksum: Option<String>,
+    pub checksum: Option<Sha256>,
+    /// Whether the checksum was looked up in the index, Cargo.lock having none
+    pub checksum_from_index: bool,
     #[allow(dead_code)]
     pub dependencies: Option<Vec<String>>,
 }
 
+/// A `[[package]]` entry as written, validated into a `Package`
+#[derive(serde::Deserialize)]
+struct LockPackage {
+    name: String,
+    version: String,
+    source: Option<String>,
+    checksum: Option<String>,
+    dependencies: Option<Vec<String>>,
+}
+
+impl TryFrom<LockPackage> for Package {
+    type Error = String;
+
+    fn try_from(package: LockPackage) -> Result<Self, Self::Error> {
+        let checksum = package
+            .checksum
+            .map(Sha256::try_from)
+            .transpose()
+            .map_err(|e| format!("{} {}: {e}", package.name, package.version))?;
+        Ok(Package {
+            name: package.name,
+            version: package.version,
+            source: package.source,
+            checksum,
+            checksum_from_index: false,
+            dependencies: package.dependencies,
+        })
+    }
+}
+
 /// Converts a string to a Cargo Canonical URL,
 /// as per https://github.com/rust-lang/cargo/blob/rust-1.82.0/src/cargo/util/canonical_url.rs
 /// Since it comes from Cargo.lock, it's already partially formatted, we can skip some steps
-fn parse_url(url: &str) -> Result<(Url,HashMap<String,String>), url::ParseError> {
+pub fn parse_url(url: &str) -> Result<(Url,HashMap<String,String>), url::ParseError> {
     // Converts a string to a Cargo Canonical URL
-    let url = url.replace("git+https://", "https://");
-    let mut parsed_url = Url::parse(&url)?;
+    let url = url.strip_prefix("git+").unwrap_or(url);
+    let mut parsed_url = Url::parse(url)?;
     
     let mut vendored_sources = HashMap::new();
 
@@ -98,300 +230,4368 @@ fn parse_url(url: &str) -> Result<(Url,HashMap<String,String>), url::ParseError>
     Ok((parsed_url,vendored_sources))
 }
 
-fn git_repo_name(git_url: &str, commit: &str) -> Result<String, url::ParseError> {
-    let (canonical,_) = parse_url(git_url)?;
-    let path = canonical.path();
-    let name: &str = path.split('/').last().unwrap_or("");
-    Ok(format!("{}-{}", name, &commit[..COMMIT_LEN]))
+/// The canonical repository URL and the commit of a `git+` lockfile source
+pub fn git_reference(source: &str) -> Option<(String, CommitHash)> {
+    let commit = CommitHash::try_from(Url::parse(source).ok()?.fragment()?).ok()?;
+    let (canonical, _) = parse_url(source).ok()?;
+    Some((canonical.to_string(), commit))
 }
 
-#[derive(serde::Serialize)]
+/// The directory name of a repository clone: its path on the host, owner
+/// included, so forks of a same-named repository tell which fork they are, a
+/// digest of the whole URL, so ones on other hosts don't share a clone either,
+/// and the commit
+fn git_repo_name(git_url: &str, commit: &CommitHash) -> Result<String, url::ParseError> {
+    use sha2::Digest;
+
+    let (canonical, _) = parse_url(git_url)?;
+    let path: Vec<_> = canonical.path().split('/').filter(|segment| !segment.is_empty()).collect();
+    let digest = hex(&sha2::Sha256::digest(canonical.as_str()));
+    Ok(format!("{}-{}-{}", path.join("-"), &digest[..8], commit.abbrev(COMMIT_LEN)))
+}
+
+/// Where a repository is cloned to, shared by every crate it provides.
+/// Both the `Git` source dest and the Shell copy commands derive from this.
+pub fn git_cache_dir(git_url: &str, commit: &CommitHash, args: &Args) -> Result<Utf8PathBuf, url::ParseError> {
+    Ok(Utf8Path::new(&args.dest(GIT_CACHE)).join(git_repo_name(git_url, commit)?))
+}
+
+/// `path` as UTF-8, for writing it into the sources verbatim. Lossy
+/// conversion would silently point the sources at a different file.
+pub fn utf8_path(path: &Path) -> anyhow::Result<&str> {
+    path.to_str().ok_or_else(|| anyhow::anyhow!("{path:?} is not valid UTF-8 and can't be written to the sources"))
+}
+
+fn utf8_path_buf(path: PathBuf) -> anyhow::Result<Utf8PathBuf> {
+    Utf8PathBuf::from_path_buf(path)
+        .map_err(|path| anyhow::anyhow!("{path:?} is not valid UTF-8 and can't be written to the sources"))
+}
+
+#[derive(Debug)]
 struct GitPackage {
-    path: PathBuf,
+    path: Utf8PathBuf,
     package: toml::Value,
-    workspace: Option<toml::Value>,
+    /// The manifest as written, which the vendored one is edited from
+    document: DocumentMut,
+    /// The `[workspace]` of the root manifest, shared by every member
+    workspace: Option<Rc<toml::Value>>,
+}
+
+const DEPENDENCY_TABLES: [&str; 5] =
+    ["dependencies", "dev-dependencies", "build-dependencies", "dev_dependencies", "build_dependencies"];
+
+/// `{ workspace = true }`, a field or dependency inherited from the workspace
+fn is_inherited(value: &toml::Value) -> bool {
+    value.get("workspace").and_then(toml::Value::as_bool) == Some(true)
+}
+
+/// `is_inherited`, of a manifest being edited
+fn is_inherited_item(item: &Item) -> bool {
+    item.get("workspace").and_then(Item::as_bool) == Some(true)
+}
+
+/// A value of the workspace, formatted to be written into a member's manifest
+fn edit_value(value: &toml::Value) -> toml_edit::Value {
+    match value {
+        toml::Value::String(s) => s.as_str().into(),
+        toml::Value::Integer(i) => (*i).into(),
+        toml::Value::Float(f) => (*f).into(),
+        toml::Value::Boolean(b) => (*b).into(),
+        toml::Value::Datetime(d) => (*d).into(),
+        toml::Value::Array(array) => array.iter().map(edit_value).collect::<toml_edit::Array>().into(),
+        toml::Value::Table(table) => {
+            table.iter().map(|(key, value)| (key.clone(), edit_value(value))).collect::<toml_edit::InlineTable>().into()
+        }
+    }
+}
+
+/// Writes `value` over `item`, keeping the whitespace and comments around it. A
+/// table keeps its header and takes the keys of `value`.
+fn replace_item(item: &mut Item, value: &toml::Value) {
+    match (item, value) {
+        (Item::Table(table), toml::Value::Table(value)) => {
+            table.clear();
+            for (key, value) in value {
+                table.insert(key, Item::Value(edit_value(value)));
+            }
+        }
+        (Item::Value(old), value) => {
+            let decor = old.decor().clone();
+            *old = edit_value(value);
+            *old.decor_mut() = decor;
+        }
+        (item, value) => *item = Item::Value(edit_value(value)),
+    }
+}
+
+/// Replaces the entry `key` of `table` with `entries`, in its place, keeping the
+/// order and formatting of the others
+fn replace_key(table: &mut dyn TableLike, key: &str, entries: Vec<(String, Item)>) {
+    let old: Vec<_> = table.iter().filter_map(|(k, _)| table.get_key_value(k)).map(|(k, item)| (k.clone(), item.clone())).collect();
+    let mut entries = Some(entries);
+    table.clear();
+    for (k, item) in old {
+        if k.get() == key {
+            for (key, item) in entries.take().into_iter().flatten() {
+                table.insert(&key, item);
+            }
+        } else {
+            table.entry_format(&k).or_insert(item);
+        }
+    }
+}
+
+/// Writes the workspace's dependency over the inherited one, with the features
+/// the member adds and the rest of what the member says about it
+fn inherit_dependency(member: &mut Item, workspace: &toml::Value) {
+    if member.get("features").is_none() && member.get("optional").is_none() {
+        return replace_item(member, workspace);
+    }
+    let dependency = match workspace {
+        toml::Value::String(version) => Map::from_iter([("version".to_string(), version.clone().into())]),
+        toml::Value::Table(dependency) => dependency.clone(),
+        _ => return replace_item(member, workspace),
+    };
+    let Some(table) = member.as_table_like_mut() else {
+        return;
+    };
+    let added: Vec<toml::Value> =
+        table.get("features").and_then(Item::as_array).into_iter().flatten().filter_map(|f| f.as_str().map(Into::into)).collect();
+    if let Some(features) = table.get_mut("features") {
+        let mut all = dependency.get("features").and_then(toml::Value::as_array).cloned().unwrap_or_default();
+        all.extend(added.into_iter().filter(|f| !all.contains(f)).collect::<Vec<_>>());
+        replace_item(features, &toml::Value::Array(all));
+    }
+    let entries = dependency
+        .iter()
+        .filter(|(key, _)| !table.contains_key(key))
+        .map(|(key, value)| (key.clone(), Item::Value(edit_value(value))))
+        .collect();
+    replace_key(table, "workspace", entries);
+}
+
+fn inherit_dependencies(dependencies: &mut Item, workspace: Option<&Map<String, toml::Value>>) {
+    let (Some(dependencies), Some(workspace)) = (dependencies.as_table_like_mut(), workspace) else {
+        return;
+    };
+    for (key, dependency) in dependencies.iter_mut() {
+        if let Some(inherited) = workspace.get(key.get()).filter(|_| is_inherited_item(dependency)) {
+            inherit_dependency(dependency, inherited);
+        }
+    }
 }
 
 impl GitPackage {
-    pub fn normalized(&self) -> toml::Value {
-        let mut package = self.package.clone();
-        if let Some(workspace) = &self.workspace {
-            for (section_key, section) in package.as_table_mut().unwrap().iter_mut() {
-                if let toml::Value::Table(section_map) = section {
-                    let mut keys_to_replace = Vec::new();
-                    for (key, value) in section_map.iter() {
-                        if let toml::Value::Table(value_map) = value {
-                            if value_map.contains_key("workspace") {
-                                keys_to_replace.push(key.clone());
-                            }
+    /// The manifest with what it inherits from the workspace written into it, as
+    /// edits that leave the rest of it, comments and all, as it was
+    pub fn normalized(&self) -> DocumentMut {
+        let mut manifest = self.document.clone();
+        let Some(workspace) = &self.workspace else {
+            return manifest;
+        };
+        let dependencies = workspace.get("dependencies").and_then(toml::Value::as_table);
+        for (section_key, section) in manifest.iter_mut() {
+            let section_key = section_key.get();
+            if DEPENDENCY_TABLES.contains(&section_key) {
+                inherit_dependencies(section, dependencies);
+            } else if section_key == "target" {
+                for (_, target) in section.as_table_like_mut().into_iter().flat_map(|targets| targets.iter_mut()) {
+                    for kind in DEPENDENCY_TABLES {
+                        if let Some(section) = target.get_mut(kind) {
+                            inherit_dependencies(section, dependencies);
                         }
                     }
-                    if let Some(workspace_section) =
-                        workspace.get(section_key).and_then(toml::Value::as_table)
-                    {
-                        for key in keys_to_replace {
-                            if let Some(workspace_value) = workspace_section.get(&key) {
-                                section_map.insert(key, workspace_value.clone());
-                            }
-                        }
+                }
+            } else if section_key == "lints" && is_inherited_item(section) {
+                if let Some(lints) = workspace.get("lints") {
+                    replace_item(section, lints);
+                }
+            } else if let (Some(section), Some(workspace_section)) =
+                (section.as_table_like_mut(), workspace.get(section_key).and_then(toml::Value::as_table))
+            {
+                for (key, value) in section.iter_mut() {
+                    if !value.as_table_like().is_some_and(|value| value.contains_key("workspace")) {
+                        continue;
+                    }
+                    if let Some(workspace_value) = workspace_section.get(key.get()) {
+                        replace_item(value, workspace_value);
                     }
                 }
             }
         }
-        package
+        manifest
     }
 }
 
 type GitPackagesType = HashMap<String, GitPackage>;
 
-#[allow(clippy::only_used_in_recursion)]
+/// The tables of an array of tables, `[[bin]]` or `bin = [{ .. }]`
+fn array_tables_mut(item: &mut Item) -> Vec<&mut dyn TableLike> {
+    match item {
+        Item::ArrayOfTables(tables) => tables.iter_mut().map(|table| table as &mut dyn TableLike).collect(),
+        Item::Value(toml_edit::Value::Array(array)) => array
+            .iter_mut()
+            .filter_map(toml_edit::Value::as_inline_table_mut)
+            .map(|table| table as &mut dyn TableLike)
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// The manifest of a vendored git package: workspace inheritance resolved and
+/// path dependencies, which point nowhere inside of the vendor directory,
+/// turned into requirements on the versions vendored next to it
+fn vendored_manifest(git_pkg: &GitPackage, packages: &GitPackagesType) -> DocumentMut {
+    fn rewrite_path_dependencies(table: &mut dyn TableLike, packages: &GitPackagesType) {
+        for kind in DEPENDENCY_TABLES {
+            let Some(dependencies) = table.get_mut(kind).and_then(Item::as_table_like_mut) else {
+                continue;
+            };
+            for (key, dep) in dependencies.iter_mut() {
+                let Some(dep) = dep.as_table_like_mut() else {
+                    continue;
+                };
+                if !dep.contains_key("path") {
+                    continue;
+                }
+                let name = dep.get("package").and_then(Item::as_str).unwrap_or(key.get()).to_string();
+                let version = packages.get(&name).filter(|_| !dep.contains_key("version")).and_then(|p| {
+                    let manifest = p.normalized();
+                    manifest.get("package")?.get("version")?.as_str().map(String::from)
+                });
+                let version = version.map(|version| ("version".to_string(), toml_edit::value(version)));
+                replace_key(dep, "path", version.into_iter().collect());
+            }
+        }
+        if let Some(targets) = table.get_mut("target").and_then(Item::as_table_like_mut) {
+            for (_, target) in targets.iter_mut() {
+                if let Some(target) = target.as_table_like_mut() {
+                    rewrite_path_dependencies(target, packages);
+                }
+            }
+        }
+    }
+
+    let mut manifest = git_pkg.normalized();
+    rewrite_path_dependencies(manifest.as_table_mut(), packages);
+    manifest
+}
+
+/// Checks that a vendored manifest says what the `original` one does to cargo: every
+/// section is the same, but for what vendoring resolves or rewrites on purpose, which
+/// are inherited fields, path dependencies and relocated paths
+pub fn verify_manifest(original: &toml::Value, emitted: &str) -> anyhow::Result<()> {
+    let emitted: toml::Value =
+        toml::from_str(emitted).map_err(|e| anyhow::anyhow!("the vendored manifest doesn't parse: {e}"))?;
+    let mut differences = Vec::new();
+    compare_tables("", original, &emitted, &mut differences, |path, key, original, emitted, differences| {
+        match key {
+            "package" => compare_fields(path, original, emitted, &["license-file", "readme", "build"], differences),
+            "lib" => compare_fields(path, original, emitted, &["path"], differences),
+            "lints" if is_inherited(original) => {}
+            key if DEPENDENCY_TABLES.contains(&key) => compare_dependencies(path, original, emitted, differences),
+            "target" => compare_tables(path, original, emitted, differences, |path, _, original, emitted, differences| {
+                compare_tables(path, original, emitted, differences, |path, key, original, emitted, differences| {
+                    match DEPENDENCY_TABLES.contains(&key) {
+                        true => compare_dependencies(path, original, emitted, differences),
+                        false if original != emitted => differences.push(format!("`{path}` changed")),
+                        false => {}
+                    }
+                })
+            }),
+            "bin" | "bench" | "example" | "test" => {
+                let rewritten: &[&str] = if key == "bin" { &["path"] } else { &[] };
+                match (original.as_array(), emitted.as_array()) {
+                    (Some(original), Some(emitted)) if original.len() == emitted.len() => {
+                        for (i, (original, emitted)) in original.iter().zip(emitted).enumerate() {
+                            compare_fields(&format!("{path}[{i}]"), original, emitted, rewritten, differences);
+                        }
+                    }
+                    _ => differences.push(format!("`{path}` changed")),
+                }
+            }
+            _ if original != emitted => differences.push(format!("`{path}` changed")),
+            _ => {}
+        }
+    });
+    if !differences.is_empty() {
+        anyhow::bail!("the vendored manifest differs from the original:\n  {}", differences.join("\n  "));
+    }
+    Ok(())
+}
+
+/// Reports the keys only one of the tables has and compares the others with `compare`
+fn compare_tables(
+    path: &str,
+    original: &toml::Value,
+    emitted: &toml::Value,
+    differences: &mut Vec<String>,
+    compare: impl Fn(&str, &str, &toml::Value, &toml::Value, &mut Vec<String>),
+) {
+    let (Some(original), Some(emitted)) = (original.as_table(), emitted.as_table()) else {
+        if original != emitted {
+            differences.push(format!("`{path}` changed"));
+        }
+        return;
+    };
+    for key in original.keys().chain(emitted.keys().filter(|key| !original.contains_key(*key))) {
+        let path = if path.is_empty() { key.clone() } else { format!("{path}.{key}") };
+        match (original.get(key), emitted.get(key)) {
+            (Some(original), Some(emitted)) => compare(&path, key, original, emitted, differences),
+            (Some(_), None) => differences.push(format!("`{path}` is missing")),
+            (None, _) => differences.push(format!("`{path}` was added")),
+        }
+    }
+}
+
+/// Compares the fields of a table, allowing `rewritten` and inherited fields to differ
+fn compare_fields(
+    path: &str,
+    original: &toml::Value,
+    emitted: &toml::Value,
+    rewritten: &[&str],
+    differences: &mut Vec<String>,
+) {
+    let (Some(original), Some(emitted)) = (original.as_table(), emitted.as_table()) else {
+        if original != emitted {
+            differences.push(format!("`{path}` changed"));
+        }
+        return;
+    };
+    for key in original.keys().chain(emitted.keys().filter(|key| !original.contains_key(*key))) {
+        if rewritten.contains(&key.as_str()) {
+            continue;
+        }
+        match (original.get(key), emitted.get(key)) {
+            (Some(original), Some(_)) if is_inherited(original) => {}
+            (Some(original), Some(emitted)) if original == emitted => {}
+            (Some(_), Some(_)) => differences.push(format!("`{path}.{key}` changed")),
+            (Some(_), None) => differences.push(format!("`{path}.{key}` is missing")),
+            (None, _) => differences.push(format!("`{path}.{key}` was added")),
+        }
+    }
+}
+
+/// Compares dependency tables: path dependencies turn into version requirements, and
+/// inherited ones must keep their added features and `optional`
+fn compare_dependencies(path: &str, original: &toml::Value, emitted: &toml::Value, differences: &mut Vec<String>) {
+    compare_tables(path, original, emitted, differences, |path, _, original, emitted, differences| {
+        if !is_inherited(original) {
+            return compare_fields(path, original, emitted, &["path", "version"], differences);
+        }
+        let features = |dep: &toml::Value| dep.get("features").and_then(toml::Value::as_array).cloned().unwrap_or_default();
+        if features(original).iter().any(|feature| !features(emitted).contains(feature)) {
+            differences.push(format!("`{path}` lost features"));
+        }
+        if original.get("optional").is_some_and(|optional| emitted.get("optional") != Some(optional)) {
+            differences.push(format!("`{path}.optional` changed"));
+        }
+    });
+}
+
+/// Finds the files a package's manifest references outside of its directory,
+/// like `license-file = "../../LICENSE"`, which the copy of the package leaves
+/// behind. The manifest is pointed at copies in the root of the vendored crate,
+/// and the files to copy there are returned, relative to the repository. Build
+/// scripts and targets bring the directory they're in, for the modules next to
+/// them, and two of them landing on one name is an error.
+fn external_files(
+    git_pkg: &GitPackage,
+    manifest: &mut DocumentMut,
+    workspace_dir: &Path,
+    package_dir: &Path,
+) -> anyhow::Result<Vec<(Utf8PathBuf, String)>> {
+    let inherited = |key: &str| {
+        git_pkg.package.get("package").and_then(|p| p.get(key)).is_some_and(|v| v.get("workspace").is_some())
+    };
+    let mut files: Vec<(Utf8PathBuf, String)> = Vec::new();
+    let mut relocate = |slot: &mut Item, inherited: bool, source_file: bool| -> anyhow::Result<()> {
+        let Some(path) = slot.as_str() else {
+            return Ok(());
+        };
+        // Inherited paths are relative to the workspace root
+        let base = if inherited { workspace_dir } else { git_pkg.path.as_std_path() };
+        let path = normalize_path(&base.join(path));
+        if let Ok(relative) = path.strip_prefix(&git_pkg.path) {
+            replace_item(slot, &utf8_path(relative)?.into());
+            return Ok(());
+        }
+        if path.starts_with("..") {
+            anyhow::bail!("{path:?} is outside of the git repository");
+        }
+        let path = utf8_path_buf(path)?;
+        let file_name = path.file_name().unwrap().to_string();
+        let (copied, name) = if source_file {
+            let dir = path.parent().unwrap();
+            if dir.as_str().is_empty() || git_pkg.path.starts_with(dir) {
+                anyhow::bail!("{path} needs the modules next to it, but its directory holds the package itself");
+            }
+            let dir_name = dir.file_name().unwrap().to_string();
+            if package_dir.join(&dir_name).exists() {
+                anyhow::bail!("{path} needs the modules next to it, but the package has a {dir_name} of its own");
+            }
+            replace_item(slot, &format!("{dir_name}/{file_name}").into());
+            (dir.to_owned(), dir_name)
+        } else {
+            replace_item(slot, &file_name.clone().into());
+            (path, file_name)
+        };
+        match files.iter().find(|(_, other)| *other == name) {
+            Some((other, _)) if *other == copied => {}
+            Some((other, _)) => anyhow::bail!("{other} and {copied} would both be copied to {name}"),
+            None => files.push((copied, name)),
+        }
+        Ok(())
+    };
+    for key in ["license-file", "readme", "build"] {
+        if let Some(slot) = manifest.get_mut("package").and_then(|p| p.get_mut(key)) {
+            relocate(slot, inherited(key), key == "build")?;
+        }
+    }
+    if let Some(slot) = manifest.get_mut("lib").and_then(|lib| lib.get_mut("path")) {
+        relocate(slot, false, true)?;
+    }
+    for bin in manifest.get_mut("bin").map(array_tables_mut).into_iter().flatten() {
+        if let Some(slot) = bin.get_mut("path") {
+            relocate(slot, false, true)?;
+        }
+    }
+    Ok(files)
+}
+
+/// The `include` or `exclude` of a package, as `cargo package` applies them
+enum PackageFilter {
+    Include(Vec<(glob::Pattern, bool)>),
+    Exclude(Vec<(glob::Pattern, bool)>),
+}
+
+impl PackageFilter {
+    /// The filter of a package's manifest, `include` winning over `exclude`.
+    /// `None` when it has neither, or when the patterns use what isn't
+    /// translated: negations and escapes.
+    fn from_manifest(git_pkg: &GitPackage) -> Option<PackageFilter> {
+        let patterns = |key: &str| -> Option<Vec<&str>> {
+            let mut value = git_pkg.package.get("package")?.get(key)?;
+            if is_inherited(value) {
+                value = git_pkg.workspace.as_ref()?.get("package")?.get(key)?;
+            }
+            let patterns: Vec<_> = value.as_array()?.iter().filter_map(toml::Value::as_str).collect();
+            (!patterns.is_empty()).then_some(patterns)
+        };
+        let (include, patterns) = match patterns("include") {
+            Some(patterns) => (true, patterns),
+            None => (false, patterns("exclude")?),
+        };
+        let patterns: Option<Vec<_>> = patterns.into_iter().map(gitignore_pattern).collect();
+        let Some(patterns) = patterns else {
+            log::debug!("Copying all of {:?}, its include or exclude can't be translated", git_pkg.path);
+            return None;
+        };
+        Some(if include { PackageFilter::Include(patterns) } else { PackageFilter::Exclude(patterns) })
+    }
+}
+
+/// A gitignore-style pattern of `include` or `exclude` as a glob of the
+/// paths relative to the package, and whether it only matches directories
+fn gitignore_pattern(pattern: &str) -> Option<(glob::Pattern, bool)> {
+    if pattern.starts_with('!') || pattern.contains('\\') {
+        return None;
+    }
+    let (pattern, dir_only) = match pattern.strip_suffix('/') {
+        Some(pattern) => (pattern, true),
+        None => (pattern, false),
+    };
+    // Without a separator a pattern matches at any depth, with one it's relative to the package
+    let glob = match pattern.strip_prefix('/') {
+        Some(anchored) => anchored.to_string(),
+        None if pattern.contains('/') => pattern.to_string(),
+        None => format!("**/{pattern}"),
+    };
+    Some((glob::Pattern::new(&glob).ok()?, dir_only))
+}
+
+/// The paths in `package_dir`, relative to it, that `cargo package` would
+/// leave out, and whether it keeps anything. Directories left out as a whole
+/// come as one path, sorted so the output doesn't change from run to run.
+/// The paths of `keep` are kept whatever the filter says.
+fn packaged_out(
+    filter: &PackageFilter,
+    package_dir: &Path,
+    relative: &Path,
+    keep: &HashSet<PathBuf>,
+) -> std::io::Result<(Vec<PathBuf>, bool)> {
+    let options = glob::MatchOptions { require_literal_separator: true, ..Default::default() };
+    let matches = |patterns: &[(glob::Pattern, bool)], path: &Path, is_dir: bool| {
+        patterns.iter().any(|(pattern, dir_only)| (is_dir || !dir_only) && pattern.matches_path_with(path, options))
+    };
+    let mut entries: Vec<_> = std::fs::read_dir(package_dir.join(relative))?.collect::<Result<_, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    let (mut removed, mut kept_any) = (Vec::new(), false);
+    for entry in entries {
+        // Checkout markers, which the clone doesn't have
+        if relative.as_os_str().is_empty() && matches!(entry.file_name().to_str(), Some(".git" | ".cargo-ok")) {
+            continue;
+        }
+        let path = relative.join(entry.file_name());
+        let is_dir = entry.file_type()?.is_dir();
+        let kept = keep.iter().any(|keep| keep.starts_with(&path));
+        match filter {
+            PackageFilter::Include(patterns) if matches(patterns, &path, is_dir) => kept_any = true,
+            PackageFilter::Exclude(patterns) if matches(patterns, &path, is_dir) && !kept => removed.push(path),
+            PackageFilter::Exclude(_) if !is_dir => {}
+            _ if is_dir => {
+                let (sub, sub_kept) = packaged_out(filter, package_dir, &path, keep)?;
+                match matches!(filter, PackageFilter::Include(_)) && !sub_kept {
+                    true => removed.push(path),
+                    false => removed.extend(sub),
+                }
+                kept_any |= sub_kept;
+            }
+            _ if kept => kept_any = true,
+            _ => removed.push(path),
+        }
+    }
+    Ok((removed, kept_any))
+}
+
+/// The removals after copying a git package, leaving what `cargo package`
+/// would from `include` or `exclude`. Nothing is removed when the manifest
+/// has neither, or a path can't be quoted plainly for the shell.
+fn package_filter_commands(
+    git_pkg: &GitPackage,
+    manifest: &DocumentMut,
+    package_dir: &Path,
+    dest: &str,
+) -> anyhow::Result<Vec<String>> {
+    let Some(filter) = PackageFilter::from_manifest(git_pkg) else {
+        return Ok(Vec::new());
+    };
+    // What cargo always packages, the manifest and the files it points at
+    let mut keep = HashSet::from([PathBuf::from("Cargo.toml")]);
+    let package = manifest.get("package");
+    let mut paths: Vec<_> = ["license-file", "readme", "build"].iter().filter_map(|key| package?.get(key)?.as_str()).collect();
+    paths.extend(manifest.get("lib").and_then(|lib| lib.get("path")?.as_str()));
+    let bins = manifest.get("bin").and_then(Item::as_array_of_tables);
+    paths.extend(bins.into_iter().flatten().filter_map(|bin| bin.get("path")?.as_str()));
+    keep.extend(paths.into_iter().map(|path| normalize_path(Path::new(path))));
+
+    let (removed, _) = packaged_out(&filter, package_dir, Path::new(""), &keep)?;
+    let mut commands = Vec::new();
+    for path in removed {
+        let Some(path) = path.to_str().filter(|path| !path.contains(['"', '$', '`', '\\'])) else {
+            log::debug!("Copying all of {:?}, {path:?} can't be removed in a shell command", git_pkg.path);
+            return Ok(Vec::new());
+        };
+        commands.push(format!(r#"rm -rf "{dest}/{path}""#));
+    }
+    Ok(commands)
+}
+
+/// Points the paths of a package's manifest at the package inside of a whole
+/// clone of its repository, for --no-shell-sources, where the clone itself is
+/// the vendored crate. What cargo would infer from the package's directory is
+/// written out, as `checkout`, the local clone, has it; the tests, examples and
+/// benches of a dependency are never built, and are left out.
+fn rooted_manifest(git_pkg: &GitPackage, manifest: &mut DocumentMut, workspace_dir: &Path, checkout: &Path) -> anyhow::Result<()> {
+    let root = |path: &Path| -> anyhow::Result<String> {
+        let path = normalize_path(path);
+        if path.starts_with("..") {
+            anyhow::bail!("{path:?} is outside of the git repository");
+        }
+        Ok(utf8_path(&path)?.to_string())
+    };
+    let set = |table: &mut dyn TableLike, key: &str, path: String| match table.get_mut(key) {
+        Some(slot) => replace_item(slot, &path.into()),
+        None => {
+            table.insert(key, toml_edit::value(path));
+        }
+    };
+    let inherited = |key: &str| {
+        git_pkg.package.get("package").and_then(|p| p.get(key)).is_some_and(|v| v.get("workspace").is_some())
+    };
+    let package_dir = git_pkg.path.as_std_path();
+    let exists = |path: &str| checkout.join(package_dir).join(path).is_file();
+    let package = manifest.entry("package").or_insert(toml_edit::table()).as_table_like_mut().unwrap();
+    for key in ["license-file", "readme", "build"] {
+        let base = if inherited(key) { workspace_dir } else { package_dir };
+        match package.get(key) {
+            Some(path) => {
+                if let Some(path) = path.as_str() {
+                    let path = root(&base.join(path))?;
+                    set(package, key, path);
+                }
+            }
+            None if key == "build" && exists("build.rs") => set(package, key, root(&package_dir.join("build.rs"))?),
+            None => {}
+        }
+    }
+    for key in ["autobins", "autoexamples", "autotests", "autobenches"] {
+        match package.get_mut(key) {
+            Some(slot) => replace_item(slot, &false.into()),
+            None => {
+                package.insert(key, toml_edit::value(false));
+            }
+        }
+    }
+    for key in ["example", "test", "bench"] {
+        manifest.remove(key);
+    }
+    let lib_path = match manifest.get("lib").and_then(|lib| lib.get("path")).and_then(Item::as_str) {
+        Some(path) => Some(path.to_string()),
+        None => exists("src/lib.rs").then(|| "src/lib.rs".to_string()),
+    };
+    if let Some(path) = lib_path {
+        let lib = manifest.entry("lib").or_insert(toml_edit::table()).as_table_like_mut().unwrap();
+        set(lib, "path", root(&package_dir.join(path))?);
+    }
+    let name = manifest["package"].get("name").and_then(Item::as_str).unwrap_or_default().to_string();
+    let autobins = git_pkg.package.get("package").and_then(|p| p.get("autobins")).and_then(toml::Value::as_bool) != Some(false);
+    let bins = manifest.entry("bin").or_insert(Item::ArrayOfTables(Default::default()));
+    let has_main = array_tables_mut(bins).iter().any(|bin| bin.get("name").and_then(Item::as_str) == Some(&name));
+    // Only the package's main binary of the inferred ones, which keeps a binary-only package buildable
+    if autobins && exists("src/main.rs") && !has_main {
+        match bins {
+            Item::ArrayOfTables(bins) => {
+                let mut bin = toml_edit::Table::new();
+                bin.insert("name", toml_edit::value(&name));
+                bins.push(bin);
+            }
+            Item::Value(toml_edit::Value::Array(bins)) => {
+                let mut bin = toml_edit::InlineTable::new();
+                bin.insert("name", name.as_str().into());
+                bins.push(bin);
+            }
+            _ => {}
+        }
+    }
+    let bins = array_tables_mut(bins);
+    let empty = bins.is_empty();
+    for bin in bins {
+        let bin_name = bin.get("name").and_then(Item::as_str).unwrap_or_default().to_string();
+        let path = match bin.get("path").and_then(Item::as_str) {
+            Some(path) => path.to_string(),
+            None if bin_name == name && exists("src/main.rs") => "src/main.rs".into(),
+            None if exists(&format!("src/bin/{bin_name}/main.rs")) => format!("src/bin/{bin_name}/main.rs"),
+            None => format!("src/bin/{bin_name}.rs"),
+        };
+        set(bin, "path", root(&package_dir.join(path))?);
+    }
+    if empty {
+        manifest.remove("bin");
+    }
+    Ok(())
+}
+
+/// Lexically resolves `.` and `..` components without touching the filesystem
+fn normalize_path(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !normalized.pop() {
+                    normalized.push("..");
+                }
+            }
+            c => normalized.push(c),
+        }
+    }
+    normalized
+}
+
+/// Expands `{a,b}` alternatives, which the glob crate doesn't understand
+fn expand_braces(pattern: &str) -> Vec<String> {
+    let Some(open) = pattern.find('{') else {
+        return vec![pattern.to_string()];
+    };
+    let Some(close) = pattern[open..].find('}').map(|c| open + c) else {
+        return vec![pattern.to_string()];
+    };
+    let (prefix, suffix) = (&pattern[..open], &pattern[close + 1..]);
+    pattern[open + 1..close]
+        .split(',')
+        .flat_map(|alt| expand_braces(&format!("{prefix}{alt}{suffix}")))
+        .collect()
+}
+
+/// Resolves `workspace.members` the way cargo does: patterns are relative to the
+/// workspace root, matches without a Cargo.toml are skipped, `workspace.exclude`
+/// is applied and duplicates are dropped. Returned paths are relative to `root_dir`.
+fn workspace_members(workspace: &toml::Value, root_dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let patterns = |key: &str| -> Vec<String> {
+        workspace
+            .get(key)
+            .and_then(|m| m.as_array())
+            .map(|m| m.iter().filter_map(|m| m.as_str()).map(String::from).collect())
+            .unwrap_or_default()
+    };
+    let excludes: Vec<PathBuf> = patterns("exclude").iter().map(|e| normalize_path(Path::new(e))).collect();
+    let root_pattern = glob::Pattern::escape(utf8_path(root_dir)?);
+
+    let mut members = Vec::new();
+    for member in patterns("members").iter().flat_map(|m| expand_braces(m)) {
+        for entry in glob::glob(&format!("{root_pattern}/{member}"))? {
+            let dir = match entry {
+                Ok(dir) => dir,
+                Err(e) => {
+                    crate::diagnostics::warn("workspace-member", e.to_string());
+                    continue;
+                }
+            };
+            if !dir.join("Cargo.toml").is_file() {
+                log::debug!("Skipping workspace member {:?} without a manifest", dir);
+                continue;
+            }
+            let member = normalize_path(dir.strip_prefix(root_dir)?);
+            if excludes.iter().any(|e| member.starts_with(e)) || members.contains(&member) {
+                continue;
+            }
+            members.push(member);
+        }
+    }
+    Ok(members)
+}
+
+/// Collects the packages of the workspace rooted at `root_dir`, keyed by name.
+/// Every `GitPackage::path` is relative to `repo_dir`, the root of the checkout,
+/// so the result does not depend on the current working directory.
 fn get_cargo_toml_packages(
-    root_toml: toml::Value,
+    root_manifest: &str,
     root_dir: impl AsRef<Path>,
+    repo_dir: &Path,
+    max_depth: usize,
 ) -> anyhow::Result<GitPackagesType> {
     let root_dir = root_dir.as_ref();
-    assert!(root_toml.get("package").is_some() || root_toml.get("workspace").is_some());
+    let root_toml: toml::Value = toml::from_str(root_manifest)?;
+    if root_toml.get("package").is_none() && root_toml.get("workspace").is_none() {
+        anyhow::bail!("{:?} has neither a [package] nor a [workspace]", root_dir.join("Cargo.toml"));
+    }
     let mut packages: GitPackagesType = HashMap::new();
+    let workspace_dir = root_dir.strip_prefix(repo_dir)?;
 
-    fn get_dep_packages<'a>(
-        entry: &'a toml::Value,
-        toml_dir: &'a Path,
-        workspace: Option<&'a toml::Value>,
-        packages: &'a mut GitPackagesType,
-        root_dir: &'a Path,
+    /// Collects the path dependencies reachable from the package manifest
+    /// `entry` in `toml_dir`, breadth first. Each manifest is visited once so
+    /// cycles terminate, and chains longer than `max_depth` are an error.
+    #[allow(clippy::too_many_arguments)]
+    fn get_dep_packages(
+        entry: &toml::Value,
+        toml_dir: &Path,
+        workspace: Option<&Rc<toml::Value>>,
+        workspace_dir: &Path,
+        packages: &mut GitPackagesType,
+        root_dir: &Path,
+        max_depth: usize,
     ) -> anyhow::Result<()> {
+        let canonical = |dir: &Path| {
+            let manifest = root_dir.join(dir).join("Cargo.toml");
+            manifest.canonicalize().unwrap_or(manifest)
+        };
+        let mut visited = HashSet::from([canonical(toml_dir)]);
+        let mut queue = VecDeque::from([(entry.clone(), toml_dir.to_path_buf(), 0)]);
+        while let Some((entry, toml_dir, depth)) = queue.pop_front() {
             // TODO: Use proper serde deserializer
-            if let Some(dependencies) = entry.get("dependencies").and_then(|d| d.as_table()) {
-                for (dep_name, dep) in dependencies {
-                    let mut dep_name = dep_name.to_string();
+            let targets = entry.get("target").and_then(|t| t.as_table());
+            let dependency_tables = std::iter::once(&entry)
+                .chain(targets.into_iter().flat_map(|t| t.values()))
+                .filter_map(|e| e.get("dependencies").and_then(|d| d.as_table()));
+            for dependencies in dependency_tables {
+                for (key, dep) in dependencies {
+                    // `dep.workspace = true` takes its path from `[workspace.dependencies]`,
+                    // where it's relative to the workspace root
+                    let inherited = dep
+                        .get("workspace")
+                        .and_then(|w| w.as_bool())
+                        .unwrap_or(false)
+                        .then(|| workspace?.get("dependencies")?.get(key))
+                        .flatten();
+                    let (dep, base_dir) = match inherited {
+                        Some(inherited) => (inherited, workspace_dir),
+                        None => (dep, toml_dir.as_path()),
+                    };
+                    // The manifest the entry is in, to point at it
+                    let manifest = root_dir.join(base_dir).join("Cargo.toml");
+                    let mut dep_name = key.to_string();
                     if let Some(package) = dep.get("package").and_then(|p| p.as_str()) {
                         dep_name = package.to_string();
                     }
-                    if dep.get("path").is_none() {
+                    let Some(dep_path) = dep.get("path").and_then(|p| p.as_str()) else {
                         continue;
-                    }
+                    };
                     if packages.contains_key(&dep_name) {
                         continue;
                     }
-                    let dep_dir = pathdiff::diff_paths(
-                        toml_dir.join(dep.get("path").and_then(|p| p.as_str()).unwrap()),
-                        Path::new("."),
-                    )
-                    .unwrap();
+                    let dep_dir = normalize_path(&base_dir.join(dep_path));
+                    if dep_dir.starts_with("..") {
+                        let error = anyhow::anyhow!(
+                            "path dependency `{dep_name}` of {:?} resolves to {:?}, outside of the git repository",
+                            toml_dir,
+                            dep_dir
+                        );
+                        return Err(dependency_error(
+                            error,
+                            &manifest,
+                            key,
+                            "the repository can't be vendored on its own, depend on a revision where the dependency is inside of it",
+                        ));
+                    }
+                    if !visited.insert(canonical(&dep_dir)) {
+                        continue;
+                    }
+                    if depth >= max_depth {
+                        let error = anyhow::anyhow!(
+                            "path dependency `{dep_name}` of {:?} is more than {max_depth} path dependencies deep",
+                            toml_dir
+                        );
+                        return Err(dependency_error(error, &manifest, key, "raise --max-path-depth if the chain is legitimate"));
+                    }
                     log::debug!("Loading dependency {} from {:?}", dep_name, dep_dir);
-                    let dep_toml: toml::Value = toml::from_str(
-                        &std::fs::read_to_string(dep_dir.join("Cargo.toml")).unwrap(),
-                    )?;
-                    assert_eq!(
-                        dep_toml
-                            .get("package")
-                            .and_then(|p| p.get("name"))
-                            .and_then(|n| n.as_str()),
-                        Some(dep_name.as_str())
-                    );
-
-                    get_dep_packages(&dep_toml, &dep_dir, workspace, packages, root_dir)?;
+                    let dep_path = root_dir.join(&dep_dir).join("Cargo.toml");
+                    let dep_manifest = std::fs::read_to_string(&dep_path)
+                        .with_context(|| format!("failed to read path dependency `{dep_name}` at {dep_path:?}"))?;
+                    let dep_toml: toml::Value = toml::from_str(&dep_manifest)?;
+                    if package_name(&dep_toml) != Some(dep_name.as_str()) {
+                        anyhow::bail!("{dep_path:?} isn't the manifest of `{dep_name}`");
+                    }
 
                     packages.insert(
                         dep_name,
                         GitPackage {
-                            path: dep_dir,
-                            package: dep.clone(),
-                            workspace: workspace.cloned(),
+                            path: utf8_path_buf(dep_dir.clone())?,
+                            package: dep_toml.clone(),
+                            document: dep_manifest.parse()?,
+                            workspace: workspace.map(Rc::clone),
                         },
                     );
+                    queue.push_back((dep_toml, dep_dir, depth + 1));
                 }
             }
-            if let Some(targets) = entry.get("target").and_then(|t| t.as_table()) {
-                for target in targets.values() {
-                    get_dep_packages(target, toml_dir, workspace, packages, root_dir)?;
-                }
-            }
-
-            Ok(())
+        }
+        Ok(())
     }
 
+    let workspace = root_toml.get("workspace").cloned().map(Rc::new);
     if let Some(package) = root_toml.get("package") {
-        get_dep_packages(&root_toml, root_dir, None, &mut packages, root_dir)?;
+        get_dep_packages(
+            &root_toml,
+            workspace_dir,
+            workspace.as_ref(),
+            workspace_dir,
+            &mut packages,
+            repo_dir,
+            max_depth,
+        )?;
+        let Some(name) = package.get("name").and_then(|n| n.as_str()) else {
+            anyhow::bail!("{:?} has a [package] without a name", root_dir.join("Cargo.toml"));
+        };
         packages.insert(
-            package
-                .get("name")
-                .and_then(|n| n.as_str())
-                .unwrap()
-                .to_string(),
+            name.to_string(),
             GitPackage {
-                path: root_dir.to_path_buf(),
+                path: utf8_path_buf(workspace_dir.to_path_buf())?,
                 package: root_toml.clone(),
+                document: root_manifest.parse()?,
                 workspace: None,
             },
         );
     }
 
-    if let Some(workspace) = root_toml.get("workspace") {
-        if let Some(members) = workspace.get("members").and_then(|m| m.as_array()) {
-            for member in members.iter().filter_map(|m| m.as_str()) {
-                for subpkg_toml in glob::glob(&format!(
-                    "{}/{}/Cargo.toml",
-                    root_dir.to_string_lossy(),
-                    member
-                ))? {
-                    match subpkg_toml {
-                        Ok(path) => {
-                            let subpkg = path.parent().unwrap();
-                            log::debug!("Loading workspace member {:?} in {:?}", path, root_dir);
-                            let pkg_toml: toml::Value =
-                                toml::from_str(&std::fs::read_to_string(&path).unwrap())?;
-                            get_dep_packages(
-                                &pkg_toml,
-                                subpkg,
-                                Some(workspace),
-                                &mut packages,
-                                root_dir,
-                            )?;
-                            packages.insert(
-                                pkg_toml
-                                    .get("package")
-                                    .and_then(|p| p.get("name"))
-                                    .and_then(|n| n.as_str())
-                                    .unwrap()
-                                    .to_string(),
-                                GitPackage {
-                                    path: subpkg.to_path_buf(),
-                                    package: pkg_toml,
-                                    workspace: Some(workspace.clone()),
-                                },
-                            );
-                        }
-                        Err(e) => eprintln!("{:?}", e),
-                    }
-                }
+    if let Some(workspace) = &workspace {
+        for member in workspace_members(workspace, root_dir)? {
+            let subpkg = normalize_path(&workspace_dir.join(&member));
+            if subpkg.starts_with("..") {
+                anyhow::bail!("workspace member {member:?} of {:?} is outside of the git repository", root_dir.join("Cargo.toml"));
             }
+            let path = repo_dir.join(&subpkg).join("Cargo.toml");
+            log::debug!("Loading workspace member {:?} in {:?}", path, root_dir);
+            let pkg_manifest = std::fs::read_to_string(&path)?;
+            let pkg_toml: toml::Value = toml::from_str(&pkg_manifest)?;
+            let Some(name) = package_name(&pkg_toml).map(str::to_string) else {
+                anyhow::bail!("{path:?} has no [package] name");
+            };
+            get_dep_packages(&pkg_toml, &subpkg, Some(workspace), workspace_dir, &mut packages, repo_dir, max_depth)?;
+            packages.insert(
+                name,
+                GitPackage {
+                    path: utf8_path_buf(subpkg)?,
+                    package: pkg_toml,
+                    document: pkg_manifest.parse()?,
+                    workspace: Some(Rc::clone(workspace)),
+                },
+            );
         }
     }
 
     Ok(packages)
 }
 
+/// Finds the root of the workspace a package belongs to, as cargo does:
+/// `package.workspace` points at it, or it's the closest ancestor with a
+/// `[workspace]` that doesn't exclude the package. The search doesn't go above
+/// `boundary`, the root of the checkout. Falls back to the package directory
+/// itself when there is no workspace.
+fn find_workspace_root(manifest_dir: &Path, boundary: Option<&Path>) -> anyhow::Result<PathBuf> {
+    let load = |dir: &Path| -> anyhow::Result<Option<toml::Value>> {
+        let candidate = dir.join("Cargo.toml");
+        if !candidate.is_file() {
+            return Ok(None);
+        }
+        Ok(Some(toml::from_str(&std::fs::read_to_string(&candidate)?)?))
+    };
+    if let Some(root) = load(manifest_dir)?
+        .as_ref()
+        .and_then(|toml| toml.get("package")?.get("workspace")?.as_str())
+    {
+        return Ok(normalize_path(&manifest_dir.join(root)));
+    }
+    let within_boundary = |dir: &&Path| boundary.is_none_or(|boundary| dir.starts_with(boundary));
+    for dir in manifest_dir.ancestors().take_while(within_boundary) {
+        let Some(workspace) = load(dir)?.and_then(|toml| toml.get("workspace").cloned()) else {
+            continue;
+        };
+        let package = manifest_dir.strip_prefix(dir)?;
+        let excluded = workspace
+            .get("exclude")
+            .and_then(|e| e.as_array())
+            .into_iter()
+            .flatten()
+            .filter_map(|e| e.as_str())
+            .any(|e| package.starts_with(normalize_path(Path::new(e))));
+        if dir == manifest_dir || !excluded {
+            return Ok(dir.to_path_buf());
+        }
+    }
+    Ok(manifest_dir.to_path_buf())
+}
+
+/// Finds the root of the git checkout containing a package, which is what the
+/// `Git` source clones. Cargo checkouts carry a `.git` and a `.cargo-ok` marker.
+fn find_repository_root(manifest_dir: &Path) -> Option<PathBuf> {
+    manifest_dir
+        .ancestors()
+        .find(|dir| dir.join(".git").exists() || dir.join(".cargo-ok").exists())
+        .map(Path::to_path_buf)
+}
+
+/// The span of the dependency `key` in the manifest `src`, from the key to the
+/// end of its value, in whichever dependency table it is
+fn dependency_span(src: &str, key: &str) -> Option<Range<usize>> {
+    let manifest = toml_edit::ImDocument::parse(src).ok()?;
+    let targets = manifest.get("target").and_then(toml_edit::Item::as_table_like);
+    let tables = std::iter::once(manifest.as_item())
+        .chain(targets.into_iter().flat_map(|targets| targets.iter().map(|(_, target)| target)))
+        .flat_map(|table| DEPENDENCY_TABLES.iter().filter_map(|kind| table.get(kind)))
+        .chain(manifest.get("workspace").and_then(|workspace| workspace.get("dependencies")));
+    for table in tables.filter_map(toml_edit::Item::as_table_like) {
+        if let Some((key, value)) = table.get_key_value(key) {
+            let span = key.span()?;
+            return Some(span.start..value.span().map_or(span.end, |value| value.end));
+        }
+    }
+    None
+}
+
+/// `error` pointing at the dependency `key` of `manifest`
+fn dependency_error(error: anyhow::Error, manifest: &Path, key: &str, suggestion: &str) -> anyhow::Error {
+    let snippet = std::fs::read_to_string(manifest)
+        .ok()
+        .and_then(|src| Some(Snippet::new(manifest.display().to_string(), &src, dependency_span(&src, key)?)));
+    Annotated::new(error, suggestion).with_snippet(snippet).into()
+}
+
+/// The span of the `[[package]]` block of `name` at `version` in `cargo_lock`,
+/// from its header to the end of its last value
+pub fn lockfile_package_span(cargo_lock: &toml_edit::ImDocument<&str>, name: &str, version: &str) -> Option<Range<usize>> {
+    let packages = cargo_lock.get("package")?.as_array_of_tables()?;
+    let block = packages
+        .iter()
+        .find(|p| p.get("name").and_then(|n| n.as_str()) == Some(name) && p.get("version").and_then(|v| v.as_str()) == Some(version))?;
+    let start = block.span()?.start;
+    let end = block.get_values().iter().filter_map(|(_, value)| value.span()).map(|span| span.end).max()?;
+    Some(start..end)
+}
+
+/// `package.name` of a manifest
+fn package_name(manifest: &toml::Value) -> Option<&str> {
+    manifest.get("package")?.get("name")?.as_str()
+}
+
+#[cfg(test)]
 fn load_toml(src: &str) -> toml::Value {
     toml::from_str(src).unwrap()
 }
 
-fn get_git_package_sources(package: &Package, manifest: &str) -> (Vec<Source>,Map<String,Value>) {
+/// Escapes `glob` for a shell, leaving its wildcards to expand
+fn shell_glob(glob: &str) -> String {
+    glob.chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '-' | '.' | '/' | '*' | '?' | '[' | ']' => c.to_string(),
+            c => format!("\\{c}"),
+        })
+        .collect()
+}
+
+/// The workspace root and the repository root of the checkout a git
+/// package's manifest is in
+pub fn git_checkout_roots(manifest: &str) -> anyhow::Result<(PathBuf, PathBuf)> {
+    // Symlinked checkouts would otherwise make the relative paths nonsensical
+    let manifest_dir = Path::new(manifest).parent().unwrap().canonicalize()?;
+    let boundary = find_repository_root(&manifest_dir);
+    let root_dir = find_workspace_root(&manifest_dir, boundary.as_deref())?;
+    // Without a checkout marker the workspace root is assumed to be the repository root
+    let repo_dir = boundary.filter(|dir| root_dir.starts_with(dir)).unwrap_or_else(|| root_dir.clone());
+    Ok((root_dir, repo_dir))
+}
+
+fn get_git_package_sources(package: &Package, manifest: &str, args: &Args) -> anyhow::Result<PackageSources> {
+    git_package_sources(package, manifest, &args.vendor_dir(), &package.name, VENDORED_SOURCES, args)
+}
+
+/// The sources of a git package vendored into `vendor_dir` as `dest_name`,
+/// and the config entry pointing its repository at the `replace_with` source
+fn git_package_sources(
+    package: &Package,
+    manifest: &str,
+    vendor_dir: &str,
+    dest_name: &str,
+    replace_with: &str,
+    args: &Args,
+) -> anyhow::Result<PackageSources> {
     let name = package.name.clone();
     let source = package.source.clone().unwrap();
 
-    let commit = Url::parse(&source)
-        .unwrap()
-        .fragment()
-        .map(|f| f.to_string())
-        .expect("The commit needs to be indicated in the fragment part");
+    let source_url = Url::parse(&source)?;
+    let Some(commit) = source_url.fragment() else {
+        anyhow::bail!("{name} {}: the source {source} doesn't name a commit", package.version);
+    };
+    let commit = CommitHash::try_from(commit).map_err(|e| anyhow::anyhow!("{name} {}: {e}", package.version))?;
 
-    let (canonical,vendored) = parse_url(&source).unwrap();
+    let (canonical, mut vendored) = parse_url(&source)?;
+    vendored.insert("replace-with".to_string(), replace_with.to_string());
 
     let repo_url = canonical.to_string();
 
-    let toml_content = std::fs::read_to_string(manifest).unwrap();
-    
-    let packages = get_cargo_toml_packages(load_toml(&toml_content), manifest.trim_end_matches("/Cargo.toml")).expect("failed to get packages from manifest");
+    let span = tracing::info_span!("git manifests").entered();
+    let (root_dir, repo_dir) = git_checkout_roots(manifest)?;
+    let toml_content = std::fs::read_to_string(root_dir.join("Cargo.toml"))?;
+
+    let packages = get_cargo_toml_packages(&toml_content, &root_dir, &repo_dir, args.max_path_depth)
+        .map_err(|e| e.context(format!("failed to get packages for {name} from {manifest}")))?;
+    span.exit();
+    let workspace_dir = root_dir.strip_prefix(&repo_dir)?.to_path_buf();
+
+    let local_repo_dir = repo_dir;
+    let repo_dir = git_cache_dir(&repo_url, &commit, args)?;
+    let dest = repo_dir.to_string();
+
+    let Some(git_pkg) = packages.get(&name) else {
+        anyhow::bail!("{} has no package {name}", root_dir.display());
+    };
+    let pkg_repo_dir = repo_dir.join(&git_pkg.path);
 
-    let dest = format!("{name}-{}", &commit[..COMMIT_LEN]);
+    let mut pkg_manifest = vendored_manifest(git_pkg, &packages);
+    // Without shell sources the repository is cloned straight into the vendor directory
+    if args.no_shell_sources {
+        let contents = pkg_manifest.to_string();
+        if args.verify_manifests {
+            verify_manifest(&git_pkg.package, &contents)
+                .map_err(|e| e.context(format!("failed to vendor {name} from {manifest}")))?;
+        }
+        rooted_manifest(git_pkg, &mut pkg_manifest, &workspace_dir, &local_repo_dir)
+            .map_err(|e| e.context(format!("failed to vendor {name} from {manifest}")))?;
+        let dest = format!("{vendor_dir}/{dest_name}");
+        let git = git_crate_source(package, &canonical, &vendored, commit, dest.clone(), &local_repo_dir, args)?;
+        let (cargo_toml, cargo_checksum) = vendored_files(pkg_manifest.to_string(), &dest);
+        return Ok((vec![git, cargo_toml, cargo_checksum], git_source_config(&canonical, vendored)));
+    }
+    let external_files = external_files(git_pkg, &mut pkg_manifest, &workspace_dir, &local_repo_dir.join(&git_pkg.path))
+        .map_err(|e| e.context(format!("failed to vendor {name} from {manifest}")))?;
 
-    let git_pkg = &packages.get(&name).unwrap();
-    let pkg_repo_dir = format!(
-        "{GIT_CACHE}/{}/{}",
-        git_repo_name(&repo_url, &commit).unwrap(),
-        git_pkg.path.to_string_lossy(),
+    // The vendor directory may not exist yet, with no registry crate unpacked into it
+    let mut commands = vec![
+        format!(r#"mkdir -p "{vendor_dir}""#),
+        format!(r#"cp -r --reflink=auto "{pkg_repo_dir}" "{vendor_dir}/{dest_name}""#),
+    ];
+    commands.extend(package_filter_commands(
+        git_pkg,
+        &pkg_manifest,
+        &local_repo_dir.join(&git_pkg.path),
+        &format!("{vendor_dir}/{dest_name}"),
+    )?);
+    commands.extend(external_files.iter().map(|(path, file_name)| {
+        format!(
+            r#"cp -r --reflink=auto "{}" "{vendor_dir}/{dest_name}/{file_name}""#,
+            repo_dir.join(path)
+        )
+    }));
+    commands.extend(
+        args.vendor_exclude
+            .iter()
+            .filter(|(crate_name, _)| *crate_name == name)
+            .map(|(_, glob)| format!(r#"rm -rf "{vendor_dir}/{dest_name}"/{}"#, shell_glob(glob))),
     );
+    let shell = Source::Shell(Shell { commands });
 
-    let shell = Source::Shell(Shell {
-        commands: vec![format!(
-            r#"cp -r --reflink=auto "{pkg_repo_dir}" "{CARGO_CRATES}/{name}""#
-        )],
-    });
+    let contents = pkg_manifest.to_string();
+    if args.verify_manifests {
+        verify_manifest(&git_pkg.package, &contents)
+            .map_err(|e| e.context(format!("failed to vendor {name} from {manifest}")))?;
+    }
+    let (cargo_toml, cargo_checksum) = vendored_files(contents, &format!("{vendor_dir}/{dest_name}"));
+    let git = git_crate_source(package, &canonical, &vendored, commit, dest, &local_repo_dir, args)?;
+    Ok((vec![git, shell, cargo_toml, cargo_checksum], git_source_config(&canonical, vendored)))
+}
 
+/// The manifest and the empty checksum files of a git crate vendored at `dest`
+fn vendored_files(manifest: String, dest: &str) -> (Source, Source) {
     let cargo_toml = Source::Inline(Inline {
-        contents: toml::to_string(&git_pkg.normalized()).unwrap(),
-        dest: format!("{CARGO_CRATES}/{name}"),
+        contents: manifest,
+        base64: false,
+        dest: dest.to_string(),
         dest_filename: "Cargo.toml".to_string(),
+        x_cargo_lock_hash: None,
     });
-
     let cargo_checksum = Source::Inline(Inline {
         contents: r#"{"package": null, "files": {}}"#.to_string(),
-        dest: format!("{CARGO_CRATES}/{name}"),
+        base64: false,
+        dest: dest.to_string(),
         dest_filename: ".cargo-checksum.json".to_string(),
+        x_cargo_lock_hash: None,
     });
-
-    let git = Source::Git(Git {
-        url: repo_url,
-        commit,
-        dest,
-    });
-
-    let mut c = Map::new();
-    c.insert(canonical.to_string().to_string(), vendored.into());
-
-    (vec![git, shell, cargo_toml, cargo_checksum],c)
+    (cargo_toml, cargo_checksum)
 }
 
-pub fn get_package_sources(
+/// The clone of `commit` of a git package's repository into `dest`, or with
+/// --git-as-archive its commit archive where the forge has them. Archives
+/// leave submodules out, repositories with some, going by their local
+/// `checkout`, are cloned.
+fn git_crate_source(
     package: &Package,
-    manifest: &str
-) -> Option<(Vec<Source>, Map<String, toml::Value>)> {
+    canonical: &Url,
+    vendored: &HashMap<String, String>,
+    commit: CommitHash,
+    dest: String,
+    checkout: &Path,
+    args: &Args,
+) -> anyhow::Result<Source> {
     let name = &package.name;
-    let version = &package.version;
-
-    if let Some(source) = package.source.as_ref() {
-        if source.starts_with("git+") {
-            let (source,c) = get_git_package_sources(package,manifest);
-            return Some((source, c));
-        }
-
-        if let Some(checksum) = package.checksum.as_ref() {
-            let archive = Source::Archive(Archive {
+    let repo_url = canonical.to_string();
+    let archive = args.git_as_archive.then(|| commit_archive_url(canonical, &commit));
+    let submodules = checkout.join(".gitmodules").is_file();
+    let unmapped = match &archive {
+        Some(None) => Some(format!("{repo_url} has no commit archives cargo flatpak knows of, cloning it for {name}")),
+        Some(Some(_)) if submodules => Some(format!("{repo_url} has submodules, which its commit archives leave out, cloning it for {name}")),
+        _ => None,
+    };
+    Ok(match archive.flatten().filter(|_| !submodules) {
+        Some(url) => {
+            let cache = crate::net::cache_dir().map(|dir| dir.join("archives.json"));
+            let sha256 = archive_sha256(&url, cache.as_deref())
+                .map_err(|e| e.context(format!("failed to download {url} for {name}")))?;
+            // The archive's top directory is stripped, the checkout lands where the clone would
+            Source::Archive(Archive {
                 archive_type: "tar-gzip".into(),
-                url: format!("{CRATES_IO}/{name}/{name}-{version}.crate"),
-                sha256: checksum.into(),
-                dest: format!("{CARGO_CRATES}/{name}-{version}"),
-            });
-
-            let inline = Source::Inline(Inline {
-                contents: format!(r#"{{"package": "{checksum}", "files": {{}}}}"#),
-                dest: format!("{CARGO_CRATES}/{name}-{version}"),
-                dest_filename: ".cargo-checksum.json".into(),
-            });
-
-            let crate_sources = vec![archive, inline];
+                url: Some(url),
+                path: None,
+                sha256,
+                dest,
+                dest_filename: None,
+            })
+        }
+        None => {
+            if let Some(message) = unmapped {
+                crate::diagnostics::emit(
+                    crate::diagnostics::Diagnostic::warning("git-archive", message).with_crate(name, &package.version),
+                );
+            }
+            Source::Git(Git {
+                url: repo_url,
+                commit,
+                dest,
+                x_checker_data: args
+                    .x_checker_data
+                    .then(|| GitChecker::from_vendored(vendored))
+                    .flatten(),
+            })
+        }
+    })
+}
 
-            let mut c = Map::new();
-            c.insert("crates-io".into(), {
-                let mut obj = Map::new();
-                obj.insert("replace-with".into(), VENDORED_SOURCES.into());
-                obj.into()
-            });
+/// The `[source]` entry of a git package's repository in the cargo config
+fn git_source_config(canonical: &Url, vendored: HashMap<String, String>) -> Map<String, toml::Value> {
+    // Cargo tells git sources apart by their reference, so one repository
+    // used at two revisions needs two distinct `[source]` entries
+    let source_key = ["rev", "tag", "branch"]
+        .iter()
+        .find_map(|kind| vendored.get(*kind).map(|value| format!("{canonical}?{kind}={value}")))
+        .unwrap_or_else(|| canonical.to_string());
+    let mut c = Map::new();
+    // Sorted, so the config doesn't change from run to run
+    c.insert(source_key, vendored.into_iter().collect::<std::collections::BTreeMap<_, _>>().into());
+    c
+}
 
-            return Some((crate_sources, c));
+/// The tarball of `commit` served by the forge hosting `repo`, for GitHub,
+/// GitLab and the Gitea instances Codeberg and gitea.com
+fn commit_archive_url(repo: &Url, commit: &CommitHash) -> Option<String> {
+    let path = repo.path().trim_matches('/');
+    let name = path.rsplit('/').next().filter(|name| !name.is_empty())?;
+    match repo.host_str()? {
+        "github.com" if path.split('/').count() == 2 => {
+            Some(format!("https://codeload.github.com/{path}/tar.gz/{commit}"))
         }
+        "gitlab.com" => Some(format!("https://gitlab.com/{path}/-/archive/{commit}/{name}-{commit}.tar.gz")),
+        host @ ("codeberg.org" | "gitea.com") => Some(format!("https://{host}/{path}/archive/{commit}.tar.gz")),
+        _ => None,
     }
+}
 
-    None
+/// The sha256 of the archive at `url`, downloaded once and then kept in
+/// `cache` by URL. The URLs name a commit, so their contents don't change.
+pub fn archive_sha256(url: &str, cache: Option<&Path>) -> anyhow::Result<Sha256> {
+    use sha2::Digest;
+    let mut cached: HashMap<String, Sha256> = cache
+        .and_then(|cache| std::fs::read_to_string(cache).ok())
+        .and_then(|cache| serde_json::from_str(&cache).ok())
+        .unwrap_or_default();
+    if let Some(sha256) = cached.get(url) {
+        return Ok(sha256.clone());
+    }
+    let mut hasher = sha2::Sha256::new();
+    std::io::copy(&mut crate::net::client().get(url)?.into_reader(), &mut hasher)?;
+    let sha256 = Sha256::try_from(hex(&hasher.finalize()))?;
+    if let Some(cache) = cache {
+        cached.insert(url.to_string(), sha256.clone());
+        let written = std::fs::create_dir_all(cache.parent().unwrap())
+            .and_then(|_| std::fs::write(cache, serde_json::to_string(&cached).unwrap()));
+        if let Err(e) = written {
+            crate::diagnostics::warn("cache", format!("could not write the archive cache {}: {e}", cache.display()));
+        }
+    }
+    Ok(sha256)
 }
 
-#[test]
-fn lock_file() {
-    let src = std::fs::read_to_string("./Cargo.lock").unwrap();
+/// The sources of a package along with its vendored config entries
+pub type PackageSources = (Vec<Source>, Map<String, toml::Value>);
 
-    let file: LockFile = toml::from_str(&src).unwrap();
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Standard base64, padded
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::new();
+    for chunk in bytes.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |n, (i, b)| n | u32::from(*b) << (16 - 8 * i));
+        for i in 0..4 {
+            match i <= chunk.len() {
+                true => encoded.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char),
+                false => encoded.push('='),
+            }
+        }
+    }
+    encoded
+}
+
+pub fn sha256_file(path: &Path) -> anyhow::Result<String> {
+    use sha2::{Digest, Sha256};
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut std::fs::File::open(path)?, &mut hasher)?;
+    Ok(hex(&hasher.finalize()))
+}
+
+/// Digest of the lockfile and of the options that shape the generated sources,
+/// embedded as `x-cargo-lock-hash` so consumers can cheaply detect stale output
+pub fn lockfile_hash(cargo_lock: &str, args: &Args) -> String {
+    use sha2::{Digest, Sha256};
+    let mut hasher = Sha256::new();
+    hasher.update(cargo_lock.as_bytes());
+    hasher.update(args.generation_options().as_bytes());
+    hex(&hasher.finalize())
+}
+
+/// Reads the `x-cargo-lock-hash` annotation back from a generated sources file
+pub fn find_lockfile_hash(sources: &SourceSet) -> Option<&str> {
+    sources.entries().iter().rev().find_map(|entry| match &entry.source {
+        Source::Inline(inline) => inline.x_cargo_lock_hash.as_deref(),
+        Source::File(file) => file.x_cargo_lock_hash.as_deref(),
+        Source::Other(other) => other.get("x-cargo-lock-hash")?.as_str(),
+        _ => None,
+    })
+}
+
+/// Cargo's index prefix for a crate name: `1`, `2`, `3/a` or `ab/cd`
+pub fn crate_prefix(name: &str) -> String {
+    match name.len() {
+        1 => "1".into(),
+        2 => "2".into(),
+        3 => format!("3/{}", &name[..1]),
+        _ => format!("{}/{}", &name[..2], &name[2..4]),
+    }
+}
+
+/// A version of a crate in a registry index
+#[derive(serde::Serialize)]
+struct IndexEntry<'a> {
+    name: &'a str,
+    vers: &'a str,
+    deps: Vec<IndexDependency<'a>>,
+    cksum: &'a Sha256,
+    features: &'a BTreeMap<String, Vec<String>>,
+    yanked: bool,
+    links: Option<&'a str>,
+}
+
+#[derive(serde::Serialize)]
+struct IndexDependency<'a> {
+    /// The name the crate is used by, renamed or not
+    name: &'a str,
+    req: String,
+    features: &'a [String],
+    optional: bool,
+    default_features: bool,
+    target: Option<String>,
+    kind: &'static str,
+    registry: Option<&'a str>,
+    /// The actual name of a renamed dependency
+    package: Option<&'a str>,
+}
+
+/// The index entries of the registry crates of `packages`, as JSON by crate
+/// name and version. Cargo resolves the lockfile against them, so they carry
+/// the dependencies and features cargo metadata reports.
+fn index_entries<'a>(packages: &[&'a Package], cargo_metadata: &Metadata) -> anyhow::Result<BTreeMap<String, Vec<(&'a str, String)>>> {
+    let mut crates: BTreeMap<String, Vec<(&str, String)>> = BTreeMap::new();
+    for package in packages {
+        let (Some(checksum), SourceKind::Registry) = (&package.checksum, SourceKind::of(package)) else {
+            continue;
+        };
+        let Some(metadata) = cargo_metadata.packages.iter().find(|p| {
+            p.name == package.name
+                && p.version.to_string() == package.version
+                && p.source.as_ref().map(|s| s.repr.as_str()) == package.source.as_deref()
+        }) else {
+            anyhow::bail!(
+                "{} {} is not reported by cargo metadata, the local registry index needs its dependencies",
+                package.name,
+                package.version
+            );
+        };
+        let deps = metadata
+            .dependencies
+            .iter()
+            .map(|dep| IndexDependency {
+                name: dep.rename.as_deref().unwrap_or(&dep.name),
+                req: dep.req.to_string(),
+                features: &dep.features,
+                optional: dep.optional,
+                default_features: dep.uses_default_features,
+                target: dep.target.as_ref().map(ToString::to_string),
+                kind: match dep.kind {
+                    DependencyKind::Development => "dev",
+                    DependencyKind::Build => "build",
+                    _ => "normal",
+                },
+                registry: dep.registry.as_deref(),
+                package: dep.rename.as_ref().map(|_| dep.name.as_str()),
+            })
+            .collect();
+        let entry = IndexEntry {
+            name: &package.name,
+            vers: &package.version,
+            deps,
+            cksum: checksum,
+            features: &metadata.features,
+            yanked: false,
+            links: metadata.links.as_deref(),
+        };
+        crates.entry(package.name.to_lowercase()).or_default().push((&package.version, serde_json::to_string(&entry)?));
+    }
+    Ok(crates)
+}
+
+/// The index the registry crates need with --vendor-strategy local-registry or
+/// registry-cache. A local registry has a file per crate with a line per
+/// version. Cargo's cache of the crates.io index has the same files in its
+/// own format, which starts with the version of the cache and of the index,
+/// and the `config.json` cargo wants even offline.
+pub fn registry_index(packages: &[&Package], cargo_metadata: &Metadata, args: &Args) -> anyhow::Result<Vec<Inline>> {
+    let index_dir = match args.vendor_strategy {
+        VendorStrategy::Directory => return Ok(Vec::new()),
+        VendorStrategy::LocalRegistry => format!("{}/index", args.local_registry_dir()),
+        VendorStrategy::RegistryCache => format!("{}/registry/index/{}", args.cargo_home_dir(), cargo_index_dir()?),
+    };
+    let mut files = Vec::new();
+    for (name, versions) in index_entries(packages, cargo_metadata)? {
+        let (contents, base64, dest) = match args.vendor_strategy {
+            VendorStrategy::RegistryCache => {
+                let mut cache = b"\x03\x02\0\0\0Unknown\0".to_vec();
+                for (version, entry) in versions {
+                    cache.extend([version.as_bytes(), b"\0", entry.as_bytes(), b"\0"].concat());
+                }
+                (base64(&cache), true, format!("{index_dir}/.cache/{}", crate_prefix(&name)))
+            }
+            _ => {
+                let lines = versions.iter().map(|(_, entry)| format!("{entry}\n")).collect();
+                (lines, false, format!("{index_dir}/{}", crate_prefix(&name)))
+            }
+        };
+        files.push(Inline { contents, base64, dest, dest_filename: name, x_cargo_lock_hash: None });
+    }
+    if args.vendor_strategy == VendorStrategy::RegistryCache && !files.is_empty() {
+        files.push(Inline {
+            contents: format!(r#"{{"dl": "{CRATES_IO}", "api": "https://crates.io"}}"#),
+            base64: false,
+            dest: index_dir,
+            dest_filename: "config.json".into(),
+            x_cargo_lock_hash: None,
+        });
+    }
+    Ok(files)
+}
+
+/// Renders a `--crate-url-template`
+pub fn crate_url(template: &str, name: &str, version: &str, checksum: &str) -> String {
+    template
+        .replace("{name}", name)
+        .replace("{version}", version)
+        .replace("{prefix}", &crate_prefix(name))
+        .replace("{checksum}", checksum)
+}
+
+/// Looks up `{name}-{version}.crate` in `--local-crates-dir`, returning its path
+/// when present. A file whose sha256 doesn't match the lockfile is an error.
+fn find_local_crate(package: &Package, checksum: &Sha256, args: &Args) -> anyhow::Result<Option<PathBuf>> {
+    let Some(dir) = &args.local_crates_dir else {
+        return Ok(None);
+    };
+    let crate_file = dir.join(format!("{}-{}.crate", package.name, package.version));
+    if !crate_file.is_file() {
+        if args.require_local {
+            anyhow::bail!("{} not found and --require-local is set", crate_file.display());
+        }
+        return Ok(None);
+    }
+    let actual = sha256_file(&crate_file)?;
+    if actual != checksum.as_str() {
+        anyhow::bail!(
+            "checksum mismatch for {}: Cargo.lock has {checksum}, file has {actual}",
+            crate_file.display()
+        );
+    }
+    Ok(Some(crate_file))
+}
+
+/// Names of the artifact dependencies (`artifact = "bin"`, a.k.a. bindeps)
+/// declared by a manifest, in any dependency table
+pub fn artifact_dependencies(manifest: &toml::Value) -> Vec<String> {
+    fn collect(entry: &toml::Value, names: &mut Vec<String>) {
+        for table in ["dependencies", "dev-dependencies", "build-dependencies"] {
+            for (name, dep) in entry.get(table).and_then(|d| d.as_table()).into_iter().flatten() {
+                if dep.get("artifact").is_some() {
+                    let name = dep.get("package").and_then(|p| p.as_str()).unwrap_or(name);
+                    names.push(name.to_string());
+                }
+            }
+        }
+        for target in entry.get("target").and_then(|t| t.as_table()).into_iter().flatten() {
+            collect(target.1, names);
+        }
+    }
+    let mut names = Vec::new();
+    collect(manifest, &mut names);
+    names
+}
+
+/// The source of crates.io packages in Cargo.lock
+pub const CRATES_IO_SOURCE: &str = "registry+https://github.com/rust-lang/crates.io-index";
+
+/// The directory of the cache of the crates.io index and crates of cargo
+/// `version`, named after a hash of the sparse index's source id. Cargo 1.85
+/// changed the hash, and cargo before 1.70 doesn't use the sparse index.
+pub fn crates_io_index_dir(version: &cargo_metadata::semver::Version) -> anyhow::Result<&'static str> {
+    match (version.major, version.minor) {
+        (1, ..=69) => anyhow::bail!(
+            "cargo {version} reads crates.io through its git index, --vendor-strategy registry-cache needs cargo 1.70 or later"
+        ),
+        (1, ..=84) => Ok("index.crates.io-6f17d22bba15001f"),
+        _ => Ok("index.crates.io-1949cf8c6b5b557f"),
+    }
+}
+
+/// The crates.io cache directory of the cargo the tool runs, which is taken
+/// to be the cargo of the build. Cargo is asked once.
+fn cargo_index_dir() -> anyhow::Result<&'static str> {
+    static DIR: std::sync::OnceLock<Result<&'static str, String>> = std::sync::OnceLock::new();
+    let dir = DIR.get_or_init(|| {
+        let version = crate::toolchain::cargo_version(&crate::toolchain::cargo()).map_err(|e| e.to_string())?;
+        crates_io_index_dir(&version).map_err(|e| e.to_string())
+    });
+    dir.clone().map_err(anyhow::Error::msg)
+}
+
+/// Lockfile sources of a local mirror of crates.io, a `local-registry` or a
+/// `directory`, by prefix. Their crates are downloaded from crates.io.
+pub const LOCAL_SOURCES: [(&str, &str); 2] = [("local-registry+", "local-registry"), ("directory+", "directory")];
+
+/// The sources of a stub of the registry package `package`, vendored in its
+/// place when no flatpak architecture builds it. Cargo still resolves the
+/// whole lockfile, so the stub keeps the features and dependencies `manifest`,
+/// the package as cargo metadata reports it, has. Its code is left out.
+pub fn stub_package_sources(package: &Package, manifest: Option<&cargo_metadata::Package>, args: &Args) -> anyhow::Result<Vec<Source>> {
+    let (name, version) = (&package.name, &package.version);
+    let Some(checksum) = &package.checksum else {
+        anyhow::bail!("{name} {version} has no checksum in Cargo.lock to vendor a stub of it with");
+    };
+    let table = |entries: &[(&str, &str)]| -> toml::Value {
+        toml::Value::Table(entries.iter().map(|(key, value)| (key.to_string(), (*value).into())).collect())
+    };
+    let mut stub = toml::Table::new();
+    stub.insert("package".into(), table(&[("name", name), ("version", version)]));
+    stub.insert("lib".into(), table(&[("path", "lib.rs")]));
+    if let Some(manifest) = manifest {
+        if !manifest.features.is_empty() {
+            let features = manifest.features.iter().map(|(feature, enables)| (feature.clone(), enables.clone().into()));
+            stub.insert("features".into(), toml::Value::Table(features.collect()));
+        }
+        for dependency in manifest.dependencies.iter().filter(|d| d.kind != cargo_metadata::DependencyKind::Development) {
+            let mut entry = toml::Table::new();
+            entry.insert("version".into(), dependency.req.to_string().into());
+            if dependency.rename.is_some() {
+                entry.insert("package".into(), dependency.name.clone().into());
+            }
+            if dependency.optional {
+                entry.insert("optional".into(), true.into());
+            }
+            if !dependency.uses_default_features {
+                entry.insert("default-features".into(), false.into());
+            }
+            if !dependency.features.is_empty() {
+                entry.insert("features".into(), dependency.features.clone().into());
+            }
+            let kind = match dependency.kind {
+                cargo_metadata::DependencyKind::Build => "build-dependencies",
+                _ => "dependencies",
+            };
+            fn subtable<'a>(table: &'a mut toml::Table, key: &str) -> &'a mut toml::Table {
+                table.entry(key).or_insert_with(|| toml::Table::new().into()).as_table_mut().unwrap()
+            }
+            let deps = match &dependency.target {
+                Some(target) => subtable(subtable(subtable(&mut stub, "target"), &target.to_string()), kind),
+                None => subtable(&mut stub, kind),
+            };
+            deps.insert(dependency.rename.as_ref().unwrap_or(&dependency.name).clone(), entry.into());
+        }
+    }
+    let dest = format!("{}/{name}-{version}", args.vendor_dir());
+    let package_checksum = match package.checksum_from_index {
+        true => "null".to_string(),
+        false => format!(r#""{checksum}""#),
+    };
+    let inline = |dest_filename: &str, contents: String| {
+        Source::Inline(Inline {
+            contents,
+            base64: false,
+            dest: dest.clone(),
+            dest_filename: dest_filename.into(),
+            x_cargo_lock_hash: None,
+        })
+    };
+    Ok(vec![
+        inline("Cargo.toml", toml::to_string(&stub)?),
+        inline("lib.rs", String::new()),
+        inline(".cargo-checksum.json", format!(r#"{{"package": {package_checksum}, "files": {{}}}}"#)),
+    ])
+}
+
+/// `manifest` is the package's Cargo.toml as reported by cargo metadata, which
+/// only git packages need. Packages cargo metadata doesn't know about, such as
+/// artifact dependencies, still work when they come from a registry.
+pub fn get_package_sources(
+    package: &Package,
+    manifest: Option<&str>,
+    args: &Args,
+) -> anyhow::Result<Option<PackageSources>> {
+    let name = &package.name;
+    let version = &package.version;
+
+    if let Some(source) = package.source.as_ref() {
+        if source.starts_with("git+") {
+            let Some(manifest) = manifest else {
+                anyhow::bail!(
+                    "git package {name} {version} is not reported by cargo metadata, \
+                     its Cargo.toml is needed to vendor it"
+                );
+            };
+            return get_git_package_sources(package, manifest, args).map(Some);
+        }
+
+        let local = LOCAL_SOURCES
+            .iter()
+            .find_map(|(prefix, kind)| Some((*kind, source.strip_prefix(prefix)?)));
+        if let (Some((kind, url)), None) = (local, &package.checksum) {
+            anyhow::bail!(
+                "{name} {version} comes from the {kind} source {url}, and without a checksum it can't be \
+                 downloaded from crates.io instead: regenerate Cargo.lock against crates.io, without the source replacement"
+            );
+        }
+
+        if let Some(checksum) = package.checksum.as_ref() {
+            let (url, path) = match find_local_crate(package, checksum, args)? {
+                Some(path) => (None, Some(utf8_path(&path)?.to_string())),
+                None => (Some(crate_url(&args.crate_url_template, name, version, checksum.as_str())), None),
+            };
+            let (crate_sources, replacement) = match args.vendor_strategy {
+                VendorStrategy::Directory => {
+                    let vendor_dir = args.vendor_dir();
+                    let archive = Source::Archive(Archive {
+                        archive_type: "tar-gzip".into(),
+                        url,
+                        path,
+                        sha256: checksum.clone(),
+                        dest: format!("{vendor_dir}/{name}-{version}"),
+                        dest_filename: args
+                            .archive_dest_filename
+                            .then(|| format!("{name}-{version}.crate")),
+                    });
+
+                    // Cargo only accepts a checksum for the package when Cargo.lock has it too
+                    let package_checksum = match package.checksum_from_index {
+                        true => "null".to_string(),
+                        false => format!(r#""{checksum}""#),
+                    };
+                    let inline = Source::Inline(Inline {
+                        contents: format!(r#"{{"package": {package_checksum}, "files": {{}}}}"#),
+                        base64: false,
+                        dest: format!("{vendor_dir}/{name}-{version}"),
+                        dest_filename: ".cargo-checksum.json".into(),
+                        x_cargo_lock_hash: None,
+                    });
+                    (vec![archive, inline], VENDORED_SOURCES)
+                }
+                // Local registries read the crates as they are downloaded, and
+                // the index of `local_registry_index` comes along
+                VendorStrategy::LocalRegistry => {
+                    let file = Source::File(File {
+                        url,
+                        path,
+                        sha256: Some(checksum.clone()),
+                        dest: args.local_registry_dir(),
+                        dest_filename: Some(format!("{name}-{version}.crate")),
+                        x_cargo_lock_hash: None,
+                    });
+                    (vec![file], VENDORED_REGISTRY)
+                }
+                // Cargo finds the crates in its cache, nothing is replaced
+                VendorStrategy::RegistryCache => {
+                    if source != CRATES_IO_SOURCE {
+                        anyhow::bail!(
+                            "{name} {version} comes from {source}, --vendor-strategy registry-cache only seeds crates.io"
+                        );
+                    }
+                    let file = Source::File(File {
+                        url,
+                        path,
+                        sha256: Some(checksum.clone()),
+                        dest: format!("{}/registry/cache/{}", args.cargo_home_dir(), cargo_index_dir()?),
+                        dest_filename: Some(format!("{name}-{version}.crate")),
+                        x_cargo_lock_hash: None,
+                    });
+                    return Ok(Some((vec![file], Map::new())));
+                }
+            };
+
+            let mut c = Map::new();
+            let mut obj = Map::new();
+            match local {
+                // The lockfile names the local source, so that is what the vendored crates replace
+                Some((kind, url)) => {
+                    let path = Url::parse(url)
+                        .ok()
+                        .and_then(|url| url.to_file_path().ok())
+                        .ok_or_else(|| anyhow::anyhow!("{name} {version}: `{url}` is not a file URL"))?;
+                    obj.insert(kind.into(), utf8_path(&path)?.into());
+                    obj.insert("replace-with".into(), replacement.into());
+                    c.insert(source.clone(), obj.into());
+                }
+                None => {
+                    obj.insert("replace-with".into(), replacement.into());
+                    c.insert("crates-io".into(), obj.into());
+                }
+            }
+
+            return Ok(Some((crate_sources, c)));
+        }
+        let suggestion = "regenerate the lockfile with `cargo generate-lockfile`, \
+                          or pass --resolve-missing-checksums to look the checksum up in the index";
+        return Err(Annotated::new(anyhow::anyhow!("{name} {version} has no checksum in Cargo.lock"), suggestion).into());
+    }
+
+    Ok(None)
+}
+
+/// Ships Cargo.lock to `dest`, inline or as a file source when `lockfile` is
+/// the path to reference, relative to `manifest_dir`
+pub fn lockfile_source(
+    contents: &str,
+    lockfile: Option<&Path>,
+    manifest_dir: &Path,
+    dest: &str,
+) -> anyhow::Result<Source> {
+    Ok(match lockfile {
+        Some(lockfile) => {
+            let path = pathdiff::diff_paths(lockfile.canonicalize()?, manifest_dir.canonicalize()?)
+                .unwrap_or_else(|| lockfile.to_path_buf());
+            Source::File(File {
+                url: None,
+                path: Some(utf8_path(&path)?.to_string()),
+                sha256: None,
+                dest: dest.into(),
+                dest_filename: Some("Cargo.lock".into()),
+                x_cargo_lock_hash: None,
+            })
+        }
+        None => Source::Inline(Inline {
+            contents: contents.to_string(),
+            base64: false,
+            dest: dest.into(),
+            dest_filename: "Cargo.lock".into(),
+            x_cargo_lock_hash: None,
+        }),
+    })
+}
+
+/// A path dependency that lives outside of the workspace, and therefore
+/// outside of the flatpak source tree
+#[derive(Debug)]
+pub struct PathDependency {
+    pub name: String,
+    pub version: String,
+    pub dir: PathBuf,
+}
+
+/// Bundles path dependencies with `dir` sources, `base_dir` being the directory
+/// of the generated sources file that flatpak-builder resolves `path` against.
+/// Cargo reads them from where the manifests point, so each goes where it is
+/// relative to `workspace`, which the build has at --dest-prefix.
+/// Without `--bundle-path-deps` these dependencies are an error, since the build would miss them.
+pub fn get_path_dependency_sources(
+    deps: &[PathDependency],
+    workspace: &Path,
+    base_dir: &Path,
+    args: &Args,
+) -> anyhow::Result<Vec<Source>> {
+    if deps.is_empty() {
+        return Ok(Vec::new());
+    }
+    if !args.bundle_path_deps {
+        let list: Vec<String> = deps
+            .iter()
+            .map(|d| format!("  {} {} ({})", d.name, d.version, d.dir.display()))
+            .collect();
+        anyhow::bail!(
+            "path dependencies outside of the workspace won't be available in the flatpak build:\n{}\n\
+             move them into the workspace or pass --bundle-path-deps",
+            list.join("\n")
+        );
+    }
+
+    let canonical = |path: &Path| path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    let (workspace, base_dir) = (canonical(workspace), canonical(base_dir));
+    let mut sources = Vec::new();
+    for dep in deps {
+        let dir = canonical(&dep.dir);
+        let path = pathdiff::diff_paths(&dir, &base_dir).unwrap_or(dir.clone());
+        let relative = pathdiff::diff_paths(&dir, &workspace).unwrap_or(dir.clone());
+        let mut dest = PathBuf::new();
+        for component in Path::new(&args.dest(".")).join(&relative).components() {
+            match component {
+                Component::CurDir => {}
+                Component::Normal(name) => dest.push(name),
+                Component::ParentDir if dest.pop() => {}
+                _ => {
+                    let up = relative.components().take_while(|c| *c == Component::ParentDir).count();
+                    anyhow::bail!(
+                        "{} {} is at {} from the workspace, which is outside of the build directory, \
+                         pass a --dest-prefix {up} directories deep or more",
+                        dep.name,
+                        dep.version,
+                        relative.display(),
+                    );
+                }
+            }
+        }
+        sources.push(Source::Dir(Dir {
+            path: utf8_path(&path)?.to_string(),
+            dest: utf8_path(&dest)?.to_string(),
+        }));
+    }
+    Ok(sources)
+}
+
+/// The owner of the Cargo.lock entry shipped with --include-lockfile
+pub const LOCKFILE_OWNER: &str = "Cargo.lock";
+/// The owner of the cargo config entry
+pub const CONFIG_OWNER: &str = "cargo config";
+
+/// One generated source and what it's for
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceEntry {
+    pub source: Source,
+    /// The directory name of the vendored crate the source belongs to, like
+    /// `anstream-0.6.15`, or [`LOCKFILE_OWNER`] and [`CONFIG_OWNER`]
+    pub owner: String,
+    /// Where the crate comes from, `None` for the lockfile and the config
+    pub kind: Option<SourceKind>,
+}
+
+/// The sources of a lockfile, each with the crate it's for and in the order
+/// flatpak-builder runs them, and the cargo config that vendors the crates.
+/// The config is a source too, the last one, once it's pushed.
+pub struct SourceSet {
+    entries: Vec<SourceEntry>,
+    pub config: CargoConfig,
+}
+
+/// What changed from one [`SourceSet`] to another, by owner
+#[derive(Debug, Default, PartialEq)]
+pub struct SourceDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub changed: Vec<String>,
+}
+
+/// A line per kind of change, each line starting with a newline
+impl std::fmt::Display for SourceDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (change, owners) in [("added", &self.added), ("removed", &self.removed), ("changed", &self.changed)] {
+            if !owners.is_empty() {
+                write!(f, "\n  {change}: {}", owners.join(", "))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl SourceDiff {
+    /// The changes on one line, `no changes` if there are none
+    pub fn summary(&self) -> String {
+        let changes: Vec<_> = [("added", &self.added), ("removed", &self.removed), ("changed", &self.changed)]
+            .into_iter()
+            .filter(|(_, owners)| !owners.is_empty())
+            .map(|(change, owners)| format!("{change} {}", owners.join(", ")))
+            .collect();
+        match changes.is_empty() {
+            true => "no changes".into(),
+            false => changes.join("; "),
+        }
+    }
+}
+
+/// The last path component of a dest, the name of a vendored crate directory
+fn dest_name(dest: &str) -> &str {
+    dest.trim_end_matches('/').rsplit('/').next().unwrap_or(dest)
+}
+
+fn same_clone(a: &Source, b: &Source) -> bool {
+    match (a, b) {
+        (Source::Git(a), Source::Git(b)) => a.url == b.url && a.commit == b.commit,
+        (Source::Archive(a), Source::Archive(b)) => a.url == b.url && a.dest == b.dest,
+        _ => false,
+    }
+}
+
+/// The crate of a `.crate` file or an index file of a local registry
+fn local_registry_owner(source: &Source) -> Option<String> {
+    match source {
+        Source::File(File { dest_filename: Some(name), .. }) => Some(name.strip_suffix(".crate")?.to_string()),
+        Source::Inline(Inline { dest, dest_filename, .. })
+            if dest.contains("/index/") && dest.ends_with(&format!("/{}", crate_prefix(dest_filename))) =>
+        {
+            Some(dest_filename.clone())
+        }
+        _ => None,
+    }
+}
+
+/// The owner of a crate's sources, which end with its `.cargo-checksum.json`
+fn crate_owner(sources: &[Source]) -> Option<String> {
+    sources.iter().rev().find_map(|source| match source {
+        Source::Inline(inline) if inline.dest_filename == ".cargo-checksum.json" => {
+            Some(dest_name(&inline.dest).to_string())
+        }
+        _ => None,
+    })
+}
+
+impl SourceSet {
+    /// An empty set whose config vendors to `vendor_dir`
+    pub fn new(vendor_dir: &str) -> Self {
+        SourceSet { entries: Vec::new(), config: CargoConfig::new(vendor_dir) }
+    }
+
+    /// Recovers the owners and the config of sources as written by
+    /// [`SourceSet::to_json`]. The owners are only exact for sources grouped
+    /// by crate, with `--group-by type` each source is taken for its own crate.
+    pub fn from_sources(sources: Vec<Source>) -> anyhow::Result<Self> {
+        let mut set = SourceSet { entries: Vec::new(), config: CargoConfig::from_toml("")? };
+        // Sources not followed by a checksum are taken for the crate at their own dest,
+        // but for the crates and index files of a local registry
+        let alone = |source: Source| {
+            let owner = local_registry_owner(&source).unwrap_or_else(|| match &source {
+                Source::Archive(Archive { dest, .. })
+                | Source::Git(Git { dest, .. })
+                | Source::Inline(Inline { dest, .. })
+                | Source::File(File { dest, .. })
+                | Source::Dir(Dir { dest, .. }) => dest_name(dest).to_string(),
+                Source::Shell(_) | Source::Other(_) => String::new(),
+            });
+            let kind = Some(Self::group_kind(std::slice::from_ref(&source)));
+            SourceEntry { source, owner, kind }
+        };
+        let mut pending = Vec::new();
+        for source in sources {
+            let (lockfile, config) = match &source {
+                Source::Inline(inline) => {
+                    let config = matches!(inline.dest_filename.as_str(), "config" | "config.toml");
+                    if config {
+                        set.config = CargoConfig::from_toml(&inline.contents)?;
+                    }
+                    (inline.dest_filename == "Cargo.lock", config)
+                }
+                Source::File(file) => {
+                    let name = file.dest_filename.as_deref();
+                    (name == Some("Cargo.lock"), name == Some("config.toml"))
+                }
+                _ => (false, false),
+            };
+            if lockfile || config {
+                set.entries.extend(pending.drain(..).map(alone));
+                let owner = if lockfile { LOCKFILE_OWNER } else { CONFIG_OWNER };
+                set.push(None, owner, source);
+                continue;
+            }
+            let owner = match &source {
+                Source::Inline(inline) if inline.dest_filename == ".cargo-checksum.json" => {
+                    Some(dest_name(&inline.dest).to_string())
+                }
+                _ => None,
+            };
+            pending.push(source);
+            if let Some(owner) = owner {
+                let kind = Some(Self::group_kind(&pending));
+                set.entries.extend(pending.drain(..).map(|source| SourceEntry { source, owner: owner.clone(), kind }));
+            }
+        }
+        set.entries.extend(pending.into_iter().map(alone));
+        Ok(set)
+    }
+
+    /// Reads a sources file written by [`SourceSet::to_json`]
+    #[cfg(test)]
+    pub fn from_reader(reader: impl std::io::Read) -> anyhow::Result<Self> {
+        Self::from_sources(serde_json::from_reader(reader)?)
+    }
+
+    fn group_kind(sources: &[Source]) -> SourceKind {
+        // Git crates are copied out of their checkout, even one from an archive
+        if sources.iter().any(|s| matches!(s, Source::Git(_) | Source::Shell(_))) {
+            SourceKind::Git
+        } else if sources.iter().any(|s| matches!(s, Source::Archive(_)) || local_registry_owner(s).is_some()) {
+            SourceKind::Registry
+        } else if sources.iter().any(|s| matches!(s, Source::Dir(_))) {
+            SourceKind::Path
+        } else {
+            SourceKind::Git
+        }
+    }
+
+    pub fn entries(&self) -> &[SourceEntry] {
+        &self.entries
+    }
+
+    pub fn sources(&self) -> Vec<&Source> {
+        self.entries.iter().map(|entry| &entry.source).collect()
+    }
+
+    pub fn push(&mut self, kind: Option<SourceKind>, owner: impl Into<String>, source: Source) {
+        self.entries.push(SourceEntry { source, owner: owner.into(), kind });
+    }
+
+    /// Whether the set already has the clone, or commit archive, `source`
+    /// stands for
+    fn has_clone(&self, source: &Source) -> bool {
+        self.entries.iter().any(|entry| same_clone(&entry.source, source))
+    }
+
+    /// Adds the sources of `package` and its `[source]` config entries.
+    /// Crates from the same repository share the clone of the first one.
+    pub fn push_package(&mut self, package: &Package, manifest: Option<&str>, args: &Args) -> anyhow::Result<()> {
+        let Some((sources, config_entries)) = get_package_sources(package, manifest, args)? else {
+            return Ok(());
+        };
+        let kind = SourceKind::of(package);
+        let owner = crate_owner(&sources).unwrap_or_else(|| format!("{}-{}", package.name, package.version));
+        for source in sources {
+            if self.has_clone(&source) {
+                continue;
+            }
+            self.push(Some(kind), owner.clone(), source);
+        }
+        self.config.add_sources(config_entries)?;
+        Ok(())
+    }
+
+    /// Pushes the git crate replacing a registry crate through `[replace]`.
+    /// Cargo still reads the replaced crate from crates.io, vendored as usual,
+    /// so the replacement goes in a directory of its own, the `replaced-sources`
+    /// source, where the two crates of the same name and version don't clash.
+    pub fn push_replacement(&mut self, package: &Package, manifest: Option<&str>, args: &Args) -> anyhow::Result<()> {
+        let Some(manifest) = manifest else {
+            anyhow::bail!("the [replace] crate {} {} is not reported by cargo metadata", package.name, package.version);
+        };
+        let replaced_dir = args.replaced_dir();
+        // Two versions of a crate may be replaced, unlike vendored git crates the copies are told apart by version
+        let dest_name = format!("{}-{}", package.name, package.version);
+        let (sources, config_entries) = git_package_sources(package, manifest, &replaced_dir, &dest_name, REPLACED_SOURCES, args)
+            .map_err(|e| e.context(format!("failed to vendor the [replace] crate {} {}", package.name, package.version)))?;
+        let owner = crate_owner(&sources).unwrap_or_else(|| format!("{}-{}", package.name, package.version));
+        for source in sources {
+            if self.has_clone(&source) {
+                continue;
+            }
+            self.push(Some(SourceKind::Git), owner.clone(), source);
+        }
+        let mut replaced = Map::new();
+        replaced.insert("directory".to_string(), replaced_dir.into());
+        self.config.section_mut("source").insert(REPLACED_SOURCES.into(), replaced.into());
+        self.config.add_sources(config_entries)?;
+        Ok(())
+    }
+
+    /// Orders the crate sources for --group-by. By type, registry archives come
+    /// first, then their checksums, then the git clones, copy commands and the
+    /// files written into the copies, then bundled path dependencies. Sources
+    /// keep their order within a group, and a crate's files always follow what
+    /// they're written into. The lockfile and the config stay last.
+    pub fn group_by(&mut self, group_by: GroupBy) {
+        if group_by == GroupBy::Type {
+            self.entries.sort_by_key(|entry| match (entry.kind, &entry.source) {
+                (None, _) => 7,
+                (_, Source::Archive(_)) => 0,
+                (Some(SourceKind::Registry), _) => 1,
+                (_, Source::Git(_)) => 2,
+                (_, Source::Shell(_)) => 3,
+                (Some(SourceKind::Git), _) => 4,
+                (_, Source::Dir(_)) => 5,
+                _ => 6,
+            });
+        }
+    }
+
+    /// Sorts the registry crates by name and version, like Cargo.lock, among
+    /// the places they hold: the other sources stay where they are, the copies
+    /// of git crates depending on the clones before them
+    pub fn sort_registry_crates(&mut self) {
+        let key = |owner: &str| {
+            let split = owner.match_indices('-').find_map(|(i, _)| Some((&owner[..i], cargo_metadata::semver::Version::parse(&owner[i + 1..]).ok()?)));
+            split.map_or((owner.to_string(), None), |(name, version)| (name.to_string(), Some(version)))
+        };
+        let mut runs: Vec<Vec<SourceEntry>> = Vec::new();
+        for entry in self.entries.drain(..) {
+            match runs.last_mut() {
+                Some(run) if run[0].owner == entry.owner && run[0].kind == entry.kind => run.push(entry),
+                _ => runs.push(vec![entry]),
+            }
+        }
+        // The index files of a local registry follow the crates
+        let crate_run = |run: &[SourceEntry]| run[0].kind == Some(SourceKind::Registry) && matches!(run[0].source, Source::Archive(_) | Source::File(_));
+        let registry: Vec<usize> = (0..runs.len()).filter(|&i| crate_run(&runs[i])).collect();
+        let mut sorted: Vec<Vec<SourceEntry>> = registry.iter().map(|&i| std::mem::take(&mut runs[i])).collect();
+        sorted.sort_by_key(|run| key(&run[0].owner));
+        for (i, run) in registry.into_iter().zip(sorted) {
+            runs[i] = run;
+        }
+        self.entries = runs.into_iter().flatten().collect();
+    }
+
+    /// Moves the last crate copied out of each git clone, or commit archive,
+    /// instead of copying it, and then removes the clone. The earlier copies
+    /// out of the clone still need it, and so do the other files of the last
+    /// crate, which are copied after the crate itself.
+    pub fn move_git_sources(&mut self) {
+        let clones: Vec<String> = self
+            .entries
+            .iter()
+            .filter_map(|entry| match &entry.source {
+                Source::Git(git) => Some(git.dest.clone()),
+                Source::Archive(archive) if entry.kind == Some(SourceKind::Git) => Some(archive.dest.clone()),
+                _ => None,
+            })
+            .collect();
+        for clone in clones {
+            let from = format!("\"{clone}/");
+            // The copy of the crate, after the vendor directory is made
+            let copy = |c: &String| c.starts_with("cp -r --reflink=auto ") && c.contains(&from);
+            let last = self.entries.iter_mut().rev().find_map(|entry| match &mut entry.source {
+                Source::Shell(shell) => Some((shell.commands.iter().position(copy)?, shell)),
+                _ => None,
+            });
+            let Some((i, shell)) = last else {
+                continue;
+            };
+            shell.commands[i] = format!("mv {}", &shell.commands[i]["cp -r --reflink=auto ".len()..]);
+            shell.commands.push(format!(r#"rm -rf "{clone}""#));
+        }
+    }
+
+    /// Orders the crates by owner, keeping the sources of each together and
+    /// in order, and the lockfile and the config last. A git clone, or the
+    /// commit archive standing for it, moves to the first crate copied out
+    /// of it, so it still comes before the copies.
+    pub fn sort_by_crate(&mut self) {
+        let checkout = |entry: &SourceEntry| match &entry.source {
+            Source::Git(git) => Some(git.dest.clone()),
+            Source::Archive(archive) if entry.kind == Some(SourceKind::Git) => Some(archive.dest.clone()),
+            _ => None,
+        };
+        let (clones, mut entries): (Vec<_>, Vec<_>) =
+            std::mem::take(&mut self.entries).into_iter().partition(|entry| checkout(entry).is_some());
+        entries.sort_by(|a, b| (a.kind.is_none(), &a.owner).cmp(&(b.kind.is_none(), &b.owner)));
+        for mut clone in clones {
+            let from = format!("\"{}/", checkout(&clone).unwrap());
+            let copies = |entry: &&SourceEntry| {
+                matches!(&entry.source, Source::Shell(shell) if shell.commands.iter().any(|c| c.contains(&from)))
+            };
+            if let Some(copy) = entries.iter().find(copies) {
+                clone.owner = copy.owner.clone();
+            }
+            let end = entries.iter().position(|entry| entry.kind.is_none()).unwrap_or(entries.len());
+            let at = entries.iter().position(|entry| entry.owner == clone.owner).unwrap_or(end);
+            entries.insert(at, clone);
+        }
+        self.entries = entries;
+    }
+
+    /// Adds the crates of `other` that aren't in the set yet, before the
+    /// lockfile and the config, along with its `[source]` config entries.
+    /// Its lockfile and config entries are dropped, an inline config of the
+    /// set is rewritten from the merged config.
+    pub fn merge(&mut self, other: SourceSet) -> anyhow::Result<()> {
+        let owners: HashSet<String> = self.entries.iter().map(|entry| entry.owner.clone()).collect();
+        let at = self.entries.iter().position(|entry| entry.kind.is_none()).unwrap_or(self.entries.len());
+        let mut added: Vec<SourceEntry> = Vec::new();
+        for entry in other.entries {
+            if entry.kind.is_none() || owners.contains(&entry.owner) {
+                continue;
+            }
+            if self.has_clone(&entry.source) || added.iter().any(|added| same_clone(&added.source, &entry.source)) {
+                continue;
+            }
+            added.push(entry);
+        }
+        self.entries.splice(at..at, added);
+        self.config.merge_sources(&other.config);
+        let contents = self.config.to_toml()?;
+        for entry in &mut self.entries {
+            if let (CONFIG_OWNER, Source::Inline(inline)) = (entry.owner.as_str(), &mut entry.source) {
+                inline.contents = contents.clone();
+            }
+        }
+        Ok(())
+    }
+
+    /// The crates `other` adds, removes and changes the sources of, in the
+    /// order of the set they're in
+    pub fn diff(&self, other: &SourceSet) -> SourceDiff {
+        fn by_owner(set: &SourceSet) -> (Vec<&str>, HashMap<&str, Vec<&Source>>) {
+            let mut owners = Vec::new();
+            let mut sources: HashMap<&str, Vec<&Source>> = HashMap::new();
+            for entry in &set.entries {
+                let group = sources.entry(&entry.owner).or_insert_with(|| {
+                    owners.push(entry.owner.as_str());
+                    Vec::new()
+                });
+                group.push(&entry.source);
+            }
+            (owners, sources)
+        }
+        let (old_owners, old) = by_owner(self);
+        let (new_owners, new) = by_owner(other);
+        SourceDiff {
+            added: new_owners.iter().filter(|o| !old.contains_key(*o)).map(|o| o.to_string()).collect(),
+            removed: old_owners.iter().filter(|o| !new.contains_key(*o)).map(|o| o.to_string()).collect(),
+            changed: old_owners
+                .iter()
+                .filter(|o| new.get(*o).is_some_and(|sources| *sources != old[*o]))
+                .map(|o| o.to_string())
+                .collect(),
+        }
+    }
+
+    /// The sources of git crates, and the others along with the lockfile and
+    /// the config, each in their order
+    pub fn partition_git(&self) -> (Vec<&Source>, Vec<&Source>) {
+        let (git, others): (Vec<_>, Vec<_>) = self.entries.iter().partition(|entry| entry.kind == Some(SourceKind::Git));
+        (git.into_iter().map(|entry| &entry.source).collect(), others.into_iter().map(|entry| &entry.source).collect())
+    }
+
+    /// Splits the sources into chunks of at most `max` entries, never
+    /// separating the sources of one crate. The cargo config stays last.
+    pub fn split(&self, max: usize) -> anyhow::Result<Vec<Vec<&Source>>> {
+        let mut chunks: Vec<Vec<&Source>> = vec![Vec::new()];
+        let mut i = 0;
+        while i < self.entries.len() {
+            let owner = &self.entries[i].owner;
+            let len = self.entries[i..].iter().take_while(|e| e.owner == *owner).count();
+            if len > max {
+                anyhow::bail!("{owner} has {len} sources, more than --split {max} allows in a file");
+            }
+            if chunks.last().unwrap().len() + len > max {
+                chunks.push(Vec::new());
+            }
+            chunks.last_mut().unwrap().extend(self.entries[i..i + len].iter().map(|e| &e.source));
+            i += len;
+        }
+        Ok(chunks)
+    }
+
+    /// Writes the sources file, as flatpak-builder reads it
+    pub fn write_json(&self, out: impl std::io::Write) -> serde_json::Result<()> {
+        serde_json::to_writer_pretty(out, &self.sources())
+    }
+
+    #[cfg(test)]
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        let mut json = Vec::new();
+        self.write_json(&mut json)?;
+        Ok(String::from_utf8(json).unwrap())
+    }
+
+    /// Writes the sources file as YAML, which flatpak-builder reads too
+    pub fn write_yaml(&self, out: impl std::io::Write) -> anyhow::Result<()> {
+        write_yaml(out, &self.sources())
+    }
+
+    #[cfg(test)]
+    pub fn to_yaml(&self) -> anyhow::Result<String> {
+        let mut yaml = Vec::new();
+        self.write_yaml(&mut yaml)?;
+        Ok(String::from_utf8(yaml).unwrap())
+    }
+}
+
+/// What a sources file is written as, flatpak-builder reading both
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum SourcesFormat {
+    Json,
+    Yaml,
+}
+
+impl SourcesFormat {
+    /// YAML for a path ending in `.yml` or `.yaml`, JSON otherwise
+    pub fn of(path: &Path) -> SourcesFormat {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("yml" | "yaml") => SourcesFormat::Yaml,
+            _ => SourcesFormat::Json,
+        }
+    }
+}
+
+/// Writes `sources` as a sources file in `format`
+pub fn write_sources(out: impl std::io::Write, sources: &[&Source], format: SourcesFormat) -> anyhow::Result<()> {
+    match format {
+        SourcesFormat::Yaml => write_yaml(out, sources),
+        SourcesFormat::Json => Ok(serde_json::to_writer_pretty(out, sources)?),
+    }
+}
+
+/// Whether YAML reads `s` unquoted as the same string, and not as a number,
+/// a boolean, null or some syntax
+pub(crate) fn yaml_plain(s: &str) -> bool {
+    s.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_' || c == '/')
+        && s.chars().all(|c| c.is_ascii_alphanumeric() || "_-./+@%~".contains(c))
+        && !["y", "n", "yes", "no", "on", "off", "true", "false", "null"].contains(&s.to_ascii_lowercase().as_str())
+}
+
+/// Writes `sources` in block style YAML. Strings that YAML could read as
+/// something else are written in double quotes, as JSON escapes them.
+fn write_yaml(mut out: impl std::io::Write, sources: &[&Source]) -> anyhow::Result<()> {
+    fn scalar(value: &serde_json::Value) -> String {
+        match value {
+            serde_json::Value::String(s) if yaml_plain(s) => s.clone(),
+            serde_json::Value::Array(a) if a.is_empty() => "[]".into(),
+            serde_json::Value::Object(o) if o.is_empty() => "{}".into(),
+            value => value.to_string(),
+        }
+    }
+    fn block(value: &serde_json::Value, indent: usize, yaml: &mut String) {
+        let nested = |value: &serde_json::Value| match value {
+            serde_json::Value::Array(a) => !a.is_empty(),
+            serde_json::Value::Object(o) => !o.is_empty(),
+            _ => false,
+        };
+        match value {
+            serde_json::Value::Array(items) => {
+                for item in items {
+                    yaml.push_str(&format!("{:indent$}- ", ""));
+                    match item {
+                        // The first key goes on the line of the dash
+                        serde_json::Value::Object(_) if nested(item) => {
+                            let mut entry = String::new();
+                            block(item, indent + 2, &mut entry);
+                            yaml.push_str(&entry[indent + 2..]);
+                        }
+                        _ if nested(item) => {
+                            yaml.push('\n');
+                            block(item, indent + 2, yaml);
+                        }
+                        _ => yaml.push_str(&format!("{}\n", scalar(item))),
+                    }
+                }
+            }
+            serde_json::Value::Object(entries) => {
+                for (key, value) in entries {
+                    let key = scalar(&key.as_str().into());
+                    match nested(value) {
+                        true => {
+                            yaml.push_str(&format!("{:indent$}{key}:\n", ""));
+                            block(value, indent + 2, yaml);
+                        }
+                        false => yaml.push_str(&format!("{:indent$}{key}: {}\n", "", scalar(value))),
+                    }
+                }
+            }
+            value => yaml.push_str(&format!("{:indent$}{}\n", "", scalar(value))),
+        }
+    }
+    let mut yaml = String::new();
+    block(&serde_json::to_value(sources)?, 0, &mut yaml);
+    if yaml.is_empty() {
+        yaml.push_str("[]\n");
+    }
+    Ok(out.write_all(yaml.as_bytes())?)
+}
+
+#[test]
+fn lock_file() {
+    let src = std::fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.lock")).unwrap();
+
+    let file: LockFile = toml::from_str(&src).unwrap();
 
     dbg!(file);
 }
 
+#[test]
+fn crates_io_index_dirs() {
+    let dir = |version: &str| crates_io_index_dir(&version.parse().unwrap()).map_err(|e| e.to_string());
+    assert_eq!(dir("1.70.0"), Ok("index.crates.io-6f17d22bba15001f"));
+    assert_eq!(dir("1.84.1"), Ok("index.crates.io-6f17d22bba15001f"));
+    assert_eq!(dir("1.85.0"), Ok("index.crates.io-1949cf8c6b5b557f"));
+    assert_eq!(dir("1.90.0-nightly"), Ok("index.crates.io-1949cf8c6b5b557f"));
+    assert_eq!(
+        dir("1.69.0"),
+        Err("cargo 1.69.0 reads crates.io through its git index, --vendor-strategy registry-cache needs cargo 1.70 or later".into())
+    );
+}
+
+#[test]
+fn malformed_hashes() {
+    let lock = "version = 3\n\n[[package]]\nname = \"anstream\"\nversion = \"0.6.15\"\n\
+                source = \"registry+https://github.com/rust-lang/crates.io-index\"\nchecksum = \"64e15c\"\n";
+    let err = toml::from_str::<LockFile>(lock).unwrap_err().to_string();
+    assert!(err.contains("anstream 0.6.15: invalid sha256 checksum `64e15c`, expected 64 hex digits"), "{err}");
+
+    let package = git_package("foo", "git+https://github.com/example/foo?branch=main#main");
+    let err = get_git_package_sources(&package, "Cargo.toml", &default_args()).unwrap_err().to_string();
+    assert_eq!(err, "foo 0.1.0: invalid git commit `main`, expected 40 or 64 hex digits");
+}
+
 #[test]
 fn source() {
     let src = Source::Inline(Inline {
         contents: "a".into(),
+        base64: false,
         dest: "a".into(),
         dest_filename: "a".into(),
+        x_cargo_lock_hash: None,
     });
 
     println!("{}", serde_json::to_string_pretty(&src).unwrap());
 }
+
+#[cfg(test)]
+fn default_args() -> Args {
+    use clap::Parser;
+    Args::parse_from(["flatpak"])
+}
+
+#[cfg(test)]
+fn registry_package(name: &str, version: &str) -> Package {
+    Package {
+        name: name.into(),
+        version: version.into(),
+        source: Some("registry+https://github.com/rust-lang/crates.io-index".into()),
+        checksum: Some(FIXTURE_CHECKSUM.try_into().unwrap()),
+        checksum_from_index: false,
+        dependencies: None,
+    }
+}
+
+/// The checksum of every registry crate of the test fixtures
+#[cfg(test)]
+pub(crate) const FIXTURE_CHECKSUM: &str = "64e15c1ab1f89faffbf04a634d5e1962e9074f2741eef6d97f3c4e322426d526";
+
+#[cfg(test)]
+pub(crate) fn write_fixture(root: &Path, files: &[(&str, impl AsRef<str>)]) {
+    for (path, contents) in files {
+        let path = root.join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, contents.as_ref()).unwrap();
+    }
+}
+
+#[cfg(test)]
+fn git_package(name: &str, source: &str) -> Package {
+    Package {
+        name: name.into(),
+        version: "0.1.0".into(),
+        source: Some(source.into()),
+        checksum: None,
+        checksum_from_index: false,
+        dependencies: None,
+    }
+}
+
+#[test]
+fn brace_expansion() {
+    assert_eq!(expand_braces("crates/*"), vec!["crates/*"]);
+    assert_eq!(expand_braces("{a,b}/x{1,2}"), vec!["a/x1", "a/x2", "b/x1", "b/x2"]);
+}
+
+#[test]
+fn workspace_member_globs() {
+    let tmp = tempfile::tempdir().unwrap();
+    let member = |name: &str| format!("[package]\nname = \"{name}\"\nversion = \"0.1.0\"\n");
+    write_fixture(
+        tmp.path(),
+        &[
+            (
+                "Cargo.toml",
+                "[workspace]\nmembers = [\"crates/*\", \"{tools,extra}/cli\", \"crates/a\"]\nexclude = [\"crates/skipped\"]\n",
+            ),
+            ("crates/a/Cargo.toml", &member("a")),
+            ("crates/b/Cargo.toml", &member("b")),
+            ("crates/skipped/Cargo.toml", &member("skipped")),
+            ("crates/docs/README.md", ""),
+            ("tools/cli/Cargo.toml", &member("cli")),
+        ],
+    );
+    let root_manifest = std::fs::read_to_string(tmp.path().join("Cargo.toml")).unwrap();
+    let root = load_toml(&root_manifest);
+
+    let members = workspace_members(root.get("workspace").unwrap(), tmp.path()).unwrap();
+    assert_eq!(
+        members,
+        vec![PathBuf::from("crates/a"), PathBuf::from("crates/b"), PathBuf::from("tools/cli")]
+    );
+
+    let packages = get_cargo_toml_packages(&root_manifest, tmp.path(), tmp.path(), 32).unwrap();
+    let mut names: Vec<_> = packages.keys().map(String::as_str).collect();
+    names.sort();
+    assert_eq!(names, ["a", "b", "cli"]);
+    // The members share the workspace table rather than holding a copy each
+    let workspace = packages["a"].workspace.as_ref().unwrap();
+    assert!(Rc::ptr_eq(workspace, packages["cli"].workspace.as_ref().unwrap()));
+    assert_eq!(Rc::strong_count(workspace), 3);
+}
+
+#[test]
+fn virtual_workspace_member() {
+    let tmp = tempfile::tempdir().unwrap();
+    write_fixture(
+        tmp.path(),
+        &[
+            (
+                "Cargo.toml",
+                "[workspace]\nmembers = [\"crates/*\"]\n\n[workspace.package]\nversion = \"1.2.3\"\n\n[workspace.dependencies]\nserde = \"1.0\"\n",
+            ),
+            (
+                "crates/foo/Cargo.toml",
+                "[package]\nname = \"foo\"\nversion.workspace = true\n\n[dependencies]\nserde.workspace = true\n",
+            ),
+        ],
+    );
+    let manifest = tmp.path().join("crates/foo/Cargo.toml");
+    let source = "git+https://github.com/example/foo-rs?rev=0123456#0123456789abcdef0123456789abcdef01234567";
+
+    let (sources, _) = get_git_package_sources(&git_package("foo", source), manifest.to_str().unwrap(), &default_args()).unwrap();
+
+    let Source::Shell(shell) = &sources[1] else { panic!("expected shell source") };
+    assert!(shell.commands[1].contains("flatpak-cargo/git/example-foo-rs-d1a67df3-0123456/crates/foo\""));
+    let Source::Inline(cargo_toml) = &sources[2] else { panic!("expected inline source") };
+    let normalized = load_toml(&cargo_toml.contents);
+    assert_eq!(normalized["package"]["version"].as_str(), Some("1.2.3"));
+    assert_eq!(normalized["dependencies"]["serde"].as_str(), Some("1.0"));
+}
+
+#[test]
+fn path_dependencies_outside_workspace() {
+    let deps = vec![PathDependency {
+        name: "shared-lib".into(),
+        version: "0.2.0".into(),
+        dir: PathBuf::from("/home/user/shared-lib"),
+    }];
+    let workspace = Path::new("/home/user/app");
+    let base_dir = workspace.join("flatpak");
+
+    let mut args = default_args();
+    let err = get_path_dependency_sources(&deps, workspace, &base_dir, &args).unwrap_err();
+    assert!(err.to_string().contains("shared-lib 0.2.0"));
+    assert!(err.to_string().contains("--bundle-path-deps"));
+
+    // Cargo looks for it next to the workspace
+    args.bundle_path_deps = true;
+    let err = get_path_dependency_sources(&deps, workspace, &base_dir, &args).unwrap_err().to_string();
+    assert_eq!(
+        err,
+        "shared-lib 0.2.0 is at ../shared-lib from the workspace, which is outside of the build directory, \
+         pass a --dest-prefix 1 directories deep or more"
+    );
+    args.dest_prefix = Some("src/app".into());
+    let sources = get_path_dependency_sources(&deps, workspace, &base_dir, &args).unwrap();
+    assert_eq!(
+        serde_json::to_value(&sources).unwrap(),
+        serde_json::json!([{"type": "dir", "path": "../../shared-lib", "dest": "src/shared-lib"}])
+    );
+
+    assert!(get_path_dependency_sources(&[], workspace, &base_dir, &default_args()).unwrap().is_empty());
+}
+
+#[test]
+fn archive_dest_filename() {
+    let package = registry_package("anstream", "0.6.15");
+    let mut args = default_args();
+
+    let (sources, _) = get_package_sources(&package, None, &args).unwrap().unwrap();
+    let json = serde_json::to_value(&sources[0]).unwrap();
+    assert!(json.get("dest-filename").is_none());
+
+    args.archive_dest_filename = true;
+    let (sources, _) = get_package_sources(&package, None, &args).unwrap().unwrap();
+    let json = serde_json::to_value(&sources[0]).unwrap();
+    assert_eq!(json["dest-filename"], "anstream-0.6.15.crate");
+}
+
+#[test]
+fn local_crates_dir() {
+    let tmp = tempfile::tempdir().unwrap();
+    let crate_file = tmp.path().join("hit-1.0.0.crate");
+    std::fs::write(&crate_file, b"crate contents").unwrap();
+    let checksum = sha256_file(&crate_file).unwrap();
+
+    let mut args = default_args();
+    args.local_crates_dir = Some(tmp.path().to_path_buf());
+    let archive = |package: &Package, args: &Args| -> anyhow::Result<Archive> {
+        match get_package_sources(package, None, args)?.unwrap().0.remove(0) {
+            Source::Archive(archive) => Ok(archive),
+            _ => panic!("expected archive source"),
+        }
+    };
+
+    let mut hit = registry_package("hit", "1.0.0");
+    hit.checksum = Some(checksum.as_str().try_into().unwrap());
+    let found = archive(&hit, &args).unwrap();
+    assert_eq!(found.path.as_deref(), crate_file.to_str());
+    assert_eq!(found.url, None);
+
+    let miss = registry_package("miss", "1.0.0");
+    let fallback = archive(&miss, &args).unwrap();
+    assert_eq!(fallback.path, None);
+    assert!(fallback.url.unwrap().ends_with("/miss/miss-1.0.0.crate"));
+
+    args.require_local = true;
+    assert!(archive(&miss, &args).unwrap_err().to_string().contains("miss-1.0.0.crate"));
+
+    let mut mismatch = registry_package("hit", "1.0.0");
+    mismatch.checksum = Some("00".repeat(32).try_into().unwrap());
+    let err = archive(&mismatch, &args).unwrap_err().to_string();
+    assert!(err.contains("checksum mismatch"));
+    assert!(err.contains("hit-1.0.0.crate"));
+}
+
+#[test]
+fn git_checker_data() {
+    let tmp = tempfile::tempdir().unwrap();
+    write_fixture(tmp.path(), &[("Cargo.toml", "[package]\nname = \"foo\"\nversion = \"0.1.0\"\n")]);
+    let manifest = tmp.path().join("Cargo.toml");
+    let mut args = default_args();
+    args.x_checker_data = true;
+    let checker = |query: &str, args: &Args| {
+        let source = format!("git+https://github.com/example/foo?{query}#0123456789abcdef0123456789abcdef01234567");
+        let (sources, _) = get_git_package_sources(&git_package("foo", &source), manifest.to_str().unwrap(), args).unwrap();
+        serde_json::to_value(&sources[0]).unwrap().get("x-checker-data").cloned()
+    };
+
+    assert_eq!(checker("branch=main", &args).unwrap(), serde_json::json!({"type": "git", "branch": "main"}));
+    assert_eq!(
+        checker("tag=v1.2.0", &args).unwrap(),
+        serde_json::json!({"type": "git", "tag-pattern": "^v([\\d.]+)$"})
+    );
+    assert_eq!(checker("rev=0123456", &args), None);
+
+    args.x_checker_data = false;
+    assert_eq!(checker("branch=main", &args), None);
+}
+
+#[test]
+fn one_repository_at_two_revisions() {
+    let tmp = tempfile::tempdir().unwrap();
+    write_fixture(
+        tmp.path(),
+        &[
+            ("Cargo.toml", "[workspace]\nmembers = [\"a\", \"b\"]\n"),
+            ("a/Cargo.toml", "[package]\nname = \"a\"\nversion = \"0.1.0\"\n"),
+            ("b/Cargo.toml", "[package]\nname = \"b\"\nversion = \"0.1.0\"\n"),
+        ],
+    );
+    let args = default_args();
+    let mut config = Map::new();
+    let mut git_dests = Vec::new();
+    for (name, rev) in [("a", "1111111111111111111111111111111111111111"), ("b", "2222222222222222222222222222222222222222")] {
+        let source = format!("git+https://github.com/example/repo?rev={rev}#{rev}");
+        let manifest = tmp.path().join(name).join("Cargo.toml");
+        let (sources, entries) = get_git_package_sources(&git_package(name, &source), manifest.to_str().unwrap(), &args).unwrap();
+        let Source::Git(git) = &sources[0] else { panic!("expected git source") };
+        git_dests.push(git.dest.clone());
+        config.extend(entries);
+    }
+
+    assert_eq!(config.len(), 2);
+    for rev in ["1111111111111111111111111111111111111111", "2222222222222222222222222222222222222222"] {
+        let entry = &config[&format!("https://github.com/example/repo?rev={rev}")];
+        assert_eq!(entry["rev"].as_str(), Some(rev));
+        assert_eq!(entry["git"].as_str(), Some("https://github.com/example/repo"));
+    }
+    assert_ne!(git_dests[0], git_dests[1]);
+}
+
+#[test]
+fn git_dest_matches_copy_source() {
+    let tmp = tempfile::tempdir().unwrap();
+    write_fixture(
+        tmp.path(),
+        &[
+            ("Cargo.toml", "[workspace]\nmembers = [\"gtk4\"]\n"),
+            ("gtk4/Cargo.toml", "[package]\nname = \"gtk4\"\nversion = \"0.9.0\"\n"),
+        ],
+    );
+    let manifest = tmp.path().join("gtk4/Cargo.toml");
+    let source = "git+https://github.com/gtk-rs/gtk4-rs?branch=main#0123456789abcdef0123456789abcdef01234567";
+
+    let (sources, _) = get_git_package_sources(&git_package("gtk4", source), manifest.to_str().unwrap(), &default_args()).unwrap();
+
+    let Source::Git(git) = &sources[0] else { panic!("expected git source") };
+    let Source::Shell(shell) = &sources[1] else { panic!("expected shell source") };
+    assert_eq!(git.dest, "flatpak-cargo/git/gtk-rs-gtk4-rs-8cd6d500-0123456");
+    assert_eq!(
+        shell.commands[1],
+        format!(r#"cp -r --reflink=auto "{}/gtk4" "cargo/vendor/gtk4""#, git.dest)
+    );
+}
+
+#[test]
+fn same_named_forks() {
+    let tmp = tempfile::tempdir().unwrap();
+    write_fixture(tmp.path(), &[("serde/Cargo.toml", "[package]\nname = \"serde\"\nversion = \"1.0.0\"\n")]);
+    let manifest = tmp.path().join("serde/Cargo.toml");
+    let commit = "0123456789abcdef0123456789abcdef01234567";
+    let fork = |owner: &str| {
+        let source = format!("git+https://github.com/{owner}/serde?branch=fix#{commit}");
+        let (sources, _) = get_git_package_sources(&git_package("serde", &source), manifest.to_str().unwrap(), &default_args()).unwrap();
+        let (Source::Git(git), Source::Shell(shell)) = (&sources[0], &sources[1]) else { panic!("expected git and shell sources") };
+        assert!(shell.commands[1].starts_with(&format!(r#"cp -r --reflink=auto "{}/"#, git.dest)));
+        git.dest.clone()
+    };
+
+    // Pinned at the same commit, of which the dest only has the first characters
+    assert_eq!(fork("alice"), "flatpak-cargo/git/alice-serde-e94d8ea3-0123456");
+    assert_eq!(fork("bob"), "flatpak-cargo/git/bob-serde-020a7f4b-0123456");
+    // Subgroups of a forge are part of the name too
+    let commit = CommitHash::try_from(commit.to_string()).unwrap();
+    assert_eq!(git_repo_name("git+https://gitlab.com/group/subgroup/serde.git", &commit).unwrap(), "group-subgroup-serde-4352c71c-0123456");
+    // And the same owner on another host is another repository
+    assert_eq!(git_repo_name("git+https://codeberg.org/alice/serde", &commit).unwrap(), "alice-serde-b81b1cf0-0123456");
+}
+
+#[test]
+fn path_dependencies_above_workspace() {
+    let tmp = tempfile::tempdir().unwrap();
+    let repo = tmp.path().join("repo");
+    let rust_workspace = "[workspace]\nmembers = [\"app\"]\n";
+    let app = |dep_path: &str| {
+        format!("[package]\nname = \"app\"\nversion = \"0.1.0\"\n\n[dependencies]\nproto = {{ path = \"{dep_path}\" }}\n")
+    };
+    write_fixture(
+        &repo,
+        &[
+            (".cargo-ok", ""),
+            ("rust/Cargo.toml", rust_workspace),
+            ("rust/app/Cargo.toml", &app("../../proto/rust-bindings")),
+            ("proto/rust-bindings/Cargo.toml", "[package]\nname = \"proto\"\nversion = \"0.1.0\"\n"),
+        ],
+    );
+    let manifest = repo.join("rust/app/Cargo.toml");
+    let source = "git+https://github.com/example/monorepo#0123456789abcdef0123456789abcdef01234567";
+
+    let (sources, _) =
+        get_git_package_sources(&git_package("proto", source), manifest.to_str().unwrap(), &default_args()).unwrap();
+    let Source::Shell(shell) = &sources[1] else { panic!("expected shell source") };
+    assert!(shell.commands[1].contains(r#""flatpak-cargo/git/example-monorepo-95e3e816-0123456/proto/rust-bindings""#));
+
+    let (sources, _) =
+        get_git_package_sources(&git_package("app", source), manifest.to_str().unwrap(), &default_args()).unwrap();
+    let Source::Shell(shell) = &sources[1] else { panic!("expected shell source") };
+    assert!(shell.commands[1].contains(r#""flatpak-cargo/git/example-monorepo-95e3e816-0123456/rust/app""#));
+
+    write_fixture(&repo, &[("rust/app/Cargo.toml", &app("../../../outside"))]);
+    let err = get_git_package_sources(&git_package("app", source), manifest.to_str().unwrap(), &default_args())
+        .unwrap_err();
+    assert!(format!("{err:#}").contains("path dependency `proto`"));
+    assert!(format!("{err:#}").contains("outside of the git repository"));
+}
+
+#[test]
+fn path_dependency_outside_rendered() {
+    use crate::diagnostics::{Diagnostic, ErrorFormat};
+
+    let tmp = tempfile::tempdir().unwrap();
+    let app = "[package]\nname = \"app\"\nversion = \"0.1.0\"\n\n\
+               [dependencies]\nserde = \"1\"\nproto = { path = \"../proto\", features = [\"std\"] }\n";
+    write_fixture(&tmp.path().join("repo"), &[(".cargo-ok", ""), ("Cargo.toml", app)]);
+    let manifest = tmp.path().join("repo/Cargo.toml");
+    let source = "git+https://github.com/example/app#0123456789abcdef0123456789abcdef01234567";
+    let err = get_git_package_sources(&git_package("app", source), manifest.to_str().unwrap(), &default_args())
+        .unwrap_err();
+    let rendered: String = Diagnostic::from_error(&err).iter().map(|d| d.render(ErrorFormat::Human)).collect();
+    let expected = [
+        "error: failed to get packages for app from $TMP/repo/Cargo.toml: path dependency `proto` of \"\" \
+         resolves to \"../proto\", outside of the git repository",
+        " --> $TMP/repo/Cargo.toml:7:1",
+        "  |",
+        "7 | proto = { path = \"../proto\", features = [\"std\"] }",
+        "  | ^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^",
+        "  |",
+        "  help: the repository can't be vendored on its own, depend on a revision where the dependency is inside of it",
+        "",
+    ];
+    assert_eq!(rendered.replace(tmp.path().to_str().unwrap(), "$TMP"), expected.join("\n"));
+}
+
+#[cfg(unix)]
+#[test]
+fn symlinked_checkout() {
+    let tmp = tempfile::tempdir().unwrap();
+    let real = tmp.path().join("real");
+    write_fixture(
+        &real,
+        &[
+            (".cargo-ok", ""),
+            ("Cargo.toml", "[workspace]\nmembers = [\"crates/*\"]\n"),
+            ("crates/foo/Cargo.toml", "[package]\nname = \"foo\"\nversion = \"0.1.0\"\n"),
+        ],
+    );
+    let link = tmp.path().join("link");
+    std::os::unix::fs::symlink(&real, &link).unwrap();
+    let manifest = link.join("crates/foo/Cargo.toml");
+    let source = "git+https://github.com/example/foo#0123456789abcdef0123456789abcdef01234567";
+
+    let (sources, _) =
+        get_git_package_sources(&git_package("foo", source), manifest.to_str().unwrap(), &default_args()).unwrap();
+
+    let Source::Shell(shell) = &sources[1] else { panic!("expected shell source") };
+    assert!(!shell.commands[1].contains(".."));
+    assert!(!shell.commands[1].contains(tmp.path().to_str().unwrap()));
+    assert!(shell.commands[1].contains(r#""flatpak-cargo/git/example-foo-de948bb5-0123456/crates/foo""#));
+}
+
+#[test]
+fn custom_cargo_home() {
+    let tmp = tempfile::tempdir().unwrap();
+    write_fixture(tmp.path(), &[("Cargo.toml", "[package]\nname = \"foo\"\nversion = \"0.1.0\"\n")]);
+    let manifest = tmp.path().join("Cargo.toml");
+    let mut args = default_args();
+    args.cargo_home = "rust/cargo-home".into();
+    args.bundle_path_deps = true;
+
+    let mut sources = Vec::new();
+    let git = git_package("foo", "git+https://github.com/example/foo#0123456789abcdef0123456789abcdef01234567");
+    sources.extend(get_package_sources(&git, manifest.to_str(), &args).unwrap().unwrap().0);
+    sources.extend(get_package_sources(&registry_package("anstream", "0.6.15"), None, &args).unwrap().unwrap().0);
+    let deps = [PathDependency { name: "shared".into(), version: "0.1.0".into(), dir: "/shared".into() }];
+    sources.extend(get_path_dependency_sources(&deps, Path::new("/"), Path::new("/app"), &args).unwrap());
+    let config = crate::config::CargoConfig::new(&args.vendor_dir()).to_toml().unwrap();
+
+    let output = serde_json::to_string(&sources).unwrap() + &config;
+    assert!(!output.contains("\"cargo/"));
+    assert!(!output.contains("'cargo/"));
+    assert!(output.contains("\"rust/cargo-home/vendor/foo\""));
+    assert!(output.contains("\"rust/cargo-home/vendor/anstream-0.6.15\""));
+
+    args.vendor_dir = Some("vendor".into());
+    let config = crate::config::CargoConfig::new(&args.vendor_dir()).to_toml().unwrap();
+    assert!(config.contains("directory = \"vendor\""));
+}
+
+#[test]
+fn lockfile_hash_changes() {
+    let args = default_args();
+    let lock = "version = 3\n";
+    assert_eq!(lockfile_hash(lock, &args), lockfile_hash(lock, &args));
+    assert_ne!(lockfile_hash(lock, &args), lockfile_hash("version = 4\n", &args));
+
+    let mut offline = default_args();
+    offline.config_offline = true;
+    assert_ne!(lockfile_hash(lock, &args), lockfile_hash(lock, &offline));
+
+    let mut other_output = default_args();
+    other_output.output = "elsewhere.json".into();
+    assert_eq!(lockfile_hash(lock, &args), lockfile_hash(lock, &other_output));
+
+    // A new commit of the application changes its source
+    let mut app = default_args();
+    app.app_origin = Some(("https://example.com/app.git".into(), CommitHash::try_from("0".repeat(40)).unwrap()));
+    let mut committed = default_args();
+    committed.app_origin = Some(("https://example.com/app.git".into(), CommitHash::try_from("1".repeat(40)).unwrap()));
+    assert_ne!(lockfile_hash(lock, &app), lockfile_hash(lock, &committed));
+
+    let (mut sources, _) = get_package_sources(&registry_package("anstream", "0.6.15"), None, &args).unwrap().unwrap();
+    sources.push(Source::Inline(Inline {
+        contents: String::new(),
+        base64: false,
+        dest: "cargo".into(),
+        dest_filename: "config".into(),
+        x_cargo_lock_hash: Some(lockfile_hash(lock, &args)),
+    }));
+    let sources = SourceSet::from_sources(sources).unwrap();
+    assert_eq!(find_lockfile_hash(&sources), Some(lockfile_hash(lock, &args).as_str()));
+}
+
+#[test]
+fn artifact_dependency_packages() {
+    let manifest = load_toml(
+        "[package]\nname = \"app\"\nversion = \"0.1.0\"\n\n\
+         [dependencies]\nserde = \"1\"\ntool = { version = \"1\", artifact = \"bin\" }\n\n\
+         [target.'cfg(unix)'.build-dependencies]\nbuilder = { git = \"https://github.com/example/builder\", artifact = \"bin\", package = \"builder-cli\" }\n",
+    );
+    assert_eq!(artifact_dependencies(&manifest), ["tool", "builder-cli"]);
+
+    // Registry artifact dependencies don't need cargo metadata
+    let args = default_args();
+    let (sources, _) = get_package_sources(&registry_package("tool", "1.0.0"), None, &args).unwrap().unwrap();
+    assert!(matches!(sources[0], Source::Archive(_)));
+
+    let git = git_package("builder-cli", "git+https://github.com/example/builder#0123456789abcdef0123456789abcdef01234567");
+    let err = get_package_sources(&git, None, &args).unwrap_err();
+    assert!(err.to_string().contains("not reported by cargo metadata"));
+}
+
+#[test]
+fn crate_url_templates() {
+    let api = "https://crates.io/api/v1/crates/{name}/{version}/download";
+    assert_eq!(crate_url(api, "serde", "1.0.0", ""), "https://crates.io/api/v1/crates/serde/1.0.0/download");
+
+    let sharded = "https://mirror.example/{prefix}/{name}/{name}-{version}-{checksum}.crate";
+    assert_eq!(crate_url(sharded, "serde", "1.0.0", "abc"), "https://mirror.example/se/rd/serde/serde-1.0.0-abc.crate");
+    assert_eq!(crate_url(sharded, "a", "0.1.0", "abc"), "https://mirror.example/1/a/a-0.1.0-abc.crate");
+    assert_eq!(crate_url(sharded, "cc", "1.0.0", "abc"), "https://mirror.example/2/cc/cc-1.0.0-abc.crate");
+    assert_eq!(crate_url(sharded, "syn", "2.0.0", "abc"), "https://mirror.example/3/s/syn/syn-2.0.0-abc.crate");
+    assert_eq!(
+        crate_url(sharded, "cargo-flatpak", "0.0.1", "abc"),
+        "https://mirror.example/ca/rg/cargo-flatpak/cargo-flatpak-0.0.1-abc.crate"
+    );
+
+    assert!(crate::cli::parse_crate_url_template(api).is_ok());
+    assert!(crate::cli::parse_crate_url_template("https://mirror.example/{name}.crate").is_err());
+}
+
+#[test]
+fn lockfile_sources() {
+    let tmp = tempfile::tempdir().unwrap();
+    let contents = "# This file is automatically @generated by Cargo.\r\nversion = 3\n\n[[package]]\nname = \"é\"\n";
+    std::fs::write(tmp.path().join("Cargo.lock"), contents).unwrap();
+
+    let inline = lockfile_source(contents, None, tmp.path(), ".").unwrap();
+    let json = serde_json::to_string(&inline).unwrap();
+    let parsed: serde_json::Value = serde_json::from_str(&json).unwrap();
+    assert_eq!(parsed["contents"].as_str(), Some(contents));
+    assert_eq!(parsed["dest-filename"], "Cargo.lock");
+
+    let file = lockfile_source(contents, Some(&tmp.path().join("Cargo.lock")), tmp.path(), ".").unwrap();
+    assert_eq!(
+        serde_json::to_value(&file).unwrap(),
+        serde_json::json!({"type": "file", "path": "Cargo.lock", "dest": ".", "dest-filename": "Cargo.lock"})
+    );
+}
+
+#[test]
+fn dest_prefix() {
+    let tmp = tempfile::tempdir().unwrap();
+    write_fixture(tmp.path(), &[("Cargo.toml", "[package]\nname = \"foo\"\nversion = \"0.1.0\"\n")]);
+    let manifest = tmp.path().join("Cargo.toml");
+    let mut args = default_args();
+    args.dest_prefix = Some("rust/".into());
+    args.bundle_path_deps = true;
+
+    let mut sources = Vec::new();
+    let git = git_package("foo", "git+https://github.com/example/foo#0123456789abcdef0123456789abcdef01234567");
+    sources.extend(get_package_sources(&git, manifest.to_str(), &args).unwrap().unwrap().0);
+    sources.extend(get_package_sources(&registry_package("anstream", "0.6.15"), None, &args).unwrap().unwrap().0);
+    let deps = [PathDependency { name: "shared".into(), version: "0.1.0".into(), dir: "/shared".into() }];
+    sources.extend(get_path_dependency_sources(&deps, Path::new("/"), Path::new("/app"), &args).unwrap());
+    sources.push(lockfile_source("version = 3\n", None, tmp.path(), &args.dest(".")).unwrap());
+
+    let json = serde_json::to_value(&sources).unwrap();
+    for source in json.as_array().unwrap() {
+        if let Some(dest) = source.get("dest") {
+            let dest = dest.as_str().unwrap();
+            assert!(dest == "rust" || dest.starts_with("rust/"), "{dest} lacks the prefix");
+        }
+    }
+    assert_eq!(
+        json[1]["commands"][1],
+        r#"cp -r --reflink=auto "rust/flatpak-cargo/git/example-foo-de948bb5-0123456/" "rust/cargo/vendor/foo""#
+    );
+    let config = crate::config::CargoConfig::new(&args.vendor_dir()).to_toml().unwrap();
+    assert!(config.contains("directory = \"rust/cargo/vendor\""));
+    assert_eq!(args.cargo_home_dir(), "rust/cargo");
+}
+
+#[test]
+fn vendor_exclude() {
+    use clap::Parser;
+
+    let tmp = tempfile::tempdir().unwrap();
+    write_fixture(
+        tmp.path(),
+        &[
+            ("Cargo.toml", "[workspace]\nmembers = [\"mylib\"]\n"),
+            ("mylib/Cargo.toml", "[package]\nname = \"mylib\"\nversion = \"0.1.0\"\n"),
+        ],
+    );
+    let manifest = tmp.path().join("mylib/Cargo.toml");
+    let source = "git+https://github.com/example/mylib#0123456789abcdef0123456789abcdef01234567";
+    let args = Args::parse_from([
+        "flatpak",
+        "--vendor-exclude=mylib=tests/fixtures",
+        "--vendor-exclude=mylib=benches/data *.bin",
+        "--vendor-exclude=other=tests",
+    ]);
+
+    let (sources, _) = get_git_package_sources(&git_package("mylib", source), manifest.to_str().unwrap(), &args).unwrap();
+    let Source::Shell(shell) = &sources[1] else { panic!("expected shell source") };
+    assert_eq!(
+        shell.commands,
+        [
+            r#"mkdir -p "cargo/vendor""#,
+            r#"cp -r --reflink=auto "flatpak-cargo/git/example-mylib-ff611f56-0123456/mylib" "cargo/vendor/mylib""#,
+            r#"rm -rf "cargo/vendor/mylib"/tests/fixtures"#,
+            r#"rm -rf "cargo/vendor/mylib"/benches/data\ *.bin"#,
+        ]
+    );
+    let Source::Inline(checksum) = &sources[3] else { panic!("expected inline source") };
+    assert_eq!(checksum.contents, r#"{"package": null, "files": {}}"#);
+
+    assert!(Args::try_parse_from(["flatpak", "--vendor-exclude=mylib=../escape"]).is_err());
+    assert!(Args::try_parse_from(["flatpak", "--vendor-exclude=mylib=/etc"]).is_err());
+}
+
+#[test]
+fn manifest_include_exclude() {
+    let tmp = tempfile::tempdir().unwrap();
+    let manifest = |name: &str, keys: &str| format!("[package]\nname = \"{name}\"\nversion = \"0.1.0\"\n{keys}");
+    let excluding = manifest("excluding", "exclude = [\"/ci\", \"*.png\", \"tests/fixtures/\", \"benches/**\"]\n");
+    let including = manifest("including", "readme = \"README.md\"\ninclude = [\"/src\", \"build.rs\", \"LICENSE*\"]\n");
+    let negated = manifest("negated", "exclude = [\"tests\", \"!tests/data\"]\n");
+    let files = ["README.md", "src/lib.rs", "tests/it.rs", "tests/fixtures/a.bin", "benches/b.rs", "ci/run.sh", "logo.png", "docs/logo.png"];
+    let mut fixture = vec![
+        ("Cargo.toml", "[workspace]\nmembers = [\"excluding\", \"including\", \"negated\"]\n".to_string()),
+        ("excluding/Cargo.toml", excluding),
+        ("including/Cargo.toml", including),
+        ("including/LICENSE-MIT", String::new()),
+        ("including/build.rs", String::new()),
+        ("including/docs/build.rs", String::new()),
+        ("including/.github/workflows/ci.yml", String::new()),
+        ("negated/Cargo.toml", negated),
+        ("negated/tests/it.rs", String::new()),
+    ];
+    let paths: Vec<_> = files.iter().flat_map(|file| [format!("excluding/{file}"), format!("including/{file}")]).collect();
+    fixture.extend(paths.iter().map(|path| (path.as_str(), String::new())));
+    write_fixture(tmp.path(), &fixture.iter().map(|(path, contents)| (*path, contents.as_str())).collect::<Vec<_>>());
+
+    let commands = |name: &str| {
+        let source = format!("git+https://github.com/example/{name}#0123456789abcdef0123456789abcdef01234567");
+        let manifest = tmp.path().join(name).join("Cargo.toml");
+        let (sources, _) = get_git_package_sources(&git_package(name, &source), manifest.to_str().unwrap(), &default_args()).unwrap();
+        let Source::Inline(checksum) = &sources[3] else { panic!("expected inline source") };
+        assert_eq!(checksum.contents, r#"{"package": null, "files": {}}"#);
+        let Source::Shell(shell) = &sources[1] else { panic!("expected shell source") };
+        assert!(shell.commands[1].starts_with("cp -r --reflink=auto "));
+        shell.commands[2..].to_vec()
+    };
+    assert_eq!(
+        commands("excluding"),
+        [
+            r#"rm -rf "cargo/vendor/excluding/benches/b.rs""#,
+            r#"rm -rf "cargo/vendor/excluding/ci""#,
+            r#"rm -rf "cargo/vendor/excluding/docs/logo.png""#,
+            r#"rm -rf "cargo/vendor/excluding/logo.png""#,
+            r#"rm -rf "cargo/vendor/excluding/tests/fixtures""#,
+        ]
+    );
+    // The readme is kept for the manifest pointing at it, the other build.rs for the pattern matching at any depth
+    assert_eq!(
+        commands("including"),
+        [
+            r#"rm -rf "cargo/vendor/including/.github""#,
+            r#"rm -rf "cargo/vendor/including/benches""#,
+            r#"rm -rf "cargo/vendor/including/ci""#,
+            r#"rm -rf "cargo/vendor/including/docs/logo.png""#,
+            r#"rm -rf "cargo/vendor/including/logo.png""#,
+            r#"rm -rf "cargo/vendor/including/tests""#,
+        ]
+    );
+    // Negations aren't translated, the whole package is copied
+    assert!(commands("negated").is_empty());
+}
+
+#[test]
+fn workspace_dependency_paths() {
+    let tmp = tempfile::tempdir().unwrap();
+    write_fixture(
+        tmp.path(),
+        &[
+            (
+                "Cargo.toml",
+                "[workspace]\nmembers = [\"crates/app\"]\n\n[workspace.dependencies]\n\
+                 foo = { path = \"crates/foo\", version = \"1\" }\n",
+            ),
+            (
+                "crates/app/Cargo.toml",
+                "[package]\nname = \"app\"\nversion = \"0.1.0\"\n\n[dependencies]\nfoo.workspace = true\n",
+            ),
+            ("crates/foo/Cargo.toml", "[package]\nname = \"foo\"\nversion = \"1.2.0\"\n"),
+        ],
+    );
+    let manifest = tmp.path().join("crates/foo/Cargo.toml");
+    let source = "git+https://github.com/example/monorepo#0123456789abcdef0123456789abcdef01234567";
+
+    let (sources, _) = get_git_package_sources(&git_package("foo", source), manifest.to_str().unwrap(), &default_args()).unwrap();
+    let Source::Shell(shell) = &sources[1] else { panic!("expected shell source") };
+    assert_eq!(
+        shell.commands[1],
+        r#"cp -r --reflink=auto "flatpak-cargo/git/example-monorepo-95e3e816-0123456/crates/foo" "cargo/vendor/foo""#
+    );
+    let Source::Inline(cargo_toml) = &sources[2] else { panic!("expected inline source") };
+    let cargo_toml: toml::Value = toml::from_str(&cargo_toml.contents).unwrap();
+    assert_eq!(cargo_toml["package"]["version"].as_str(), Some("1.2.0"));
+}
+
+#[test]
+fn workspace_root_discovery() {
+    let tmp = tempfile::tempdir().unwrap();
+    let tmp = tmp.path().canonicalize().unwrap();
+    let member = "[package]\nname = \"foo\"\nversion.workspace = true\nedition.workspace = true\n\n\
+                  [dependencies]\nserde.workspace = true\n";
+    write_fixture(
+        &tmp,
+        &[
+            ("Cargo.toml", "[workspace]\nmembers = [\"repo\"]\n"),
+            ("repo/.git/HEAD", ""),
+            (
+                "repo/Cargo.toml",
+                "[workspace]\nmembers = [\"crates/core/*\"]\nexclude = [\"crates/core/standalone\"]\n\n\
+                 [workspace.package]\nversion = \"2.1.0\"\nedition = \"2021\"\n\n\
+                 [workspace.dependencies]\nserde = \"1.0.200\"\n",
+            ),
+            ("repo/crates/core/foo/Cargo.toml", member),
+            ("repo/crates/core/standalone/Cargo.toml", "[package]\nname = \"standalone\"\nversion = \"0.1.0\"\n"),
+            ("repo/tools/Cargo.toml", "[workspace]\nmembers = [\"../other/bar\"]\n"),
+            ("repo/other/bar/Cargo.toml", "[package]\nname = \"bar\"\nversion = \"0.1.0\"\nworkspace = \"../../tools\"\n"),
+        ],
+    );
+    let repo = tmp.join("repo");
+    let root = |dir: &str| find_workspace_root(&repo.join(dir), Some(&repo)).unwrap();
+    assert_eq!(root("crates/core/foo"), repo);
+    assert_eq!(root("crates/core/standalone"), repo.join("crates/core/standalone"));
+    assert_eq!(root("other/bar"), repo.join("tools"));
+    // The enclosing workspace outside of the checkout is ignored
+    assert_eq!(find_workspace_root(&repo, Some(&repo)).unwrap(), repo);
+
+    let manifest = repo.join("crates/core/foo/Cargo.toml");
+    let source = "git+https://github.com/example/repo#0123456789abcdef0123456789abcdef01234567";
+    let (sources, _) = get_git_package_sources(&git_package("foo", source), manifest.to_str().unwrap(), &default_args()).unwrap();
+    let Source::Shell(shell) = &sources[1] else { panic!("expected shell source") };
+    assert_eq!(
+        shell.commands[1],
+        r#"cp -r --reflink=auto "flatpak-cargo/git/example-repo-ffc8e3be-0123456/crates/core/foo" "cargo/vendor/foo""#
+    );
+    let Source::Inline(cargo_toml) = &sources[2] else { panic!("expected inline source") };
+    let cargo_toml: toml::Value = toml::from_str(&cargo_toml.contents).unwrap();
+    assert_eq!(cargo_toml["package"]["version"].as_str(), Some("2.1.0"));
+    assert_eq!(cargo_toml["package"]["edition"].as_str(), Some("2021"));
+    assert_eq!(cargo_toml["dependencies"]["serde"].as_str(), Some("1.0.200"));
+}
+
+#[test]
+fn vendored_path_dependencies() {
+    let tmp = tempfile::tempdir().unwrap();
+    let repo = tmp.path().join("repo");
+    write_fixture(
+        &repo,
+        &[
+            (".git/HEAD", ""),
+            ("Cargo.toml", "[workspace]\nmembers = [\"crates/*\"]\n\n[workspace.package]\nversion = \"0.3.0\"\n"),
+            (
+                "crates/foo/Cargo.toml",
+                "[package]\nname = \"foo\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\n\
+                 [dependencies]\nbar = { path = \"../bar\" }\n\n\
+                 [target.'cfg(unix)'.dependencies]\nbaz = { path = \"../baz\", version = \"0.3\" }\n\n\
+                 [dev-dependencies]\nbar-tests = { package = \"bar\", path = \"../bar\" }\n",
+            ),
+            ("crates/foo/src/lib.rs", "pub use bar::BAR;\n"),
+            ("crates/bar/Cargo.toml", "[package]\nname = \"bar\"\nversion.workspace = true\n"),
+            ("crates/bar/src/lib.rs", "pub const BAR: u32 = 1;\n"),
+            ("crates/baz/Cargo.toml", "[package]\nname = \"baz\"\nversion = \"0.3.1\"\n"),
+            ("crates/baz/src/lib.rs", ""),
+        ],
+    );
+    let source = "git+https://github.com/example/repo#0123456789abcdef0123456789abcdef01234567";
+
+    // Lay the sources out the way flatpak-builder would
+    let vendor = tmp.path().join("vendor");
+    let mut manifests = HashMap::new();
+    for name in ["foo", "bar", "baz"] {
+        let manifest = repo.join("crates").join(name).join("Cargo.toml");
+        let (sources, _) =
+            get_git_package_sources(&git_package(name, source), manifest.to_str().unwrap(), &default_args()).unwrap();
+        std::fs::create_dir_all(vendor.join(name).join("src")).unwrap();
+        std::fs::copy(repo.join("crates").join(name).join("src/lib.rs"), vendor.join(name).join("src/lib.rs")).unwrap();
+        for source in &sources[2..] {
+            let Source::Inline(inline) = source else { panic!("expected inline source") };
+            std::fs::write(vendor.join(name).join(&inline.dest_filename), &inline.contents).unwrap();
+        }
+        let Source::Inline(cargo_toml) = &sources[2] else { panic!("expected inline source") };
+        manifests.insert(name, toml::from_str::<toml::Value>(&cargo_toml.contents).unwrap());
+    }
+    let foo = &manifests["foo"];
+    assert_eq!(foo["dependencies"]["bar"].as_table().unwrap().get("path"), None);
+    assert_eq!(foo["dependencies"]["bar"]["version"].as_str(), Some("0.3.0"));
+    assert_eq!(foo["target"]["cfg(unix)"]["dependencies"]["baz"]["version"].as_str(), Some("0.3"));
+    assert_eq!(foo["dev-dependencies"]["bar-tests"]["version"].as_str(), Some("0.3.0"));
+
+    write_fixture(
+        &tmp.path().join("app"),
+        &[
+            ("Cargo.toml", "[package]\nname = \"app\"\nversion = \"0.1.0\"\n\n[dependencies]\nfoo = \"0.1\"\n"),
+            ("src/main.rs", "fn main() {\n    let _ = foo::BAR;\n}\n"),
+            (
+                ".cargo/config.toml",
+                "[source.crates-io]\nreplace-with = \"vendored\"\n\n[source.vendored]\ndirectory = \"../vendor\"\n",
+            ),
+        ],
+    );
+    let output = std::process::Command::new(std::env::var("CARGO").unwrap_or_else(|_| "cargo".into()))
+        .args(["check", "--offline", "--quiet"])
+        .current_dir(tmp.path().join("app"))
+        .env("CARGO_HOME", tmp.path().join("cargo-home"))
+        .env("CARGO_TARGET_DIR", tmp.path().join("target"))
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+}
+
+#[test]
+fn files_outside_of_the_package() {
+    let tmp = tempfile::tempdir().unwrap();
+    write_fixture(
+        tmp.path(),
+        &[
+            (".git/HEAD", ""),
+            ("LICENSE", "MIT"),
+            ("README.md", "readme"),
+            (
+                "Cargo.toml",
+                "[workspace]\nmembers = [\"crates/foo\"]\n\n[workspace.package]\nreadme = \"README.md\"\n",
+            ),
+            (
+                "crates/foo/Cargo.toml",
+                "[package]\nname = \"foo\"\nversion = \"0.1.0\"\nlicense-file = \"../../LICENSE\"\n\
+                 readme.workspace = true\nbuild = \"build.rs\"\n\n[lib]\npath = \"../shared/lib.rs\"\n",
+            ),
+        ],
+    );
+    let manifest = tmp.path().join("crates/foo/Cargo.toml");
+    let source = "git+https://github.com/example/repo#0123456789abcdef0123456789abcdef01234567";
+
+    let (sources, _) = get_git_package_sources(&git_package("foo", source), manifest.to_str().unwrap(), &default_args()).unwrap();
+    let Source::Shell(shell) = &sources[1] else { panic!("expected shell source") };
+    let git = "flatpak-cargo/git/example-repo-ffc8e3be-0123456";
+    assert_eq!(
+        shell.commands,
+        [
+            r#"mkdir -p "cargo/vendor""#.to_string(),
+            format!(r#"cp -r --reflink=auto "{git}/crates/foo" "cargo/vendor/foo""#),
+            format!(r#"cp -r --reflink=auto "{git}/LICENSE" "cargo/vendor/foo/LICENSE""#),
+            format!(r#"cp -r --reflink=auto "{git}/README.md" "cargo/vendor/foo/README.md""#),
+            format!(r#"cp -r --reflink=auto "{git}/crates/shared" "cargo/vendor/foo/shared""#),
+        ]
+    );
+    let Source::Inline(cargo_toml) = &sources[2] else { panic!("expected inline source") };
+    let cargo_toml: toml::Value = toml::from_str(&cargo_toml.contents).unwrap();
+    assert_eq!(cargo_toml["package"]["license-file"].as_str(), Some("LICENSE"));
+    assert_eq!(cargo_toml["package"]["readme"].as_str(), Some("README.md"));
+    assert_eq!(cargo_toml["package"]["build"].as_str(), Some("build.rs"));
+    assert_eq!(cargo_toml["lib"]["path"].as_str(), Some("shared/lib.rs"));
+
+    // A binary from another directory of the same name would overwrite the library's
+    std::fs::write(
+        &manifest,
+        "[package]\nname = \"foo\"\nversion = \"0.1.0\"\n\n[lib]\npath = \"../shared/lib.rs\"\n\n\
+         [[bin]]\nname = \"foo\"\npath = \"../../tools/shared/main.rs\"\n",
+    )
+    .unwrap();
+    let err = get_git_package_sources(&git_package("foo", source), manifest.to_str().unwrap(), &default_args()).unwrap_err();
+    assert!(format!("{err:#}").contains("crates/shared and tools/shared would both be copied to shared"), "{err:#}");
+}
+
+#[test]
+fn path_dependency_cycles() {
+    let tmp = tempfile::tempdir().unwrap();
+    let package = |name: &str, deps: &str| {
+        format!("[package]\nname = \"{name}\"\nversion = \"0.1.0\"\n\n[dependencies]\n{deps}")
+    };
+    write_fixture(
+        tmp.path(),
+        &[
+            (".git/HEAD", ""),
+            ("Cargo.toml", "[workspace]\nmembers = [\"crates/foo\"]\n"),
+            ("crates/foo/Cargo.toml", &package("foo", "a = { path = \"../a\" }\n")),
+            ("crates/a/Cargo.toml", &package("a", "b = { path = \"../b\" }\n")),
+            // Only a dev-dependency cycle is legal in cargo, but the crawl must not care
+            ("crates/b/Cargo.toml", &package("b", "a = { path = \"../a\" }\nc = { path = \"../c\" }\n")),
+            ("crates/c/Cargo.toml", &package("c", "")),
+        ],
+    );
+    let root = std::fs::read_to_string(tmp.path().join("Cargo.toml")).unwrap();
+
+    let packages = get_cargo_toml_packages(&root, tmp.path(), tmp.path(), 32).unwrap();
+    let mut names: Vec<_> = packages.keys().map(String::as_str).collect();
+    names.sort();
+    assert_eq!(names, ["a", "b", "c", "foo"]);
+    assert_eq!(packages["c"].path, Path::new("crates/c"));
+
+    let err = get_cargo_toml_packages(&root, tmp.path(), tmp.path(), 2).unwrap_err().to_string();
+    assert_eq!(
+        err,
+        "path dependency `c` of \"crates/b\" is more than 2 path dependencies deep"
+    );
+}
+
+#[test]
+fn replaced_and_vendored_from_one_source() {
+    let tmp = tempfile::tempdir().unwrap();
+    write_fixture(
+        tmp.path(),
+        &[
+            ("Cargo.toml", "[workspace]\nmembers = [\"a\", \"b\"]\n"),
+            ("a/Cargo.toml", "[package]\nname = \"a\"\nversion = \"0.1.0\"\n"),
+            ("b/Cargo.toml", "[package]\nname = \"b\"\nversion = \"0.1.0\"\n"),
+        ],
+    );
+    let source = "git+https://github.com/example/fork#0123456789abcdef0123456789abcdef01234567";
+    let manifest = |name: &str| tmp.path().join(name).join("Cargo.toml").to_str().unwrap().to_string();
+    let args = default_args();
+
+    let mut sources = SourceSet::new(&args.vendor_dir());
+    sources.push_replacement(&git_package("a", source), Some(&manifest("a")), &args).unwrap();
+    let Source::Shell(shell) = &sources.entries()[1].source else { panic!("expected shell source") };
+    assert!(shell.commands[1].ends_with(r#""cargo/vendor-replaced/a-0.1.0""#), "{}", shell.commands[1]);
+    // b would be looked up in the replaced directory, which doesn't have it
+    let err = sources.push_package(&git_package("b", source), Some(&manifest("b")), &args).unwrap_err().to_string();
+    assert_eq!(
+        err,
+        "https://github.com/example/fork would be replaced with both replaced-sources and vendored-sources, \
+         cargo reads all of its crates from one source"
+    );
+}
+
+#[test]
+fn malformed_git_manifests() {
+    let package = |name: &str, deps: &str| format!("[package]\nname = \"{name}\"\n\n[dependencies]\n{deps}");
+    let cases = [
+        ("[dependencies]\n", vec![]),
+        ("[package\n", vec![]),
+        ("[package]\nversion = \"0.1.0\"\n", vec![]),
+        (&*package("foo", "a = { path = \"a\" }\n"), vec![]),
+        (&*package("foo", "a = { path = \"a\" }\n"), vec![("a/Cargo.toml", package("b", ""))]),
+        (&*package("foo", "a = { path = \"a\" }\n"), vec![("a/Cargo.toml", "[package]\n".to_string())]),
+        ("[workspace]\nmembers = [\"a\"]\n", vec![("a/Cargo.toml", "[lib]\n".to_string())]),
+    ];
+    for (root, files) in cases {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut files: Vec<_> = files.iter().map(|(path, contents)| (*path, contents.as_str())).collect();
+        files.push(("Cargo.toml", root));
+        write_fixture(tmp.path(), &files);
+        assert!(get_cargo_toml_packages(root, tmp.path(), tmp.path(), 32).is_err(), "{root}");
+    }
+}
+
+#[test]
+fn sources_round_trip() {
+    use clap::Parser;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let repo = tmp.path().join("repo");
+    write_fixture(
+        &repo,
+        &[
+            (".git/HEAD", ""),
+            ("Cargo.toml", "[workspace]\nmembers = [\"foo\"]\n"),
+            ("foo/Cargo.toml", "[package]\nname = \"foo\"\nversion = \"0.1.0\"\n"),
+        ],
+    );
+    std::fs::write(tmp.path().join("Cargo.lock"), "version = 3\n").unwrap();
+    let manifest = repo.join("foo/Cargo.toml");
+    let manifest = manifest.to_str().unwrap();
+    let git = git_package("foo", "git+https://github.com/example/repo?tag=v1.2#0123456789abcdef0123456789abcdef01234567");
+    let deps = [PathDependency { name: "shared".into(), version: "0.2.0".into(), dir: tmp.path().join("shared") }];
+
+    let mut generated = Vec::new();
+    for flags in [&[][..], &["--bundle-path-deps", "--x-checker-data", "--archive-dest-filename"][..]] {
+        let args = Args::parse_from(["flatpak"].iter().chain(flags));
+        generated.extend(get_package_sources(&registry_package("anstream", "0.6.15"), None, &args).unwrap().unwrap().0);
+        generated.extend(get_git_package_sources(&git, manifest, &args).unwrap().0);
+        generated.extend(get_path_dependency_sources(&deps, tmp.path(), &repo, &args).unwrap_or_default());
+    }
+    generated.push(lockfile_source("version = 3\n", None, tmp.path(), ".").unwrap());
+    generated.push(lockfile_source("", Some(&tmp.path().join("Cargo.lock")), tmp.path(), ".").unwrap());
+    let json = serde_json::to_string_pretty(&generated).unwrap();
+    for kind in ["archive", "inline", "git", "shell", "dir", "file", "x-checker-data"] {
+        assert!(json.contains(&format!("\"{kind}\"")), "{kind} isn't covered");
+    }
+
+    let parsed: Vec<Source> = serde_json::from_str(&json).unwrap();
+    assert!(!parsed.iter().any(|s| matches!(s, Source::Other(_))));
+    assert_eq!(serde_json::to_string_pretty(&parsed).unwrap(), json);
+
+    // Types and fields we don't generate are kept as they are
+    let foreign = r#"[
+  {
+    "type": "patch",
+    "path": "fix.patch",
+    "options": [
+      "-p1"
+    ]
+  },
+  {
+    "type": "archive",
+    "archive-type": "tar-gzip",
+    "url": "https://example.com/a.tar.gz",
+    "sha256": "00",
+    "dest": "a",
+    "strip-components": 2
+  },
+  {
+    "dest": "no-type"
+  }
+]"#;
+    let parsed: Vec<Source> = serde_json::from_str(foreign).unwrap();
+    assert!(parsed.iter().all(|s| matches!(s, Source::Other(_))));
+    assert_eq!(serde_json::to_string_pretty(&parsed).unwrap(), foreign);
+}
+
+#[test]
+fn local_registry_and_directory_sources() {
+    let lock = format!(
+        r#"
+        version = 3
+
+        [[package]]
+        name = "anstream"
+        version = "0.6.15"
+        source = "local-registry+file:///srv/registry"
+        checksum = "{FIXTURE_CHECKSUM}"
+
+        [[package]]
+        name = "url"
+        version = "2.5.0"
+        source = "directory+file:///usr/share/cargo/registry"
+    "#
+    );
+    let lock: LockFile = toml::from_str(&lock).unwrap();
+
+    // With a checksum the crate comes from crates.io, replacing the local source
+    let (sources, config) = get_package_sources(&lock.package[0], None, &default_args()).unwrap().unwrap();
+    let Source::Archive(archive) = &sources[0] else { panic!("expected archive source") };
+    assert_eq!(archive.url.as_deref(), Some("https://static.crates.io/crates/anstream/anstream-0.6.15.crate"));
+    assert_eq!(archive.dest, "cargo/vendor/anstream-0.6.15");
+    let entry = &config["local-registry+file:///srv/registry"];
+    assert_eq!(entry["local-registry"].as_str(), Some("/srv/registry"));
+    assert_eq!(entry["replace-with"].as_str(), Some(VENDORED_SOURCES));
+    assert!(!config.contains_key("crates-io"));
+
+    let err = get_package_sources(&lock.package[1], None, &default_args()).unwrap_err().to_string();
+    assert_eq!(
+        err,
+        "url 2.5.0 comes from the directory source file:///usr/share/cargo/registry, and without a checksum it can't be \
+         downloaded from crates.io instead: regenerate Cargo.lock against crates.io, without the source replacement"
+    );
+
+    // Neither is left out of the vendored packages
+    let tmp = tempfile::tempdir().unwrap();
+    let (mut metadata, _) = crate::generate::fixture_workspace(tmp.path());
+    metadata.resolve = None;
+    let vendored = crate::generate::vendored_packages(&lock, &metadata).unwrap();
+    let names: Vec<_> = vendored.iter().map(|p| p.name.as_str()).collect();
+    assert_eq!(names, ["anstream", "url"]);
+}
+
+#[cfg(unix)]
+#[test]
+fn non_utf8_paths_are_errors() {
+    use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+    let tmp = tempfile::tempdir().unwrap();
+    let dir = tmp.path().join(OsStr::from_bytes(b"caf\xe9"));
+    std::fs::create_dir(&dir).unwrap();
+    let crate_file = dir.join("hit-1.0.0.crate");
+    std::fs::write(&crate_file, b"crate contents").unwrap();
+
+    let mut args = default_args();
+    args.local_crates_dir = Some(dir.clone());
+    let mut hit = registry_package("hit", "1.0.0");
+    hit.checksum = Some(sha256_file(&crate_file).unwrap().as_str().try_into().unwrap());
+    let error = get_package_sources(&hit, None, &args).unwrap_err().to_string();
+    assert!(error.contains(r"caf\xE9") && error.contains("not valid UTF-8"), "{error}");
+
+    let lockfile = dir.join("Cargo.lock");
+    std::fs::write(&lockfile, "version = 3\n").unwrap();
+    let error = lockfile_source("version = 3\n", Some(&lockfile), tmp.path(), "cargo").unwrap_err().to_string();
+    assert!(error.contains(r"caf\xE9/Cargo.lock"), "{error}");
+}
+
+/// Crates of a gtk4-rs checkout in `root`, between two crates.io ones
+#[cfg(test)]
+fn gtk_packages(root: &Path) -> Vec<(Package, Option<String>)> {
+    std::fs::write(root.join("Cargo.toml"), "[workspace]\nmembers = [\"gtk4\", \"gdk4\"]\n").unwrap();
+    for name in ["gtk4", "gdk4"] {
+        std::fs::create_dir(root.join(name)).unwrap();
+        std::fs::write(
+            root.join(name).join("Cargo.toml"),
+            format!("[package]\nname = \"{name}\"\nversion = \"0.9.0\"\n"),
+        )
+        .unwrap();
+    }
+    let package = |name: &str, source: &str, checksum: Option<&str>| Package {
+        name: name.into(),
+        version: "0.9.0".into(),
+        source: Some(source.into()),
+        checksum: checksum.map(|checksum| checksum.try_into().unwrap()),
+        checksum_from_index: false,
+        dependencies: None,
+    };
+    let registry = "registry+https://github.com/rust-lang/crates.io-index";
+    let git = "git+https://github.com/gtk-rs/gtk4-rs?branch=main#0123456789abcdef0123456789abcdef01234567";
+    let checksum = Some(FIXTURE_CHECKSUM);
+    let manifest = |name: &str| Some(root.join(name).join("Cargo.toml").to_str().unwrap().to_string());
+    vec![
+        (package("anstream", registry, checksum), None),
+        (package("gdk4", git, None), manifest("gdk4")),
+        (package("gtk4", git, None), manifest("gtk4")),
+        (package("url", registry, checksum), None),
+    ]
+}
+
+/// A set of `packages`, with the cargo config last
+#[cfg(test)]
+fn source_set<'a>(packages: impl IntoIterator<Item = &'a (Package, Option<String>)>) -> SourceSet {
+    let args = default_args();
+    let mut sources = SourceSet::new(&args.vendor_dir());
+    for (package, manifest) in packages {
+        sources.push_package(package, manifest.as_deref(), &args).unwrap();
+    }
+    let config = Inline {
+        contents: sources.config.to_toml().unwrap(),
+        base64: false,
+        dest: args.cargo_home_dir(),
+        dest_filename: "config".into(),
+        x_cargo_lock_hash: None,
+    };
+    sources.push(None, CONFIG_OWNER, Source::Inline(config));
+    sources
+}
+
+#[cfg(test)]
+fn owners(sources: &SourceSet) -> Vec<&str> {
+    let mut owners: Vec<&str> = sources.entries().iter().map(|entry| entry.owner.as_str()).collect();
+    owners.dedup();
+    owners
+}
+
+#[test]
+fn group_by() {
+    let tmp = tempfile::tempdir().unwrap();
+    let packages = gtk_packages(tmp.path());
+    let args = default_args();
+    let mut sources = SourceSet::new(&args.vendor_dir());
+    for (package, manifest) in &packages {
+        sources.push_package(package, manifest.as_deref(), &args).unwrap();
+    }
+    let shared = Dir { path: "../shared".into(), dest: "shared".into() };
+    sources.push(Some(SourceKind::Path), "shared", Source::Dir(shared));
+
+    let layout = |group_by: GroupBy| -> Vec<String> {
+        let mut sources = SourceSet { entries: sources.entries.clone(), config: CargoConfig::new("cargo/vendor") };
+        sources.group_by(group_by);
+        serde_json::to_value(sources.sources())
+            .unwrap()
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|s| {
+                let target = s.get("dest-filename").or(s.get("commands")).map(|t| t.to_string()).unwrap_or_default();
+                let dest = s.get("dest").and_then(|d| d.as_str()).unwrap_or("");
+                format!("{} {dest} {target}", s["type"].as_str().unwrap()).trim_end().to_string()
+            })
+            .collect()
+    };
+    let gdk4_copy = r#"["mkdir -p \"cargo/vendor\"","cp -r --reflink=auto \"flatpak-cargo/git/gtk-rs-gtk4-rs-8cd6d500-0123456/gdk4\" \"cargo/vendor/gdk4\""]"#;
+    let gtk4_copy = r#"["mkdir -p \"cargo/vendor\"","cp -r --reflink=auto \"flatpak-cargo/git/gtk-rs-gtk4-rs-8cd6d500-0123456/gtk4\" \"cargo/vendor/gtk4\""]"#;
+
+    assert_eq!(
+        layout(GroupBy::Crate),
+        [
+            "archive cargo/vendor/anstream-0.9.0",
+            "inline cargo/vendor/anstream-0.9.0 \".cargo-checksum.json\"",
+            "git flatpak-cargo/git/gtk-rs-gtk4-rs-8cd6d500-0123456",
+            &format!("shell  {gdk4_copy}"),
+            "inline cargo/vendor/gdk4 \"Cargo.toml\"",
+            "inline cargo/vendor/gdk4 \".cargo-checksum.json\"",
+            &format!("shell  {gtk4_copy}"),
+            "inline cargo/vendor/gtk4 \"Cargo.toml\"",
+            "inline cargo/vendor/gtk4 \".cargo-checksum.json\"",
+            "archive cargo/vendor/url-0.9.0",
+            "inline cargo/vendor/url-0.9.0 \".cargo-checksum.json\"",
+            "dir shared",
+        ]
+    );
+    assert_eq!(
+        layout(GroupBy::Type),
+        [
+            "archive cargo/vendor/anstream-0.9.0",
+            "archive cargo/vendor/url-0.9.0",
+            "inline cargo/vendor/anstream-0.9.0 \".cargo-checksum.json\"",
+            "inline cargo/vendor/url-0.9.0 \".cargo-checksum.json\"",
+            "git flatpak-cargo/git/gtk-rs-gtk4-rs-8cd6d500-0123456",
+            &format!("shell  {gdk4_copy}"),
+            &format!("shell  {gtk4_copy}"),
+            "inline cargo/vendor/gdk4 \"Cargo.toml\"",
+            "inline cargo/vendor/gdk4 \".cargo-checksum.json\"",
+            "inline cargo/vendor/gtk4 \"Cargo.toml\"",
+            "inline cargo/vendor/gtk4 \".cargo-checksum.json\"",
+            "dir shared",
+        ]
+    );
+}
+
+#[test]
+fn source_set_push_package() {
+    let tmp = tempfile::tempdir().unwrap();
+    let sources = source_set(&gtk_packages(tmp.path()));
+    assert_eq!(owners(&sources), ["anstream-0.9.0", "gdk4", "gtk4", "url-0.9.0", CONFIG_OWNER]);
+    // gtk4 copies out of the clone made for gdk4
+    let clones = sources.sources().iter().filter(|s| matches!(s, Source::Git(_))).count();
+    assert_eq!(clones, 1);
+    let kinds: Vec<_> = sources.entries().iter().map(|entry| entry.kind).collect();
+    assert_eq!(kinds[..3], [Some(SourceKind::Registry), Some(SourceKind::Registry), Some(SourceKind::Git)]);
+    assert_eq!(kinds.last().unwrap(), &None);
+
+    let config = sources.config.to_toml().unwrap();
+    assert!(config.contains("[source.crates-io]") && config.contains("https://github.com/gtk-rs/gtk4-rs"), "{config}");
+}
+
+#[test]
+fn source_set_sort_by_crate() {
+    let tmp = tempfile::tempdir().unwrap();
+    let packages = gtk_packages(tmp.path());
+    let mut sources = source_set(packages.iter().rev());
+    assert_eq!(owners(&sources), ["url-0.9.0", "gtk4", "gdk4", "anstream-0.9.0", CONFIG_OWNER]);
+
+    sources.sort_by_crate();
+    assert_eq!(owners(&sources), ["anstream-0.9.0", "gdk4", "gtk4", "url-0.9.0", CONFIG_OWNER]);
+    // The clone made for gtk4 moves ahead of the first copy out of it
+    assert!(matches!(sources.entries()[2].source, Source::Git(_)));
+    assert_eq!(sources.entries()[2].owner, "gdk4");
+    assert!(matches!(sources.entries()[3].source, Source::Shell(_)));
+
+    let sorted = sources.sources().into_iter().cloned().collect::<Vec<_>>();
+    sources.sort_by_crate();
+    assert_eq!(sources.sources().into_iter().cloned().collect::<Vec<_>>(), sorted);
+}
+
+#[test]
+fn source_set_merge() {
+    let tmp = tempfile::tempdir().unwrap();
+    let packages = gtk_packages(tmp.path());
+    let mut sources = source_set(&packages[..1]);
+    sources.merge(source_set(&packages)).unwrap();
+
+    assert_eq!(owners(&sources), ["anstream-0.9.0", "gdk4", "gtk4", "url-0.9.0", CONFIG_OWNER]);
+    assert_eq!(sources.sources().len(), source_set(&packages).sources().len());
+    let Some(Source::Inline(config)) = sources.sources().last().copied() else { panic!("expected the config last") };
+    assert_eq!(config.contents, sources.config.to_toml().unwrap());
+    assert!(config.contents.contains("https://github.com/gtk-rs/gtk4-rs"));
+
+    // Crates already in the set are kept as they are
+    let before = sources.sources().into_iter().cloned().collect::<Vec<_>>();
+    sources.merge(source_set(&packages[1..])).unwrap();
+    assert_eq!(sources.sources().into_iter().cloned().collect::<Vec<_>>(), before);
+}
+
+#[test]
+fn source_set_to_yaml() {
+    let tmp = tempfile::tempdir().unwrap();
+    let packages = gtk_packages(tmp.path());
+    let yaml = source_set(&packages[..1]).to_yaml().unwrap();
+    assert_eq!(
+        yaml,
+        format!(
+            r#"- type: archive
+  archive-type: tar-gzip
+  url: "https://static.crates.io/crates/anstream/anstream-0.9.0.crate"
+  sha256: "{FIXTURE_CHECKSUM}"
+  dest: cargo/vendor/anstream-0.9.0
+- type: inline
+  contents: "{{\"package\": \"{FIXTURE_CHECKSUM}\", \"files\": {{}}}}"
+  dest: cargo/vendor/anstream-0.9.0
+  dest-filename: ".cargo-checksum.json"
+- type: inline
+  contents: "[source.vendored-sources]\ndirectory = \"cargo/vendor\"\n\n[source.crates-io]\nreplace-with = \"vendored-sources\"\n"
+  dest: cargo
+  dest-filename: config
+"#
+        )
+    );
+
+    // Lists nest under their key
+    let yaml = source_set(&packages[1..2]).to_yaml().unwrap();
+    assert!(yaml.contains("- type: shell\n  commands:\n    - \"mkdir -p \\\"cargo/vendor\\\"\"\n    - \"cp -r "), "{yaml}");
+
+    let tmp = tmp.path().join("cargo-sources.yml");
+    let mut written = Vec::new();
+    write_sources(&mut written, &source_set(&packages[..1]).sources(), SourcesFormat::of(&tmp)).unwrap();
+    assert_eq!(String::from_utf8(written).unwrap(), source_set(&packages[..1]).to_yaml().unwrap());
+}
+
+#[test]
+fn move_git_sources() {
+    let tmp = tempfile::tempdir().unwrap();
+    let packages = gtk_packages(tmp.path());
+    let clone = "flatpak-cargo/git/gtk-rs-gtk4-rs-8cd6d500-0123456";
+    let mkdir = r#"mkdir -p "cargo/vendor""#.to_string();
+    let shells = |sources: &SourceSet| -> Vec<Vec<String>> {
+        let shells = sources.sources().into_iter().filter_map(|source| match source {
+            Source::Shell(shell) => Some(shell.commands.clone()),
+            _ => None,
+        });
+        shells.collect()
+    };
+    for group_by in [GroupBy::Crate, GroupBy::Type] {
+        let mut sources = source_set(&packages);
+        sources.group_by(group_by);
+        sources.move_git_sources();
+        // gdk4 is copied first, so the clone is still there for gtk4
+        assert_eq!(
+            shells(&sources),
+            [
+                vec![mkdir.clone(), format!(r#"cp -r --reflink=auto "{clone}/gdk4" "cargo/vendor/gdk4""#)],
+                vec![mkdir.clone(), format!(r#"mv "{clone}/gtk4" "cargo/vendor/gtk4""#), format!(r#"rm -rf "{clone}""#)],
+            ]
+        );
+    }
+
+    // Run in order, both crates are vendored and the clone is gone
+    let build = tmp.path().join("build");
+    for name in ["gtk4", "gdk4"] {
+        std::fs::create_dir_all(build.join(clone).join(name)).unwrap();
+        std::fs::write(build.join(clone).join(name).join("lib.rs"), "").unwrap();
+    }
+    std::fs::create_dir_all(build.join("cargo/vendor")).unwrap();
+    let mut sources = source_set(&packages);
+    sources.move_git_sources();
+    for commands in shells(&sources) {
+        let status = std::process::Command::new("sh").arg("-c").arg(commands.join(" && ")).current_dir(&build).status().unwrap();
+        assert!(status.success());
+    }
+    assert!(build.join("cargo/vendor/gdk4/lib.rs").is_file() && build.join("cargo/vendor/gtk4/lib.rs").is_file());
+    assert!(!build.join(clone).exists());
+}
+
+#[test]
+fn source_set_diff() {
+    let tmp = tempfile::tempdir().unwrap();
+    let mut packages = gtk_packages(tmp.path());
+    let old = source_set(&packages[..3]);
+    packages[0].0.checksum = Some("a".repeat(64).try_into().unwrap());
+    let new = source_set(&packages[1..]);
+
+    let diff = old.diff(&new);
+    assert_eq!(
+        diff,
+        SourceDiff {
+            added: vec!["url-0.9.0".into()],
+            removed: vec!["anstream-0.9.0".into()],
+            changed: vec![CONFIG_OWNER.into()],
+        }
+    );
+    assert_eq!(old.diff(&old), SourceDiff::default());
+    assert_eq!(diff.to_string(), "\n  added: url-0.9.0\n  removed: anstream-0.9.0\n  changed: cargo config");
+    assert_eq!(diff.summary(), "added url-0.9.0; removed anstream-0.9.0; changed cargo config");
+    assert_eq!(SourceDiff::default().summary(), "no changes");
+
+    let changed = source_set(&packages[..3]);
+    // The same crates make the same config
+    assert_eq!(old.diff(&changed).changed, ["anstream-0.9.0"]);
+}
+
+#[test]
+fn source_set_round_trip() {
+    let tmp = tempfile::tempdir().unwrap();
+    let mut sources = source_set(&gtk_packages(tmp.path()));
+    let config = sources.entries.pop().unwrap();
+    let deps = [PathDependency { name: "shared".into(), version: "0.1.0".into(), dir: tmp.path().join("shared") }];
+    let mut args = default_args();
+    args.bundle_path_deps = true;
+    for source in get_path_dependency_sources(&deps, tmp.path(), tmp.path(), &args).unwrap() {
+        sources.push(Some(SourceKind::Path), "shared", source);
+    }
+    sources.push(None, LOCKFILE_OWNER, lockfile_source("version = 3\n", None, tmp.path(), ".").unwrap());
+    sources.entries.push(config);
+
+    let read = SourceSet::from_reader(sources.to_json().unwrap().as_bytes()).unwrap();
+    assert_eq!(read.entries(), sources.entries());
+    assert_eq!(read.config.to_toml().unwrap(), sources.config.to_toml().unwrap());
+    assert_eq!(sources.diff(&read), SourceDiff::default());
+}
+
+#[test]
+fn commit_archive_urls() {
+    let commit = CommitHash::try_from("0123456789abcdef0123456789abcdef01234567").unwrap();
+    let url = |repo: &str| commit_archive_url(&parse_url(repo).unwrap().0, &commit);
+    assert_eq!(
+        url("git+https://github.com/gtk-rs/gtk4-rs.git?branch=main").as_deref(),
+        Some("https://codeload.github.com/gtk-rs/gtk4-rs/tar.gz/0123456789abcdef0123456789abcdef01234567")
+    );
+    assert_eq!(
+        url("https://gitlab.com/group/sub/lib").as_deref(),
+        Some(
+            "https://gitlab.com/group/sub/lib/-/archive/0123456789abcdef0123456789abcdef01234567/\
+             lib-0123456789abcdef0123456789abcdef01234567.tar.gz"
+        )
+    );
+    assert_eq!(
+        url("https://codeberg.org/owner/lib/").as_deref(),
+        Some("https://codeberg.org/owner/lib/archive/0123456789abcdef0123456789abcdef01234567.tar.gz")
+    );
+    assert_eq!(url("https://github.com/owner/lib/extra"), None);
+    assert_eq!(url("https://git.example.org/owner/lib"), None);
+}
+
+#[test]
+fn commit_archive_checksums() {
+    use std::sync::atomic::Ordering;
+
+    let tarball = b"not really a tarball, but the bytes are what's hashed";
+    let (server, requests) = crate::index::serve(HashMap::from([("/owner/lib/tar.gz/0123456", tarball.to_vec())]));
+    let url = format!("{server}owner/lib/tar.gz/0123456");
+
+    let tmp = tempfile::tempdir().unwrap();
+    let cache = tmp.path().join("cache/archives.json");
+    std::fs::write(tmp.path().join("lib.tar.gz"), tarball).unwrap();
+    let expected = sha256_file(&tmp.path().join("lib.tar.gz")).unwrap();
+    assert_eq!(archive_sha256(&url, Some(&cache)).unwrap().as_str(), expected);
+    assert_eq!(archive_sha256(&url, Some(&cache)).unwrap().as_str(), expected);
+    assert_eq!(requests.load(Ordering::SeqCst), 1);
+    let cached: HashMap<String, Sha256> = serde_json::from_str(&std::fs::read_to_string(&cache).unwrap()).unwrap();
+    assert_eq!(cached[&url].as_str(), expected);
+
+    assert_eq!(archive_sha256(&url, None).unwrap().as_str(), expected);
+    assert_eq!(requests.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn git_as_archive() {
+    let tmp = tempfile::tempdir().unwrap();
+    let repo = tmp.path().join("checkout");
+    write_fixture(
+        &repo,
+        &[
+            ("Cargo.toml", "[workspace]\nmembers = [\"gtk4\", \"gdk4\"]\n"),
+            ("gtk4/Cargo.toml", "[package]\nname = \"gtk4\"\nversion = \"0.1.0\"\n"),
+            ("gdk4/Cargo.toml", "[package]\nname = \"gdk4\"\nversion = \"0.1.0\"\n"),
+        ],
+    );
+    let commit = "0123456789abcdef0123456789abcdef01234567";
+    let url = format!("https://codeload.github.com/gtk-rs/gtk4-rs/tar.gz/{commit}");
+    let checksum = FIXTURE_CHECKSUM;
+    // Only seen by the lookups of --git-as-archive, the only test using the shared archives cache
+    write_fixture(&crate::net::cache_dir().unwrap(), &[("archives.json", &format!(r#"{{"{url}": "{checksum}"}}"#))]);
+
+    let mut args = default_args();
+    args.git_as_archive = true;
+    let mut sources = SourceSet::new(&args.vendor_dir());
+    for name in ["gdk4", "gtk4"] {
+        let package = git_package(name, &format!("git+https://github.com/gtk-rs/gtk4-rs?branch=main#{commit}"));
+        let manifest = repo.join(name).join("Cargo.toml");
+        sources.push_package(&package, manifest.to_str(), &args).unwrap();
+    }
+    let Source::Archive(archive) = &sources.entries()[0].source else { panic!("expected the commit archive first") };
+    assert_eq!(archive.url.as_deref(), Some(url.as_str()));
+    assert_eq!(archive.sha256.as_str(), checksum);
+    assert_eq!(archive.dest, "flatpak-cargo/git/gtk-rs-gtk4-rs-8cd6d500-0123456");
+    assert!(matches!(&sources.entries()[1].source, Source::Shell(shell) if shell.commands[1].contains(&archive.dest)));
+    // The crates share the archive, like they would a clone
+    assert_eq!(sources.sources().iter().filter(|s| matches!(s, Source::Archive(_))).count(), 1);
+    assert!(sources.sources().iter().all(|s| !matches!(s, Source::Git(_))));
+    assert!(sources.entries().iter().all(|entry| entry.kind == Some(SourceKind::Git)));
+    assert!(sources.config.to_toml().unwrap().contains("https://github.com/gtk-rs/gtk4-rs"));
+
+    // Hosts without commit archives are still cloned
+    let package = git_package("gdk4", &format!("git+https://git.example.org/gtk4-rs?branch=main#{commit}"));
+    let manifest = repo.join("gdk4/Cargo.toml");
+    let (sources, _) = get_git_package_sources(&package, manifest.to_str().unwrap(), &args).unwrap();
+    assert!(matches!(sources[0], Source::Git(_)));
+
+    // And so are repositories with submodules, which the archives lack
+    write_fixture(&repo, &[(".gitmodules", "[submodule \"vendor/gtk\"]\n")]);
+    let package = git_package("gdk4", &format!("git+https://github.com/gtk-rs/gtk4-rs?branch=main#{commit}"));
+    let (sources, warnings) = crate::diagnostics::capture(crate::diagnostics::ErrorFormat::Human, || {
+        get_git_package_sources(&package, manifest.to_str().unwrap(), &args).unwrap().0
+    });
+    assert!(matches!(sources[0], Source::Git(_)));
+    assert!(warnings.contains("https://github.com/gtk-rs/gtk4-rs has submodules, which its commit archives leave out"), "{warnings}");
+}
+
+/// A git workspace with a member using the parts of a manifest that are easy to
+/// lose when rewriting it
+#[cfg(test)]
+fn gnarly_workspace(root: &Path) {
+    write_fixture(
+        root,
+        &[
+            (
+                "Cargo.toml",
+                "[workspace]\nmembers = [\"crates/*\"]\n\n[workspace.package]\nversion = \"2.0.0\"\nedition = \"2021\"\n\
+                 license = \"MIT\"\n\n[workspace.dependencies]\nserde = { version = \"1.0\", default-features = false }\n\
+                 log = \"0.4\"\nhelper = { path = \"crates/helper\" }\n\n[workspace.lints.rust]\nunsafe_code = \"forbid\"\n",
+            ),
+            (
+                "crates/gnarly/Cargo.toml",
+                "[package]\nname = \"gnarly\"\nversion.workspace = true\nedition.workspace = true\nlicense.workspace = true\n\
+                 autobins = false\n\n[package.metadata.docs.rs]\nall-features = true\nrustdoc-args = [\"--cfg\", \"docsrs\"]\n\n\
+                 [package.metadata.deb]\nassets = [[\"target/release/gnarly\", \"usr/bin/\", \"755\"]]\n\n\
+                 [lib]\npath = \"src/lib.rs\"\n\n[[bin]]\nname = \"gnarly\"\npath = \"src/main.rs\"\n\n\
+                 [[bin]]\nname = \"gnarly-tool\"\npath = \"src/bin/tool.rs\"\nrequired-features = [\"cli\"]\n\n\
+                 [[bench]]\nname = \"speed\"\nharness = false\n\n[[example]]\nname = \"demo\"\nrequired-features = [\"serde\"]\n\n\
+                 [features]\ndefault = [\"std\"]\nstd = []\ncli = [\"dep:log\"]\nserde = [\"dep:serde\", \"serde?/std\"]\n\n\
+                 [dependencies]\nserde = { workspace = true, optional = true, features = [\"derive\"] }\n\
+                 log = { workspace = true, optional = true }\nhelper = { workspace = true }\n\n\
+                 [dev-dependencies]\nlog.workspace = true\n\n\
+                 [target.'cfg(unix)'.build-dependencies]\nhelper = { path = \"../helper\" }\n\n[lints]\nworkspace = true\n",
+            ),
+            ("crates/gnarly/src/lib.rs", ""),
+            ("crates/gnarly/src/main.rs", "fn main() {}\n"),
+            ("crates/gnarly/src/bin/tool.rs", "fn main() {}\n"),
+            ("crates/gnarly/benches/speed.rs", "fn main() {}\n"),
+            ("crates/gnarly/examples/demo.rs", "fn main() {}\n"),
+            ("crates/helper/Cargo.toml", "[package]\nname = \"helper\"\nversion = \"2.0.0\"\n"),
+            ("crates/helper/src/lib.rs", ""),
+        ],
+    );
+}
+
+#[test]
+fn normalized_manifests_keep_their_meaning() {
+    use clap::Parser;
+
+    let tmp = tempfile::tempdir().unwrap();
+    gnarly_workspace(tmp.path());
+    let manifest = tmp.path().join("crates/gnarly/Cargo.toml");
+    let source = "git+https://github.com/example/gnarly?rev=0123456#0123456789abcdef0123456789abcdef01234567";
+    let args = Args::parse_from(["cargo-flatpak", "--verify-manifests"]);
+    let (sources, _) = get_git_package_sources(&git_package("gnarly", source), manifest.to_str().unwrap(), &args).unwrap();
+    let Source::Inline(cargo_toml) = &sources[2] else { panic!("expected inline source") };
+    let emitted: toml::Value = toml::from_str(&cargo_toml.contents).unwrap();
+    assert_eq!(emitted["package"]["version"].as_str(), Some("2.0.0"));
+    assert_eq!(emitted["lints"]["rust"]["unsafe_code"].as_str(), Some("forbid"));
+    let serde = emitted["dependencies"]["serde"].as_table().unwrap();
+    assert_eq!(serde["optional"].as_bool(), Some(true));
+    assert_eq!(serde["default-features"].as_bool(), Some(false));
+    assert_eq!(serde["features"].as_array().unwrap(), &[toml::Value::from("derive")]);
+    assert_eq!(emitted["dependencies"]["helper"]["version"].as_str(), Some("2.0.0"));
+    assert_eq!(emitted["dev-dependencies"]["log"].as_str(), Some("0.4"));
+
+    // Cargo reads the vendored copy the way it reads the original
+    let copy = tempfile::tempdir().unwrap();
+    let vendored = copy.path().join("gnarly");
+    for file in ["src/lib.rs", "src/main.rs", "src/bin/tool.rs", "benches/speed.rs", "examples/demo.rs"] {
+        std::fs::create_dir_all(vendored.join(file).parent().unwrap()).unwrap();
+        std::fs::copy(tmp.path().join("crates/gnarly").join(file), vendored.join(file)).unwrap();
+    }
+    std::fs::write(vendored.join("Cargo.toml"), &cargo_toml.contents).unwrap();
+    let cargo_metadata = |manifest: &Path| {
+        let output = std::process::Command::new(std::env::var("CARGO").unwrap_or_else(|_| "cargo".into()))
+            .args(["metadata", "--no-deps", "--offline", "--format-version", "1", "--manifest-path"])
+            .arg(manifest)
+            .env("CARGO_HOME", tmp.path().join("cargo-home"))
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        let metadata: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+        let package = metadata["packages"].as_array().unwrap().iter().find(|p| p["name"] == "gnarly").unwrap().clone();
+        let mut targets: Vec<_> =
+            package["targets"].as_array().unwrap().iter().map(|t| (t["name"].clone(), t["kind"].clone())).collect();
+        targets.sort_by_key(|target| target.0.to_string());
+        (targets, package["features"].clone(), package["metadata"].clone())
+    };
+    assert_eq!(cargo_metadata(&vendored.join("Cargo.toml")), cargo_metadata(&manifest));
+
+    let original: toml::Value = toml::from_str(&std::fs::read_to_string(&manifest).unwrap()).unwrap();
+    let mut broken = emitted.clone();
+    broken["bin"].as_array_mut().unwrap().pop();
+    broken["features"]["cli"] = toml::Value::Array(Vec::new());
+    broken["package"]["metadata"].as_table_mut().unwrap().remove("deb");
+    broken["dependencies"]["serde"].as_table_mut().unwrap().remove("optional");
+    let err = verify_manifest(&original, &toml::to_string(&broken).unwrap()).unwrap_err().to_string();
+    assert_eq!(
+        err,
+        "the vendored manifest differs from the original:\n  `package.metadata` changed\n  `bin` changed\n  \
+         `features` changed\n  `dependencies.serde.optional` changed"
+    );
+    assert!(verify_manifest(&original, "[package\n").is_err());
+}
+
+#[test]
+fn normalized_manifests_keep_their_formatting() {
+    let tmp = tempfile::tempdir().unwrap();
+    let member = "\
+# The widget, as upstream writes it
+[package]
+name = \"widget\" # not inherited
+version.workspace = true
+edition = { workspace = true }   # inherited, with a comment
+description = \"\"\"
+A widget\"\"\"
+
+# Dependencies, in no particular order
+[dependencies]
+zeta = \"1\"      # first, though last alphabetically
+serde = { workspace = true, features = [\"derive\"] } # adds a feature
+log.workspace = true
+helper = { path = \"../helper\", default-features = false }
+
+[target.'cfg(unix)'.dependencies]
+libc = { workspace = true, optional = true }
+
+[lints]
+workspace = true
+";
+    write_fixture(
+        tmp.path(),
+        &[
+            (
+                "Cargo.toml",
+                "[workspace]\nmembers = [\"widget\", \"helper\"]\n\n[workspace.package]\nversion = \"1.4.0\"\nedition = \"2021\"\n\n\
+                 [workspace.dependencies]\nserde = { version = \"1.0\", default-features = false }\nlog = \"0.4\"\nlibc = \"0.2\"\n\n\
+                 [workspace.lints.rust]\nunsafe_code = \"forbid\"\n",
+            ),
+            ("widget/Cargo.toml", member),
+            ("helper/Cargo.toml", "[package]\nname = \"helper\"\nversion.workspace = true\n"),
+        ],
+    );
+    let root = std::fs::read_to_string(tmp.path().join("Cargo.toml")).unwrap();
+    let packages = get_cargo_toml_packages(&root, tmp.path(), tmp.path(), 32).unwrap();
+    let normalized = packages["widget"].normalized().to_string();
+    // Line by line, only the inherited entries differ
+    assert_eq!(normalized.lines().count(), member.lines().count());
+    let changed: Vec<_> = member.lines().zip(normalized.lines()).filter(|(original, normalized)| original != normalized).collect();
+    assert!(changed.iter().all(|(original, _)| original.contains("workspace")), "{changed:?}");
+    let changed: Vec<_> = changed.into_iter().map(|(_, normalized)| normalized).collect();
+    assert_eq!(
+        changed,
+        [
+            "version = \"1.4.0\"",
+            "edition = \"2021\"   # inherited, with a comment",
+            "serde = { version = \"1.0\", default-features = false, features = [\"derive\"] } # adds a feature",
+            "log = \"0.4\"",
+            "libc = { version = \"0.2\", optional = true }",
+            "rust = { unsafe_code = \"forbid\" }",
+        ]
+    );
+
+    // The path dependency is rewritten in place too
+    let vendored = vendored_manifest(&packages["widget"], &packages).to_string();
+    assert_eq!(
+        vendored.replace("helper = { version = \"1.4.0\", default-features = false }", "helper = { path = \"../helper\", default-features = false }"),
+        normalized
+    );
+}
+
+#[test]
+fn base64_padding() {
+    let encoded: Vec<_> = ["", "f", "fo", "foo", "foob", "fooba", "foobar"].iter().map(|s| base64(s.as_bytes())).collect();
+    assert_eq!(encoded, ["", "Zg==", "Zm8=", "Zm9v", "Zm9vYg==", "Zm9vYmE=", "Zm9vYmFy"]);
+    assert_eq!(base64(b"\x03\x02\0\xff"), "AwIA/w==");
+}
+
+#[test]
+fn stub_packages() {
+    let tmp = tempfile::tempdir().unwrap();
+    write_fixture(
+        tmp.path(),
+        &[
+            (
+                "Cargo.toml",
+                "[package]\nname = \"winthing\"\nversion = \"0.3.0\"\n\n[features]\ndefault = [\"std\"]\nstd = []\nsync = [\"dep:lock\"]\n\n\
+                 [dependencies]\nlock = { path = \"lock\", optional = true, default-features = false }\n\n\
+                 [target.'cfg(windows)'.dependencies]\nwinbase = { path = \"winbase\", package = \"base\", features = [\"ui\"] }\n\n\
+                 [dev-dependencies]\ntests = { path = \"tests\" }\n",
+            ),
+            ("src/lib.rs", ""),
+            ("lock/Cargo.toml", "[package]\nname = \"lock\"\nversion = \"1.0.0\"\n"),
+            ("lock/src/lib.rs", ""),
+            ("winbase/Cargo.toml", "[package]\nname = \"base\"\nversion = \"1.0.0\"\n\n[features]\nui = []\n"),
+            ("winbase/src/lib.rs", ""),
+            ("tests/Cargo.toml", "[package]\nname = \"tests\"\nversion = \"1.0.0\"\n"),
+            ("tests/src/lib.rs", ""),
+        ],
+    );
+    let metadata = cargo_metadata::MetadataCommand::new().manifest_path(tmp.path().join("Cargo.toml")).no_deps().exec().unwrap();
+    let package = Package {
+        name: "winthing".into(),
+        version: "0.3.0".into(),
+        source: Some(CRATES_IO_SOURCE.into()),
+        checksum: Some(FIXTURE_CHECKSUM.try_into().unwrap()),
+        checksum_from_index: false,
+        dependencies: None,
+    };
+    let sources = stub_package_sources(&package, Some(&metadata.packages[0]), &default_args()).unwrap();
+    let [Source::Inline(manifest), Source::Inline(lib), Source::Inline(checksum)] = sources.as_slice() else {
+        panic!("expected three inline sources");
+    };
+    assert_eq!((manifest.dest.as_str(), manifest.dest_filename.as_str()), ("cargo/vendor/winthing-0.3.0", "Cargo.toml"));
+    let stub: toml::Value = toml::from_str(&manifest.contents).unwrap();
+    assert_eq!(
+        stub,
+        toml::toml! {
+            [package]
+            name = "winthing"
+            version = "0.3.0"
+
+            [lib]
+            path = "lib.rs"
+
+            [features]
+            default = ["std"]
+            std = []
+            sync = ["dep:lock"]
+
+            [dependencies.lock]
+            version = "*"
+            optional = true
+            default-features = false
+
+            [target."cfg(windows)".dependencies.winbase]
+            version = "*"
+            package = "base"
+            features = ["ui"]
+        }
+        .into()
+    );
+    assert_eq!((lib.dest_filename.as_str(), lib.contents.as_str()), ("lib.rs", ""));
+    assert_eq!(checksum.contents, format!(r#"{{"package": "{FIXTURE_CHECKSUM}", "files": {{}}}}"#));
+}
+
+#[test]
+fn no_shell_sources() {
+    use clap::Parser;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let repo = tmp.path().join("repo");
+    gnarly_workspace(&repo);
+    write_fixture(&repo, &[("crates/helper/build.rs", "fn main() {}\n"), ("LICENSE", "MIT\n")]);
+    let source = "git+https://github.com/example/gnarly?rev=0123456#0123456789abcdef0123456789abcdef01234567";
+    let args = Args::parse_from(["cargo-flatpak", "--no-shell-sources", "--verify-manifests"]);
+    let build = tmp.path().join("build");
+    for name in ["gnarly", "helper"] {
+        let manifest = repo.join(format!("crates/{name}/Cargo.toml"));
+        let (sources, config) = get_git_package_sources(&git_package(name, source), manifest.to_str().unwrap(), &args).unwrap();
+        assert!(!sources.iter().any(|source| matches!(source, Source::Shell(_))));
+        let [Source::Git(git), Source::Inline(cargo_toml), Source::Inline(checksum)] = sources.as_slice() else {
+            panic!("expected a git source and its files, got {sources:?}")
+        };
+        assert_eq!((git.url.as_str(), git.dest.as_str()), ("https://github.com/example/gnarly", &*format!("cargo/vendor/{name}")));
+        assert_eq!((cargo_toml.dest.as_str(), checksum.dest.as_str()), (git.dest.as_str(), git.dest.as_str()));
+        assert!(config.contains_key("https://github.com/example/gnarly?rev=0123456"));
+
+        // The clone, with the files written on top
+        let vendored = build.join(&git.dest);
+        std::fs::create_dir_all(vendored.parent().unwrap()).unwrap();
+        let status = std::process::Command::new("cp").arg("-r").arg(&repo).arg(&vendored).status().unwrap();
+        assert!(status.success());
+        std::fs::write(vendored.join("Cargo.toml"), &cargo_toml.contents).unwrap();
+    }
+    let emitted: toml::Value = toml::from_str(&std::fs::read_to_string(build.join("cargo/vendor/helper/Cargo.toml")).unwrap()).unwrap();
+    assert_eq!(emitted["lib"]["path"].as_str(), Some("crates/helper/src/lib.rs"));
+    assert_eq!(emitted["package"]["build"].as_str(), Some("crates/helper/build.rs"));
+
+    // The same targets, of the same files, but those never built for a dependency
+    let targets = |manifest: &Path, root: &Path| {
+        let output = std::process::Command::new(std::env::var("CARGO").unwrap_or_else(|_| "cargo".into()))
+            .args(["metadata", "--no-deps", "--offline", "--format-version", "1", "--manifest-path"])
+            .arg(manifest)
+            .env("CARGO_HOME", tmp.path().join("cargo-home"))
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        let metadata: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+        let package = metadata["packages"].as_array().unwrap().iter().find(|p| p["name"] == "gnarly").unwrap().clone();
+        let mut targets: Vec<_> = package["targets"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|t| !["bench", "example"].contains(&t["kind"][0].as_str().unwrap()))
+            .map(|t| (t["name"].as_str().unwrap().to_string(), Path::new(t["src_path"].as_str().unwrap()).strip_prefix(root).unwrap().to_path_buf()))
+            .collect();
+        targets.sort();
+        targets
+    };
+    let vendored = build.join("cargo/vendor/gnarly");
+    assert_eq!(targets(&vendored.join("Cargo.toml"), &vendored), targets(&repo.join("crates/gnarly/Cargo.toml"), &repo));
+}
+
+#[test]
+fn separate_git_sources() {
+    let tmp = tempfile::tempdir().unwrap();
+    let sources = source_set(&gtk_packages(tmp.path()));
+    let (git, others) = sources.partition_git();
+
+    // Each source lands in exactly one of the files, in its order
+    let owners_of = |subset: &[&Source]| -> Vec<&str> {
+        let mut owners: Vec<_> = sources
+            .entries()
+            .iter()
+            .filter(|entry| subset.iter().any(|source| std::ptr::eq(*source, &entry.source)))
+            .map(|entry| entry.owner.as_str())
+            .collect();
+        owners.dedup();
+        owners
+    };
+    assert_eq!(owners_of(&git), ["gdk4", "gtk4"]);
+    assert_eq!(owners_of(&others), ["anstream-0.9.0", "url-0.9.0", CONFIG_OWNER]);
+    assert_eq!(git.len() + others.len(), sources.entries().len());
+    let kept = |git: bool| -> Vec<&Source> {
+        let entries = sources.entries().iter().filter(|entry| (entry.kind == Some(SourceKind::Git)) == git);
+        entries.map(|entry| &entry.source).collect()
+    };
+    assert_eq!((&git, &others), (&kept(true), &kept(false)));
+    assert!(!others.iter().any(|source| matches!(source, Source::Git(_) | Source::Shell(_))));
+
+    // Read back together, the two files are the single one
+    let (output, git_output) = (tmp.path().join("cargo-sources.json"), tmp.path().join("cargo-git-sources.json"));
+    std::fs::write(&output, serde_json::to_string(&others).unwrap()).unwrap();
+    std::fs::write(&git_output, serde_json::to_string(&git).unwrap()).unwrap();
+    let read = crate::generate::read_sources(&output, Some(&git_output)).unwrap();
+    assert_eq!(read.diff(&sources), SourceDiff::default());
+    let mut single = read.sources();
+    let mut expected = sources.sources();
+    let key = |source: &&Source| serde_json::to_string(source).unwrap();
+    single.sort_by_key(key);
+    expected.sort_by_key(key);
+    assert_eq!(single, expected);
+    assert_eq!(crate::generate::read_sources(&output, None).unwrap().diff(&sources).added, ["gdk4", "gtk4"]);
+
+    // Merging into the pair keeps what it has
+    let mut merged = read;
+    merged.merge(source_set(&gtk_packages(tempfile::tempdir().unwrap().path()))).unwrap();
+    assert_eq!(merged.diff(&sources), SourceDiff::default());
+}
+
+#[cfg(test)]
+const URL_SCHEMES: &[&str] = &["https", "http", "ssh", "git"];
+#[cfg(test)]
+const URL_HOSTS: &[&str] = &["github.com", "gitlab.gnome.org", "git.example.org:8443", "127.0.0.1", "[::1]"];
+#[cfg(test)]
+const URL_SEGMENTS: &[&str] = &["foo", "Bar-rs", "a.b", "x_y", "%C3%A9"];
+#[cfg(test)]
+const URL_SUFFIXES: &[&str] = &["", "/", ".git", ".git/"];
+#[cfg(test)]
+const URL_QUERIES: &[&str] = &["", "?rev=v1", "?tag=v1.0", "?branch=main", "?branch=main&rev=abc"];
+#[cfg(test)]
+const URL_PIECES: &[&str] = &[
+    "git+", "https://", "ssh://git@", "file:///", "data:", "github.com", ":", "/", "//", ".git", "?", "&", "#", "rev=", "%",
+    "%zz", "@", "[", "]", "é", "\\", " ", "\0", "..",
+];
+#[cfg(test)]
+const LOCKFILE_JUNK: &[&str] =
+    &["\"", "[", "]]", "=", "\n", "#", "version = 99", "checksum = \"00\"", "é", "source = 1", "[[package]]"];
+/// Where the manifests of the walked repositories are, `..` leaving it
+#[cfg(test)]
+const MANIFEST_DIRS: &[&str] = &["", "a", "a/b", "crates/c", "../outside"];
+#[cfg(test)]
+const DEPENDENCY_PATHS: &[&str] =
+    &[".", "a", "a/b", "../a", "crates/c", "crates/*", "missing", "..", "/", "../outside", "a/../crates/c", "a/b/../../.."];
+
+/// A manifest in `MANIFEST_DIRS[dir]`, mostly of the package `p{dir}` and of a
+/// workspace, with path dependencies mostly on the other manifests, as well
+/// as anywhere else, and sometimes something broken appended
+#[cfg(test)]
+fn arbitrary_manifest(dir: usize) -> impl proptest::strategy::Strategy<Value = String> {
+    use proptest::prelude::*;
+    use proptest::sample::select;
+
+    let name = prop_oneof![3 => Just(Some(dir)), 1 => proptest::option::of(0..MANIFEST_DIRS.len())];
+    let path = proptest::option::weighted(0.25, select(DEPENDENCY_PATHS));
+    let dependency = (0..MANIFEST_DIRS.len(), path, 0..3usize);
+    let member = prop_oneof![select(MANIFEST_DIRS), select(DEPENDENCY_PATHS)];
+    (
+        name,
+        proptest::collection::vec(dependency, 0..4),
+        proptest::option::weighted(0.3, proptest::collection::vec(member, 0..3)),
+        proptest::option::weighted(0.1, select(LOCKFILE_JUNK)),
+    )
+        .prop_map(move |(name, dependencies, members, junk)| {
+            let mut manifest = name.map(|name| format!("[package]\nname = \"p{name}\"\nversion = \"0.1.0\"\n\n")).unwrap_or_default();
+            manifest += "[dependencies]\n";
+            for (i, (dependency, path, how)) in dependencies.into_iter().enumerate() {
+                // The path from this manifest to the one of the dependency, or anywhere
+                let path = path.map(str::to_string).unwrap_or_else(|| {
+                    let (from, to) = (Path::new("/repo").join(MANIFEST_DIRS[dir]), Path::new("/repo").join(MANIFEST_DIRS[dependency]));
+                    pathdiff::diff_paths(normalize_path(&to), normalize_path(&from)).unwrap().display().to_string()
+                });
+                manifest += &match how {
+                    0 => format!("p{dependency} = {{ path = \"{path}\" }}\n"),
+                    1 => format!("alias{i} = {{ package = \"p{dependency}\", path = \"{path}\" }}\n"),
+                    _ => format!("p{dependency} = {{ workspace = true }}\n"),
+                };
+            }
+            if let Some(members) = members {
+                let members: Vec<_> = members.iter().map(|member| format!("\"{member}\"")).collect();
+                manifest += &format!("\n[workspace]\nmembers = [{}]\n\n[workspace.dependencies]\np1 = {{ path = \"a\" }}\n", members.join(", "));
+            }
+            manifest + junk.unwrap_or_default()
+        })
+}
+
+#[cfg(test)]
+proptest::proptest! {
+    /// Well-formed sources canonicalize to the repository, whatever they are decorated with
+    #[test]
+    fn parse_url_canonicalizes(
+        git_prefix: bool,
+        scheme in proptest::sample::select(URL_SCHEMES),
+        host in proptest::sample::select(URL_HOSTS),
+        segments in proptest::collection::vec(proptest::sample::select(URL_SEGMENTS), 1..4),
+        suffix in proptest::sample::select(URL_SUFFIXES),
+        query in proptest::sample::select(URL_QUERIES),
+    ) {
+        let commit = "0123456789abcdef0123456789abcdef01234567";
+        let path = segments.join("/");
+        let source = format!("{}{scheme}://{host}/{path}{suffix}{query}#{commit}", if git_prefix { "git+" } else { "" });
+
+        let (canonical, vendored) = parse_url(&source).unwrap_or_else(|e| panic!("{source}: {e}"));
+        proptest::prop_assert_eq!(canonical.as_str(), format!("{scheme}://{host}/{path}"));
+        proptest::prop_assert!(canonical.host().is_some());
+        proptest::prop_assert_eq!(&vendored["git"], canonical.as_str());
+        proptest::prop_assert_eq!(&vendored["replace-with"], VENDORED_SOURCES);
+        let reference = query.strip_prefix('?').map(|query| query.split('&').next_back().unwrap());
+        let reference = reference.and_then(|reference| reference.split_once('='));
+        proptest::prop_assert_eq!(reference.map(|(key, _)| (key, vendored[key].as_str())), reference);
+        proptest::prop_assert_eq!(git_reference(&source).map(|(url, _)| url), Some(canonical.to_string()));
+    }
+
+    /// Anything else never panics, and is either an error or a canonical URL
+    #[test]
+    fn parse_url_never_panics(pieces in proptest::collection::vec(proptest::sample::select(URL_PIECES), 0..12), tail in "\\PC{0,16}") {
+        let url = pieces.concat() + &tail;
+        if let Ok((canonical, vendored)) = parse_url(&url) {
+            proptest::prop_assert_eq!((canonical.query(), canonical.fragment()), (None, None));
+            proptest::prop_assert_eq!(&vendored["git"], canonical.as_str());
+        }
+        let _ = git_reference(&url);
+    }
+
+    /// Lines of a real lockfile dropped, duplicated or swapped, junk spliced in
+    /// and the end cut off parse or fail, and never panic
+    #[test]
+    fn lockfile_parsing_never_panics(
+        mutations in proptest::collection::vec(
+            (0..4usize, proptest::prelude::any::<proptest::sample::Index>(), proptest::prelude::any::<proptest::sample::Index>(), proptest::sample::select(LOCKFILE_JUNK)),
+            1..5,
+        ),
+        truncate in proptest::option::of(proptest::prelude::any::<proptest::sample::Index>()),
+    ) {
+        let src = std::fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.lock")).unwrap();
+        let git = "\n[[package]]\nname = \"foo\"\nversion = \"0.1.0\"\n\
+                   source = \"git+https://github.com/example/foo?rev=v1#0123456789abcdef0123456789abcdef01234567\"\n";
+        let src = format!("{src}{git}");
+        let mut mutated: Vec<_> = src.lines().collect();
+        for (mutation, i, j, junk) in mutations {
+            let (i, j) = (i.index(mutated.len()), j.index(mutated.len()));
+            match mutation {
+                0 => drop(mutated.remove(i)),
+                1 => mutated.insert(i, mutated[j]),
+                2 => mutated.swap(i, j),
+                _ => mutated.insert(i, junk),
+            }
+        }
+        let mut mutated = mutated.join("\n");
+        if let Some(end) = truncate.filter(|_| !mutated.is_empty()) {
+            let mut end = end.index(mutated.len());
+            while !mutated.is_char_boundary(end) {
+                end -= 1;
+            }
+            mutated.truncate(end);
+        }
+        if let Ok(lock) = toml::from_str::<LockFile>(&mutated) {
+            for source in lock.package.iter().filter_map(|package| package.source.as_deref()) {
+                let _ = git_reference(source);
+            }
+        }
+    }
+
+    /// Walking the manifests of a repository, however they depend on each other
+    /// and wherever they point, fails or finds packages inside of it
+    #[test]
+    fn manifest_walk_stays_in_repository(
+        root in arbitrary_manifest(0),
+        manifests in (1..MANIFEST_DIRS.len()).map(|dir| proptest::option::weighted(0.8, arbitrary_manifest(dir))).collect::<Vec<_>>(),
+    ) {
+        let tmp = tempfile::tempdir().unwrap();
+        let repo = tmp.path().join("repo");
+        let mut files = vec![("Cargo.toml".to_string(), root.clone())];
+        for (dir, manifest) in MANIFEST_DIRS[1..].iter().zip(manifests) {
+            files.extend(manifest.map(|manifest| (format!("{dir}/Cargo.toml"), manifest)));
+        }
+        write_fixture(&repo, &files.iter().map(|(path, contents)| (path.as_str(), contents.as_str())).collect::<Vec<_>>());
+
+        if let Ok(packages) = get_cargo_toml_packages(&root, &repo, &repo, 8) {
+            for (name, package) in &packages {
+                let path = package.path.as_std_path();
+                proptest::prop_assert!(path.is_relative() && !path.starts_with(".."), "{name}: {path:?}");
+                proptest::prop_assert!(repo.join(path).join("Cargo.toml").is_file(), "{name}: {path:?}");
+                proptest::prop_assert_eq!(package_name(&package.package), Some(name.as_str()));
+            }
+        }
+    }
+}
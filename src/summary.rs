@@ -0,0 +1,224 @@
+use std::collections::BTreeMap;
+
+use cargo_metadata::semver::Version;
+
+use crate::sources::{Source, SourceSet};
+use crate::COMMIT_LEN;
+
+/// A crate of a sources file: its name, version and the commit of a git crate
+#[derive(Debug, Clone, PartialEq)]
+struct Vendored {
+    name: String,
+    version: String,
+    commit: Option<String>,
+}
+
+impl Vendored {
+    fn describe(&self) -> String {
+        match &self.commit {
+            Some(commit) => format!("{} (`{commit}`)", self.version),
+            None => self.version.clone(),
+        }
+    }
+}
+
+/// The crates of `sources` by owner. Registry crates are named after their
+/// directory, `<name>-<version>`, git crates read from the manifest written
+/// for them; the index files of a local registry aren't crates.
+fn vendored(sources: &SourceSet) -> BTreeMap<&str, Vendored> {
+    let split_owner = |owner: &str| {
+        owner.match_indices('-').find_map(|(i, _)| {
+            Version::parse(&owner[i + 1..]).ok().map(|_| (owner[..i].to_string(), owner[i + 1..].to_string()))
+        })
+    };
+    let clones: Vec<_> = sources
+        .sources()
+        .into_iter()
+        .filter_map(|source| match source {
+            Source::Git(git) => Some((format!("\"{}/", git.dest), git.commit.abbrev(COMMIT_LEN).to_string())),
+            _ => None,
+        })
+        .collect();
+    let mut crates = BTreeMap::new();
+    let mut commits = BTreeMap::new();
+    for entry in sources.entries().iter().filter(|entry| entry.kind.is_some()) {
+        match &entry.source {
+            Source::Git(git) => {
+                commits.insert(entry.owner.as_str(), git.commit.abbrev(COMMIT_LEN).to_string());
+            }
+            // A crate copied out of a clone in the sources of another crate from the repository
+            Source::Shell(shell) => {
+                if let Some((_, commit)) = clones.iter().find(|(dest, _)| shell.commands.iter().any(|c| c.contains(dest))) {
+                    commits.insert(entry.owner.as_str(), commit.clone());
+                }
+            }
+            Source::Inline(inline) if inline.dest_filename == "Cargo.toml" => {
+                let manifest: toml::Value = toml::from_str(&inline.contents).unwrap_or(toml::Value::Table(Default::default()));
+                let field = |key: &str| manifest.get("package")?.get(key)?.as_str().map(String::from);
+                if let (Some(name), Some(version)) = (field("name"), field("version")) {
+                    crates.insert(entry.owner.as_str(), Vendored { name, version, commit: None });
+                }
+            }
+            _ => {}
+        }
+        if !crates.contains_key(entry.owner.as_str()) {
+            if let Some((name, version)) = split_owner(&entry.owner) {
+                crates.insert(entry.owner.as_str(), Vendored { name, version, commit: None });
+            }
+        }
+    }
+    for (owner, commit) in commits {
+        if let Some(vendored) = crates.get_mut(owner) {
+            vendored.commit = Some(commit);
+        }
+    }
+    crates
+}
+
+/// How a crate changed from one sources file to the next
+#[derive(Debug, PartialEq)]
+enum Change {
+    Added(Vendored),
+    Removed(Vendored),
+    Updated(Vendored, Vendored),
+}
+
+impl Change {
+    fn name(&self) -> &str {
+        match self {
+            Change::Added(new) | Change::Updated(_, new) => &new.name,
+            Change::Removed(old) => &old.name,
+        }
+    }
+}
+
+/// The crates `diff` tells changed from `old` to `new`, where a crate
+/// removed at one version and added at another was updated
+fn changes(old: &SourceSet, new: &SourceSet) -> Vec<Change> {
+    let diff = old.diff(new);
+    let (old_crates, new_crates) = (vendored(old), vendored(new));
+    let mut removed: Vec<_> = diff.removed.iter().filter_map(|owner| old_crates.get(owner.as_str())).collect();
+    let mut changes = Vec::new();
+    for owner in &diff.added {
+        let Some(added) = new_crates.get(owner.as_str()) else {
+            continue;
+        };
+        match removed.iter().position(|old| old.name == added.name) {
+            Some(i) => changes.push(Change::Updated(removed.remove(i).clone(), added.clone())),
+            None => changes.push(Change::Added(added.clone())),
+        }
+    }
+    changes.extend(removed.into_iter().map(|old| Change::Removed(old.clone())));
+    for owner in &diff.changed {
+        if let (Some(old), Some(new)) = (old_crates.get(owner.as_str()), new_crates.get(owner.as_str())) {
+            changes.push(Change::Updated(old.clone(), new.clone()));
+        }
+    }
+    changes.sort_by(|a, b| a.name().cmp(b.name()));
+    changes
+}
+
+/// The git repositories cloned at another commit in `new` than in `old`
+fn new_commits(old: &SourceSet, new: &SourceSet) -> Vec<String> {
+    let clones = |sources: &SourceSet| -> BTreeMap<String, Vec<String>> {
+        let mut clones: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for source in sources.sources() {
+            if let Source::Git(git) = source {
+                let commits = clones.entry(git.url.clone()).or_default();
+                let commit = git.commit.abbrev(COMMIT_LEN).to_string();
+                if !commits.contains(&commit) {
+                    commits.push(commit);
+                }
+            }
+        }
+        clones
+    };
+    let (old, new) = (clones(old), clones(new));
+    let mut lines = Vec::new();
+    for (url, commits) in &new {
+        let previous = old.get(url);
+        for commit in commits.iter().filter(|commit| !previous.is_some_and(|previous| previous.contains(commit))) {
+            lines.push(match previous {
+                Some(previous) => format!("- {url}: `{}` → `{commit}`", previous.join("`, `")),
+                None => format!("- {url}: `{commit}`, newly cloned"),
+            });
+        }
+    }
+    lines
+}
+
+/// A Markdown comment on the changes from the sources file `old` to `new`: a
+/// table of the crates added, removed and updated, the totals, and the new
+/// commits of git repositories in a collapsed section
+pub fn markdown(old: &SourceSet, new: &SourceSet) -> String {
+    let changes = changes(old, new);
+    let mut markdown = String::from("### Cargo sources\n\n");
+    if changes.is_empty() {
+        markdown += "No crates changed.\n";
+    } else {
+        markdown += "| Crate | Change | Old | New |\n|---|---|---|---|\n";
+        for change in &changes {
+            let (kind, old, new) = match change {
+                Change::Added(new) => ("added", String::new(), new.describe()),
+                Change::Removed(old) => ("removed", old.describe(), String::new()),
+                Change::Updated(old, new) => ("updated", old.describe(), new.describe()),
+            };
+            markdown += &format!("| {} | {kind} | {old} | {new} |\n", change.name());
+        }
+        let count = |kind: fn(&Change) -> bool| changes.iter().filter(|c| kind(c)).count();
+        markdown += &format!(
+            "\n**{} added, {} removed, {} updated**\n",
+            count(|c| matches!(c, Change::Added(_))),
+            count(|c| matches!(c, Change::Removed(_))),
+            count(|c| matches!(c, Change::Updated(..))),
+        );
+    }
+    let commits = new_commits(old, new);
+    if !commits.is_empty() {
+        markdown += &format!("\n<details>\n<summary>New git commits</summary>\n\n{}\n\n</details>\n", commits.join("\n"));
+    }
+    markdown
+}
+
+#[test]
+fn markdown_summary() {
+    use clap::Parser;
+
+    use crate::sources::Package;
+
+    let tmp = tempfile::tempdir().unwrap();
+    std::fs::write(tmp.path().join("Cargo.toml"), "[workspace]\nmembers = [\"gtk4\"]\n").unwrap();
+    std::fs::create_dir(tmp.path().join("gtk4")).unwrap();
+    std::fs::write(tmp.path().join("gtk4/Cargo.toml"), "[package]\nname = \"gtk4\"\nversion = \"0.9.0\"\n").unwrap();
+    let manifest = tmp.path().join("gtk4/Cargo.toml").to_str().unwrap().to_string();
+    let args = crate::cli::Args::parse_from(["flatpak"]);
+    let checksum = crate::sources::FIXTURE_CHECKSUM;
+    let sources = |packages: &[(&str, &str)], gtk4_commit: &str| {
+        let mut sources = SourceSet::new(&args.vendor_dir());
+        for (name, version) in packages {
+            let package = Package {
+                name: name.to_string(),
+                version: version.to_string(),
+                source: Some("registry+https://github.com/rust-lang/crates.io-index".into()),
+                checksum: Some(checksum.try_into().unwrap()),
+                checksum_from_index: false,
+                dependencies: None,
+            };
+            sources.push_package(&package, None, &args).unwrap();
+        }
+        let gtk4 = Package {
+            name: "gtk4".into(),
+            version: "0.9.0".into(),
+            source: Some(format!("git+https://github.com/gtk-rs/gtk4-rs?branch=main#{gtk4_commit}")),
+            checksum: None,
+            checksum_from_index: false,
+            dependencies: None,
+        };
+        sources.push_package(&gtk4, Some(&manifest), &args).unwrap();
+        sources
+    };
+    let old = sources(&[("anstream", "0.6.14"), ("url", "2.5.0")], "0123456789abcdef0123456789abcdef01234567");
+    let new = sources(&[("anstream", "0.6.15"), ("log", "0.4.22")], "89abcdef0123456789abcdef0123456789abcdef");
+    assert_eq!(markdown(&old, &new), include_str!("testdata/summary.md"));
+    assert_eq!(markdown(&new, &new), "### Cargo sources\n\nNo crates changed.\n");
+}
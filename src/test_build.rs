@@ -0,0 +1,223 @@
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use anyhow::Context;
+
+use crate::cli::Args;
+use crate::sources::SourceSet;
+
+/// What the project copy leaves out: the build output and the repository
+const SKIPPED: [&str; 2] = ["target", ".git"];
+
+/// Copies the tree at `from` to `to`, symlinks as symlinks, leaving out the
+/// entries named in `skipped`
+fn copy_tree(from: &Path, to: &Path, skipped: &[&str]) -> anyhow::Result<()> {
+    std::fs::create_dir_all(to)?;
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        if skipped.iter().any(|skipped| entry.file_name() == *skipped) {
+            continue;
+        }
+        let (from, to) = (entry.path(), to.join(entry.file_name()));
+        let file_type = entry.file_type()?;
+        if file_type.is_symlink() {
+            std::os::unix::fs::symlink(std::fs::read_link(&from)?, &to)?;
+        } else if file_type.is_dir() {
+            copy_tree(&from, &to, &[])?;
+        } else {
+            std::fs::copy(&from, &to)?;
+        }
+    }
+    Ok(())
+}
+
+/// A new directory in the system's temporary directory, named after `purpose`
+pub fn temp_dir(purpose: &str) -> anyhow::Result<PathBuf> {
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+    loop {
+        let count = COUNT.fetch_add(1, Ordering::Relaxed);
+        let dir = std::env::temp_dir().join(format!("cargo-flatpak-{purpose}-{}-{count}", std::process::id()));
+        match std::fs::create_dir(&dir) {
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            created => return Ok(created.map(|_| dir)?),
+        }
+    }
+}
+
+/// What a test build cargo failed fails with. Cargo has already told why.
+#[derive(Debug)]
+pub struct BuildFailed(pub ExitStatus);
+
+impl BuildFailed {
+    /// Cargo's exit code, to exit with, or 1 when a signal ended it
+    pub fn exit_code(&self) -> u8 {
+        self.0.code().and_then(|code| u8::try_from(code).ok()).filter(|&code| code != 0).unwrap_or(1)
+    }
+}
+
+impl std::fmt::Display for BuildFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "the test build failed with {}", self.0)
+    }
+}
+
+impl std::error::Error for BuildFailed {}
+
+/// Lays `sources` out with the vendor script the way flatpak-builder would,
+/// over a copy of `workspace` in a temporary build directory, then runs cargo
+/// offline against them: `cargo check`, or with `quick` only `cargo metadata`.
+/// Cargo's output goes to the terminal, and its status is returned. The
+/// directory is removed unless `keep` is set.
+pub fn test_build(
+    sources: &SourceSet,
+    workspace: &Path,
+    manifest_dir: &Path,
+    args: &Args,
+    keep: bool,
+    quick: bool,
+) -> anyhow::Result<ExitStatus> {
+    let dir = temp_dir("test-build")?;
+    let status = build_in(&dir, sources, workspace, manifest_dir, args, quick);
+    match keep {
+        true => crate::diagnostics::note("test-build", format!("kept the build directory {}", dir.join("build").display())),
+        false => {
+            let _ = std::fs::remove_dir_all(&dir);
+        }
+    }
+    status
+}
+
+fn build_in(
+    dir: &Path,
+    sources: &SourceSet,
+    workspace: &Path,
+    manifest_dir: &Path,
+    args: &Args,
+    quick: bool,
+) -> anyhow::Result<ExitStatus> {
+    let build = dir.join("build");
+    // Without the trailing `.` of the default destination, which create_dir_all trips on
+    let project: PathBuf = build.join(args.dest(".")).components().collect();
+    copy_tree(workspace, &project, &SKIPPED).with_context(|| format!("failed to copy {}", workspace.display()))?;
+
+    let script = dir.join("vendor.sh");
+    std::fs::write(&script, crate::script::vendor_script(sources, manifest_dir)?)?;
+    let status = Command::new("bash").arg(&script).current_dir(&build).status().context("failed to run bash")?;
+    if !status.success() {
+        anyhow::bail!("laying the sources out failed with {status}");
+    }
+
+    let mut command = Command::new(crate::toolchain::cargo());
+    match quick {
+        true => command.args(["metadata", "--offline", "--locked", "--format-version", "1"]).stdout(std::process::Stdio::null()),
+        false => command.args(["check", "--offline", "--locked"]),
+    };
+    command
+        .current_dir(&project)
+        .env("CARGO_HOME", build.join(args.cargo_home_dir()))
+        .env("CARGO_TARGET_DIR", dir.join("target"))
+        .status()
+        .context("failed to run cargo")
+}
+
+#[test]
+fn vendored_project_builds() {
+    use clap::Parser;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let (metadata, cargo_lock, project, template) = crate::generate::packed_crates_fixture(tmp.path());
+    let args = Args::parse_from(["flatpak", "--crate-url-template", &template, "--include-lockfile"]);
+    let output = project.join("cargo-sources.json");
+    let generated = crate::generate::generate(&args, &metadata, &cargo_lock, "hash".into(), &output).unwrap();
+    for quick in [false, true] {
+        let status = test_build(&generated, &project, &project, &args, false, quick).unwrap();
+        assert!(status.success());
+    }
+    // Nothing vendored, nothing to build against
+    let empty = SourceSet::from_sources(Vec::new()).unwrap();
+    assert!(!test_build(&empty, &project, &project, &args, false, true).unwrap().success());
+    // The project itself is left alone
+    assert!(!project.join("cargo").exists());
+    assert!(!project.join("Cargo.lock").exists());
+}
+
+#[test]
+fn git_dependency_builds() {
+    use clap::Parser;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let (remote, project) = (tmp.path().join("remote"), tmp.path().join("app"));
+    crate::sources::write_fixture(
+        &remote,
+        &[
+            ("Cargo.toml", "[package]\nname = \"dep\"\nversion = \"0.1.0\"\n"),
+            ("src/lib.rs", "pub const DEP: u32 = 1;\n"),
+        ],
+    );
+    let git = |args: &[&str]| {
+        let output = Command::new("git").args(args).current_dir(&remote).output().unwrap();
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    };
+    git(&["init", "-q"]);
+    git(&["add", "."]);
+    git(&["-c", "user.name=test", "-c", "user.email=test@example.com", "commit", "-q", "-m", "dep"]);
+    let url = url::Url::from_directory_path(&remote).unwrap();
+    let manifest = format!("[package]\nname = \"app\"\nversion = \"0.1.0\"\n\n[dependencies]\ndep = {{ git = \"{url}\" }}\n");
+    crate::sources::write_fixture(
+        &project,
+        &[("Cargo.toml", manifest.as_str()), ("src/main.rs", "const _: () = assert!(dep::DEP == 1);\n\nfn main() {}\n")],
+    );
+    // Cargo clones the dependency into a cargo home of its own
+    let cargo_home = tmp.path().join("cargo-home");
+    let metadata = crate::toolchain::metadata_command(&crate::toolchain::cargo())
+        .current_dir(&project)
+        .env("CARGO_HOME", &cargo_home)
+        .exec()
+        .unwrap();
+    let cargo_lock = std::fs::read_to_string(project.join("Cargo.lock")).unwrap();
+
+    let args = Args::parse_from(["flatpak", "--include-lockfile"]);
+    let output = project.join("cargo-sources.json");
+    let generated = crate::generate::generate(&args, &metadata, &cargo_lock, "hash".into(), &output).unwrap();
+    assert!(test_build(&generated, &project, &project, &args, false, false).unwrap().success());
+
+    // Cargo's own exit code is passed on
+    std::fs::write(project.join("src/main.rs"), "const _: () = assert!(dep::DEP == 2);\n\nfn main() {}\n").unwrap();
+    let status = test_build(&generated, &project, &project, &args, false, false).unwrap();
+    assert_eq!(BuildFailed(status).exit_code(), 101);
+}
+
+#[test]
+fn path_dependency_builds() {
+    use clap::Parser;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let project = tmp.path().join("app");
+    crate::sources::write_fixture(
+        tmp.path(),
+        &[
+            ("libs/shared/Cargo.toml", "[package]\nname = \"shared\"\nversion = \"0.1.0\"\n"),
+            ("libs/shared/src/lib.rs", "pub const SHARED: u32 = 1;\n"),
+            (
+                "app/Cargo.toml",
+                "[package]\nname = \"app\"\nversion = \"0.1.0\"\n\n[dependencies]\nshared = { path = \"../libs/shared\" }\n",
+            ),
+            ("app/src/main.rs", "const _: () = assert!(shared::SHARED == 1);\n\nfn main() {}\n"),
+        ],
+    );
+    let metadata = crate::toolchain::metadata_command(&crate::toolchain::cargo())
+        .current_dir(&project)
+        .other_options(vec!["--offline".into()])
+        .exec()
+        .unwrap();
+    let cargo_lock = std::fs::read_to_string(project.join("Cargo.lock")).unwrap();
+
+    // The workspace goes a directory down, for the dependency to be next to it
+    let args = Args::parse_from(["flatpak", "--bundle-path-deps", "--dest-prefix", "src/app", "--include-lockfile"]);
+    let output = project.join("cargo-sources.json");
+    let generated = crate::generate::generate(&args, &metadata, &cargo_lock, "hash".into(), &output).unwrap();
+    let dirs: Vec<_> = generated.sources().into_iter().filter(|source| matches!(source, crate::sources::Source::Dir(_))).collect();
+    assert_eq!(serde_json::to_value(dirs).unwrap(), serde_json::json!([{"type": "dir", "path": "../libs/shared", "dest": "src/libs/shared"}]));
+    assert!(test_build(&generated, &project, &project, &args, false, false).unwrap().success());
+}
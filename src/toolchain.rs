@@ -0,0 +1,183 @@
+use std::path::{Path, PathBuf};
+
+use cargo_metadata::semver::Version;
+use cargo_metadata::MetadataCommand;
+
+use crate::diagnostics::{self, Diagnostic};
+
+/// The SDK extension building with the latest stable rust of its branch
+pub const RUST_STABLE: &str = "rust-stable";
+/// The SDK extension building with a nightly rust
+pub const RUST_NIGHTLY: &str = "rust-nightly";
+
+/// A toolchain other than stable pinned by the workspace's rustup toolchain file
+#[derive(Debug, Clone, PartialEq)]
+pub struct Toolchain {
+    /// `rust-toolchain` or `rust-toolchain.toml`
+    pub file: &'static str,
+    /// What the file pins, like `nightly-2024-05-01` or `1.75.0`
+    pub channel: String,
+}
+
+impl Toolchain {
+    pub fn is_nightly(&self) -> bool {
+        self.channel.starts_with("nightly")
+    }
+
+    /// The SDK extension closest to the pin, whose rust still isn't exactly it
+    pub fn sdk_extension(&self) -> &'static str {
+        match self.is_nightly() {
+            true => RUST_NIGHTLY,
+            false => RUST_STABLE,
+        }
+    }
+
+    /// Warns that the flatpak build doesn't use the pinned toolchain
+    pub fn warn(&self) {
+        let (message, suggestion) = match self.is_nightly() {
+            true => (
+                format!("{} pins the toolchain {}, the build needs the rust-nightly SDK extension instead of rust-stable, whose nightly may be of another date", self.file, self.channel),
+                "add org.freedesktop.Sdk.Extension.rust-nightly to the sdk-extensions of the manifest",
+            ),
+            false => (
+                format!("{} pins the toolchain {}, but the rust-stable SDK extension builds with the latest stable of its branch", self.file, self.channel),
+                "pick the runtime version whose rust-stable has it, or pass --ignore-toolchain",
+            ),
+        };
+        diagnostics::emit(Diagnostic::warning("rust-toolchain", message).with_suggestion(suggestion));
+    }
+}
+
+/// The toolchain the rustup toolchain file at the root of `workspace` pins,
+/// `None` without one, or when it's stable. `rust-toolchain` comes first, as
+/// with rustup, and has the channel alone or the TOML of `rust-toolchain.toml`.
+pub fn pinned(workspace: &Path) -> anyhow::Result<Option<Toolchain>> {
+    for file in ["rust-toolchain", "rust-toolchain.toml"] {
+        let Ok(contents) = std::fs::read_to_string(workspace.join(file)) else {
+            continue;
+        };
+        let channel = match toml::from_str::<toml::Table>(&contents) {
+            Ok(table) => match table.get("toolchain").and_then(|t| t.get("channel")) {
+                Some(toml::Value::String(channel)) => channel.clone(),
+                Some(_) => anyhow::bail!("the toolchain channel of {} is not a string", workspace.join(file).display()),
+                // Components or targets alone, for the default toolchain
+                None => return Ok(None),
+            },
+            Err(_) if file == "rust-toolchain" => contents.trim().to_string(),
+            Err(e) => anyhow::bail!("failed to parse {}: {}", workspace.join(file).display(), e.message()),
+        };
+        if channel.is_empty() || channel == "stable" {
+            return Ok(None);
+        }
+        return Ok(Some(Toolchain { file, channel }));
+    }
+    Ok(None)
+}
+
+/// The cargo the tool runs: --cargo-path, kept in `CARGO` for the rest of the
+/// run, or the one cargo sets `CARGO` to for its subcommands, or cargo on PATH
+pub fn cargo() -> PathBuf {
+    std::env::var_os("CARGO").map_or_else(|| "cargo".into(), PathBuf::from)
+}
+
+/// `cargo metadata`, run with `cargo`
+pub fn metadata_command(cargo: &Path) -> MetadataCommand {
+    let mut command = MetadataCommand::new();
+    command.cargo_path(cargo);
+    command
+}
+
+/// Where `cargo` is, looked up on PATH when it's a bare name, and its version,
+/// as --verbose reports them
+pub fn describe_cargo(cargo: &Path) -> anyhow::Result<String> {
+    let resolved = match cargo.components().count() {
+        1 => std::env::split_paths(&std::env::var_os("PATH").unwrap_or_default())
+            .map(|dir| dir.join(cargo))
+            .find(|path| path.is_file())
+            .unwrap_or_else(|| cargo.to_path_buf()),
+        _ => cargo.to_path_buf(),
+    };
+    let output = std::process::Command::new(cargo)
+        .arg("--version")
+        .output()
+        .map_err(|e| anyhow::anyhow!("failed to run {}: {e}", cargo.display()))?;
+    if !output.status.success() {
+        anyhow::bail!("`{} --version` failed with {}", cargo.display(), output.status);
+    }
+    Ok(format!("{} ({})", resolved.display(), String::from_utf8_lossy(&output.stdout).trim()))
+}
+
+/// The version of `cargo`, as `cargo --version` reports it
+pub fn cargo_version(cargo: &Path) -> anyhow::Result<Version> {
+    let output = std::process::Command::new(cargo)
+        .arg("--version")
+        .output()
+        .map_err(|e| anyhow::anyhow!("failed to run {}: {e}", cargo.display()))?;
+    if !output.status.success() {
+        anyhow::bail!("`{} --version` failed with {}", cargo.display(), output.status);
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let version = stdout.strip_prefix("cargo ").and_then(|version| version.split_whitespace().next());
+    version
+        .and_then(|version| Version::parse(version).ok())
+        .ok_or_else(|| anyhow::anyhow!("can't tell the version of cargo from `{}`", stdout.trim()))
+}
+
+#[test]
+fn pinned_toolchains() {
+    let tmp = tempfile::tempdir().unwrap();
+    assert_eq!(pinned(tmp.path()).unwrap(), None);
+
+    let toml = tmp.path().join("rust-toolchain.toml");
+    std::fs::write(&toml, "[toolchain]\nchannel = \"nightly-2024-05-01\"\ncomponents = [\"rustfmt\"]\n").unwrap();
+    let toolchain = pinned(tmp.path()).unwrap().unwrap();
+    assert_eq!(toolchain, Toolchain { file: "rust-toolchain.toml", channel: "nightly-2024-05-01".into() });
+    assert_eq!(toolchain.sdk_extension(), RUST_NIGHTLY);
+    let ((), warnings) = diagnostics::capture(diagnostics::ErrorFormat::Human, || toolchain.warn());
+    assert_eq!(
+        warnings,
+        "warning: rust-toolchain.toml pins the toolchain nightly-2024-05-01, the build needs the rust-nightly SDK \
+         extension instead of rust-stable, whose nightly may be of another date\n  \
+         help: add org.freedesktop.Sdk.Extension.rust-nightly to the sdk-extensions of the manifest\n"
+    );
+
+    std::fs::write(&toml, "[toolchain]\nchannel = \"stable\"\n").unwrap();
+    assert_eq!(pinned(tmp.path()).unwrap(), None);
+    std::fs::write(&toml, "[toolchain]\ncomponents = [\"clippy\"]\n").unwrap();
+    assert_eq!(pinned(tmp.path()).unwrap(), None);
+
+    // The legacy file wins, with the channel alone
+    std::fs::write(tmp.path().join("rust-toolchain"), "1.75.0\n").unwrap();
+    let toolchain = pinned(tmp.path()).unwrap().unwrap();
+    assert_eq!(toolchain, Toolchain { file: "rust-toolchain", channel: "1.75.0".into() });
+    assert_eq!(toolchain.sdk_extension(), RUST_STABLE);
+}
+
+#[test]
+fn cargo_path() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let tmp = tempfile::tempdir().unwrap();
+    std::fs::write(tmp.path().join("Cargo.toml"), "[package]\nname = \"app\"\nversion = \"0.1.0\"\n").unwrap();
+    std::fs::create_dir(tmp.path().join("src")).unwrap();
+    std::fs::write(tmp.path().join("src/main.rs"), "fn main() {}\n").unwrap();
+    let calls = tmp.path().join("calls");
+    let wrapper = tmp.path().join("cargo-wrapper");
+    let script = format!("#!/bin/sh\necho \"$1\" >> {}\nexec {} \"$@\"\n", calls.display(), cargo().display());
+    std::fs::write(&wrapper, script).unwrap();
+    std::fs::set_permissions(&wrapper, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let metadata = metadata_command(&wrapper).manifest_path(tmp.path().join("Cargo.toml")).exec().unwrap();
+    assert_eq!(metadata.packages[0].name, "app");
+    let described = describe_cargo(&wrapper).unwrap();
+    assert!(described.starts_with(&format!("{} (cargo ", wrapper.display())), "{described}");
+    assert_eq!(std::fs::read_to_string(&calls).unwrap(), "metadata\n--version\n");
+    assert!(describe_cargo(&tmp.path().join("missing")).is_err());
+
+    // A relative path holds wherever cargo runs, a bare name is left to PATH
+    let relative = pathdiff::diff_paths(&wrapper, std::env::current_dir().unwrap()).unwrap();
+    let parsed = crate::cli::parse_cargo_path(relative.to_str().unwrap()).unwrap();
+    assert_eq!(parsed, wrapper.canonicalize().unwrap());
+    assert_eq!(crate::cli::parse_cargo_path("cargo").unwrap(), Path::new("cargo"));
+    assert!(crate::cli::parse_cargo_path("missing/cargo").unwrap_err().starts_with("missing/cargo: "));
+}
@@ -0,0 +1,44 @@
+use std::path::Path;
+
+use tracing_chrome::{ChromeLayerBuilder, FlushGuard};
+use tracing_subscriber::prelude::*;
+
+/// The subscriber of --trace, writing the spans to `path` as Chrome trace
+/// events, which about://tracing and ui.perfetto.dev open, along with their
+/// fields. The trace is complete once the guard is dropped.
+fn chrome_subscriber(path: &Path) -> (impl tracing::Subscriber + Send + Sync, FlushGuard) {
+    let (layer, guard) = ChromeLayerBuilder::new().file(path).include_args(true).build();
+    (tracing_subscriber::registry().with(layer), guard)
+}
+
+/// Traces the rest of the run to `path`. Without a subscriber the spans are
+/// all but free, so nothing is set up unless --trace is given.
+pub fn start(path: &Path) -> anyhow::Result<FlushGuard> {
+    let (subscriber, guard) = chrome_subscriber(path);
+    tracing::subscriber::set_global_default(subscriber)?;
+    Ok(guard)
+}
+
+#[test]
+fn chrome_trace() {
+    use clap::Parser;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let (metadata, cargo_lock) = crate::generate::fixture_workspace(tmp.path());
+    let args = crate::cli::Args::parse_from(["flatpak"]);
+    let path = tmp.path().join("trace.json");
+    let (subscriber, guard) = chrome_subscriber(&path);
+    tracing::subscriber::with_default(subscriber, || {
+        crate::generate::generate(&args, &metadata, &cargo_lock, "hash".into(), &tmp.path().join("cargo-sources.json")).unwrap();
+    });
+    drop(guard);
+
+    let events: Vec<serde_json::Value> = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+    let began = |name: &str| events.iter().filter(|e| e["ph"] == "B" && e["name"] == name).collect::<Vec<_>>();
+    for phase in ["parse lockfile", "packages", "config"] {
+        assert_eq!(began(phase).len(), 1, "{phase}");
+    }
+    // Slow crates stand out by name
+    let crates: Vec<_> = began("package").iter().map(|e| e["args"]["crate"].as_str().unwrap()).collect();
+    assert_eq!(crates, ["anstream 0.6.15", "url 2.5.0"]);
+}
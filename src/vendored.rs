@@ -0,0 +1,241 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+
+use serde_json::Value;
+
+use crate::sources::{sha256_file, Source, SourceSet};
+
+/// What a crate's sources stage in the build directory
+#[derive(Debug, PartialEq)]
+enum Staged {
+    /// An extracted crate, with the `.cargo-checksum.json` written into it
+    Dir { checksum: Value },
+    /// A `.crate` file, of a local registry or cargo's download cache
+    CrateFile { sha256: String },
+}
+
+/// The crates `sources` stage, by their path in the build directory
+fn staged_crates(sources: &SourceSet) -> BTreeMap<String, (String, Staged)> {
+    let mut crates = BTreeMap::new();
+    for entry in sources.entries().iter().filter(|entry| entry.kind.is_some()) {
+        match &entry.source {
+            Source::Inline(inline) if inline.dest_filename == ".cargo-checksum.json" => {
+                let checksum = serde_json::from_str(&inline.contents).unwrap_or_default();
+                crates.insert(inline.dest.clone(), (entry.owner.clone(), Staged::Dir { checksum }));
+            }
+            Source::File(file) => {
+                let (Some(name), Some(sha256)) = (file.dest_filename.as_deref(), &file.sha256) else {
+                    continue;
+                };
+                if name.ends_with(".crate") {
+                    let sha256 = sha256.as_str().to_string();
+                    crates.insert(format!("{}/{name}", file.dest), (entry.owner.clone(), Staged::CrateFile { sha256 }));
+                }
+            }
+            _ => {}
+        }
+    }
+    crates
+}
+
+/// The directory of `build_dir` the sources were staged in: `build_dir` itself,
+/// or the latest of the module build directories under it that has `dir`, as
+/// in `.flatpak-builder/build/<module>-<n>`
+fn staging_dir(build_dir: &Path, dir: &str) -> anyhow::Result<PathBuf> {
+    let mut level = vec![build_dir.to_path_buf()];
+    for _ in 0..4 {
+        let mut found: Vec<_> = level.iter().filter(|candidate| candidate.join(dir).is_dir()).collect();
+        found.sort_by_key(|candidate| std::fs::metadata(candidate).and_then(|m| m.modified()).ok());
+        if let Some(found) = found.pop() {
+            return Ok(found.clone());
+        }
+        level = level
+            .iter()
+            .filter_map(|dir| std::fs::read_dir(dir).ok())
+            .flatten()
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.is_dir() && !path.is_symlink())
+            .collect();
+    }
+    anyhow::bail!("{} has no {dir} directory, nor do the build directories under it", build_dir.display())
+}
+
+/// What's wrong with the extracted crate at `dir`, whose sources write `expected`
+/// as its `.cargo-checksum.json`
+fn check_dir(dir: &Path, expected: &Value) -> Vec<String> {
+    if !dir.is_dir() {
+        return vec!["missing".into()];
+    }
+    let mut problems = Vec::new();
+    if !dir.join("Cargo.toml").is_file() {
+        problems.push("has no Cargo.toml".into());
+    }
+    let checksum: Value = match std::fs::read_to_string(dir.join(".cargo-checksum.json")).map(|c| serde_json::from_str(&c)) {
+        Ok(Ok(checksum)) => checksum,
+        Ok(Err(e)) => return [problems, vec![format!(".cargo-checksum.json doesn't parse: {e}")]].concat(),
+        Err(_) => return [problems, vec!["has no .cargo-checksum.json".into()]].concat(),
+    };
+    if checksum["package"] != expected["package"] {
+        problems.push(format!("the package checksum is {}, the sources have {}", checksum["package"], expected["package"]));
+    }
+    for (file, sha256) in checksum["files"].as_object().into_iter().flatten() {
+        match sha256_file(&dir.join(file)) {
+            Ok(actual) if Some(actual.as_str()) == sha256.as_str() => {}
+            Ok(_) => problems.push(format!("{file} doesn't match its checksum")),
+            Err(_) => problems.push(format!("{file} is missing")),
+        }
+    }
+    problems
+}
+
+/// The checks of one crate, or of something staged that the sources don't have
+#[derive(Debug, PartialEq)]
+pub struct Finding {
+    pub name: String,
+    /// Empty when the crate is staged as the sources have it
+    pub problems: Vec<String>,
+}
+
+/// Checks the crates staged in `build_dir` against `sources`: each one is
+/// there, extracted crates have the package checksum of the sources and match
+/// the file checksums they list, `.crate` files hash to their checksum, and
+/// nothing else is in their directories
+pub fn check(sources: &SourceSet, build_dir: &Path) -> anyhow::Result<Vec<Finding>> {
+    let crates = staged_crates(sources);
+    let parents: BTreeSet<_> = crates.keys().filter_map(|path| Some(Path::new(path).parent()?.to_path_buf())).collect();
+    let Some(first) = parents.first() else {
+        anyhow::bail!("the sources stage no crates");
+    };
+    let base = staging_dir(build_dir, crate::sources::utf8_path(first)?)?;
+    let mut findings = Vec::new();
+    for (path, (owner, staged)) in &crates {
+        let problems = match staged {
+            Staged::Dir { checksum } => check_dir(&base.join(path), checksum),
+            Staged::CrateFile { sha256 } => match sha256_file(&base.join(path)) {
+                Ok(actual) if &actual == sha256 => Vec::new(),
+                Ok(actual) => vec![format!("the sha256 is {actual}, the sources have {sha256}")],
+                Err(_) => vec!["missing".into()],
+            },
+        };
+        findings.push(Finding { name: owner.clone(), problems });
+    }
+    for parent in &parents {
+        let mut extra: Vec<_> = std::fs::read_dir(base.join(parent))
+            .into_iter()
+            .flatten()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| {
+                let name = entry.file_name().to_string_lossy().to_string();
+                let path = format!("{}/{name}", crate::sources::utf8_path(parent).unwrap_or_default());
+                // The index of a local registry lives next to its crates
+                let packaged = entry.path().is_dir() || name.ends_with(".crate");
+                packaged && name != "index" && !crates.contains_key(&path)
+            })
+            .map(|entry| entry.file_name().to_string_lossy().to_string())
+            .collect();
+        extra.sort();
+        findings.extend(extra.into_iter().map(|name| Finding {
+            name: format!("{}/{name}", parent.display()),
+            problems: vec!["not in the sources".into()],
+        }));
+    }
+    Ok(findings)
+}
+
+/// Prints a line per crate and a summary, failing on any discrepancy
+pub fn verify_vendored(sources: &SourceSet, build_dir: &Path) -> anyhow::Result<()> {
+    let findings = check(sources, build_dir)?;
+    let mut failed = 0;
+    for finding in &findings {
+        match finding.problems.as_slice() {
+            [] => println!("ok    {}", finding.name),
+            problems => {
+                failed += 1;
+                println!("FAIL  {}: {}", finding.name, problems.join(", "));
+            }
+        }
+    }
+    println!("{} checked, {failed} failed", findings.len());
+    if failed > 0 {
+        anyhow::bail!("{failed} vendored crates don't match the sources");
+    }
+    Ok(())
+}
+
+#[test]
+fn vendored_trees() {
+    use clap::Parser;
+
+    use crate::cli::Args;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let (metadata, cargo_lock, build, template) = crate::generate::packed_crates_fixture(tmp.path());
+    let generate = |strategy: &str| {
+        let args = Args::parse_from(["flatpak", "--vendor-strategy", strategy, "--crate-url-template", &template]);
+        crate::generate::generate(&args, &metadata, &cargo_lock, "hash".into(), &build.join("cargo-sources.json")).unwrap()
+    };
+    let stage = |sources: &SourceSet, dir: &Path| {
+        std::fs::create_dir_all(dir).unwrap();
+        let script = dir.join("vendor.sh");
+        std::fs::write(&script, crate::script::vendor_script(sources, dir).unwrap()).unwrap();
+        assert!(std::process::Command::new("bash").arg(&script).current_dir(dir).status().unwrap().success());
+    };
+    let failures = |findings: Vec<Finding>| -> Vec<(String, Vec<String>)> {
+        findings.into_iter().filter(|f| !f.problems.is_empty()).map(|f| (f.name, f.problems)).collect()
+    };
+
+    // Found in the module build directory under the flatpak-builder state directory
+    let sources = generate("directory");
+    let module_dir = tmp.path().join("state/build/app-1");
+    stage(&sources, &module_dir);
+    let findings = check(&sources, &tmp.path().join("state")).unwrap();
+    let names: Vec<_> = findings.iter().map(|f| f.name.as_str()).collect();
+    assert_eq!(names, ["bar-0.1.0", "qux-0.1.0"]);
+    assert_eq!(failures(findings), []);
+    verify_vendored(&sources, &module_dir).unwrap();
+
+    let vendor = module_dir.join("cargo/vendor");
+    std::fs::write(vendor.join("qux-0.1.0/.cargo-checksum.json"), r#"{"package": "0000", "files": {}}"#).unwrap();
+    std::fs::remove_dir_all(vendor.join("bar-0.1.0")).unwrap();
+    std::fs::create_dir(vendor.join("stray-1.0.0")).unwrap();
+    assert_eq!(
+        failures(check(&sources, &module_dir).unwrap()),
+        [
+            ("bar-0.1.0".to_string(), vec!["missing".to_string()]),
+            ("qux-0.1.0".into(), vec![format!("the package checksum is \"0000\", the sources have {}", sources_checksum(&sources, "qux"))]),
+            ("cargo/vendor/stray-1.0.0".into(), vec!["not in the sources".into()]),
+        ]
+    );
+    let err = verify_vendored(&sources, &module_dir).unwrap_err();
+    assert_eq!(err.to_string(), "3 vendored crates don't match the sources");
+
+    // The files cargo vendor lists are hashed too
+    let qux = vendor.join("qux-0.1.0");
+    let package = sources_checksum(&sources, "qux");
+    std::fs::write(qux.join(".cargo-checksum.json"), format!(r#"{{"package": {package}, "files": {{"src/lib.rs": "0000"}}}}"#)).unwrap();
+    assert_eq!(check_dir(&qux, &serde_json::json!({ "package": package })), ["src/lib.rs doesn't match its checksum"]);
+
+    let sources = generate("local-registry");
+    let registry_build = tmp.path().join("registry-build");
+    stage(&sources, &registry_build);
+    assert_eq!(failures(check(&sources, &registry_build).unwrap()), []);
+    std::fs::write(registry_build.join("cargo/local-registry/bar-0.1.0.crate"), "").unwrap();
+    let [(name, problems)] = failures(check(&sources, &registry_build).unwrap()).try_into().unwrap();
+    assert_eq!(name, "bar-0.1.0");
+    assert!(problems[0].starts_with("the sha256 is e3b0c442"), "{problems:?}");
+
+    let err = check(&sources, &tmp.path().join("nowhere")).unwrap_err();
+    assert!(err.to_string().ends_with("has no cargo/local-registry directory, nor do the build directories under it"));
+}
+
+/// The package checksum `sources` write for `name`, as JSON
+#[cfg(test)]
+fn sources_checksum(sources: &SourceSet, name: &str) -> Value {
+    staged_crates(sources)
+        .into_values()
+        .find_map(|(owner, staged)| match staged {
+            Staged::Dir { checksum } if owner.starts_with(&format!("{name}-")) => Some(checksum["package"].clone()),
+            _ => None,
+        })
+        .unwrap()
+}
@@ -0,0 +1,156 @@
+use std::collections::HashMap;
+
+use crate::net;
+
+/// A remote resource referenced by a sources file
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Check {
+    Url(String),
+    Git { url: String, commit: String },
+}
+
+impl std::fmt::Display for Check {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Check::Url(url) => write!(f, "{url}"),
+            Check::Git { url, commit } => write!(f, "{url} @ {commit}"),
+        }
+    }
+}
+
+/// Lists the downloads of a sources file, duplicates removed
+pub fn checks(sources: &serde_json::Value) -> Vec<Check> {
+    let mut checks = Vec::new();
+    for source in sources.as_array().into_iter().flatten() {
+        let field = |key: &str| source.get(key).and_then(|v| v.as_str()).map(String::from);
+        let check = match field("type").as_deref() {
+            Some("archive" | "file") => field("url").map(Check::Url),
+            Some("git") => field("url").zip(field("commit")).map(|(url, commit)| Check::Git { url, commit }),
+            _ => None,
+        };
+        if let Some(check) = check.filter(|c| !checks.contains(c)) {
+            checks.push(check);
+        }
+    }
+    checks
+}
+
+/// HEAD request following redirects, with a ranged GET for servers rejecting HEAD
+pub fn check_url(client: &net::Client, url: &str) -> Result<(), String> {
+    match client.head(url) {
+        Ok(_) => Ok(()),
+        Err(net::RequestError::Status(405 | 403 | 501)) => match client.send(url, |agent| agent.get(url).set("Range", "bytes=0-0")) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(e.to_string()),
+        },
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Fetches `commit` from `url` into a throwaway bare repository. `ls-remote`
+/// would only match it against the ref names, and pass for any commit.
+pub fn check_git(url: &str, commit: &str) -> Result<(), String> {
+    let dir = crate::test_build::temp_dir("verify-git").map_err(|e| e.to_string())?;
+    let result = fetch_commit(&dir, url, commit);
+    let _ = std::fs::remove_dir_all(&dir);
+    result
+}
+
+fn fetch_commit(dir: &std::path::Path, url: &str, commit: &str) -> Result<(), String> {
+    let timeout = net::client().retry().timeout;
+    let mut init = net::git();
+    init.arg("init").arg("-q").arg("--bare").arg(dir);
+    let mut fetch = net::git();
+    fetch.arg("-C").arg(dir).args(["fetch", "-q", "--depth", "1", url, commit]);
+    for command in [&mut init, &mut fetch] {
+        match net::output_with_timeout(command, timeout) {
+            Ok(output) if output.status.success() => {}
+            Ok(output) => return Err(String::from_utf8_lossy(&output.stderr).trim().to_string()),
+            Err(e) => return Err(e.to_string()),
+        }
+    }
+    Ok(())
+}
+
+/// Runs the checks on `jobs` threads
+pub fn run(checks: &[Check], jobs: usize) -> HashMap<Check, Result<(), String>> {
+    let results = net::parallel(checks, jobs, |check| match check {
+        Check::Url(url) => check_url(net::client(), url),
+        Check::Git { url, commit } => check_git(url, commit),
+    });
+    checks.iter().cloned().zip(results).collect()
+}
+
+/// Prints a line per download and a summary, failing if anything is unreachable
+pub fn verify_urls(sources: &serde_json::Value, jobs: usize) -> anyhow::Result<()> {
+    let checks = checks(sources);
+    let results = run(&checks, jobs);
+    let mut failed = 0;
+    for check in &checks {
+        match &results[check] {
+            Ok(()) => println!("ok    {check}"),
+            Err(e) => {
+                failed += 1;
+                println!("FAIL  {check}: {e}");
+            }
+        }
+    }
+    println!("{} checked, {} reachable, {failed} failed", checks.len(), checks.len() - failed);
+    if failed > 0 {
+        anyhow::bail!("{failed} sources are unreachable");
+    }
+    Ok(())
+}
+
+/// Serves canned responses on a local port: `/ok` answers HEAD, `/no-head`
+/// only answers ranged GETs and anything else is a 404
+#[cfg(test)]
+fn test_server() -> String {
+    let (server, _) = crate::index::serve_with(|method, path| match (method, path) {
+        ("HEAD", "/ok") => (200, Vec::new()),
+        ("HEAD", "/no-head") => (405, Vec::new()),
+        ("GET", "/no-head") => (206, Vec::new()),
+        _ => (404, Vec::new()),
+    });
+    server.trim_end_matches('/').to_string()
+}
+
+#[test]
+fn verify_archive_urls() {
+    let server = test_server();
+    let sources = serde_json::json!([
+        {"type": "archive", "url": format!("{server}/ok"), "sha256": "", "dest": "a"},
+        {"type": "archive", "url": format!("{server}/ok"), "sha256": "", "dest": "b"},
+        {"type": "archive", "url": format!("{server}/no-head"), "sha256": "", "dest": "c"},
+        {"type": "archive", "url": format!("{server}/missing"), "sha256": "", "dest": "d"},
+        {"type": "inline", "contents": "", "dest": "e", "dest-filename": "f"},
+    ]);
+
+    let checks = checks(&sources);
+    assert_eq!(checks.len(), 3);
+    let results = run(&checks, 2);
+    assert_eq!(results[&checks[0]], Ok(()));
+    assert_eq!(results[&checks[1]], Ok(()));
+    assert_eq!(results[&checks[2]], Err("HTTP 404".to_string()));
+    assert!(verify_urls(&sources, 2).is_err());
+}
+
+#[test]
+fn verify_git_commit() {
+    let tmp = tempfile::tempdir().unwrap();
+    let git = |args: &[&str]| {
+        let output = std::process::Command::new("git").args(args).current_dir(tmp.path()).output().unwrap();
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        String::from_utf8(output.stdout).unwrap()
+    };
+    git(&["init", "-q"]);
+    git(&["-c", "user.name=test", "-c", "user.email=test@example.com", "commit", "-q", "--allow-empty", "-m", "init"]);
+    let commit = git(&["rev-parse", "HEAD"]).trim().to_string();
+    let url = format!("file://{}", tmp.path().display());
+
+    assert_eq!(check_git(&url, &commit), Ok(()));
+    assert!(check_git(&format!("{url}/missing"), &commit).is_err());
+    // A commit the repository doesn't have, which no ref is named after either
+    let err = check_git(&url, "0123456789abcdef0123456789abcdef01234567").unwrap_err();
+    assert!(err.contains("not our ref"), "{err}");
+}
@@ -0,0 +1,115 @@
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::Duration;
+
+use cargo_metadata::Metadata;
+use notify::{Event, RecursiveMode, Watcher};
+
+/// How long the files must stay untouched before regenerating, `cargo update`
+/// and editors write them more than once
+pub const DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// The files generation reads: Cargo.lock and the manifests of the workspace,
+/// which decide the git and path dependencies
+pub fn watched_files(metadata: &Metadata) -> Vec<PathBuf> {
+    let root = metadata.workspace_root.as_std_path();
+    let mut files = vec![root.join("Cargo.lock"), root.join("Cargo.toml")];
+    for package in metadata.workspace_packages() {
+        let manifest = package.manifest_path.as_std_path().to_path_buf();
+        if !files.contains(&manifest) {
+            files.push(manifest);
+        }
+    }
+    files
+}
+
+/// Blocks until an event touches one of `files`, and then until none has come
+/// for `debounce`. False once the watcher is gone.
+pub fn wait_for_change(events: &Receiver<notify::Result<Event>>, files: &[PathBuf], debounce: Duration) -> bool {
+    let touches = |event: &notify::Result<Event>| match event {
+        // Reading the files, which generation does too, changes nothing
+        Ok(event) => !event.kind.is_access() && event.paths.iter().any(|path| files.contains(path)),
+        // Events may have been lost, a change among them
+        Err(_) => true,
+    };
+    loop {
+        match events.recv() {
+            Ok(event) if touches(&event) => break,
+            Ok(_) => {}
+            Err(_) => return false,
+        }
+    }
+    loop {
+        match events.recv_timeout(debounce) {
+            Ok(_) => {}
+            Err(RecvTimeoutError::Timeout | RecvTimeoutError::Disconnected) => return true,
+        }
+    }
+}
+
+/// Watches the directories of `files`, where an editor replacing a file is seen
+/// too, and stops watching those none of them is in anymore
+fn watch_dirs(watcher: &mut impl Watcher, dirs: &mut Vec<PathBuf>, files: &[PathBuf]) -> anyhow::Result<()> {
+    let mut wanted: Vec<_> = files.iter().filter_map(|file| file.parent()).map(Path::to_path_buf).collect();
+    wanted.sort();
+    wanted.dedup();
+    for dir in dirs.iter().filter(|dir| !wanted.contains(dir)) {
+        let _ = watcher.unwatch(dir);
+    }
+    for dir in wanted.iter().filter(|dir| !dirs.contains(dir)) {
+        watcher.watch(dir, RecursiveMode::NonRecursive).map_err(|e| anyhow::anyhow!("failed to watch {}: {e}", dir.display()))?;
+    }
+    *dirs = wanted;
+    Ok(())
+}
+
+/// Calls `regenerate` after every change of `files` and prints the summary it
+/// returns, then watches the files it returns, which a new workspace member
+/// adds to. A failed regeneration is reported and the watch goes on; Ctrl-C
+/// ends it, and since outputs are replaced atomically it can't leave one half-written.
+pub fn watch(
+    mut files: Vec<PathBuf>,
+    debounce: Duration,
+    mut regenerate: impl FnMut() -> anyhow::Result<(String, Vec<PathBuf>)>,
+) -> anyhow::Result<()> {
+    let (sender, events) = std::sync::mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender)?;
+    let mut dirs = Vec::new();
+    watch_dirs(&mut watcher, &mut dirs, &files)?;
+    crate::diagnostics::note("watch", format!("watching {} files for changes", files.len()));
+    while wait_for_change(&events, &files, debounce) {
+        match regenerate() {
+            Ok((summary, watched)) => {
+                println!("{summary}");
+                files = watched;
+                watch_dirs(&mut watcher, &mut dirs, &files)?;
+            }
+            Err(e) => crate::diagnostics::report(&e),
+        }
+    }
+    anyhow::bail!("the file watcher stopped")
+}
+
+#[test]
+fn debounced_changes() {
+    use notify::event::{AccessKind, CreateKind, EventKind, ModifyKind};
+
+    let files = vec![PathBuf::from("/ws/Cargo.lock"), PathBuf::from("/ws/Cargo.toml")];
+    let event = |kind: EventKind, path: &str| Ok(Event::new(kind).add_path(PathBuf::from(path)));
+    let (sender, events) = std::sync::mpsc::channel();
+    // Reading the files and writing others isn't a change
+    sender.send(event(EventKind::Access(AccessKind::Any), "/ws/Cargo.lock")).unwrap();
+    sender.send(event(EventKind::Modify(ModifyKind::Any), "/ws/cargo-sources.json")).unwrap();
+    // A burst of writes is one change
+    for _ in 0..3 {
+        sender.send(event(EventKind::Modify(ModifyKind::Any), "/ws/Cargo.lock")).unwrap();
+    }
+    assert!(wait_for_change(&events, &files, Duration::from_millis(10)));
+    assert!(events.try_recv().is_err());
+
+    // A manifest appearing counts as a change too
+    sender.send(event(EventKind::Create(CreateKind::File), "/ws/Cargo.toml")).unwrap();
+    drop(sender);
+    assert!(wait_for_change(&events, &files, Duration::from_millis(10)));
+    assert!(!wait_for_change(&events, &files, Duration::from_millis(10)));
+}
@@ -0,0 +1,74 @@
+use std::path::Path;
+use std::process::Command;
+
+fn write(root: &Path, files: &[(&str, &str)]) {
+    for (path, contents) in files {
+        let path = root.join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, contents).unwrap();
+    }
+}
+
+fn run(command: &mut Command) {
+    let output = command.output().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+}
+
+/// The sources generated from a subdirectory of the workspace are the ones
+/// generated from its root, the paths into git checkouts included
+#[test]
+fn generated_from_nested_directory() {
+    let tmp = tempfile::tempdir().unwrap();
+    let cargo_home = tmp.path().join("cargo-home");
+    let git = |args: &[&str]| {
+        let mut git = Command::new("git");
+        git.args(["-c", "user.name=test", "-c", "user.email=test@example.com"]).args(args).current_dir(tmp.path().join("foo"));
+        run(&mut git);
+    };
+    // A git dependency with a path dependency of its own
+    write(
+        &tmp.path().join("foo"),
+        &[
+            ("Cargo.toml", "[package]\nname = \"foo\"\nversion = \"0.1.0\"\n\n[dependencies]\nbar = { path = \"crates/bar\" }\n"),
+            ("src/lib.rs", ""),
+            ("crates/bar/Cargo.toml", "[package]\nname = \"bar\"\nversion = \"0.1.0\"\n"),
+            ("crates/bar/src/lib.rs", ""),
+        ],
+    );
+    git(&["init", "-q"]);
+    git(&["add", "-A"]);
+    git(&["commit", "-q", "-m", "init"]);
+
+    let repo = tmp.path().join("repo");
+    let manifest = format!(
+        "[package]\nname = \"app\"\nversion = \"0.1.0\"\n\n[dependencies]\nfoo = {{ git = \"file://{}\" }}\n\
+         local = {{ path = \"crates/bar\" }}\n\n[workspace]\nmembers = [\"crates/bar\"]\n",
+        tmp.path().join("foo").display()
+    );
+    write(
+        &repo,
+        &[
+            ("Cargo.toml", &manifest),
+            ("src/main.rs", "fn main() {}\n"),
+            ("crates/bar/Cargo.toml", "[package]\nname = \"local\"\nversion = \"0.1.0\"\n"),
+            ("crates/bar/src/lib.rs", ""),
+            ("crates/bar/src/nested/.keep", ""),
+        ],
+    );
+    run(Command::new("cargo").arg("generate-lockfile").current_dir(&repo).env("CARGO_HOME", &cargo_home));
+
+    let generate = |dir: &Path, output: &str| {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_cargo-flatpak"));
+        command
+            .args(["flatpak", "--output", output, "--cache-dir"])
+            .arg(tmp.path().join("cache"))
+            .current_dir(dir)
+            .env("CARGO_HOME", &cargo_home);
+        run(&mut command);
+        std::fs::read_to_string(repo.join(output)).unwrap()
+    };
+    let from_root = generate(&repo, "root.json");
+    let from_nested = generate(&repo.join("crates/bar/src/nested"), "nested.json");
+    assert!(from_root.contains("\"type\": \"git\"") && from_root.contains("/crates/bar\\\" "), "{from_root}");
+    assert_eq!(from_root, from_nested);
+}